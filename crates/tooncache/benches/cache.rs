@@ -1,6 +1,45 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
-use tooncache::ToonCache;
+use tooncache::{LruCache, ToonCache, DEFAULT_SAMPLES, HASH_SEED_ENV};
+
+/// Seed used when `TOONCACHE_HASH_SEED` is not set
+const DEFAULT_BENCH_SEED: u64 = 0x5EED;
+
+/// Reader threads in the contended benchmarks
+const READER_THREADS: u64 = 4;
+
+fn bench_seed() -> u64 {
+    std::env::var(HASH_SEED_ENV)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_BENCH_SEED)
+}
+
+/// Build a cache with a fixed hash seed so runs can be compared
+///
+/// The seed comes from `TOONCACHE_HASH_SEED` if set. Fixing the seed is for
+/// measurement only; production caches keep the random default.
+fn seeded_cache(path: &std::path::Path, capacity: usize) -> ToonCache {
+    ToonCache::with_hash_seed(path, capacity, bench_seed()).unwrap()
+}
+
+/// Time `iters` cached gets split across `READER_THREADS` threads
+fn contended_gets(cache: &ToonCache, ids: &[u64], iters: u64) -> Duration {
+    let per_thread = iters.div_ceil(READER_THREADS);
+    let start = Instant::now();
+    std::thread::scope(|scope| {
+        for t in 0..READER_THREADS {
+            scope.spawn(move || {
+                for i in 0..per_thread {
+                    let id = ids[((i + t * 17) as usize) % ids.len()];
+                    black_box(cache.get(id).unwrap());
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
 
 fn bench_cached_get(c: &mut Criterion) {
     let mut group = c.benchmark_group("cached_get");
@@ -9,7 +48,7 @@ fn bench_cached_get(c: &mut Criterion) {
 
     group.bench_function("get_1kb_cached", |b| {
         let dir = TempDir::new().unwrap();
-        let cache = ToonCache::new(dir.path(), 1000).unwrap();
+        let cache = seeded_cache(dir.path(), 1000);
         let data = vec![b'x'; 1024];
 
         // Pre-populate and warm cache
@@ -40,7 +79,7 @@ fn bench_mixed_50_50(c: &mut Criterion) {
 
     group.bench_function("50_read_50_write_cached", |b| {
         let dir = TempDir::new().unwrap();
-        let cache = ToonCache::new(dir.path(), 1000).unwrap();
+        let cache = seeded_cache(dir.path(), 1000);
         let data = vec![b'x'; 1024];
 
         // Pre-populate
@@ -70,7 +109,7 @@ fn bench_cache_miss(c: &mut Criterion) {
 
     group.bench_function("get_1kb_miss", |b| {
         let dir = TempDir::new().unwrap();
-        let cache = ToonCache::new(dir.path(), 10).unwrap(); // Small cache
+        let cache = seeded_cache(dir.path(), 10); // Small cache
         let data = vec![b'x'; 1024];
 
         // Pre-populate with more than cache size
@@ -90,10 +129,79 @@ fn bench_cache_miss(c: &mut Criterion) {
     group.finish();
 }
 
+fn bench_batch_get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("batch_get");
+    group.sample_size(50);
+    group.throughput(Throughput::Elements(100));
+
+    // Half the rows fit, so every batch has hits and misses
+    let dir = TempDir::new().unwrap();
+    let cache = seeded_cache(dir.path(), 50);
+    let data = vec![b'x'; 1024];
+    let ids: Vec<u64> = (0..100).map(|_| cache.put(&data).unwrap()).collect();
+
+    group.bench_function("get_batch_100", |b| {
+        b.iter(|| black_box(cache.get_batch(&ids)));
+    });
+    group.bench_function("get_100", |b| {
+        b.iter(|| {
+            for &id in &ids {
+                black_box(cache.get(id).unwrap());
+            }
+        });
+    });
+
+    group.finish();
+}
+
+fn bench_contended_get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("contended_get");
+    group.sample_size(20);
+    group.throughput(Throughput::Elements(1));
+
+    let dir = TempDir::new().unwrap();
+    let strict = seeded_cache(dir.path(), 1000);
+    let approx_dir = TempDir::new().unwrap();
+    let approx =
+        ToonCache::with_approx_lru_seed(approx_dir.path(), 1000, DEFAULT_SAMPLES, bench_seed())
+            .unwrap();
+    let data = vec![b'x'; 1024];
+
+    for (name, cache) in [("strict_lru", &strict), ("approx_lru", &approx)] {
+        let ids: Vec<u64> = (0..100).map(|_| cache.put(&data).unwrap()).collect();
+        group.bench_function(name, |b| {
+            b.iter_custom(|iters| contended_gets(cache, &ids, iters));
+        });
+    }
+
+    group.finish();
+}
+
+/// Puts of new keys into a full LRU, each evicting the least recently used
+fn bench_write_churn(c: &mut Criterion) {
+    let mut group = c.benchmark_group("write_churn");
+    group.sample_size(50);
+    group.throughput(Throughput::Elements(1));
+
+    group.bench_function("put_new_full", |b| {
+        let mut lru = LruCache::with_seed(10_000, bench_seed());
+        let mut key = 0u64;
+        b.iter(|| {
+            key += 1;
+            lru.put(key, black_box(key));
+        });
+    });
+
+    group.finish();
+}
+
 criterion_group!(
     benches,
     bench_cached_get,
     bench_mixed_50_50,
-    bench_cache_miss
+    bench_cache_miss,
+    bench_batch_get,
+    bench_contended_get,
+    bench_write_churn
 );
 criterion_main!(benches);
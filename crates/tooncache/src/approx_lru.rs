@@ -0,0 +1,526 @@
+//! Approximate LRU cache evicting by random sampling
+//!
+//! Keeps no recency list: each entry records a last-access stamp from a
+//! shared logical clock, and eviction samples a few random entries and drops
+//! the least recently used of them, as Redis does. `get` therefore needs only
+//! `&self`, so lookups can share a read lock.
+//!
+//! As Redis' LRU clock, the clock is coarse: a ticker thread advances it
+//! every millisecond and `get` only reads it, so concurrent hits do not
+//! fight over one counter. Puts, which hold `&mut self`, also step it, so
+//! writes and the hits between them stay ordered; hits in the same
+//! millisecond with no put between them share a stamp.
+//!
+//! In LFU mode (`with_lfu`) each entry also keeps Redis' logarithmic access
+//! counter: 8 bits that start at `LFU_INIT` and grow ever more rarely as they
+//! rise, so they take about a million hits to saturate. Eviction then drops
+//! the sampled entry with the lowest counter, the least recently used among
+//! equals. Unlike Redis the counters do not decay over time.
+
+use ahash::RandomState;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::lru::{seeded_hasher, EvictCallback, HASH_SEED_ENV};
+
+/// Entries sampled per eviction when none is given (Redis' default)
+pub const DEFAULT_SAMPLES: usize = 5;
+
+/// Access counter of a new entry in LFU mode, so it outlives its first
+/// evictions long enough to be hit (Redis' `LFU_INIT_VAL`)
+pub const LFU_INIT: u8 = 5;
+
+/// Milliseconds counted by the ticker thread, started with the first cache
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Clock steps per tick, room for that many puts in a millisecond to stay
+/// apart before puts run ahead of the ticker
+const TICK_SHIFT: u32 = 20;
+
+/// A hit raises a counter `c` with probability `1 / ((c - LFU_INIT) * LFU_LOG_FACTOR + 1)`
+/// (Redis' default `lfu-log-factor`)
+const LFU_LOG_FACTOR: u64 = 10;
+
+struct Entry<K, V> {
+    key: K,
+    value: V,
+    /// Clock reading at the last `get` or `put`
+    last_access: AtomicU64,
+    /// Number of `get` hits since the key was inserted
+    hits: AtomicU64,
+    /// Logarithmic access counter, kept in LFU mode only
+    frequency: AtomicU8,
+}
+
+/// Fixed-capacity cache evicting the oldest of `samples` random entries
+///
+/// With `samples` at least the number of entries every entry is looked at,
+/// which is exact LRU. Smaller samples trade accuracy for cheaper evictions.
+pub struct ApproxLru<K, V> {
+    map: HashMap<K, usize, RandomState>,
+    /// Dense entry storage, so a random index is a random entry
+    entries: Vec<Entry<K, V>>,
+    /// Reading after the last put; the ticker's reading once it passes that
+    clock: u64,
+    capacity: usize,
+    samples: usize,
+    /// xorshift64 state for sampling
+    rng: u64,
+    /// Evict by access counter rather than recency
+    lfu: bool,
+    on_evict: Option<EvictCallback<K, V>>,
+}
+
+impl<K, V> ApproxLru<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    /// Create a cache sampling `samples` entries per eviction
+    ///
+    /// The hasher is randomly seeded unless `HASH_SEED_ENV` is set.
+    ///
+    /// # Panics
+    /// Panics if `capacity` or `samples` is 0
+    pub fn new(capacity: usize, samples: usize) -> Self {
+        match std::env::var(HASH_SEED_ENV)
+            .ok()
+            .and_then(|s| s.parse().ok())
+        {
+            Some(seed) => Self::with_seed(capacity, samples, seed),
+            None => {
+                let hasher = RandomState::new();
+                let rng = hasher.hash_one(capacity);
+                Self::with_hasher(capacity, samples, hasher, rng)
+            }
+        }
+    }
+
+    /// Create a cache whose hasher and sampling use a fixed seed
+    ///
+    /// For reproducible benchmarks and tests only (see `HASH_SEED_ENV`).
+    pub fn with_seed(capacity: usize, samples: usize, seed: u64) -> Self {
+        Self::with_hasher(capacity, samples, seeded_hasher(seed), seed)
+    }
+
+    fn with_hasher(capacity: usize, samples: usize, hasher: RandomState, rng: u64) -> Self {
+        assert!(capacity > 0, "Capacity must be greater than 0");
+        assert!(samples > 0, "Samples must be greater than 0");
+        start_ticker();
+
+        Self {
+            map: HashMap::with_capacity_and_hasher(capacity, hasher),
+            entries: Vec::with_capacity(capacity),
+            clock: 0,
+            capacity,
+            samples,
+            rng: rng | 1, // xorshift must not start at 0
+            lfu: false,
+            on_evict: None,
+        }
+    }
+
+    /// Evict the least frequently used of the sampled entries instead
+    ///
+    /// Meant right after construction; entries already cached start from
+    /// their current counters, which are only kept from then on.
+    pub fn with_lfu(mut self) -> Self {
+        self.lfu = true;
+        self
+    }
+
+    /// Whether entries are evicted by access counter (see `with_lfu`)
+    pub fn is_lfu(&self) -> bool {
+        self.lfu
+    }
+
+    /// Set a callback fired for every entry evicted to make room
+    ///
+    /// As for `LruCache::set_on_evict`, it does not fire for overwrites,
+    /// `remove` or `clear`.
+    pub fn set_on_evict(&mut self, f: EvictCallback<K, V>) {
+        self.on_evict = Some(f);
+    }
+
+    /// Get a value from the cache, marking it as just used
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let entry = &self.entries[*self.map.get(key)?];
+        let now = self.now();
+        // Hot entries are usually stamped already; skip the shared write
+        if entry.last_access.load(Ordering::Relaxed) != now {
+            entry.last_access.store(now, Ordering::Relaxed);
+        }
+        let hits = entry.hits.fetch_add(1, Ordering::Relaxed);
+        if self.lfu {
+            bump_frequency(&entry.frequency, now.wrapping_add(hits));
+        }
+        Some(&entry.value)
+    }
+
+    /// Access counter of a cached key, None if it is not cached or not in LFU mode
+    pub fn frequency(&self, key: &K) -> Option<u8> {
+        if !self.lfu {
+            return None;
+        }
+        let entry = &self.entries[*self.map.get(key)?];
+        Some(entry.frequency.load(Ordering::Relaxed))
+    }
+
+    /// Check whether a key is cached, without marking it as used
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.map.contains_key(key)
+    }
+
+    /// Insert a key-value pair into the cache, returning the value it replaces
+    pub fn put(&mut self, key: K, value: V) -> Option<V> {
+        let now = self.tick();
+        if let Some(&idx) = self.map.get(&key) {
+            let entry = &mut self.entries[idx];
+            *entry.last_access.get_mut() = now;
+            return Some(std::mem::replace(&mut entry.value, value));
+        }
+
+        if self.entries.len() >= self.capacity {
+            self.evict();
+        }
+        self.map.insert(key.clone(), self.entries.len());
+        self.entries.push(Entry {
+            key,
+            value,
+            last_access: AtomicU64::new(now),
+            hits: AtomicU64::new(0),
+            frequency: AtomicU8::new(LFU_INIT),
+        });
+        None
+    }
+
+    /// Remove a key from the cache
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let idx = self.map.remove(key)?;
+        Some(self.take(idx).value)
+    }
+
+    /// The `n` keys with the most `get` hits, most hit first
+    pub fn hottest(&self, n: usize) -> Vec<(K, u64)> {
+        // n comes from the client, so never size the heap past what it can hold
+        let n = n.min(self.len());
+        if n == 0 {
+            return Vec::new();
+        }
+
+        let mut top = BinaryHeap::with_capacity(n + 1);
+        for (idx, entry) in self.entries.iter().enumerate() {
+            top.push(Reverse((entry.hits.load(Ordering::Relaxed), idx)));
+            if top.len() > n {
+                top.pop();
+            }
+        }
+
+        top.into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((hits, idx))| (self.entries[idx].key.clone(), hits))
+            .collect()
+    }
+
+    /// Get the current size of the cache
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check if the cache is empty
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Get the maximum number of entries
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Change the capacity, evicting sampled entries if shrinking
+    ///
+    /// Returns the number of entries evicted
+    pub fn set_capacity(&mut self, capacity: usize) -> usize {
+        assert!(capacity > 0, "Capacity must be greater than 0");
+
+        let mut evicted = 0;
+        while self.entries.len() > capacity {
+            self.evict();
+            evicted += 1;
+        }
+        self.capacity = capacity;
+
+        evicted
+    }
+
+    /// Get the number of entries sampled per eviction
+    pub fn samples(&self) -> usize {
+        self.samples
+    }
+
+    /// Clear the cache
+    pub fn clear(&mut self) {
+        self.map.clear();
+        self.entries.clear();
+    }
+
+    /// Iterate over the cached values in no particular order
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.entries.iter().map(|entry| &entry.value)
+    }
+
+    /// Clock reading for a hit
+    fn now(&self) -> u64 {
+        self.clock.max(TICKS.load(Ordering::Relaxed) << TICK_SHIFT)
+    }
+
+    /// Clock reading for a put: after every earlier reading, before every later one
+    fn tick(&mut self) -> u64 {
+        let now = self.now() + 1;
+        self.clock = now + 1;
+        now
+    }
+
+    /// Drop the least recently (or in LFU mode, frequently) used of
+    /// `samples` random entries
+    fn evict(&mut self) {
+        let len = self.entries.len();
+        if len == 0 {
+            return;
+        }
+
+        let mut coldest = ((u8::MAX, u64::MAX), 0);
+        for i in 0..self.samples.min(len) {
+            let idx = if self.samples >= len {
+                i
+            } else {
+                (self.next_random() % len as u64) as usize
+            };
+            let entry = &self.entries[idx];
+            let frequency = if self.lfu {
+                entry.frequency.load(Ordering::Relaxed)
+            } else {
+                0
+            };
+            let rank = (frequency, entry.last_access.load(Ordering::Relaxed));
+            if rank < coldest.0 {
+                coldest = (rank, idx);
+            }
+        }
+
+        let idx = coldest.1;
+        self.map.remove(&self.entries[idx].key);
+        let entry = self.take(idx);
+        if let Some(on_evict) = &self.on_evict {
+            on_evict(&entry.key, &entry.value);
+        }
+    }
+
+    /// Remove the entry at `idx`, whose key is already out of the map
+    fn take(&mut self, idx: usize) -> Entry<K, V> {
+        let entry = self.entries.swap_remove(idx);
+        if let Some(moved) = self.entries.get(idx) {
+            self.map.insert(moved.key.clone(), idx);
+        }
+        entry
+    }
+
+    fn next_random(&mut self) -> u64 {
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng = x;
+        x
+    }
+}
+
+/// Start the thread that advances `TICKS`, unless it is running
+fn start_ticker() {
+    static TICKER: OnceLock<()> = OnceLock::new();
+    TICKER.get_or_init(|| {
+        std::thread::Builder::new()
+            .name("approx-lru-clock".to_string())
+            .spawn(|| loop {
+                std::thread::sleep(Duration::from_millis(1));
+                TICKS.fetch_add(1, Ordering::Relaxed);
+            })
+            .expect("failed to spawn the LRU clock thread");
+    });
+}
+
+/// Block until the ticker next advances, so the hits after it stamp later
+#[cfg(test)]
+pub(crate) fn wait_for_tick() {
+    let start = TICKS.load(Ordering::Relaxed);
+    while TICKS.load(Ordering::Relaxed) == start {
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Maybe raise an LFU counter for a hit, given a `seed` that varies per hit
+///
+/// The seed, mixed, stands in for a random number: `get` only has `&self`,
+/// so it cannot advance the sampling RNG. The clock alone repeats between
+/// ticks, so callers add the entry's hit count.
+fn bump_frequency(frequency: &AtomicU8, seed: u64) {
+    let counter = frequency.load(Ordering::Relaxed);
+    if counter == u8::MAX {
+        return;
+    }
+
+    let base = counter.saturating_sub(LFU_INIT) as u64;
+    // splitmix64 finalizer
+    let mut x = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^= x >> 31;
+    if x.is_multiple_of(base * LFU_LOG_FACTOR + 1) {
+        // A racing hit may have raised it already; one raise is enough
+        let _ =
+            frequency.compare_exchange(counter, counter + 1, Ordering::Relaxed, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_approx_lru_basic() {
+        let mut cache = ApproxLru::with_seed(2, DEFAULT_SAMPLES, 1);
+        cache.put(1, "one");
+        cache.put(2, "two");
+
+        assert_eq!(cache.get(&1), Some(&"one"));
+        cache.put(2, "deux");
+        assert_eq!(cache.get(&2), Some(&"deux"));
+        assert_eq!(cache.len(), 2);
+
+        assert_eq!(cache.remove(&1), Some("one"));
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&2), Some(&"deux"));
+
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_approx_lru_full_sample_is_exact() {
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let mut cache = ApproxLru::with_seed(3, 3, 7);
+        let log = evicted.clone();
+        cache.set_on_evict(Box::new(move |k: &i32, _: &i32| {
+            log.lock().unwrap().push(*k);
+        }));
+
+        cache.put(1, 10);
+        cache.put(2, 20);
+        cache.put(3, 30);
+        cache.get(&1);
+        cache.put(4, 40); // 2 is least recently used
+        cache.get(&3);
+        cache.put(5, 50); // then 1
+
+        assert_eq!(*evicted.lock().unwrap(), vec![2, 1]);
+        assert_eq!(cache.get(&3), Some(&30));
+        assert_eq!(cache.get(&4), Some(&40));
+        assert_eq!(cache.get(&5), Some(&50));
+    }
+
+    #[test]
+    fn test_approx_lru_hits_ordered_by_tick() {
+        let mut cache = ApproxLru::with_seed(2, 2, 9);
+        cache.put(1, 10);
+        cache.put(2, 20);
+        cache.get(&1);
+        wait_for_tick();
+        cache.get(&2);
+
+        // 1 was hit a tick before 2, so it goes first
+        cache.put(3, 30);
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&2), Some(&20));
+    }
+
+    #[test]
+    fn test_approx_lru_sampled_eviction() {
+        let mut cache = ApproxLru::with_seed(100, 5, 42);
+        for i in 0..100 {
+            cache.put(i, i);
+        }
+        // Keep the first half hot; evictions should mostly hit the cold half
+        for i in 0..50 {
+            cache.get(&i);
+        }
+        for i in 100..120 {
+            cache.put(i, i);
+        }
+
+        assert_eq!(cache.len(), 100);
+        let hot_kept = (0..50).filter(|i| cache.get(i).is_some()).count();
+        assert!(hot_kept >= 40, "only {} hot keys kept", hot_kept);
+
+        // Every key still maps to its own entry after the swap removals
+        for (key, &idx) in &cache.map {
+            assert_eq!(cache.entries[idx].key, *key);
+        }
+    }
+
+    #[test]
+    fn test_lfu_evicts_least_frequently_used() {
+        let mut cache = ApproxLru::with_seed(3, 3, 5).with_lfu();
+        assert!(cache.is_lfu());
+        cache.put(1, 10);
+        cache.put(2, 20);
+        cache.put(3, 30);
+        for _ in 0..1000 {
+            cache.get(&1);
+        }
+        cache.get(&2);
+
+        // Counters grow logarithmically: one hit raises a new entry, a
+        // thousand raise it a dozen or so times rather than a thousand
+        let hot = cache.frequency(&1).unwrap();
+        assert_eq!(cache.frequency(&2), Some(LFU_INIT + 1));
+        assert_eq!(cache.frequency(&3), Some(LFU_INIT));
+        assert!(
+            hot > LFU_INIT + 5 && hot < LFU_INIT + 30,
+            "hot counter {}",
+            hot
+        );
+
+        // 3 is the least frequently used, though the most recently written
+        cache.put(3, 33);
+        cache.put(4, 40);
+        assert_eq!(cache.get(&3), None);
+        assert_eq!(cache.get(&1), Some(&10));
+
+        // Plain LRU mode keeps no counters
+        let mut lru = ApproxLru::with_seed(3, 3, 5);
+        lru.put(1, 10);
+        assert_eq!(lru.frequency(&1), None);
+    }
+
+    #[test]
+    fn test_approx_lru_set_capacity_and_hottest() {
+        let mut cache = ApproxLru::with_seed(10, 2, 3);
+        for i in 0..10 {
+            cache.put(i, i);
+        }
+        cache.get(&4);
+        cache.get(&4);
+        cache.get(&7);
+        assert_eq!(cache.hottest(2), vec![(4, 2), (7, 1)]);
+        assert_eq!(cache.hottest(usize::MAX).len(), cache.len());
+
+        assert_eq!(cache.set_capacity(4), 6);
+        assert_eq!(cache.len(), 4);
+        assert_eq!(cache.capacity(), 4);
+    }
+}
@@ -2,25 +2,179 @@
 
 use parking_lot::RwLock;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use toonstoredb::{Error, Result, ToonStore};
+use toonstoredb::{
+    CompactProgress, Error, Result, RowMeta, SizeHistogram, ToonStore, ValuePage, MAX_VALUE_SIZE,
+};
 
-use crate::lru::LruCache;
+use crate::approx_lru::ApproxLru;
+use crate::lru::{EvictCallback, LruCache};
 use crate::stats::CacheStats;
 
+/// Where a `ToonCache` spends its memory, from `ToonCache::memory_report`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryReport {
+    /// Rows in the storage index, deleted ones included
+    pub index_entries: usize,
+    /// Memory allocated to the storage index in bytes
+    pub index_bytes: usize,
+    /// Deleted rows that still hold an index entry
+    pub deleted_rows: usize,
+    /// Values held in the cache
+    pub cache_entries: usize,
+    /// Total length of the cached values in bytes
+    pub cache_bytes: usize,
+    /// Node slots allocated by the LRU, free ones included
+    pub lru_nodes: usize,
+    /// LRU node slots waiting for reuse
+    pub lru_free_nodes: usize,
+}
+
+/// Eviction policy behind a `ToonCache`
+enum Policy {
+    /// Exact LRU; a hit reorders the list, so it takes the write lock
+    Strict(LruCache<u64, Vec<u8>>),
+    /// Sampled LRU; a hit only takes the read lock
+    Approx(ApproxLru<u64, Vec<u8>>),
+    /// Capacity 0: nothing is cached and every read goes to storage
+    Off,
+}
+
+impl Policy {
+    /// `policy()`, or `Off` for a capacity of 0, which the LRUs reject
+    fn sized(capacity: usize, policy: impl FnOnce() -> Policy) -> Policy {
+        if capacity == 0 {
+            Policy::Off
+        } else {
+            policy()
+        }
+    }
+
+    fn set_on_evict(&mut self, f: EvictCallback<u64, Vec<u8>>) {
+        match self {
+            Policy::Strict(lru) => lru.set_on_evict(f),
+            Policy::Approx(lru) => lru.set_on_evict(f),
+            Policy::Off => {}
+        }
+    }
+
+    fn put(&mut self, row_id: u64, value: Vec<u8>) -> Option<Vec<u8>> {
+        match self {
+            Policy::Strict(lru) => lru.put(row_id, value),
+            Policy::Approx(lru) => lru.put(row_id, value),
+            Policy::Off => None,
+        }
+    }
+
+    fn contains(&self, row_id: &u64) -> bool {
+        match self {
+            Policy::Strict(lru) => lru.contains_key(row_id),
+            Policy::Approx(lru) => lru.contains_key(row_id),
+            Policy::Off => false,
+        }
+    }
+
+    fn remove(&mut self, row_id: &u64) -> Option<Vec<u8>> {
+        match self {
+            Policy::Strict(lru) => lru.remove(row_id),
+            Policy::Approx(lru) => lru.remove(row_id),
+            Policy::Off => None,
+        }
+    }
+
+    fn frequency(&self, row_id: &u64) -> Option<u8> {
+        match self {
+            Policy::Strict(_) | Policy::Off => None,
+            Policy::Approx(lru) => lru.frequency(row_id),
+        }
+    }
+
+    fn is_lfu(&self) -> bool {
+        matches!(self, Policy::Approx(lru) if lru.is_lfu())
+    }
+
+    fn hottest(&self, n: usize) -> Vec<(u64, u64)> {
+        match self {
+            Policy::Strict(lru) => lru.hottest(n),
+            Policy::Approx(lru) => lru.hottest(n),
+            Policy::Off => Vec::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Policy::Strict(lru) => lru.len(),
+            Policy::Approx(lru) => lru.len(),
+            Policy::Off => 0,
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        match self {
+            Policy::Strict(lru) => lru.capacity(),
+            Policy::Approx(lru) => lru.capacity(),
+            Policy::Off => 0,
+        }
+    }
+
+    fn set_capacity(&mut self, capacity: usize) -> usize {
+        match self {
+            Policy::Strict(lru) => lru.set_capacity(capacity),
+            Policy::Approx(lru) => lru.set_capacity(capacity),
+            Policy::Off => 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        match self {
+            Policy::Strict(lru) => lru.clear(),
+            Policy::Approx(lru) => lru.clear(),
+            Policy::Off => {}
+        }
+    }
+
+    /// Allocated and free node slots; the sampled LRU keeps one per entry
+    fn nodes(&self) -> (usize, usize) {
+        match self {
+            Policy::Strict(lru) => (lru.node_count(), lru.free_count()),
+            Policy::Approx(lru) => (lru.len(), 0),
+            Policy::Off => (0, 0),
+        }
+    }
+}
+
 /// Cached storage layer combining LRU cache with ToonStore backend
+///
+/// Reads never see a stale value: whatever a `put` or `delete` has returned
+/// is what every later `get` on any thread observes. A value read from
+/// storage is only cached, under the cache lock, if its row is still live
+/// and storage has not been cleared since; deletes and clears change storage
+/// while holding that lock.
 pub struct ToonCache {
     /// Underlying persistent storage
     store: Arc<ToonStore>,
 
     /// LRU cache for hot data
-    cache: Arc<RwLock<LruCache<u64, Vec<u8>>>>,
+    cache: Arc<RwLock<Policy>>,
 
     /// Cache statistics
     stats: Arc<CacheStats>,
 
+    /// Total length of the cached values, kept by every put, remove and eviction
+    cache_bytes: Arc<AtomicUsize>,
+
     /// Cache capacity
-    capacity: usize,
+    capacity: AtomicUsize,
+
+    /// Longest value admitted to the cache in bytes (0 = no limit)
+    max_value_size: AtomicUsize,
+
+    /// Bumped under the cache lock whenever row IDs may be reused
+    epoch: AtomicU64,
+
+    /// Bumped once a delete, clear or reload has taken effect
+    generation: AtomicU64,
 }
 
 impl ToonCache {
@@ -28,18 +182,106 @@ impl ToonCache {
     ///
     /// # Arguments
     /// * `path` - Database directory path
-    /// * `capacity` - Maximum number of items in cache
+    /// * `capacity` - Maximum number of items in cache; 0 disables caching
+    ///   and every read goes to storage
     ///
     /// # Returns
     /// * `Result<ToonCache>` - Cache-enabled database handle
     pub fn new<P: AsRef<Path>>(path: P, capacity: usize) -> Result<Self> {
+        Self::with_policy(
+            path,
+            Policy::sized(capacity, || Policy::Strict(LruCache::new(capacity))),
+        )
+    }
+
+    /// Create a ToonCache using approximate LRU (see `ApproxLru`)
+    ///
+    /// Cache hits only share a read lock, so concurrent readers do not
+    /// serialize; each eviction drops the least recently used of `samples`
+    /// random entries instead of the exact LRU entry.
+    ///
+    /// # Panics
+    /// Panics if `samples` is 0 while `capacity` is not
+    pub fn with_approx_lru<P: AsRef<Path>>(
+        path: P,
+        capacity: usize,
+        samples: usize,
+    ) -> Result<Self> {
+        Self::with_policy(
+            path,
+            Policy::sized(capacity, || {
+                Policy::Approx(ApproxLru::new(capacity, samples))
+            }),
+        )
+    }
+
+    /// Create a ToonCache evicting by access frequency (see `ApproxLru::with_lfu`)
+    ///
+    /// Sampled as for `with_approx_lru`, but each eviction drops the least
+    /// frequently used of the `samples` entries.
+    ///
+    /// # Panics
+    /// Panics if `samples` is 0 while `capacity` is not
+    pub fn with_lfu<P: AsRef<Path>>(path: P, capacity: usize, samples: usize) -> Result<Self> {
+        Self::with_policy(
+            path,
+            Policy::sized(capacity, || {
+                Policy::Approx(ApproxLru::new(capacity, samples).with_lfu())
+            }),
+        )
+    }
+
+    /// Create a ToonCache whose LRU hasher uses a fixed seed
+    ///
+    /// For reproducible benchmarks only; see `HASH_SEED_ENV`.
+    pub fn with_hash_seed<P: AsRef<Path>>(path: P, capacity: usize, seed: u64) -> Result<Self> {
+        Self::with_policy(
+            path,
+            Policy::sized(capacity, || {
+                Policy::Strict(LruCache::with_seed(capacity, seed))
+            }),
+        )
+    }
+
+    /// Create an approximate-LRU ToonCache with a fixed hash and sampling seed
+    ///
+    /// For reproducible benchmarks only; see `HASH_SEED_ENV`.
+    pub fn with_approx_lru_seed<P: AsRef<Path>>(
+        path: P,
+        capacity: usize,
+        samples: usize,
+        seed: u64,
+    ) -> Result<Self> {
+        Self::with_policy(
+            path,
+            Policy::sized(capacity, || {
+                Policy::Approx(ApproxLru::with_seed(capacity, samples, seed))
+            }),
+        )
+    }
+
+    fn with_policy<P: AsRef<Path>>(path: P, mut lru: Policy) -> Result<Self> {
         let store = ToonStore::open(path)?;
+        let stats = Arc::new(CacheStats::new());
+        let capacity = lru.capacity();
+
+        let evictions = stats.clone();
+        let cache_bytes = Arc::new(AtomicUsize::new(0));
+        let evicted_bytes = cache_bytes.clone();
+        lru.set_on_evict(Box::new(move |_: &u64, value: &Vec<u8>| {
+            evictions.record_eviction();
+            evicted_bytes.fetch_sub(value.len(), Ordering::Relaxed);
+        }));
 
         Ok(Self {
             store: Arc::new(store),
-            cache: Arc::new(RwLock::new(LruCache::new(capacity))),
-            stats: Arc::new(CacheStats::new()),
-            capacity,
+            cache: Arc::new(RwLock::new(lru)),
+            stats,
+            cache_bytes,
+            capacity: AtomicUsize::new(capacity),
+            max_value_size: AtomicUsize::new(0),
+            epoch: AtomicU64::new(0),
+            generation: AtomicU64::new(0),
         })
     }
 
@@ -51,18 +293,65 @@ impl ToonCache {
     /// # Returns
     /// * `Result<u64>` - Row ID of inserted line
     pub fn put(&self, line: &[u8]) -> Result<u64> {
-        let row_id = self.store.put(line)?;
+        self.put_tagged(line, 0)
+    }
 
-        // Cache the value
-        let mut cache = self.cache.write();
-        cache.put(row_id, line.to_vec());
-        self.stats.record_insert();
+    /// Put a value with a type tag (see `ToonStore::put_tagged`)
+    pub fn put_tagged(&self, line: &[u8], tag: u8) -> Result<u64> {
+        self.put_tagged_limited(line, tag, MAX_VALUE_SIZE)
+    }
+
+    /// Put a tagged value of up to `max_value_size` bytes (see
+    /// `ToonStore::put_tagged_limited`)
+    pub fn put_tagged_limited(&self, line: &[u8], tag: u8, max_value_size: usize) -> Result<u64> {
+        let epoch = self.epoch.load(Ordering::Acquire);
+        let row_id = self.store.put_tagged_limited(line, tag, max_value_size)?;
+        self.insert_new(epoch, row_id, line);
 
         Ok(row_id)
     }
 
+    /// Put a TOON record checked against its collection's schema (see `ToonStore::put_validated`)
+    pub fn put_validated(&self, line: &[u8]) -> Result<u64> {
+        let epoch = self.epoch.load(Ordering::Acquire);
+        let row_id = self.store.put_validated(line)?;
+        self.insert_new(epoch, row_id, line);
+
+        Ok(row_id)
+    }
+
+    /// Register the fields of a collection (see `ToonStore::register_schema`)
+    pub fn register_schema(&self, collection: &str, fields: Vec<String>) -> Result<()> {
+        self.store.register_schema(collection, fields)
+    }
+
+    /// Fields registered for a collection (see `ToonStore::schema`)
+    pub fn schema(&self, collection: &str) -> Option<Vec<String>> {
+        self.store.schema(collection)
+    }
+
+    /// Cache a row just written, unless a clear raced the write and the ID is reused
+    fn insert_new(&self, epoch: u64, row_id: u64, line: &[u8]) {
+        if !self.admits(line.len()) {
+            return;
+        }
+        let mut cache = self.cache.write();
+        if self.epoch.load(Ordering::Acquire) == epoch {
+            self.cache_put(&mut cache, row_id, line.to_vec());
+            self.stats.record_insert();
+        }
+    }
+
+    /// Get the type tag of a row without reading it (bypasses cache)
+    pub fn row_tag(&self, row_id: u64) -> Result<u8> {
+        self.store.row_tag(row_id)
+    }
+
     /// Get a value from cache or storage
     ///
+    /// Values over `max_value_size` are read from storage every time and
+    /// count as misses.
+    ///
     /// # Arguments
     /// * `row_id` - Row ID to retrieve
     ///
@@ -70,23 +359,187 @@ impl ToonCache {
     /// * `Result<Vec<u8>>` - Raw TOON line data
     pub fn get(&self, row_id: u64) -> Result<Vec<u8>> {
         // Try cache first
-        {
-            let mut cache = self.cache.write();
-            if let Some(value) = cache.get(&row_id) {
-                self.stats.record_hit();
-                return Ok(value.clone());
-            }
+        if let Some(value) = self.cached(row_id, <[u8]>::to_vec) {
+            self.stats.record_hit();
+            return Ok(value);
         }
 
         // Cache miss - fetch from storage
         self.stats.record_miss();
+        let epoch = self.epoch.load(Ordering::Acquire);
         let value = self.store.get(row_id)?;
+        self.fill(epoch, row_id, &value);
+
+        Ok(value)
+    }
+
+    /// Get several values at once, in the order given
+    ///
+    /// The cache is looked through under one lock, the misses are read from
+    /// storage in one `ToonStore::get_many` call, and the values read are
+    /// cached under one more lock, rather than locking per row. Hits,
+    /// misses and the cache's consistency are as if each row were read with
+    /// `get`.
+    pub fn get_batch(&self, row_ids: &[u64]) -> Vec<Result<Vec<u8>>> {
+        let mut results: Vec<Option<Result<Vec<u8>>>> = self
+            .cached_many(row_ids)
+            .into_iter()
+            .map(|value| value.map(Ok))
+            .collect();
+
+        let misses: Vec<usize> = (0..row_ids.len())
+            .filter(|&i| results[i].is_none())
+            .collect();
+        for _ in 0..row_ids.len() - misses.len() {
+            self.stats.record_hit();
+        }
+        if !misses.is_empty() {
+            for _ in &misses {
+                self.stats.record_miss();
+            }
+            let epoch = self.epoch.load(Ordering::Acquire);
+            let miss_ids: Vec<u64> = misses.iter().map(|&i| row_ids[i]).collect();
+            let fetched = self.store.get_many(&miss_ids);
+
+            {
+                let mut cache = self.cache.write();
+                if self.epoch.load(Ordering::Acquire) == epoch {
+                    for (&row_id, value) in miss_ids.iter().zip(&fetched) {
+                        if let Ok(value) = value {
+                            if self.admits(value.len()) && self.store.value_len(row_id).is_ok() {
+                                self.cache_put(&mut cache, row_id, value.clone());
+                            }
+                        }
+                    }
+                }
+            }
 
-        // Update cache
+            for (i, value) in misses.into_iter().zip(fetched) {
+                results[i] = Some(value);
+            }
+        }
+
+        results.into_iter().map(Option::unwrap).collect()
+    }
+
+    /// `cached` for several rows under one lock
+    fn cached_many(&self, row_ids: &[u64]) -> Vec<Option<Vec<u8>>> {
+        {
+            let cache = self.cache.read();
+            match &*cache {
+                Policy::Approx(lru) => {
+                    return row_ids.iter().map(|id| lru.get(id).cloned()).collect()
+                }
+                Policy::Off => return vec![None; row_ids.len()],
+                Policy::Strict(_) => {}
+            }
+        }
+
+        match &mut *self.cache.write() {
+            Policy::Strict(lru) => row_ids.iter().map(|id| lru.get(id).cloned()).collect(),
+            Policy::Approx(lru) => row_ids.iter().map(|id| lru.get(id).cloned()).collect(),
+            Policy::Off => vec![None; row_ids.len()],
+        }
+    }
+
+    /// Cache a value read from storage while `epoch` was current
+    ///
+    /// Skipped if the row was deleted or storage cleared since the read, so
+    /// a slow reader cannot bring back a value a delete already dropped.
+    fn fill(&self, epoch: u64, row_id: u64, value: &[u8]) {
+        if !self.admits(value.len()) {
+            return;
+        }
         let mut cache = self.cache.write();
-        cache.put(row_id, value.clone());
+        if self.epoch.load(Ordering::Acquire) == epoch && self.store.value_len(row_id).is_ok() {
+            self.cache_put(&mut cache, row_id, value.to_vec());
+        }
+    }
 
-        Ok(value)
+    /// Cache `value` for `row_id`, counting its bytes in place of any it replaces
+    ///
+    /// Evictions the put makes room with are uncounted by the eviction callback.
+    fn cache_put(&self, cache: &mut Policy, row_id: u64, value: Vec<u8>) {
+        if matches!(cache, Policy::Off) {
+            return;
+        }
+        self.cache_bytes.fetch_add(value.len(), Ordering::Relaxed);
+        if let Some(old) = cache.put(row_id, value) {
+            self.cache_bytes.fetch_sub(old.len(), Ordering::Relaxed);
+        }
+    }
+
+    /// Drop the cached value of `row_id`, if any
+    fn uncache(&self, cache: &mut Policy, row_id: u64) {
+        if let Some(old) = cache.remove(&row_id) {
+            self.cache_bytes.fetch_sub(old.len(), Ordering::Relaxed);
+        }
+    }
+
+    /// Drop every cached value
+    fn uncache_all(&self, cache: &mut Policy) {
+        cache.clear();
+        self.cache_bytes.store(0, Ordering::Relaxed);
+    }
+
+    /// Get `len` bytes of a value starting at byte `start`
+    ///
+    /// Served from the cache when the value is cached; otherwise only the
+    /// range is read from storage and the cache is left as it was.
+    pub fn get_range(&self, row_id: u64, start: usize, len: usize) -> Result<Vec<u8>> {
+        let range = self.cached(row_id, |value| {
+            let start = start.min(value.len());
+            let end = start.saturating_add(len).min(value.len());
+            value[start..end].to_vec()
+        });
+        if let Some(range) = range {
+            self.stats.record_hit();
+            return Ok(range);
+        }
+
+        self.stats.record_miss();
+        self.store.get_range(row_id, start, len)
+    }
+
+    /// Apply `f` to the cached value of `row_id`, marking it as just used
+    ///
+    /// Approximate LRU only needs the read lock; strict LRU takes the write
+    /// lock to move the entry to the front.
+    fn cached<R>(&self, row_id: u64, f: impl FnOnce(&[u8]) -> R) -> Option<R> {
+        {
+            let cache = self.cache.read();
+            match &*cache {
+                Policy::Approx(lru) => return lru.get(&row_id).map(|value| f(value)),
+                Policy::Off => return None,
+                Policy::Strict(_) => {}
+            }
+        }
+
+        match &mut *self.cache.write() {
+            Policy::Strict(lru) => lru.get(&row_id).map(|value| f(value)),
+            Policy::Approx(lru) => lru.get(&row_id).map(|value| f(value)),
+            Policy::Off => None,
+        }
+    }
+
+    /// Get the byte length of a value without reading it (bypasses cache)
+    pub fn value_len(&self, row_id: u64) -> Result<usize> {
+        self.store.value_len(row_id)
+    }
+
+    /// Get the byte length a row takes up on disk (bypasses cache)
+    pub fn stored_len(&self, row_id: u64) -> Result<usize> {
+        self.store.stored_len(row_id)
+    }
+
+    /// Count stored values by length in power-of-two buckets (bypasses cache)
+    pub fn size_histogram(&self, sample: Option<usize>) -> Result<SizeHistogram> {
+        self.store.size_histogram(sample)
+    }
+
+    /// Check whether a row's value is cached, without counting a hit or marking it as used
+    pub fn is_cached(&self, row_id: u64) -> bool {
+        self.cache.read().contains(&row_id)
     }
 
     /// Delete a value from cache and storage
@@ -99,10 +552,62 @@ impl ToonCache {
     pub fn delete(&self, row_id: u64) -> Result<()> {
         // Remove from cache
         let mut cache = self.cache.write();
-        cache.remove(&row_id);
+        self.uncache(&mut cache, row_id);
 
         // Delete from storage
-        self.store.delete(row_id)
+        let deleted = self.store.delete(row_id);
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        deleted
+    }
+
+    /// Update some fields of a TOON record (see `ToonStore::update_fields`)
+    ///
+    /// # Returns
+    /// * `Result<u64>` - Row ID of the updated record
+    pub fn update_fields(&self, row_id: u64, updates: &[(&str, &[u8])]) -> Result<u64> {
+        let new_row_id = self.store.update_fields(row_id, updates)?;
+        self.uncache(&mut self.cache.write(), row_id);
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        Ok(new_row_id)
+    }
+
+    /// Overwrite a row with a new value (see `ToonStore::replace`)
+    ///
+    /// # Returns
+    /// * `Result<u64>` - Row ID of the new value
+    pub fn replace(&self, row_id: u64, line: &[u8], tag: u8, max_value_size: usize) -> Result<u64> {
+        let epoch = self.epoch.load(Ordering::Acquire);
+        let new_row_id = self.store.replace(row_id, line, tag, max_value_size)?;
+
+        self.uncache(&mut self.cache.write(), row_id);
+        self.insert_new(epoch, new_row_id, line);
+        self.generation.fetch_add(1, Ordering::AcqRel);
+
+        Ok(new_row_id)
+    }
+
+    /// Creation and last update time of a row (bypasses cache)
+    pub fn row_metadata(&self, row_id: u64) -> Option<RowMeta> {
+        self.store.row_metadata(row_id)
+    }
+
+    /// Compact up to `budget_rows` rows of storage (see `ToonStore::compact_incremental`)
+    ///
+    /// Row IDs are unchanged, so cached values stay valid.
+    pub fn compact_incremental(&self, budget_rows: usize) -> Result<CompactProgress> {
+        self.store.compact_incremental(budget_rows)
+    }
+
+    /// Sync storage to disk, reopen it from the files and drop every cached value
+    ///
+    /// Subsequent reads are served from the reloaded files.
+    pub fn reload(&self) -> Result<()> {
+        let mut cache = self.cache.write();
+        self.store.reload()?;
+        self.uncache_all(&mut cache);
+        self.epoch.fetch_add(1, Ordering::AcqRel);
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        Ok(())
     }
 
     /// Scan all non-deleted rows (bypasses cache)
@@ -113,25 +618,139 @@ impl ToonCache {
         self.store.scan()
     }
 
+    /// Find live rows by cursor (see `ToonStore::scan_cursor`, bypasses cache)
+    pub fn scan_cursor(
+        &self,
+        cursor: u64,
+        count: usize,
+        tag: Option<u8>,
+    ) -> Result<(Vec<u64>, u64)> {
+        self.store.scan_cursor(cursor, count, tag)
+    }
+
+    /// Find live rows and their data by cursor (see `ToonStore::scan_with_values`, bypasses cache)
+    pub fn scan_with_values(&self, cursor: u64, count: usize) -> Result<ValuePage> {
+        self.store.scan_with_values(cursor, count)
+    }
+
+    /// Find rows whose TOON `field` is `value` (see `ToonStore::find_by`, bypasses cache)
+    pub fn find_by(&self, field: &str, value: &[u8]) -> Result<Vec<u64>> {
+        self.store.find_by(field, value)
+    }
+
+    /// Count rows whose TOON `field` is `value` (see `ToonStore::count_by`, bypasses cache)
+    pub fn count_by(&self, field: &str, value: &[u8]) -> Result<usize> {
+        self.store.count_by(field, value)
+    }
+
+    /// Counter bumped after every delete, clear or reload
+    ///
+    /// Rows are never rewritten in place, so a value read while this was
+    /// unchanged is still the row's value. `ThreadLocalCache` relies on it.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
     /// Get cache statistics
     pub fn stats(&self) -> &CacheStats {
         &self.stats
     }
 
+    /// Top `n` cached row IDs by read count, most read first
+    ///
+    /// Only rows currently in the cache are counted: a row's count starts
+    /// when it is cached and is lost when it is evicted or cleared.
+    pub fn hot_keys(&self, n: usize) -> Vec<(u64, u64)> {
+        self.cache.read().hottest(n)
+    }
+
+    /// Whether the cache evicts by access frequency (see `with_lfu`)
+    pub fn is_lfu(&self) -> bool {
+        self.cache.read().is_lfu()
+    }
+
+    /// LFU access counter of a cached row, None if it is not cached or the
+    /// cache is not in LFU mode; does not count as an access
+    pub fn frequency(&self, row_id: u64) -> Option<u8> {
+        self.cache.read().frequency(&row_id)
+    }
+
     /// Get current cache size
     pub fn cache_len(&self) -> usize {
         self.cache.read().len()
     }
 
+    /// Report the sizes of the index and cache
+    ///
+    /// Cached bytes are a running total, so this only holds the cache lock
+    /// for a few counter reads.
+    pub fn memory_report(&self) -> MemoryReport {
+        let cache = self.cache.read();
+        let index_entries = self.store.len();
+        MemoryReport {
+            index_entries,
+            index_bytes: self.store.index_bytes(),
+            deleted_rows: index_entries.saturating_sub(self.store.live_len()),
+            cache_entries: cache.len(),
+            cache_bytes: self.cache_bytes.load(Ordering::Relaxed),
+            lru_nodes: cache.nodes().0,
+            lru_free_nodes: cache.nodes().1,
+        }
+    }
+
     /// Get cache capacity
     pub fn capacity(&self) -> usize {
-        self.capacity
+        self.capacity.load(Ordering::Relaxed)
+    }
+
+    /// Longest value the cache admits in bytes (0 = no limit)
+    pub fn max_value_size(&self) -> usize {
+        self.max_value_size.load(Ordering::Relaxed)
+    }
+
+    /// Stop caching values longer than `bytes` (0 = cache values of any size)
+    ///
+    /// One large value can evict many small hot ones, so values above the
+    /// limit go straight to storage on put and are never cached on get.
+    /// Values already cached stay until evicted.
+    pub fn set_max_value_size(&self, bytes: usize) {
+        self.max_value_size.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Whether a value of `len` bytes may be cached; never with capacity 0
+    pub(crate) fn admits(&self, len: usize) -> bool {
+        let max = self.max_value_size();
+        self.capacity() > 0 && (max == 0 || len <= max)
+    }
+
+    /// Resize the cache at runtime (storage remains unchanged)
+    ///
+    /// Shrinking evicts least recently used entries until the new capacity is met.
+    /// A cache created with capacity 0 stays disabled and keeps capacity 0.
+    ///
+    /// # Panics
+    /// Panics if `capacity` is 0 and the cache is not disabled
+    pub fn set_capacity(&self, capacity: usize) {
+        // Evictions are counted by the LRU's eviction callback
+        let mut cache = self.cache.write();
+        cache.set_capacity(capacity);
+        self.capacity.store(cache.capacity(), Ordering::Relaxed);
+    }
+
+    /// Delete every row from storage and the cache
+    pub fn clear(&self) -> Result<()> {
+        let mut cache = self.cache.write();
+        let cleared = self.store.clear();
+        self.uncache_all(&mut cache);
+        self.epoch.fetch_add(1, Ordering::AcqRel);
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        cleared
     }
 
     /// Clear the cache (storage remains unchanged)
     pub fn clear_cache(&self) {
         let mut cache = self.cache.write();
-        cache.clear();
+        self.uncache_all(&mut cache);
         self.stats.reset();
     }
 
@@ -140,6 +759,26 @@ impl ToonCache {
         self.store.len()
     }
 
+    /// Get the number of non-deleted rows in storage
+    pub fn live_len(&self) -> usize {
+        self.store.live_len()
+    }
+
+    /// Get the size of the data file in bytes
+    pub fn db_size(&self) -> u64 {
+        self.store.db_size()
+    }
+
+    /// Get the size limit for the data file in bytes
+    pub fn max_db_size(&self) -> u64 {
+        self.store.max_db_size()
+    }
+
+    /// Set the size limit for the data file (see `ToonStore::set_max_db_size`)
+    pub fn set_max_db_size(&self, bytes: u64) {
+        self.store.set_max_db_size(bytes);
+    }
+
     /// Check if the database is empty
     pub fn is_empty(&self) -> bool {
         self.store.is_empty()
@@ -215,6 +854,52 @@ mod tests {
         // id0 should be evicted (cache miss)
         cache.get(id0).unwrap();
         assert_eq!(cache.stats().misses(), 1);
+        // Re-caching id0 on the miss evicted id1
+        assert_eq!(cache.stats().evictions(), 2);
+    }
+
+    #[test]
+    fn test_zero_capacity_passes_through() {
+        let dir = TempDir::new().unwrap();
+        let caches = [
+            ToonCache::new(dir.path().join("strict"), 0).unwrap(),
+            ToonCache::with_approx_lru(dir.path().join("approx"), 0, 5).unwrap(),
+            ToonCache::with_lfu(dir.path().join("lfu"), 0, 5).unwrap(),
+        ];
+
+        for cache in &caches {
+            let id0 = cache.put(b"data 0").unwrap();
+            let id1 = cache.put(b"data 1").unwrap();
+            assert_eq!(cache.get(id0).unwrap(), b"data 0");
+            assert_eq!(cache.get(id0).unwrap(), b"data 0");
+            let batch: Vec<Vec<u8>> = cache
+                .get_batch(&[id1, id0])
+                .into_iter()
+                .map(Result::unwrap)
+                .collect();
+            assert_eq!(batch, vec![b"data 1".to_vec(), b"data 0".to_vec()]);
+            assert_eq!(cache.get_range(id1, 5, 1).unwrap(), b"1");
+
+            // Every read is a miss and nothing is ever held
+            assert_eq!(cache.stats().hits(), 0);
+            assert_eq!(cache.stats().misses(), 5);
+            assert_eq!(cache.cache_len(), 0);
+            assert!(!cache.is_cached(id0));
+            assert_eq!(cache.hot_keys(10), vec![]);
+            assert_eq!(cache.memory_report().cache_entries, 0);
+
+            let id2 = cache.replace(id0, b"data 2", 0, MAX_VALUE_SIZE).unwrap();
+            assert_eq!(cache.get(id2).unwrap(), b"data 2");
+            cache.delete(id1).unwrap();
+            assert!(matches!(cache.get(id1), Err(Error::NotFound)));
+
+            // Resizing leaves the cache disabled
+            cache.set_capacity(10);
+            assert_eq!(cache.capacity(), 0);
+            cache.get(id2).unwrap();
+            assert_eq!(cache.cache_len(), 0);
+            cache.clear().unwrap();
+        }
     }
 
     #[test]
@@ -264,6 +949,298 @@ mod tests {
         assert_eq!(cache.stats().hits(), 0);
     }
 
+    #[test]
+    fn test_cache_set_capacity() {
+        let dir = TempDir::new().unwrap();
+        let cache = ToonCache::new(dir.path(), 10).unwrap();
+
+        for i in 0..5 {
+            cache.put(format!("data {}", i).as_bytes()).unwrap();
+        }
+
+        cache.set_capacity(2);
+        assert_eq!(cache.capacity(), 2);
+        assert_eq!(cache.cache_len(), 2);
+        assert_eq!(cache.stats().evictions(), 3);
+
+        // Evicted rows are still served from storage
+        assert_eq!(cache.get(0).unwrap(), b"data 0");
+    }
+
+    #[test]
+    fn test_cache_max_value_size() {
+        let dir = TempDir::new().unwrap();
+        let cache = ToonCache::new(dir.path(), 10).unwrap();
+        cache.set_max_value_size(8);
+        assert_eq!(cache.max_value_size(), 8);
+
+        let small = cache.put(b"small").unwrap();
+        let large = cache.put(b"much too large").unwrap();
+        assert_eq!(cache.cache_len(), 1);
+
+        // Large values are served from storage, never admitted
+        assert_eq!(cache.get(large).unwrap(), b"much too large");
+        assert_eq!(cache.get(large).unwrap(), b"much too large");
+        assert_eq!(cache.get(small).unwrap(), b"small");
+        assert_eq!(cache.cache_len(), 1);
+        assert_eq!(cache.stats().hits(), 1);
+        assert_eq!(cache.stats().misses(), 2);
+
+        let new_large = cache
+            .replace(small, b"now too large", 0, MAX_VALUE_SIZE)
+            .unwrap();
+        assert_eq!(cache.cache_len(), 0);
+        assert_eq!(cache.get(new_large).unwrap(), b"now too large");
+
+        cache.set_max_value_size(0);
+        cache.get(large).unwrap();
+        assert_eq!(cache.cache_len(), 1);
+    }
+
+    #[test]
+    fn test_cache_get_range() {
+        let dir = TempDir::new().unwrap();
+        let cache = ToonCache::new(dir.path(), 1).unwrap();
+
+        let id0 = cache.put(b"hello world").unwrap();
+        assert_eq!(cache.get_range(id0, 6, 5).unwrap(), b"world");
+        assert_eq!(cache.stats().hits(), 1);
+
+        // Evicted: read from storage without re-caching
+        cache.put(b"other").unwrap();
+        assert_eq!(cache.get_range(id0, 0, 5).unwrap(), b"hello");
+        assert_eq!(cache.stats().misses(), 1);
+        assert_eq!(cache.cache_len(), 1);
+        assert_eq!(cache.stats().evictions(), 1);
+    }
+
+    #[test]
+    fn test_cache_get_batch() {
+        let dir = TempDir::new().unwrap();
+        let cache = ToonCache::new(dir.path(), 3).unwrap();
+        let ids: Vec<u64> = (0..6)
+            .map(|i| cache.put(format!("value {}", i).as_bytes()).unwrap())
+            .collect();
+        cache.delete(ids[4]).unwrap();
+
+        // IDs 3 and 5 are still cached, the rest come from storage
+        let wanted = [ids[5], ids[0], 99, ids[4], ids[3], ids[0]];
+        let batch = cache.get_batch(&wanted);
+        assert_eq!(batch.len(), wanted.len());
+        for (&row_id, value) in wanted.iter().zip(&batch) {
+            match cache.store.get(row_id) {
+                Ok(expected) => assert_eq!(value.as_ref().unwrap(), &expected),
+                Err(_) => assert!(matches!(value, Err(Error::NotFound))),
+            }
+        }
+        assert_eq!(cache.stats().hits(), 2);
+        assert_eq!(cache.stats().misses(), 4);
+
+        // Rows read from storage were cached
+        assert!(cache.is_cached(ids[0]));
+        assert!(!cache.is_cached(ids[4]));
+        assert!(cache.get_batch(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_cache_hot_keys() {
+        let dir = TempDir::new().unwrap();
+        let cache = ToonCache::new(dir.path(), 10).unwrap();
+
+        let cold = cache.put(b"cold").unwrap();
+        let hot = cache.put(b"hot").unwrap();
+        cache.put(b"unread").unwrap();
+        cache.get(cold).unwrap();
+        for _ in 0..10 {
+            cache.get(hot).unwrap();
+        }
+
+        assert_eq!(cache.hot_keys(2), vec![(hot, 10), (cold, 1)]);
+    }
+
+    #[test]
+    fn test_cache_read_your_writes() {
+        let dir = TempDir::new().unwrap();
+        check_read_your_writes(&ToonCache::new(dir.path(), 4).unwrap());
+    }
+
+    #[test]
+    fn test_approx_cache_read_your_writes() {
+        let dir = TempDir::new().unwrap();
+        check_read_your_writes(&ToonCache::with_approx_lru(dir.path(), 4, 2).unwrap());
+    }
+
+    fn check_read_your_writes(cache: &ToonCache) {
+        use std::sync::mpsc;
+
+        let (sender, receiver) = mpsc::channel::<(u64, Vec<u8>)>();
+        let receiver = std::sync::Mutex::new(receiver);
+
+        // Readers get every row as soon as its put returned, on another thread
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| loop {
+                    let Ok((row_id, value)) = receiver.lock().unwrap().recv() else {
+                        break;
+                    };
+                    assert_eq!(cache.get(row_id).unwrap(), value);
+                });
+            }
+
+            for i in 0..2000 {
+                let value = format!("value {}", i).into_bytes();
+                let row_id = cache.put(&value).unwrap();
+                if i % 3 == 0 {
+                    cache.delete(row_id).unwrap();
+                    assert!(matches!(cache.get(row_id), Err(Error::NotFound)));
+                } else {
+                    sender.send((row_id, value)).unwrap();
+                }
+            }
+            drop(sender);
+        });
+    }
+
+    #[test]
+    fn test_approx_cache_hit_shares_read_lock() {
+        let dir = TempDir::new().unwrap();
+        let cache = ToonCache::with_approx_lru_seed(dir.path(), 2, 5, 1).unwrap();
+        let id0 = cache.put(b"data 0").unwrap();
+        let id1 = cache.put(b"data 1").unwrap();
+
+        // A strict LRU hit would wait for this guard to drop
+        {
+            let _reader = cache.cache.read();
+            assert_eq!(cache.get_range(id1, 5, 1).unwrap(), b"1");
+            // Hits between the same two ticks tie on recency
+            crate::approx_lru::wait_for_tick();
+            assert_eq!(cache.get(id0).unwrap(), b"data 0");
+        }
+        assert_eq!(cache.stats().hits(), 2);
+
+        // id1 was used least recently and is evicted, even with sampling
+        cache.put(b"data 2").unwrap();
+        assert_eq!(cache.stats().evictions(), 1);
+        cache.get(id0).unwrap();
+        assert_eq!(cache.stats().misses(), 0);
+        cache.get(id1).unwrap();
+        assert_eq!(cache.stats().misses(), 1);
+        assert_eq!(cache.memory_report().lru_nodes, 2);
+    }
+
+    #[test]
+    fn test_thread_local_hit_skips_lock() {
+        let dir = TempDir::new().unwrap();
+        let shared = Arc::new(ToonCache::new(dir.path(), 10).unwrap());
+        let id = shared.put(b"data").unwrap();
+        let mut local = crate::ThreadLocalCache::new(shared.clone(), 4);
+        local.get(id).unwrap();
+
+        // A shared-cache read would wait for this writer forever
+        let _writer = shared.cache.write();
+        assert_eq!(local.get(id).unwrap(), b"data");
+        assert_eq!(local.hits(), 1);
+    }
+
+    #[test]
+    fn test_cache_slow_read_not_cached() {
+        let dir = TempDir::new().unwrap();
+        let cache = ToonCache::new(dir.path(), 4).unwrap();
+
+        // A read from storage that finishes after the row was deleted
+        let row_id = cache.put(b"old").unwrap();
+        cache.clear_cache();
+        let epoch = cache.epoch.load(Ordering::Acquire);
+        let value = cache.store.get(row_id).unwrap();
+        cache.delete(row_id).unwrap();
+        cache.fill(epoch, row_id, &value);
+        assert!(matches!(cache.get(row_id), Err(Error::NotFound)));
+
+        // ... or after a clear, once the row ID was handed out again
+        cache.put(b"other").unwrap();
+        cache.clear_cache();
+        let epoch = cache.epoch.load(Ordering::Acquire);
+        let value = cache.store.get(row_id + 1).unwrap();
+        cache.clear().unwrap();
+        assert_eq!(cache.put(b"new").unwrap(), 0);
+        cache.fill(epoch, 0, &value);
+        assert_eq!(cache.get(0).unwrap(), b"new");
+        assert!(matches!(cache.get(1), Err(Error::NotFound)));
+    }
+
+    #[test]
+    fn test_memory_report() {
+        let dir = TempDir::new().unwrap();
+        let cache = ToonCache::new(dir.path(), 10).unwrap();
+
+        for _ in 0..20 {
+            cache.put(&[b'x'; 100]).unwrap();
+        }
+        let report = cache.memory_report();
+        assert_eq!(report.index_entries, 20);
+        assert!(report.index_bytes >= 20 * 8);
+        assert_eq!(report.deleted_rows, 0);
+        assert_eq!(report.cache_entries, 10);
+        assert_eq!(report.cache_bytes, 10 * 100);
+        assert_eq!(report.lru_nodes, 10); // Evictions reuse their node slot
+        assert_eq!(report.lru_free_nodes, 0);
+
+        // Deleting a cached row frees its node, an evicted one only counts as deleted
+        cache.delete(19).unwrap();
+        cache.delete(0).unwrap();
+        let report = cache.memory_report();
+        assert_eq!(report.deleted_rows, 2);
+        assert_eq!(report.cache_entries, 9);
+        assert_eq!(report.cache_bytes, 9 * 100);
+        assert_eq!(report.lru_nodes, 10);
+        assert_eq!(report.lru_free_nodes, 1);
+
+        // The byte count follows replaces, shrinking and clearing
+        cache.replace(18, &[b'y'; 40], 0, MAX_VALUE_SIZE).unwrap();
+        assert_eq!(cache.memory_report().cache_bytes, 8 * 100 + 40);
+        cache.set_capacity(4);
+        assert_eq!(cache.memory_report().cache_bytes, 3 * 100 + 40);
+        cache.clear_cache();
+        assert_eq!(cache.memory_report().cache_bytes, 0);
+
+        let dir = TempDir::new().unwrap();
+        let cache = ToonCache::with_approx_lru(dir.path(), 10, 3).unwrap();
+        for _ in 0..20 {
+            cache.put(&[b'x'; 100]).unwrap();
+        }
+        assert_eq!(cache.memory_report().cache_bytes, 10 * 100);
+    }
+
+    #[test]
+    fn test_cache_reload() {
+        let dir = TempDir::new().unwrap();
+        let cache = ToonCache::new(dir.path(), 10).unwrap();
+
+        let id0 = cache.put(b"data 0").unwrap();
+        let id1 = cache.put(b"data 1").unwrap();
+        cache.delete(id1).unwrap();
+
+        cache.reload().unwrap();
+        assert_eq!(cache.cache_len(), 0);
+        assert_eq!(cache.get(id0).unwrap(), b"data 0");
+        assert_eq!(cache.stats().misses(), 1);
+        assert!(cache.get(id1).is_err());
+    }
+
+    #[test]
+    fn test_cache_clear_storage() {
+        let dir = TempDir::new().unwrap();
+        let cache = ToonCache::new(dir.path(), 10).unwrap();
+
+        let row_id = cache.put(b"data 0").unwrap();
+        cache.clear().unwrap();
+
+        assert_eq!(cache.cache_len(), 0);
+        assert_eq!(cache.live_len(), 0);
+        assert!(cache.get(row_id).is_err());
+        assert_eq!(cache.scan().count(), 0);
+    }
+
     #[test]
     fn test_cache_scan() {
         let dir = TempDir::new().unwrap();
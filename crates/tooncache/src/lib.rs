@@ -15,12 +15,17 @@
 
 #![warn(missing_docs)]
 
+mod approx_lru;
 mod cache;
+mod local;
 mod lru;
 mod stats;
 
-pub use cache::ToonCache;
-pub use stats::CacheStats;
+pub use approx_lru::{ApproxLru, DEFAULT_SAMPLES, LFU_INIT};
+pub use cache::{MemoryReport, ToonCache};
+pub use local::ThreadLocalCache;
+pub use lru::{EvictCallback, LruCache, HASH_SEED_ENV};
+pub use stats::{CacheStats, StatsDelta, StatsRates, StatsSnapshot};
 
 #[cfg(test)]
 mod tests {
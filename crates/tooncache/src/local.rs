@@ -0,0 +1,178 @@
+//! Per-connection L1 cache in front of a shared `ToonCache`
+//!
+//! A `ThreadLocalCache` is owned by one task or thread and needs no lock:
+//! repeated reads of a row it holds never touch the shared cache.
+//!
+//! ## Staleness
+//! Rows are never rewritten in place (an update writes a new row and deletes
+//! the old one), so an L1 entry can only go stale when its row is deleted or
+//! storage is cleared or reloaded. Each of those bumps
+//! `ToonCache::generation`, and every `get` compares it with the generation
+//! the L1 was filled under, dropping all entries when it moved. A read
+//! therefore never returns a value whose delete has already returned on any
+//! thread; a read racing a delete may still see the old value, as it could
+//! from the shared cache. The price is that any delete empties every L1.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use toonstoredb::Result;
+
+use crate::cache::ToonCache;
+
+/// Small unsynchronized cache checked before a shared `ToonCache`
+pub struct ThreadLocalCache {
+    shared: Arc<ToonCache>,
+    entries: HashMap<u64, Vec<u8>>,
+    /// Insertion order, oldest first, for eviction
+    order: VecDeque<u64>,
+    capacity: usize,
+    /// Shared generation the entries were read under
+    generation: u64,
+    hits: u64,
+}
+
+impl ThreadLocalCache {
+    /// Wrap `shared` with an L1 of up to `capacity` values
+    ///
+    /// # Panics
+    /// Panics if `capacity` is 0
+    pub fn new(shared: Arc<ToonCache>, capacity: usize) -> Self {
+        assert!(capacity > 0, "Capacity must be greater than 0");
+
+        let generation = shared.generation();
+        Self {
+            shared,
+            entries: HashMap::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            capacity,
+            generation,
+            hits: 0,
+        }
+    }
+
+    /// Get a value from the L1, or else the shared cache
+    pub fn get(&mut self, row_id: u64) -> Result<Vec<u8>> {
+        let generation = self.shared.generation();
+        if generation != self.generation {
+            self.clear();
+            self.generation = generation;
+        }
+
+        if let Some(value) = self.entries.get(&row_id) {
+            self.hits += 1;
+            return Ok(value.clone());
+        }
+
+        let value = self.shared.get(row_id)?;
+        // A delete that finished during the read may have dropped this row
+        if self.shared.generation() == generation && self.shared.admits(value.len()) {
+            self.insert(row_id, value.clone());
+        }
+        Ok(value)
+    }
+
+    /// Put a value through to the shared cache (see `ToonCache::put`)
+    pub fn put(&mut self, line: &[u8]) -> Result<u64> {
+        self.shared.put(line)
+    }
+
+    /// Delete a value from the shared cache, emptying every L1
+    pub fn delete(&mut self, row_id: u64) -> Result<()> {
+        self.shared.delete(row_id)
+    }
+
+    /// The shared cache behind this L1
+    pub fn shared(&self) -> &Arc<ToonCache> {
+        &self.shared
+    }
+
+    /// Reads served by this L1 without touching the shared cache
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Number of values held by this L1
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check if this L1 holds no values
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Drop every value held by this L1
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    fn insert(&mut self, row_id: u64, value: Vec<u8>) {
+        if self.entries.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(row_id, value);
+        self.order.push_back(row_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use toonstoredb::Error;
+
+    #[test]
+    fn test_local_hits_skip_shared_cache() {
+        let dir = TempDir::new().unwrap();
+        let shared = Arc::new(ToonCache::new(dir.path(), 100).unwrap());
+        let mut local = ThreadLocalCache::new(shared.clone(), 2);
+
+        let ids: Vec<u64> = (0..3)
+            .map(|i| local.put(format!("value {}", i).as_bytes()).unwrap())
+            .collect();
+        assert_eq!(local.get(ids[0]).unwrap(), b"value 0");
+        assert_eq!(shared.stats().hits(), 1);
+
+        // Served from the L1: the shared cache sees nothing
+        assert_eq!(local.get(ids[0]).unwrap(), b"value 0");
+        assert_eq!(shared.stats().hits(), 1);
+        assert_eq!(local.hits(), 1);
+
+        // The oldest entry makes room once the L1 is full
+        local.get(ids[1]).unwrap();
+        local.get(ids[2]).unwrap();
+        assert_eq!(local.len(), 2);
+        local.get(ids[0]).unwrap();
+        assert_eq!(shared.stats().hits(), 4);
+    }
+
+    #[test]
+    fn test_local_sees_other_deletes() {
+        let dir = TempDir::new().unwrap();
+        let shared = Arc::new(ToonCache::new(dir.path(), 100).unwrap());
+        let mut reader = ThreadLocalCache::new(shared.clone(), 8);
+        let id = shared.put(b"old").unwrap();
+        let kept = shared.put(b"kept").unwrap();
+        reader.get(id).unwrap();
+        reader.get(kept).unwrap();
+
+        // A delete on another thread empties the reader's L1 on its next get
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut writer = ThreadLocalCache::new(shared.clone(), 8);
+                writer.delete(id).unwrap();
+            });
+        });
+        assert!(matches!(reader.get(id), Err(Error::NotFound)));
+        assert_eq!(reader.get(kept).unwrap(), b"kept");
+        assert_eq!(reader.hits(), 0);
+
+        shared.clear().unwrap();
+        let reused = shared.put(b"new").unwrap();
+        assert_eq!(reused, id);
+        assert_eq!(reader.get(reused).unwrap(), b"new");
+    }
+}
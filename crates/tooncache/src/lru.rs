@@ -3,17 +3,30 @@
 //! Uses intrusive linked list for O(1) eviction.
 
 use ahash::RandomState;
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
 use std::hash::Hash;
 
 /// Node in the LRU doubly-linked list
 struct Node<K, V> {
     key: K,
     value: V,
+    /// Number of `get` hits since the key was inserted
+    hits: u64,
     prev: Option<usize>,
     next: Option<usize>,
 }
 
+/// Environment variable holding a fixed `u64` hash seed for `LruCache::new`
+///
+/// Only meant for benchmarks, so consecutive runs hash identically and can be
+/// compared. Leave it unset in production: a fixed seed makes the key hashing
+/// predictable. Unset or unparsable values keep the random seed.
+pub const HASH_SEED_ENV: &str = "TOONCACHE_HASH_SEED";
+
+/// Callback invoked with the key and value of each evicted entry
+pub type EvictCallback<K, V> = Box<dyn Fn(&K, &V) + Send + Sync>;
+
 /// LRU cache with fixed capacity
 pub struct LruCache<K, V> {
     map: HashMap<K, usize, RandomState>,
@@ -22,6 +35,7 @@ pub struct LruCache<K, V> {
     tail: Option<usize>,
     free_list: Vec<usize>,
     capacity: usize,
+    on_evict: Option<EvictCallback<K, V>>,
 }
 
 impl<K, V> LruCache<K, V>
@@ -30,47 +44,89 @@ where
     V: Clone,
 {
     /// Create a new LRU cache with the given capacity
+    ///
+    /// The hasher is randomly seeded unless `HASH_SEED_ENV` is set.
     pub fn new(capacity: usize) -> Self {
+        let hasher = match std::env::var(HASH_SEED_ENV)
+            .ok()
+            .and_then(|s| s.parse().ok())
+        {
+            Some(seed) => seeded_hasher(seed),
+            None => RandomState::new(),
+        };
+        Self::with_hasher(capacity, hasher)
+    }
+
+    /// Create a new LRU cache whose hasher uses a fixed seed
+    ///
+    /// For reproducible benchmarks only (see `HASH_SEED_ENV`).
+    pub fn with_seed(capacity: usize, seed: u64) -> Self {
+        Self::with_hasher(capacity, seeded_hasher(seed))
+    }
+
+    fn with_hasher(capacity: usize, hasher: RandomState) -> Self {
         assert!(capacity > 0, "Capacity must be greater than 0");
 
         Self {
-            map: HashMap::with_capacity_and_hasher(capacity, RandomState::new()),
+            map: HashMap::with_capacity_and_hasher(capacity, hasher),
             nodes: Vec::with_capacity(capacity),
             head: None,
             tail: None,
             free_list: Vec::new(),
             capacity,
+            on_evict: None,
         }
     }
 
+    /// Set a callback fired for every entry evicted to make room
+    ///
+    /// The callback runs once per eviction, after the node has been unlinked
+    /// and removed from the map, while the cache is still mutably borrowed.
+    /// It does not fire when a value is overwritten, removed with `remove`
+    /// (the value is returned to the caller instead) or dropped by `clear`.
+    pub fn set_on_evict(&mut self, f: EvictCallback<K, V>) {
+        self.on_evict = Some(f);
+    }
+
     /// Get a value from the cache
     pub fn get(&mut self, key: &K) -> Option<&V> {
         if let Some(&idx) = self.map.get(key) {
             self.move_to_front(idx);
-            self.nodes[idx].as_ref().map(|node| &node.value)
+            self.nodes[idx].as_mut().map(|node| {
+                node.hits = node.hits.saturating_add(1);
+                &node.value
+            })
         } else {
             None
         }
     }
 
-    /// Insert a key-value pair into the cache
-    pub fn put(&mut self, key: K, value: V) {
+    /// Check whether a key is cached, without marking it as used
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.map.contains_key(key)
+    }
+
+    /// Insert a key-value pair into the cache, returning the value it replaces
+    pub fn put(&mut self, key: K, value: V) -> Option<V> {
         if let Some(&idx) = self.map.get(&key) {
             // Update existing
-            if let Some(node) = &mut self.nodes[idx] {
-                node.value = value;
-            }
+            let old = self.nodes[idx]
+                .as_mut()
+                .map(|node| std::mem::replace(&mut node.value, value));
             self.move_to_front(idx);
+            old
         } else {
             // Insert new
             if self.map.len() >= self.capacity {
-                self.evict();
+                let overflow = self.map.len() + 1 - self.capacity;
+                self.evict_batch(overflow);
             }
 
             let idx = self.alloc_node();
             self.nodes[idx] = Some(Node {
                 key: key.clone(),
                 value,
+                hits: 0,
                 prev: None,
                 next: self.head,
             });
@@ -87,6 +143,7 @@ where
             }
 
             self.map.insert(key, idx);
+            None
         }
     }
 
@@ -101,17 +158,80 @@ where
         }
     }
 
+    /// The `n` keys with the most `get` hits, most hit first
+    ///
+    /// One pass over the entries, keeping a heap of the best `n`. Counts start
+    /// at zero when a key is inserted and are kept when its value is
+    /// overwritten; an evicted key loses its count.
+    pub fn hottest(&self, n: usize) -> Vec<(K, u64)> {
+        // n comes from the client, so never size the heap past what it can hold
+        let n = n.min(self.len());
+        if n == 0 {
+            return Vec::new();
+        }
+
+        let mut top = BinaryHeap::with_capacity(n + 1);
+        for (idx, node) in self.nodes.iter().enumerate() {
+            if let Some(node) = node {
+                top.push(Reverse((node.hits, idx)));
+                if top.len() > n {
+                    top.pop();
+                }
+            }
+        }
+
+        top.into_sorted_vec()
+            .into_iter()
+            .filter_map(|Reverse((hits, idx))| {
+                self.nodes[idx]
+                    .as_ref()
+                    .map(|node| (node.key.clone(), hits))
+            })
+            .collect()
+    }
+
     /// Get the current size of the cache
     pub fn len(&self) -> usize {
         self.map.len()
     }
 
     /// Check if the cache is empty
-    #[allow(dead_code)] // Used in public API later
     pub fn is_empty(&self) -> bool {
         self.map.is_empty()
     }
 
+    /// Number of node slots allocated, free ones included
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Number of node slots freed by removals and waiting for reuse
+    pub fn free_count(&self) -> usize {
+        self.free_list.len()
+    }
+
+    /// Iterate over the cached values in no particular order
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.nodes.iter().flatten().map(|node| &node.value)
+    }
+
+    /// Get the maximum number of entries
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Change the capacity, evicting least recently used entries if shrinking
+    ///
+    /// Returns the number of entries evicted
+    pub fn set_capacity(&mut self, capacity: usize) -> usize {
+        assert!(capacity > 0, "Capacity must be greater than 0");
+
+        let evicted = self.evict_batch(self.map.len().saturating_sub(capacity));
+        self.capacity = capacity;
+
+        evicted
+    }
+
     /// Clear the cache
     pub fn clear(&mut self) {
         self.map.clear();
@@ -172,14 +292,36 @@ where
         }
     }
 
-    fn evict(&mut self) {
-        if let Some(tail_idx) = self.tail {
-            if let Some(node) = self.nodes[tail_idx].take() {
-                self.map.remove(&node.key);
-                self.unlink(tail_idx);
-                self.free_node(tail_idx);
+    /// Evict up to `n` least recently used entries, returning how many were
+    ///
+    /// The tail is walked once and only the new tail is relinked, rather
+    /// than unlinking each node on its own. `on_evict` fires for each entry,
+    /// least recently used first.
+    pub fn evict_batch(&mut self, n: usize) -> usize {
+        let mut evicted = 0;
+        let mut cursor = self.tail;
+        while evicted < n {
+            let Some(idx) = cursor else {
+                break;
+            };
+            let Some(node) = self.nodes[idx].take() else {
+                break;
+            };
+            cursor = node.prev;
+            self.map.remove(&node.key);
+            if let Some(on_evict) = &self.on_evict {
+                on_evict(&node.key, &node.value);
             }
+            self.free_node(idx);
+            evicted += 1;
         }
+
+        self.tail = cursor;
+        match cursor.and_then(|idx| self.nodes[idx].as_mut()) {
+            Some(tail) => tail.next = None,
+            None => self.head = None,
+        }
+        evicted
     }
 
     fn alloc_node(&mut self) -> usize {
@@ -197,6 +339,15 @@ where
     }
 }
 
+pub(crate) fn seeded_hasher(seed: u64) -> RandomState {
+    RandomState::with_seeds(
+        seed,
+        seed ^ 0x9E37_79B9_7F4A_7C15,
+        seed ^ 0xBF58_476D_1CE4_E5B9,
+        seed ^ 0x94D0_49BB_1331_11EB,
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -224,6 +375,12 @@ mod tests {
         assert_eq!(cache.get(&1), None);
         assert_eq!(cache.get(&2), Some(&"b"));
         assert_eq!(cache.get(&3), Some(&"c"));
+
+        // Checking for a key does not save it from eviction
+        assert!(cache.contains_key(&2));
+        cache.put(4, "d");
+        assert!(!cache.contains_key(&2));
+        assert!(cache.contains_key(&3));
     }
 
     #[test]
@@ -265,6 +422,102 @@ mod tests {
         assert!(cache.is_empty());
     }
 
+    #[test]
+    fn test_lru_set_capacity() {
+        let mut cache = LruCache::new(3);
+
+        cache.put(1, "a");
+        cache.put(2, "b");
+        cache.put(3, "c");
+        cache.get(&1); // 2 is now least recently used
+
+        assert_eq!(cache.set_capacity(1), 2);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(&1), Some(&"a"));
+
+        // Growing never evicts
+        assert_eq!(cache.set_capacity(5), 0);
+        cache.put(2, "b");
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_lru_on_evict() {
+        use std::sync::{Arc, Mutex};
+
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let mut cache = LruCache::new(2);
+        let log = evicted.clone();
+        cache.set_on_evict(Box::new(move |k: &i32, v: &&str| {
+            log.lock().unwrap().push((*k, *v));
+        }));
+
+        cache.put(1, "a");
+        cache.put(2, "b");
+        cache.put(2, "B"); // Overwrite, not an eviction
+        cache.remove(&2); // Explicit removal, not an eviction
+        cache.put(2, "b");
+        cache.put(3, "c"); // Over capacity, evicts 1
+        assert_eq!(*evicted.lock().unwrap(), vec![(1, "a")]);
+
+        cache.set_capacity(1); // Evicts 2
+        assert_eq!(*evicted.lock().unwrap(), vec![(1, "a"), (2, "b")]);
+    }
+
+    #[test]
+    fn test_lru_evict_batch() {
+        let mut cache = LruCache::new(4);
+        for i in 1..=4 {
+            cache.put(i, i * 10);
+        }
+        cache.get(&1); // Order is now 1, 4, 3, 2
+
+        assert_eq!(cache.evict_batch(2), 2);
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.contains_key(&2) && !cache.contains_key(&3));
+        // The list is still intact at both ends
+        cache.put(5, 50);
+        cache.get(&4);
+        assert_eq!(cache.evict_batch(1), 1);
+        assert!(!cache.contains_key(&1));
+        assert_eq!(cache.evict_batch(10), 2);
+        assert!(cache.is_empty());
+        assert_eq!(cache.evict_batch(1), 0);
+        cache.put(6, 60);
+        assert_eq!(cache.get(&6), Some(&60));
+    }
+
+    #[test]
+    fn test_lru_with_seed() {
+        let a: LruCache<u64, u64> = LruCache::with_seed(4, 42);
+        let b: LruCache<u64, u64> = LruCache::with_seed(4, 42);
+        let c: LruCache<u64, u64> = LruCache::with_seed(4, 43);
+        assert_eq!(a.map.hasher().hash_one(7u64), b.map.hasher().hash_one(7u64));
+        assert_ne!(a.map.hasher().hash_one(7u64), c.map.hasher().hash_one(7u64));
+
+        let mut cache = LruCache::with_seed(2, 42);
+        cache.put(1, "a");
+        assert_eq!(cache.get(&1), Some(&"a"));
+    }
+
+    #[test]
+    fn test_lru_hottest() {
+        let mut cache = LruCache::new(3);
+        cache.put(1, "a");
+        cache.put(2, "b");
+        cache.put(3, "c");
+        for _ in 0..5 {
+            cache.get(&2);
+        }
+        cache.get(&3);
+        cache.put(2, "B"); // Overwrite keeps the count
+
+        assert_eq!(cache.hottest(2), vec![(2, 5), (3, 1)]);
+        assert_eq!(cache.hottest(10).len(), 3);
+        assert_eq!(cache.hottest(usize::MAX).len(), 3);
+        assert!(cache.hottest(0).is_empty());
+    }
+
     #[test]
     fn test_lru_overwrite() {
         let mut cache = LruCache::new(2);
@@ -1,6 +1,10 @@
 //! Cache statistics tracking
 
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Length of the recent hit-ratio window in seconds
+pub const WINDOW_SECS: u64 = 60;
 
 /// Statistics for cache performance tracking
 #[derive(Debug, Default)]
@@ -9,6 +13,175 @@ pub struct CacheStats {
     misses: AtomicU64,
     evictions: AtomicU64,
     inserts: AtomicU64,
+    recent: WindowedStats,
+}
+
+/// Counter values of a `CacheStats` at one moment, from `CacheStats::snapshot`
+///
+/// Keep the previous sample and take the `delta` to see what happened in
+/// between, e.g. for an exporter that reports every few seconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    /// Total hits
+    pub hits: u64,
+    /// Total misses
+    pub misses: u64,
+    /// Total evictions
+    pub evictions: u64,
+    /// Total inserts
+    pub inserts: u64,
+}
+
+/// Counter changes between two `StatsSnapshot`s
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatsDelta {
+    /// Hits since the previous snapshot
+    pub hits: u64,
+    /// Misses since the previous snapshot
+    pub misses: u64,
+    /// Evictions since the previous snapshot
+    pub evictions: u64,
+    /// Inserts since the previous snapshot
+    pub inserts: u64,
+}
+
+/// Counter changes per second, from `StatsDelta::per_second`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct StatsRates {
+    /// Hits per second
+    pub hits: f64,
+    /// Misses per second
+    pub misses: f64,
+    /// Evictions per second
+    pub evictions: f64,
+    /// Inserts per second
+    pub inserts: f64,
+}
+
+impl StatsSnapshot {
+    /// What changed since `prev`, an earlier snapshot of the same stats
+    ///
+    /// A counter lower than in `prev` was reset in between (see
+    /// `CacheStats::reset`); its whole current value is counted as new.
+    pub fn delta(&self, prev: &StatsSnapshot) -> StatsDelta {
+        let since = |now: u64, before: u64| now.checked_sub(before).unwrap_or(now);
+        StatsDelta {
+            hits: since(self.hits, prev.hits),
+            misses: since(self.misses, prev.misses),
+            evictions: since(self.evictions, prev.evictions),
+            inserts: since(self.inserts, prev.inserts),
+        }
+    }
+}
+
+impl StatsDelta {
+    /// Hit ratio of the lookups in this delta (0.0 to 1.0)
+    pub fn hit_ratio(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+
+    /// Average rates over `elapsed`, the time between the two snapshots
+    ///
+    /// All zero if `elapsed` is zero.
+    pub fn per_second(&self, elapsed: Duration) -> StatsRates {
+        let secs = elapsed.as_secs_f64();
+        let rate = |count: u64| if secs > 0.0 { count as f64 / secs } else { 0.0 };
+        StatsRates {
+            hits: rate(self.hits),
+            misses: rate(self.misses),
+            evictions: rate(self.evictions),
+            inserts: rate(self.inserts),
+        }
+    }
+}
+
+/// Hits and misses recorded during one second of the window
+#[derive(Debug, Default)]
+struct Bucket {
+    /// Second (since the window started) this bucket currently counts
+    second: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Hit/miss counts over the last [`WINDOW_SECS`] seconds
+///
+/// A ring of per-second buckets; a bucket is recycled lazily the first time
+/// it is written in a new second. Counts are approximate under contention.
+#[derive(Debug)]
+pub struct WindowedStats {
+    start: Instant,
+    buckets: Vec<Bucket>,
+}
+
+impl Default for WindowedStats {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            buckets: (0..WINDOW_SECS).map(|_| Bucket::default()).collect(),
+        }
+    }
+}
+
+impl WindowedStats {
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_secs()
+    }
+
+    fn bucket_at(&self, second: u64) -> &Bucket {
+        let bucket = &self.buckets[(second % WINDOW_SECS) as usize];
+        let stamp = bucket.second.load(Ordering::Relaxed);
+        if stamp != second
+            && bucket
+                .second
+                .compare_exchange(stamp, second, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            bucket.hits.store(0, Ordering::Relaxed);
+            bucket.misses.store(0, Ordering::Relaxed);
+        }
+        bucket
+    }
+
+    fn record_hit_at(&self, second: u64) {
+        self.bucket_at(second).hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_miss_at(&self, second: u64) {
+        self.bucket_at(second)
+            .misses
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn hit_ratio_at(&self, now: u64) -> f64 {
+        let (mut hits, mut misses) = (0, 0);
+        for bucket in &self.buckets {
+            let second = bucket.second.load(Ordering::Relaxed);
+            if second <= now && now - second < WINDOW_SECS {
+                hits += bucket.hits.load(Ordering::Relaxed);
+                misses += bucket.misses.load(Ordering::Relaxed);
+            }
+        }
+
+        let total = hits + misses;
+        if total == 0 {
+            0.0
+        } else {
+            hits as f64 / total as f64
+        }
+    }
+
+    fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.hits.store(0, Ordering::Relaxed);
+            bucket.misses.store(0, Ordering::Relaxed);
+        }
+    }
 }
 
 impl CacheStats {
@@ -20,11 +193,13 @@ impl CacheStats {
     /// Record a cache hit
     pub fn record_hit(&self) {
         self.hits.fetch_add(1, Ordering::Relaxed);
+        self.recent.record_hit_at(self.recent.now());
     }
 
     /// Record a cache miss
     pub fn record_miss(&self) {
         self.misses.fetch_add(1, Ordering::Relaxed);
+        self.recent.record_miss_at(self.recent.now());
     }
 
     /// Record an eviction
@@ -68,12 +243,46 @@ impl CacheStats {
         }
     }
 
+    /// Calculate hit ratio over the last [`WINDOW_SECS`] seconds (0.0 to 1.0)
+    pub fn recent_hit_ratio(&self) -> f64 {
+        self.recent.hit_ratio_at(self.recent.now())
+    }
+
+    /// Current value of every counter
+    ///
+    /// Counters are read one after the other, so a snapshot taken while
+    /// others record may be off by the events recorded in between.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            hits: self.hits(),
+            misses: self.misses(),
+            evictions: self.evictions(),
+            inserts: self.inserts(),
+        }
+    }
+
+    /// Read and zero every counter, for reset-on-read reporting
+    ///
+    /// Each counter is swapped with 0 in one atomic step, so every event is
+    /// in exactly one taken snapshot, unlike a `snapshot` followed by
+    /// `reset`, which drops events recorded in between. The recent hit
+    /// ratio window is left as it is.
+    pub fn take(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            hits: self.hits.swap(0, Ordering::Relaxed),
+            misses: self.misses.swap(0, Ordering::Relaxed),
+            evictions: self.evictions.swap(0, Ordering::Relaxed),
+            inserts: self.inserts.swap(0, Ordering::Relaxed),
+        }
+    }
+
     /// Reset all statistics
     pub fn reset(&self) {
         self.hits.store(0, Ordering::Relaxed);
         self.misses.store(0, Ordering::Relaxed);
         self.evictions.store(0, Ordering::Relaxed);
         self.inserts.store(0, Ordering::Relaxed);
+        self.recent.reset();
     }
 }
 
@@ -106,4 +315,117 @@ mod tests {
         assert_eq!(stats.misses(), 0);
         assert_eq!(stats.hit_ratio(), 0.0);
     }
+
+    #[test]
+    fn test_snapshot_delta() {
+        let stats = CacheStats::new();
+        stats.record_hit();
+        stats.record_insert();
+        let before = stats.snapshot();
+
+        for _ in 0..30 {
+            stats.record_hit();
+        }
+        for _ in 0..10 {
+            stats.record_miss();
+        }
+        stats.record_eviction();
+        let after = stats.snapshot();
+        assert_eq!(after.hits, 31);
+
+        let delta = after.delta(&before);
+        assert_eq!(
+            delta,
+            StatsDelta {
+                hits: 30,
+                misses: 10,
+                evictions: 1,
+                inserts: 0,
+            }
+        );
+        assert_eq!(delta.hit_ratio(), 0.75);
+
+        let rates = delta.per_second(Duration::from_secs(10));
+        assert_eq!(rates.hits, 3.0);
+        assert_eq!(rates.misses, 1.0);
+        assert_eq!(rates.evictions, 0.1);
+        assert_eq!(delta.per_second(Duration::ZERO), StatsRates::default());
+
+        // After a reset, everything since counts
+        stats.reset();
+        stats.record_hit();
+        assert_eq!(stats.snapshot().delta(&after).hits, 1);
+    }
+
+    #[test]
+    fn test_take_loses_nothing() {
+        let stats = CacheStats::new();
+        let done = std::sync::atomic::AtomicBool::new(false);
+
+        let taken = std::thread::scope(|scope| {
+            let taker = scope.spawn(|| {
+                let mut taken = StatsSnapshot::default();
+                while !done.load(Ordering::Acquire) {
+                    let snapshot = stats.take();
+                    taken.hits += snapshot.hits;
+                    taken.misses += snapshot.misses;
+                }
+                taken
+            });
+
+            let recorders: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        for i in 0..10_000 {
+                            if i % 4 == 0 {
+                                stats.record_miss();
+                            } else {
+                                stats.record_hit();
+                            }
+                        }
+                    })
+                })
+                .collect();
+            for recorder in recorders {
+                recorder.join().unwrap();
+            }
+            done.store(true, Ordering::Release);
+            taker.join().unwrap()
+        });
+
+        let rest = stats.take();
+        assert_eq!(taken.hits + rest.hits, 30_000);
+        assert_eq!(taken.misses + rest.misses, 10_000);
+        assert_eq!(stats.snapshot(), StatsSnapshot::default());
+    }
+
+    #[test]
+    fn test_recent_hit_ratio_ages_out() {
+        let window = WindowedStats::default();
+
+        window.record_hit_at(0);
+        window.record_hit_at(0);
+        window.record_miss_at(30);
+        assert_eq!(window.hit_ratio_at(30), 2.0 / 3.0);
+
+        // The hits from second 0 have left the window, the miss has not
+        assert_eq!(window.hit_ratio_at(60), 0.0);
+        window.record_hit_at(61);
+        assert_eq!(window.hit_ratio_at(61), 0.5);
+
+        // Everything ages out eventually
+        assert_eq!(window.hit_ratio_at(200), 0.0);
+    }
+
+    #[test]
+    fn test_recent_hit_ratio_tracks_lifetime_when_fresh() {
+        let stats = CacheStats::new();
+
+        stats.record_hit();
+        stats.record_miss();
+
+        assert_eq!(stats.recent_hit_ratio(), 0.5);
+        stats.reset();
+        assert_eq!(stats.recent_hit_ratio(), 0.0);
+    }
 }
@@ -19,6 +19,35 @@ fn bench_put(c: &mut Criterion) {
     group.finish();
 }
 
+fn bench_bulk_load(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bulk_load");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(100_000));
+
+    let lines: Vec<Vec<u8>> = (0..100_000)
+        .map(|i| format!("user:{},Alice,alice@example.com", i).into_bytes())
+        .collect();
+
+    group.bench_function("loop_put_100k", |b| {
+        b.iter(|| {
+            let dir = TempDir::new().unwrap();
+            let db = ToonStore::open(dir.path()).unwrap();
+            for line in &lines {
+                black_box(db.put(line).unwrap());
+            }
+        });
+    });
+
+    group.bench_function("bulk_put_100k", |b| {
+        b.iter(|| {
+            let dir = TempDir::new().unwrap();
+            let db = ToonStore::open(dir.path()).unwrap();
+            black_box(db.bulk_put(lines.iter().map(|l| l.as_slice())).unwrap());
+        });
+    });
+    group.finish();
+}
+
 fn bench_get(c: &mut Criterion) {
     let mut group = c.benchmark_group("get");
     group.sample_size(50);
@@ -121,6 +150,7 @@ fn bench_scan(c: &mut Criterion) {
 criterion_group!(
     benches,
     bench_put,
+    bench_bulk_load,
     bench_get,
     bench_delete,
     bench_scan,
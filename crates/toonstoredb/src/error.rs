@@ -2,6 +2,7 @@
 
 use std::fmt;
 use std::io;
+use std::path::PathBuf;
 
 /// Result type alias for toonstoredb operations
 pub type Result<T> = std::result::Result<T, Error>;
@@ -15,17 +16,38 @@ pub enum Error {
     /// Parse error
     Parse(String),
 
-    /// Value too large (max 1 MB)
+    /// Value larger than the write's limit (1 MB unless raised)
     ValueTooLarge(usize),
 
-    /// Database full (max 1 GB)
+    /// Database reached its size limit (1 GB unless lowered)
     DatabaseFull(u64),
 
+    /// Database holds as many live keys as its `max_keys` allows
+    TooManyKeys(usize),
+
     /// Key not found
     NotFound,
 
     /// Database is closed
     Closed,
+
+    /// Database directory is already open in another handle or process
+    Locked(PathBuf),
+
+    /// Write attempted through a handle from `ToonStore::open_read_only`
+    ReadOnly,
+
+    /// Read-only open of a store still in an older on-disk format
+    NeedsUpgrade(u32),
+
+    /// Field not present in the record's schema header
+    UnknownField(String),
+
+    /// On-disk file failed validation (bad checksum, truncated)
+    Corrupt(String),
+
+    /// Record does not match the schema registered for its collection
+    SchemaViolation(String),
 }
 
 impl fmt::Display for Error {
@@ -33,10 +55,31 @@ impl fmt::Display for Error {
         match self {
             Error::Io(e) => write!(f, "I/O error: {}", e),
             Error::Parse(msg) => write!(f, "Parse error: {}", msg),
-            Error::ValueTooLarge(size) => write!(f, "Value too large: {} bytes (max 1 MB)", size),
-            Error::DatabaseFull(size) => write!(f, "Database full: {} bytes (max 1 GB)", size),
+            Error::ValueTooLarge(size) => write!(f, "Value too large: {} bytes", size),
+            Error::DatabaseFull(size) => write!(f, "Database full: {} bytes", size),
+            Error::TooManyKeys(max) => {
+                write!(
+                    f,
+                    "Too many keys: limit of {} reached, delete some first",
+                    max
+                )
+            }
             Error::NotFound => write!(f, "Key not found"),
             Error::Closed => write!(f, "Database is closed"),
+            Error::Locked(path) => write!(
+                f,
+                "Database directory {} is already open in another handle or process",
+                path.display()
+            ),
+            Error::ReadOnly => write!(f, "Database is open read-only"),
+            Error::NeedsUpgrade(version) => write!(
+                f,
+                "Data file version {} needs upgrading: open the store writable once",
+                version
+            ),
+            Error::UnknownField(name) => write!(f, "Unknown field: {}", name),
+            Error::Corrupt(msg) => write!(f, "Corrupt database file: {}", msg),
+            Error::SchemaViolation(msg) => write!(f, "Schema violation: {}", msg),
         }
     }
 }
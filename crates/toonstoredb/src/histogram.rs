@@ -0,0 +1,93 @@
+//! Value size histogram
+//!
+//! Lengths are counted in power-of-two buckets, each named by its upper
+//! bound: the bucket of `2^i` holds lengths above `2^(i-1)` up to `2^i`,
+//! and the first bucket, of 1, holds empty and one-byte values.
+
+/// Counts of value lengths per power-of-two bucket, see `ToonStore::size_histogram`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SizeHistogram {
+    /// Upper bound in bytes and number of values of each bucket, smallest
+    /// first, up to the bucket of the largest value
+    pub buckets: Vec<(u64, u64)>,
+    /// Values counted
+    pub rows: u64,
+    /// Shortest value counted (0 if none)
+    pub min: u64,
+    /// Longest value counted (0 if none)
+    pub max: u64,
+    /// Total length of the values counted
+    pub total_bytes: u64,
+    /// Whether only a sample of the live rows was counted
+    pub sampled: bool,
+}
+
+impl SizeHistogram {
+    /// Count one value of `len` bytes
+    pub fn add(&mut self, len: u64) {
+        let bucket = len.max(1).next_power_of_two();
+        let index = bucket.trailing_zeros() as usize;
+        if self.buckets.len() <= index {
+            let start = self.buckets.len();
+            self.buckets.extend((start..=index).map(|i| (1 << i, 0)));
+        }
+        self.buckets[index].1 += 1;
+
+        self.min = if self.rows == 0 {
+            len
+        } else {
+            self.min.min(len)
+        };
+        self.max = self.max.max(len);
+        self.total_bytes += len;
+        self.rows += 1;
+    }
+
+    /// Average value length (0 if none were counted)
+    pub fn mean(&self) -> f64 {
+        if self.rows == 0 {
+            return 0.0;
+        }
+        self.total_bytes as f64 / self.rows as f64
+    }
+}
+
+impl FromIterator<u64> for SizeHistogram {
+    fn from_iter<I: IntoIterator<Item = u64>>(lengths: I) -> Self {
+        let mut histogram = SizeHistogram::default();
+        for len in lengths {
+            histogram.add(len);
+        }
+        histogram
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_power_of_two_buckets() {
+        let histogram: SizeHistogram = [0, 1, 2, 3, 4, 5, 8, 9, 100].into_iter().collect();
+        assert_eq!(
+            histogram.buckets,
+            vec![
+                (1, 2),
+                (2, 1),
+                (4, 2),
+                (8, 2),
+                (16, 1),
+                (32, 0),
+                (64, 0),
+                (128, 1)
+            ]
+        );
+        assert_eq!(histogram.rows, 9);
+        assert_eq!((histogram.min, histogram.max), (0, 100));
+        assert_eq!(histogram.mean(), 132.0 / 9.0);
+
+        let empty = SizeHistogram::default();
+        assert!(empty.buckets.is_empty());
+        assert_eq!((empty.min, empty.max, empty.mean()), (0, 0, 0.0));
+    }
+}
@@ -11,11 +11,20 @@
 #![warn(missing_docs)]
 
 mod error;
+mod histogram;
+mod meta;
 mod parser;
+mod schema;
 mod storage;
 
 pub use error::{Error, Result};
-pub use storage::ToonStore;
+pub use histogram::SizeHistogram;
+pub use meta::RowMeta;
+pub use parser::ToonRecord;
+pub use storage::{
+    CompactProgress, FsyncPolicy, StoreConfig, ToonStore, ValuePage, VerifyLevel, LOCK_FILE,
+    MAX_DB_SIZE, MAX_VALUE_SIZE, MAX_VALUE_SIZE_LIMIT,
+};
 
 #[cfg(test)]
 mod tests {
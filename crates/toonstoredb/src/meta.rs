@@ -0,0 +1,132 @@
+//! Row metadata sidecar
+//!
+//! `db.toon.meta` holds a magic, a `u32` format version and then 16 bytes
+//! per row by row ID: the creation and last update time, each a `u64` of
+//! milliseconds since the Unix epoch. All zeros means unknown: the row was
+//! deleted, or written before the sidecar existed.
+//!
+//! The data and index files do not depend on it, so it is written entry by
+//! entry without its own checksum. A missing or unreadable sidecar is
+//! recreated with every row unknown rather than failing the open, and one
+//! shorter than the index (a crash between the index and sidecar writes)
+//! is padded the same way.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::Result;
+
+/// Sidecar file magic
+const META_MAGIC: &[u8] = b"TOONMETA";
+
+/// Sidecar format version
+const META_VERSION: u32 = 1;
+
+/// Sidecar header length: magic + version
+const META_HEADER_LEN: u64 = META_MAGIC.len() as u64 + 4;
+
+/// Bytes of one sidecar entry
+const META_ENTRY_LEN: u64 = 16;
+
+/// Creation and last update time of a row, see `ToonStore::row_metadata`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RowMeta {
+    /// When the row, or the first row it replaced, was written (Unix milliseconds)
+    pub created_at: u64,
+    /// When the row was written (Unix milliseconds)
+    pub updated_at: u64,
+}
+
+impl RowMeta {
+    /// Metadata of a row written now, replacing one created at `created_at`
+    pub(crate) fn written(created_at: Option<u64>) -> Self {
+        let now = now_millis();
+        RowMeta {
+            created_at: created_at.unwrap_or(now),
+            updated_at: now,
+        }
+    }
+
+    /// Whether the times are known
+    pub(crate) fn is_known(&self) -> bool {
+        self.created_at != 0
+    }
+
+    fn to_bytes(self) -> [u8; META_ENTRY_LEN as usize] {
+        let mut bytes = [0u8; META_ENTRY_LEN as usize];
+        bytes[..8].copy_from_slice(&self.created_at.to_le_bytes());
+        bytes[8..].copy_from_slice(&self.updated_at.to_le_bytes());
+        bytes
+    }
+}
+
+/// Open the sidecar in `path` and read the metadata of `rows` rows
+///
+/// Read-only, an invalid sidecar reads as unknown metadata and the file
+/// is left as it is.
+pub(crate) fn open(path: &Path, rows: usize, read_only: bool) -> Result<(File, Vec<RowMeta>)> {
+    let meta_path = path.join("db.toon.meta");
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(!read_only)
+        .create(!read_only)
+        .truncate(false)
+        .open(meta_path)?;
+
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    let valid = bytes.len() as u64 >= META_HEADER_LEN
+        && &bytes[..META_MAGIC.len()] == META_MAGIC
+        && bytes[META_MAGIC.len()..META_HEADER_LEN as usize] == META_VERSION.to_le_bytes();
+    if !valid {
+        if !read_only {
+            reset(&mut file)?;
+        }
+        bytes.clear();
+    }
+
+    let mut meta: Vec<RowMeta> = bytes
+        .get(META_HEADER_LEN as usize..)
+        .unwrap_or_default()
+        .chunks_exact(META_ENTRY_LEN as usize)
+        .map(|entry| RowMeta {
+            created_at: u64::from_le_bytes(entry[..8].try_into().unwrap()),
+            updated_at: u64::from_le_bytes(entry[8..].try_into().unwrap()),
+        })
+        .collect();
+    meta.resize(rows, RowMeta::default());
+    if !read_only {
+        file.set_len(META_HEADER_LEN + rows as u64 * META_ENTRY_LEN)?;
+    }
+
+    Ok((file, meta))
+}
+
+/// Truncate the sidecar back to its header
+pub(crate) fn reset(file: &mut File) -> Result<()> {
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(META_MAGIC)?;
+    file.write_all(&META_VERSION.to_le_bytes())?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Write the entries of consecutive rows starting at `first_row`
+pub(crate) fn write(file: &mut File, first_row: u64, entries: &[RowMeta]) -> Result<()> {
+    let bytes: Vec<u8> = entries.iter().flat_map(|meta| meta.to_bytes()).collect();
+    file.seek(SeekFrom::Start(
+        META_HEADER_LEN + first_row * META_ENTRY_LEN,
+    ))?;
+    file.write_all(&bytes)?;
+    file.flush()?;
+    Ok(())
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
@@ -5,25 +5,42 @@
 //! TOON001
 //! [version: u32]
 //! [row_count: u32]
-//! ...TOON lines (\n-terminated)...
+//! ...rows: [length: u32][TOON line]...
 //! ```
 //!
+//! Version 1 files ended every line with `\n` instead of a length prefix.
+//!
 //! TOON line format (from toondb spec):
 //! ```text
 //! collection[count]{field1,field2,...}:
 //!   value1,value2,...
 //!   value1,value2,...
 //! ```
+//!
+//! A stored row holds a single record on one line:
+//! ```text
+//! user:1{id,name,age}: user:1,John Doe,30
+//! ```
+//! Values containing `,`, `"`, `\`, a newline or leading whitespace are
+//! double-quoted with backslash escapes.
 
-use nom::{bytes::complete::take_until, character::complete::char, sequence::terminated, IResult};
+use nom::{
+    bytes::complete::take_until,
+    character::complete::char,
+    sequence::{delimited, terminated, tuple},
+    IResult,
+};
 
 use crate::error::{Error, Result};
 
 /// Magic header for TOON files
 pub const TOON_MAGIC: &[u8] = b"TOON001\n";
 
-/// Magic header for TOON index files
-pub const TOON_IDX_MAGIC: &[u8] = b"TOONIDX1";
+/// Magic header for TOON index files (version 2: trailing checksum)
+pub const TOON_IDX_MAGIC: &[u8] = b"TOONIDX2";
+
+/// Magic header of version 1 index files, which have no checksum
+pub const TOON_IDX_MAGIC_V1: &[u8] = b"TOONIDX1";
 
 /// TOON file header
 #[derive(Debug, Clone, PartialEq)]
@@ -90,6 +107,166 @@ pub fn parse_line(input: &[u8]) -> IResult<&[u8], &[u8]> {
     terminated(take_until("\n"), char('\n'))(input)
 }
 
+/// A single TOON record: a name, its field schema and one value per field
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToonRecord {
+    /// Record name (e.g. `user:1`)
+    pub name: String,
+    /// Field names from the schema header, in order
+    pub fields: Vec<String>,
+    /// Raw value for each field, in the same order as `fields`
+    pub values: Vec<Vec<u8>>,
+}
+
+/// Parse `name{field1,field2,...}:` returning the name, field list and the rest
+fn record_header(input: &[u8]) -> IResult<&[u8], (&[u8], &[u8])> {
+    terminated(
+        tuple((
+            take_until("{"),
+            delimited(char('{'), take_until("}"), char('}')),
+        )),
+        char(':'),
+    )(input)
+}
+
+impl ToonRecord {
+    /// Parse a single-line TOON record
+    pub fn parse(line: &[u8]) -> Result<Self> {
+        let (rest, (name, fields)) = record_header(line)?;
+
+        let fields: Vec<String> = String::from_utf8_lossy(fields)
+            .split(',')
+            .map(|f| f.trim().to_string())
+            .collect();
+
+        // Values may follow on the same line after optional spaces
+        let start = rest.iter().position(|&b| b != b' ').unwrap_or(rest.len());
+        let values = parse_values(&rest[start..])?;
+
+        if values.len() != fields.len() {
+            return Err(Error::Parse(format!(
+                "record has {} fields but {} values",
+                fields.len(),
+                values.len()
+            )));
+        }
+
+        Ok(Self {
+            name: String::from_utf8_lossy(name).to_string(),
+            fields,
+            values,
+        })
+    }
+
+    /// Serialize back to a single line (no trailing newline)
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut line = format!("{}{{{}}}: ", self.name, self.fields.join(",")).into_bytes();
+        for (i, value) in self.values.iter().enumerate() {
+            if i > 0 {
+                line.push(b',');
+            }
+            write_value(&mut line, value);
+        }
+        line
+    }
+
+    /// Value of a field, if the schema has it
+    pub fn get(&self, field: &str) -> Option<&[u8]> {
+        self.fields
+            .iter()
+            .position(|f| f == field)
+            .map(|i| self.values[i].as_slice())
+    }
+
+    /// Replace the value of a field; fields outside the schema are rejected
+    pub fn set(&mut self, field: &str, value: &[u8]) -> Result<()> {
+        let i = self
+            .fields
+            .iter()
+            .position(|f| f == field)
+            .ok_or_else(|| Error::UnknownField(field.to_string()))?;
+        self.values[i] = value.to_vec();
+        Ok(())
+    }
+}
+
+/// Split a comma-separated value list, honouring quoted values
+fn parse_values(input: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut values = Vec::new();
+    let mut pos = 0;
+
+    loop {
+        let mut value = Vec::new();
+
+        if input.get(pos) == Some(&b'"') {
+            pos += 1;
+            loop {
+                match input.get(pos) {
+                    Some(b'"') => {
+                        pos += 1;
+                        break;
+                    }
+                    Some(b'\\') => {
+                        let escaped = match input.get(pos + 1) {
+                            Some(b'n') => b'\n',
+                            Some(b'r') => b'\r',
+                            Some(&b) => b,
+                            None => return Err(Error::Parse("unterminated escape".to_string())),
+                        };
+                        value.push(escaped);
+                        pos += 2;
+                    }
+                    Some(&b) => {
+                        value.push(b);
+                        pos += 1;
+                    }
+                    None => return Err(Error::Parse("unterminated quoted value".to_string())),
+                }
+            }
+        } else {
+            while let Some(&b) = input.get(pos) {
+                if b == b',' {
+                    break;
+                }
+                value.push(b);
+                pos += 1;
+            }
+        }
+
+        values.push(value);
+
+        match input.get(pos) {
+            Some(b',') => pos += 1,
+            None => return Ok(values),
+            Some(_) => return Err(Error::Parse("expected ',' after value".to_string())),
+        }
+    }
+}
+
+/// Append a value, quoting it if it would not survive `parse_values` unquoted
+fn write_value(out: &mut Vec<u8>, value: &[u8]) {
+    let needs_quotes = value.first() == Some(&b' ')
+        || value
+            .iter()
+            .any(|&b| matches!(b, b',' | b'"' | b'\\' | b'\n' | b'\r'));
+
+    if !needs_quotes {
+        out.extend_from_slice(value);
+        return;
+    }
+
+    out.push(b'"');
+    for &b in value {
+        match b {
+            b'"' | b'\\' => out.extend_from_slice(&[b'\\', b]),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            b'\r' => out.extend_from_slice(b"\\r"),
+            _ => out.push(b),
+        }
+    }
+    out.push(b'"');
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -147,4 +324,38 @@ mod tests {
             100
         );
     }
+
+    #[test]
+    fn test_record_round_trip() {
+        let line = b"user:1{id,name,email}: user:1,John Doe,john@example.com";
+        let record = ToonRecord::parse(line).unwrap();
+
+        assert_eq!(record.name, "user:1");
+        assert_eq!(record.fields, vec!["id", "name", "email"]);
+        assert_eq!(record.get("name"), Some(&b"John Doe"[..]));
+        assert_eq!(record.serialize(), line.to_vec());
+    }
+
+    #[test]
+    fn test_record_quoting() {
+        let mut record = ToonRecord::parse(b"note{title,body}:a,b").unwrap();
+        record.set("body", b"line one\nsays \"hi, there\"").unwrap();
+
+        let line = record.serialize();
+        assert!(!line.contains(&b'\n'));
+        assert_eq!(ToonRecord::parse(&line).unwrap(), record);
+    }
+
+    #[test]
+    fn test_record_rejects_bad_input() {
+        assert!(ToonRecord::parse(b"no header here").is_err());
+        assert!(ToonRecord::parse(b"r{a,b}: only-one").is_err());
+        assert!(ToonRecord::parse(b"r{a}: \"open").is_err());
+
+        let mut record = ToonRecord::parse(b"r{a}: 1").unwrap();
+        assert!(matches!(
+            record.set("missing", b"x"),
+            Err(Error::UnknownField(_))
+        ));
+    }
 }
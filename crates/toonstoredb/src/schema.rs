@@ -0,0 +1,125 @@
+//! Field schemas registered per collection
+//!
+//! A record belongs to the collection named by its record name up to the
+//! first `:`, so `user:1{id,name}: ...` is in collection `user`. Schemas are
+//! kept in `db.toon.schema` as a JSON object mapping each collection to its
+//! field names, rewritten through a temporary file on every change.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+use crate::parser::ToonRecord;
+
+/// Registered schemas, and the file they persist to (none in memory)
+pub(crate) struct Schemas {
+    path: Option<PathBuf>,
+    fields: BTreeMap<String, Vec<String>>,
+}
+
+impl Schemas {
+    /// Schemas of an in-memory store, never written anywhere
+    pub(crate) fn memory() -> Self {
+        Schemas {
+            path: None,
+            fields: BTreeMap::new(),
+        }
+    }
+
+    /// Load the schemas of the database in `dir`, if any were registered
+    pub(crate) fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join("db.toon.schema");
+        let fields = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| Error::Corrupt(format!("schema file: {}", e)))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Schemas {
+            path: Some(path),
+            fields,
+        })
+    }
+
+    /// Set the fields of `collection`, replacing any previous schema
+    pub(crate) fn register(&mut self, collection: &str, fields: Vec<String>) -> Result<()> {
+        if fields.is_empty() {
+            return Err(Error::Parse(
+                "a schema needs at least one field".to_string(),
+            ));
+        }
+        for (i, field) in fields.iter().enumerate() {
+            if field.is_empty() || field.trim() != field || field.contains([',', '{', '}']) {
+                return Err(Error::Parse(format!("invalid field name {:?}", field)));
+            }
+            if fields[..i].contains(field) {
+                return Err(Error::Parse(format!("duplicate field {:?}", field)));
+            }
+        }
+
+        let mut updated = self.fields.clone();
+        updated.insert(collection.to_string(), fields);
+        if let Some(path) = &self.path {
+            let json = serde_json::to_vec_pretty(&updated)
+                .map_err(|e| Error::Parse(format!("schema file: {}", e)))?;
+            let tmp_path = path.with_extension("schema.tmp");
+            std::fs::write(&tmp_path, json)?;
+            std::fs::rename(&tmp_path, path)?;
+        }
+        self.fields = updated;
+
+        Ok(())
+    }
+
+    /// Fields registered for `collection`
+    pub(crate) fn get(&self, collection: &str) -> Option<&[String]> {
+        self.fields.get(collection).map(Vec::as_slice)
+    }
+
+    /// Check a record against the schema of its collection, if it has one
+    pub(crate) fn check(&self, record: &ToonRecord) -> Result<()> {
+        let collection = collection(&record.name);
+        let Some(expected) = self.get(collection) else {
+            return Ok(());
+        };
+        if record.fields == expected {
+            return Ok(());
+        }
+
+        let missing: Vec<&str> = expected
+            .iter()
+            .filter(|field| !record.fields.contains(field))
+            .map(String::as_str)
+            .collect();
+        let unexpected: Vec<&str> = record
+            .fields
+            .iter()
+            .filter(|field| !expected.contains(field))
+            .map(String::as_str)
+            .collect();
+        let mut problems = Vec::new();
+        if !missing.is_empty() {
+            problems.push(format!("missing {}", missing.join(",")));
+        }
+        if !unexpected.is_empty() {
+            problems.push(format!("unexpected {}", unexpected.join(",")));
+        }
+        if problems.is_empty() {
+            problems.push("fields out of order or repeated".to_string());
+        }
+
+        Err(Error::SchemaViolation(format!(
+            "collection {} expects {{{}}}, row has {{{}}}: {}",
+            collection,
+            expected.join(","),
+            record.fields.join(","),
+            problems.join(", ")
+        )))
+    }
+}
+
+/// Collection of a record: its name up to the first `:`
+fn collection(name: &str) -> &str {
+    name.split(':').next().unwrap_or(name)
+}
@@ -1,128 +1,797 @@
 //! Storage engine implementation
 //!
 //! File layout:
-//! - `db.toon`: Data file with TOON header + rows
-//! - `db.toon.idx`: Index file mapping row IDs to offsets
+//! - `db.toon`: Data file with TOON header + rows, each a `u32` length and
+//!   a `u32` CRC-32 of the row followed by that many bytes, so values may
+//!   hold any byte and reads notice corruption (format version 3; version 1
+//!   files of newline-terminated rows and version 2 files without checksums
+//!   are upgraded on open)
+//! - `db.toon.idx`: Index file mapping row IDs to offsets, followed by a
+//!   checksum over all of them. Each entry also holds the row's type tag
+//!   and whether the row is compressed (see `StoreConfig`); a compressed
+//!   row is its value's length as a `u32` followed by the deflated value
+//! - `db.toon.meta`: Creation and update time of every row (see `meta`)
+//! - `db.toon.schema`: Field schemas registered per collection (see `schema`)
+//! - `db.toon.lock`: Empty file the open handle holds an exclusive lock on,
+//!   so a second open of the directory fails with `Error::Locked`; handles
+//!   from `ToonStore::open_read_only` write nothing and skip the lock
+//!
+//! `sync` and `close` rewrite the index into `db.toon.idx.tmp` and rename it
+//! over the real file, so a crash mid-rewrite leaves the old index intact.
+//!
+//! `compact_incremental` copies rows into `db.toon.compact` a few at a time.
+//! When the last row is copied, the new index is written to
+//! `db.toon.idx.compact` and both files are renamed into place, data file
+//! first. Open finishes a rename the crash interrupted, or drops the
+//! compaction files of a pass that had not got that far.
+//!
+//! A store opened with `ToonStore::open_memory` keeps its rows in memory
+//! instead and never touches the filesystem.
 
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
 use parking_lot::RwLock;
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 use crate::error::{Error, Result};
-use crate::parser::{create_header, parse_header, TOON_IDX_MAGIC, TOON_MAGIC};
+use crate::histogram::SizeHistogram;
+use crate::meta::{self, RowMeta};
+use crate::parser::{
+    create_header, parse_header, ToonRecord, TOON_IDX_MAGIC, TOON_IDX_MAGIC_V1, TOON_MAGIC,
+};
+use crate::schema::Schemas;
+
+/// Maximum value size (1 MB), the default limit of `put_tagged_limited`
+pub const MAX_VALUE_SIZE: usize = 1024 * 1024;
+
+/// Largest value any caller may store (512 MB)
+pub const MAX_VALUE_SIZE_LIMIT: usize = 512 * 1024 * 1024;
+
+/// Maximum database size (1 GB), the default and upper bound of `set_max_db_size`
+pub const MAX_DB_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// File in a database directory locked by the handle that has it open
+pub const LOCK_FILE: &str = "db.toon.lock";
+
+/// One page of `ToonStore::scan_with_values`: row IDs with their data, and the next cursor
+pub type ValuePage = (Vec<(u64, Vec<u8>)>, u64);
+
+/// Data file format version: rows prefixed with length and checksum
+const DATA_VERSION: u32 = 3;
+
+/// Bytes of the length and checksum in front of every row
+const ROW_PREFIX_LEN: u64 = 8;
+
+/// Bytes of the length in front of every version 2 row
+const V2_ROW_PREFIX_LEN: u64 = 4;
+
+/// Rows `VerifyLevel::Quick` checks, spread evenly over the store
+const QUICK_VERIFY_ROWS: usize = 1024;
+
+/// Index entries keep the row's type tag in their top byte
+const TAG_SHIFT: u32 = 56;
+
+/// Index entry bit set for rows stored compressed, just below the tag
+const COMPRESSED_BIT: u64 = 1 << (TAG_SHIFT - 1);
+
+/// Offset part of an index entry
+const OFFSET_MASK: u64 = COMPRESSED_BIT - 1;
+
+/// Bytes of the value length in front of a compressed row
+const COMPRESSED_LEN_PREFIX: usize = 4;
 
-/// Maximum value size (1 MB)
-const MAX_VALUE_SIZE: usize = 1024 * 1024;
+/// Index file header length: magic + entry count
+const IDX_HEADER_LEN: u64 = TOON_IDX_MAGIC.len() as u64 + 4;
 
-/// Maximum database size (1 GB)
-const MAX_DB_SIZE: u64 = 1024 * 1024 * 1024;
+/// How much of the data file `ToonStore::open_verified` checks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyLevel {
+    /// Check nothing beyond what `open` checks
+    None,
+    /// Check up to 1024 rows spread over the store
+    Quick,
+    /// Check every row
+    Full,
+}
+
+/// Progress report from `ToonStore::compact_incremental`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactProgress {
+    /// Rows copied or skipped as deleted by this call
+    pub rows_processed: usize,
+    /// Rows left before the compacted file replaces the data file
+    pub rows_remaining: usize,
+    /// Bytes freed, set by the call that finishes a pass
+    pub bytes_reclaimed: u64,
+    /// The pass is finished; another call starts a new one
+    pub done: bool,
+}
+
+/// When a file-backed store fsyncs its writes, see `StoreConfig::fsync`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Only `sync` and `close` fsync; a power loss may lose anything since
+    #[default]
+    Never,
+    /// A background thread fsyncs once a second, bounding the loss to about that
+    EverySec,
+    /// Every put and delete fsyncs before returning; slow, but nothing is lost
+    Always,
+}
+
+/// Options of a `ToonStore`, set with `ToonStore::with_config`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StoreConfig {
+    /// Deflate values of at least this many bytes before writing them
+    ///
+    /// A value is only stored compressed when that makes it smaller, and
+    /// reads inflate it again, so callers always see the bytes they put.
+    /// Rows written before are left as they are; compressed and plain rows
+    /// mix freely. In-memory stores never compress. `None` turns it off.
+    pub compress_threshold: Option<usize>,
+
+    /// Most live rows the store may hold; puts beyond fail with `Error::TooManyKeys`
+    ///
+    /// Deleted rows do not count, so deleting one makes room for a new
+    /// one. `replace` never fails on it, as it does not add a row overall.
+    /// `None` means no limit.
+    pub max_keys: Option<usize>,
+
+    /// When writes are fsynced; in-memory stores ignore it
+    ///
+    /// Every write reaches the OS before it returns whatever the policy, so
+    /// it survives the process crashing; the policy is about the machine
+    /// going down before the OS has written it out.
+    pub fsync: FsyncPolicy,
+}
+
+/// A compaction pass in progress
+struct Compaction {
+    /// Compacted data file being filled (`db.toon.compact`)
+    file: File,
+    /// New offset of every row copied so far, by row ID (0 for deleted rows)
+    offsets: Vec<u64>,
+    /// Size of the compacted file so far
+    size: u64,
+}
+
+/// Files, offsets, row lengths and data file size of a freshly opened database
+type Opened = (File, File, Vec<Option<u64>>, Vec<u32>, u64);
 
 /// ToonStore is the main database handle
 pub struct ToonStore {
-    /// Path to the database directory
-    #[allow(dead_code)] // Will be used for compaction
-    path: PathBuf,
+    /// Where the rows are kept, shared with the `FsyncPolicy::EverySec` thread
+    backend: Arc<Backend>,
+
+    /// In-memory index: row_id -> offset in data file, tagged (None = deleted)
+    index: Arc<RwLock<Vec<Option<u64>>>>,
 
-    /// Data file handle
-    data_file: Arc<RwLock<File>>,
+    /// Byte length of every row by row ID (deleted rows keep theirs)
+    lengths: Arc<RwLock<Vec<u32>>>,
 
-    /// Index file handle
-    idx_file: Arc<RwLock<File>>,
+    /// Creation and update time of every row by row ID (zeroed when deleted)
+    meta: Arc<RwLock<Vec<RowMeta>>>,
 
-    /// In-memory index: row_id -> offset in data file (None = deleted)
-    index: Arc<RwLock<Vec<Option<u64>>>>,
+    /// Number of non-deleted rows in the index
+    live_rows: Arc<RwLock<usize>>,
 
-    /// Current database size
+    /// Current database size: the end of the last complete row
+    ///
+    /// Appends start here rather than at the file's physical end, and only
+    /// move it once every byte of the row is written.
     db_size: Arc<RwLock<u64>>,
 
+    /// Size at which writes fail with `Error::DatabaseFull`
+    max_db_size: Arc<RwLock<u64>>,
+
+    /// Field schemas checked by `put_validated`
+    schemas: Arc<RwLock<Schemas>>,
+
+    /// Options set with `with_config`
+    config: StoreConfig,
+
+    /// Bumped by every `with_config`; the `FsyncPolicy::EverySec` thread
+    /// keeps going only while it still holds the generation it started at
+    sync_generation: Arc<AtomicU64>,
+
+    /// Opened with `open_read_only`: every write fails with `Error::ReadOnly`
+    read_only: bool,
+
     /// Is the database closed?
     closed: Arc<RwLock<bool>>,
 }
 
+/// Row storage behind a `ToonStore`
+///
+/// Lock order is always: `db_size`, then the backend's data lock
+/// (`data_file` or the memory rows), then `compaction`, then `index`, then
+/// `idx_file`, then `meta_file`.
+/// `lengths`, `meta` and `live_rows` are only held briefly while `index` is.
+enum Backend {
+    /// Data and index files in a directory
+    File {
+        /// Path to the database directory
+        path: PathBuf,
+        /// Data file handle
+        data_file: RwLock<File>,
+        /// Index file handle
+        idx_file: RwLock<File>,
+        /// Row metadata sidecar handle
+        meta_file: RwLock<File>,
+        /// Compaction pass in progress, if any
+        compaction: RwLock<Option<Compaction>>,
+        /// Exclusive lock on `LOCK_FILE`, released on close or drop (None = read-only)
+        lock: Option<File>,
+    },
+    /// Rows held in memory by row ID (deleted rows are emptied)
+    Memory(RwLock<Vec<Vec<u8>>>),
+}
+
 impl ToonStore {
     /// Open or create a database at the given path
     ///
+    /// The directory stays locked until the handle is dropped; opening it
+    /// again meanwhile, from this process or another, fails with
+    /// `Error::Locked` rather than letting two writers interleave.
+    ///
     /// # Arguments
     /// * `path` - Directory path for the database files
     ///
     /// # Returns
     /// * `Result<ToonStore>` - Database handle
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with_repair(path, false)
+    }
+
+    /// Open or create a database, optionally repairing row count mismatches
+    ///
+    /// Opening cross-checks the header row count, the number of index
+    /// entries and the number of rows in the data file, which reads the
+    /// whole data file once. They only disagree after a crash mid-write.
+    /// Without `repair` a mismatch fails with `Error::Parse`, and an index
+    /// left with a stale checksum by such a crash with `Error::Corrupt`;
+    /// with it, all three are truncated to the rows they agree on, the rest
+    /// is dropped and the index is rewritten with a fresh checksum.
+    pub fn open_with_repair<P: AsRef<Path>>(path: P, repair: bool) -> Result<Self> {
         let path = path.as_ref();
         std::fs::create_dir_all(path)?;
+        let lock = Self::lock_dir(path)?;
 
         let data_path = path.join("db.toon");
         let idx_path = path.join("db.toon.idx");
+        Self::recover_compaction(path)?;
 
-        let (data_file, idx_file, index, db_size) = if data_path.exists() {
+        let opened = if data_path.exists() {
             // Open existing database
-            Self::open_existing(&data_path, &idx_path)?
+            Self::open_existing(&data_path, &idx_path, repair, false)?
         } else {
             // Create new database
             Self::create_new(&data_path, &idx_path)?
         };
 
+        Self::from_opened(path, opened, Some(lock))
+    }
+
+    /// Open a database for reading only
+    ///
+    /// Nothing is written and the directory is not locked, so any number of
+    /// read-only handles may share it with each other and with one writer.
+    /// Puts, deletes and the other writes, `sync` and `close` fail with
+    /// `Error::ReadOnly`. The handle sees the rows as they were when it
+    /// opened; `reload` catches up with what a writer added since. The
+    /// directory must exist and be in the current format, i.e. opened for
+    /// writing at least once since an upgrade; older stores fail with
+    /// `Error::NeedsUpgrade`. Rows a writer is still
+    /// adding are left out; opening while a writer deletes may fail on the
+    /// index checksum, and succeed when retried.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let opened = Self::open_existing(
+            &path.join("db.toon"),
+            &Self::read_only_index_path(path),
+            false,
+            true,
+        )?;
+        Self::from_opened(path, opened, None)
+    }
+
+    /// Index file a read-only handle reads, which cannot finish an interrupted compaction
+    ///
+    /// A compaction that crashed after renaming the data file left the
+    /// matching index at `db.toon.idx.compact` (see `recover_compaction`).
+    fn read_only_index_path(path: &Path) -> PathBuf {
+        let compact_idx = path.join("db.toon.idx.compact");
+        if compact_idx.exists() && !path.join("db.toon.compact").exists() {
+            compact_idx
+        } else {
+            path.join("db.toon.idx")
+        }
+    }
+
+    /// Build a file-backed store from its opened files, read-only without a `lock`
+    fn from_opened(path: &Path, opened: Opened, lock: Option<File>) -> Result<Self> {
+        let (data_file, idx_file, index, lengths, db_size) = opened;
+        let read_only = lock.is_none();
+        let live_rows = index.iter().filter(|offset| offset.is_some()).count();
+        let (meta_file, meta) = meta::open(path, index.len(), read_only)?;
+        let schemas = Schemas::load(path)?;
+
         Ok(ToonStore {
-            path: path.to_path_buf(),
-            data_file: Arc::new(RwLock::new(data_file)),
-            idx_file: Arc::new(RwLock::new(idx_file)),
+            backend: Arc::new(Backend::File {
+                path: path.to_path_buf(),
+                data_file: RwLock::new(data_file),
+                idx_file: RwLock::new(idx_file),
+                meta_file: RwLock::new(meta_file),
+                compaction: RwLock::new(None),
+                lock,
+            }),
             index: Arc::new(RwLock::new(index)),
+            lengths: Arc::new(RwLock::new(lengths)),
+            meta: Arc::new(RwLock::new(meta)),
+            live_rows: Arc::new(RwLock::new(live_rows)),
             db_size: Arc::new(RwLock::new(db_size)),
+            max_db_size: Arc::new(RwLock::new(MAX_DB_SIZE)),
+            schemas: Arc::new(RwLock::new(schemas)),
+            config: StoreConfig::default(),
+            sync_generation: Arc::new(AtomicU64::new(0)),
+            read_only,
             closed: Arc::new(RwLock::new(false)),
         })
     }
 
+    /// Take the exclusive lock on a database directory
+    ///
+    /// The lock is advisory (`flock` on Unix) and goes with the file handle,
+    /// so the operating system releases it if the process dies.
+    fn lock_dir(path: &Path) -> Result<File> {
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path.join(LOCK_FILE))?;
+        match lock.try_lock() {
+            Ok(()) => Ok(lock),
+            Err(TryLockError::WouldBlock) => Err(Error::Locked(path.to_path_buf())),
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+
+    /// Open a database and check rows against their checksums
+    ///
+    /// `open` only follows the row lengths, so a damaged row goes unnoticed
+    /// until it is read. Also returns the IDs of the rows that failed, see
+    /// `verify`.
+    pub fn open_verified<P: AsRef<Path>>(path: P, level: VerifyLevel) -> Result<(Self, Vec<u64>)> {
+        let db = Self::open(path)?;
+        let corrupt = db.verify(level)?;
+        Ok((db, corrupt))
+    }
+
+    /// Read live rows and return the IDs of those failing their checksum
+    ///
+    /// `Quick` reads at most 1024 rows, evenly spaced by row ID; `Full`
+    /// reads them all. Rows in memory are never corrupt.
+    pub fn verify(&self, level: VerifyLevel) -> Result<Vec<u64>> {
+        if *self.closed.read() {
+            return Err(Error::Closed);
+        }
+
+        let data_file = match (&*self.backend, level) {
+            (_, VerifyLevel::None) | (Backend::Memory(_), _) => return Ok(Vec::new()),
+            (Backend::File { data_file, .. }, _) => data_file,
+        };
+
+        let live: Vec<(u64, u64)> = self
+            .index
+            .read()
+            .iter()
+            .enumerate()
+            .filter_map(|(row_id, entry)| entry.map(|entry| (row_id as u64, entry & OFFSET_MASK)))
+            .collect();
+        let step = match level {
+            VerifyLevel::Quick => live.len().div_ceil(QUICK_VERIFY_ROWS).max(1),
+            _ => 1,
+        };
+
+        let mut data_file = data_file.write();
+        let mut corrupt = Vec::new();
+        for &(row_id, offset) in live.iter().step_by(step) {
+            match read_row_at(&mut data_file, offset) {
+                Ok(_) => {}
+                Err(Error::Corrupt(_)) => corrupt.push(row_id),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(corrupt)
+    }
+
+    /// Create an empty database that lives only in memory
+    ///
+    /// Supports the same operations as a file-backed store, with the same
+    /// size limits; `db_size` counts row bytes as they would be on disk,
+    /// without the header. `sync` and `reload` have nothing to do and
+    /// everything is lost when the handle is dropped.
+    pub fn open_memory() -> Self {
+        ToonStore {
+            backend: Arc::new(Backend::Memory(RwLock::new(Vec::new()))),
+            index: Arc::new(RwLock::new(Vec::new())),
+            lengths: Arc::new(RwLock::new(Vec::new())),
+            meta: Arc::new(RwLock::new(Vec::new())),
+            live_rows: Arc::new(RwLock::new(0)),
+            db_size: Arc::new(RwLock::new(0)),
+            max_db_size: Arc::new(RwLock::new(MAX_DB_SIZE)),
+            schemas: Arc::new(RwLock::new(Schemas::memory())),
+            config: StoreConfig::default(),
+            sync_generation: Arc::new(AtomicU64::new(0)),
+            read_only: false,
+            closed: Arc::new(RwLock::new(false)),
+        }
+    }
+
+    /// Use `config` for everything written from now on
+    pub fn with_config(mut self, config: StoreConfig) -> Self {
+        let every_sec = config.fsync == FsyncPolicy::EverySec;
+        self.config = config;
+        // Retires any thread started before, so there is never more than one
+        let generation = self.sync_generation.fetch_add(1, Ordering::Relaxed) + 1;
+        if every_sec {
+            self.spawn_syncer(generation);
+        }
+        self
+    }
+
+    /// Start the thread that fsyncs every second under `FsyncPolicy::EverySec`
+    ///
+    /// It stops once the store is closed or dropped, or configured again.
+    fn spawn_syncer(&self, generation: u64) {
+        if self.read_only || !matches!(*self.backend, Backend::File { .. }) {
+            return;
+        }
+
+        let backend = Arc::downgrade(&self.backend);
+        let current = Arc::clone(&self.sync_generation);
+        let closed = Arc::clone(&self.closed);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_secs(1));
+            let Some(backend) = backend.upgrade() else {
+                return;
+            };
+            if current.load(Ordering::Relaxed) != generation || *closed.read() {
+                return;
+            }
+            if let Backend::File {
+                data_file,
+                idx_file,
+                meta_file,
+                ..
+            } = &*backend
+            {
+                // One file at a time, so writers only ever wait for one fsync
+                let _ = data_file.read().sync_data();
+                let _ = idx_file.read().sync_data();
+                let _ = meta_file.read().sync_data();
+            }
+        });
+    }
+
+    /// Fsync the files a write just went to, under `FsyncPolicy::Always`
+    fn sync_written(&self, files: &[&File]) -> Result<()> {
+        if self.config.fsync == FsyncPolicy::Always {
+            for file in files {
+                file.sync_data()?;
+                #[cfg(test)]
+                SYNCS.with(|syncs| syncs.set(syncs.get() + 1));
+            }
+        }
+        Ok(())
+    }
+
+    /// Options the store was configured with
+    pub fn config(&self) -> &StoreConfig {
+        &self.config
+    }
+
+    /// Fail unless the store may be written: open, and not read-only
+    fn writable(&self) -> Result<()> {
+        if *self.closed.read() {
+            return Err(Error::Closed);
+        }
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Stored form of `line` if it is to be written compressed
+    fn compress(&self, line: &[u8]) -> Option<Vec<u8>> {
+        if matches!(*self.backend, Backend::Memory(_)) {
+            return None;
+        }
+        let threshold = self.config.compress_threshold?;
+        if line.len() < threshold {
+            return None;
+        }
+
+        let mut stored = Vec::with_capacity(line.len() / 2);
+        stored.extend_from_slice(&(line.len() as u32).to_le_bytes());
+        let mut encoder = DeflateEncoder::new(stored, Compression::fast());
+        encoder.write_all(line).ok()?;
+        let stored = encoder.finish().ok()?;
+        (stored.len() < line.len()).then_some(stored)
+    }
+
     fn open_existing(
         data_path: &Path,
         idx_path: &Path,
-    ) -> Result<(File, File, Vec<Option<u64>>, u64)> {
-        let mut data_file = OpenOptions::new().read(true).write(true).open(data_path)?;
-
-        let mut idx_file = OpenOptions::new().read(true).write(true).open(idx_path)?;
+        repair: bool,
+        read_only: bool,
+    ) -> Result<Opened> {
+        let mut data_file = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(data_path)?;
 
         // Read and validate data file header
         let mut header_buf = vec![0u8; TOON_MAGIC.len() + 8];
         data_file.read_exact(&mut header_buf)?;
-        let _header = parse_header(&header_buf)?;
+        let header = parse_header(&header_buf)?;
+        if !(1..=DATA_VERSION).contains(&header.version) {
+            return Err(Error::Parse(format!(
+                "Unsupported data file version {}",
+                header.version
+            )));
+        }
 
         // Read index file
-        let mut idx_magic = vec![0u8; TOON_IDX_MAGIC.len()];
-        idx_file.read_exact(&mut idx_magic)?;
-
-        if idx_magic != TOON_IDX_MAGIC {
-            return Err(Error::Parse("Invalid index file magic".to_string()));
-        }
-
-        let mut count_buf = [0u8; 4];
-        idx_file.read_exact(&mut count_buf)?;
-        let count = u32::from_le_bytes(count_buf);
-
-        // Read offsets
-        let mut index = Vec::with_capacity(count as usize);
-        for _ in 0..count {
-            let mut offset_buf = [0u8; 8];
-            idx_file.read_exact(&mut offset_buf)?;
-            let offset = u64::from_le_bytes(offset_buf);
-            // Offset of 0 means deleted (we never write at offset 0 due to header)
-            if offset == 0 {
-                index.push(None);
-            } else {
-                index.push(Some(offset));
+        let idx_bytes = std::fs::read(idx_path)?;
+        if idx_bytes.len() < IDX_HEADER_LEN as usize {
+            return Err(Error::Corrupt("index file header is truncated".to_string()));
+        }
+
+        let magic = &idx_bytes[..TOON_IDX_MAGIC.len()];
+        let has_checksum = match magic {
+            m if m == TOON_IDX_MAGIC => true,
+            m if m == TOON_IDX_MAGIC_V1 => false,
+            _ => return Err(Error::Parse("Invalid index file magic".to_string())),
+        };
+
+        // The entries run to the end of the file, followed by the checksum.
+        // The count in the header is not trusted: a crash between the
+        // writes of a put or delete leaves entries, checksum and count out
+        // of step, and may cut the last word short, which is dropped here.
+        let words: Vec<Option<u64>> = idx_bytes[IDX_HEADER_LEN as usize..]
+            .chunks_exact(8)
+            .map(|chunk| {
+                let offset = u64::from_le_bytes(chunk.try_into().unwrap());
+                // Offset of 0 means deleted (we never write at offset 0 due to header)
+                if offset == 0 {
+                    None
+                } else {
+                    Some(offset)
+                }
+            })
+            .collect();
+        let torn = !(idx_bytes.len() - IDX_HEADER_LEN as usize).is_multiple_of(8);
+        let (mut index, rebuild) = match words.split_last() {
+            _ if !has_checksum => (words, false),
+            Some((&stored, entries)) if stored.unwrap_or(0) == index_checksum(entries) => {
+                (entries.to_vec(), torn)
+            }
+            // Interrupted mid-write: every word may be an entry; the row
+            // count cross-check below drops whatever the write did not finish
+            _ if repair => (words, true),
+            _ => {
+                return Err(Error::Corrupt(
+                    "index checksum mismatch, open with repair to rebuild the index".to_string(),
+                ))
+            }
+        };
+
+        let mut idx_file = if has_checksum {
+            OpenOptions::new()
+                .read(true)
+                .write(!read_only)
+                .open(idx_path)?
+        } else if read_only {
+            File::open(idx_path)?
+        } else {
+            // Upgrade version 1 indexes so later writes can keep the checksum current
+            Self::write_index(idx_path, &index)?
+        };
+
+        // Cross-check the row counts
+        let header_rows = header.row_count as usize;
+        let header_len = header_buf.len() as u64;
+        let mut row_ends = match header.version {
+            1 => Self::line_ends(&mut data_file, header_len)?,
+            2 => Self::row_ends(&mut data_file, header_len, V2_ROW_PREFIX_LEN)?,
+            _ => Self::row_ends(&mut data_file, header_len, ROW_PREFIX_LEN)?,
+        };
+        if read_only {
+            // A writer adds the data row, then the index entry, then the header count
+            let rows = header_rows.min(index.len()).min(row_ends.len());
+            index.truncate(rows);
+            row_ends.truncate(rows);
+            if header.version < DATA_VERSION {
+                return Err(Error::NeedsUpgrade(header.version));
+            }
+        } else if header_rows != index.len() || row_ends.len() != index.len() {
+            if !repair {
+                return Err(Error::Parse(format!(
+                    "Row count mismatch: header has {}, index has {}, data file has {}",
+                    header_rows,
+                    index.len(),
+                    row_ends.len()
+                )));
+            }
+
+            let rows = header_rows.min(index.len()).min(row_ends.len());
+            let data_len = rows
+                .checked_sub(1)
+                .map_or(header_buf.len() as u64, |last| row_ends[last]);
+            data_file.set_len(data_len)?;
+            data_file.seek(SeekFrom::Start(TOON_MAGIC.len() as u64 + 4))?;
+            data_file.write_all(&(rows as u32).to_le_bytes())?;
+            data_file.sync_all()?;
+
+            index.truncate(rows);
+            row_ends.truncate(rows);
+            idx_file = Self::write_index(idx_path, &index)?;
+        } else if rebuild {
+            // The rows agree, only the checksum or the file's tail is off
+            idx_file = Self::write_index(idx_path, &index)?;
+        }
+
+        if header.version < DATA_VERSION {
+            drop((data_file, idx_file));
+            Self::upgrade(data_path, idx_path, &index, &row_ends, header.version)?;
+            return Self::open_existing(data_path, idx_path, false, false);
+        }
+
+        // Drop a row cut short by a crash mid-put; it never reached the index
+        let db_size = row_ends.last().copied().unwrap_or(header_buf.len() as u64);
+        if !read_only && data_file.seek(SeekFrom::End(0))? > db_size {
+            data_file.set_len(db_size)?;
+        }
+
+        // Each row runs from the end of the previous one, after its length
+        let mut row_start = header_buf.len() as u64;
+        let mut lengths: Vec<u32> = row_ends
+            .iter()
+            .map(|&end| {
+                let len = end - row_start - ROW_PREFIX_LEN;
+                row_start = end;
+                len as u32
+            })
+            .collect();
+
+        // A compressed row starts with the length of the value it holds
+        for (len, entry) in lengths.iter_mut().zip(&index) {
+            if let Some(entry) = entry.filter(|entry| entry & COMPRESSED_BIT != 0) {
+                let mut prefix = [0u8; COMPRESSED_LEN_PREFIX];
+                data_file.seek(SeekFrom::Start((entry & OFFSET_MASK) + ROW_PREFIX_LEN))?;
+                data_file.read_exact(&mut prefix)?;
+                *len = u32::from_le_bytes(prefix);
             }
         }
 
-        // Get database size
-        let db_size = data_file.seek(SeekFrom::End(0))?;
+        Ok((data_file, idx_file, index, lengths, db_size))
+    }
+
+    /// End position of every complete row after the header
+    ///
+    /// Follows the row lengths, each at the start of a `prefix_len` byte
+    /// row prefix; a row cut short at the end of the file is not counted.
+    fn row_ends(data_file: &mut File, header_len: u64, prefix_len: u64) -> Result<Vec<u64>> {
+        let file_len = data_file.seek(SeekFrom::End(0))?;
+        let mut reader = BufReader::with_capacity(64 * 1024, &mut *data_file);
+        reader.seek(SeekFrom::Start(header_len))?;
+
+        let mut ends = Vec::new();
+        let mut pos = header_len;
+        let mut prefix = [0u8; ROW_PREFIX_LEN as usize];
+        let prefix = &mut prefix[..prefix_len as usize];
+        while pos + prefix_len <= file_len {
+            reader.read_exact(prefix)?;
+            let len = u32::from_le_bytes(prefix[..4].try_into().unwrap()) as u64;
+            let end = pos + prefix_len + len;
+            if end > file_len {
+                break;
+            }
+            reader.seek_relative(len as i64)?;
+            ends.push(end);
+            pos = end;
+        }
 
-        Ok((data_file, idx_file, index, db_size))
+        Ok(ends)
     }
 
-    fn create_new(
+    /// Rewrite an older data file in the current format
+    ///
+    /// Version 1 rows end in a newline, version 2 rows start with their
+    /// length but have no checksum. Goes through the compaction files and
+    /// renames, so a crash part way leaves either the old files or the new
+    /// ones (see `recover_compaction`). Deleted rows are not copied.
+    fn upgrade(
         data_path: &Path,
         idx_path: &Path,
-    ) -> Result<(File, File, Vec<Option<u64>>, u64)> {
+        index: &[Option<u64>],
+        row_ends: &[u64],
+        version: u32,
+    ) -> Result<()> {
+        let path = data_path.parent().unwrap_or(Path::new("."));
+        let compact_path = path.join("db.toon.compact");
+        let compact_idx_path = path.join("db.toon.idx.compact");
+
+        let mut old = BufReader::new(File::open(data_path)?);
+        let header_len = (TOON_MAGIC.len() + 8) as u64;
+        old.seek(SeekFrom::Start(header_len))?;
+
+        let header = create_header(DATA_VERSION, index.len() as u32);
+        let mut new = BufWriter::new(File::create(&compact_path)?);
+        new.write_all(&header)?;
+
+        let mut new_index = Vec::with_capacity(index.len());
+        let mut size = header.len() as u64;
+        let mut row_start = header_len;
+        let mut old_row = Vec::new();
+        let mut new_row = Vec::new();
+        for (entry, &end) in index.iter().zip(row_ends) {
+            old_row.resize((end - row_start) as usize, 0);
+            old.read_exact(&mut old_row)?;
+            row_start = end;
+
+            let row: &[u8] = match (entry, version) {
+                (None, _) => &[],
+                (Some(_), 1) => &old_row[..old_row.len() - 1], // Without the newline
+                (Some(_), _) => &old_row[V2_ROW_PREFIX_LEN as usize..],
+            };
+            new_index.push(entry.map(|entry| size | (entry & !OFFSET_MASK)));
+            new_row.clear();
+            push_row(&mut new_row, row);
+            new.write_all(&new_row)?;
+            size += row_size(row);
+        }
+        new.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+        Self::write_index_file(&compact_idx_path, &new_index)?;
+        std::fs::rename(&compact_path, data_path)?;
+        std::fs::rename(&compact_idx_path, idx_path)?;
+
+        Ok(())
+    }
+
+    /// End position (just past the newline) of every line of a version 1 data file
+    fn line_ends(data_file: &mut File, header_len: u64) -> Result<Vec<u64>> {
+        data_file.seek(SeekFrom::Start(header_len))?;
+
+        let mut ends = Vec::new();
+        let mut pos = header_len;
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let n = data_file.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            for (i, &b) in buffer[..n].iter().enumerate() {
+                if b == b'\n' {
+                    ends.push(pos + i as u64 + 1);
+                }
+            }
+            pos += n as u64;
+        }
+
+        Ok(ends)
+    }
+
+    fn create_new(data_path: &Path, idx_path: &Path) -> Result<Opened> {
         let mut data_file = OpenOptions::new()
             .read(true)
             .write(true)
@@ -138,16 +807,17 @@ impl ToonStore {
             .open(idx_path)?;
 
         // Write TOON header
-        let header = create_header(1, 0);
+        let header = create_header(DATA_VERSION, 0);
         data_file.write_all(&header)?;
 
         // Write index header
         idx_file.write_all(TOON_IDX_MAGIC)?;
         idx_file.write_all(&0u32.to_le_bytes())?; // count = 0
+        idx_file.write_all(&0u64.to_le_bytes())?; // checksum of no entries
 
         let db_size = header.len() as u64;
 
-        Ok((data_file, idx_file, Vec::new(), db_size))
+        Ok((data_file, idx_file, Vec::new(), Vec::new(), db_size))
     }
 
     /// Put a TOON line into the database
@@ -158,52 +828,299 @@ impl ToonStore {
     /// # Returns
     /// * `Result<u64>` - Row ID of the inserted line
     pub fn put(&self, line: &[u8]) -> Result<u64> {
-        if *self.closed.read() {
-            return Err(Error::Closed);
+        self.put_tagged(line, 0)
+    }
+
+    /// Put a TOON line with a type tag, returned later by `row_tag`
+    ///
+    /// Tags mean whatever the caller needs, e.g. which data type a row
+    /// holds; untagged rows have tag 0. The tag is stored in the row's index
+    /// entry.
+    pub fn put_tagged(&self, line: &[u8], tag: u8) -> Result<u64> {
+        self.put_tagged_limited(line, tag, MAX_VALUE_SIZE)
+    }
+
+    /// Put a tagged TOON line of up to `max_value_size` bytes
+    ///
+    /// For callers trusted with values above `MAX_VALUE_SIZE`; the limit is
+    /// capped at `MAX_VALUE_SIZE_LIMIT`.
+    pub fn put_tagged_limited(&self, line: &[u8], tag: u8, max_value_size: usize) -> Result<u64> {
+        self.put_row(line, tag, max_value_size, None, false)
+    }
+
+    /// Register the fields every record of `collection` must have
+    ///
+    /// A record's collection is its name up to the first `:`. Replaces any
+    /// schema the collection had and is saved at once; rows already stored
+    /// are not checked. Only `put_validated` enforces schemas.
+    pub fn register_schema(&self, collection: &str, fields: Vec<String>) -> Result<()> {
+        self.writable()?;
+
+        self.schemas.write().register(collection, fields)
+    }
+
+    /// Fields registered for `collection`, if it has a schema
+    pub fn schema(&self, collection: &str) -> Option<Vec<String>> {
+        self.schemas.read().get(collection).map(<[String]>::to_vec)
+    }
+
+    /// Put a TOON record, checking it against its collection's schema
+    ///
+    /// Fails with `Error::SchemaViolation` if the record's fields are not
+    /// exactly the registered ones, in order. Records of collections
+    /// without a schema are stored as by `put`; lines that are not TOON
+    /// records fail to parse.
+    pub fn put_validated(&self, line: &[u8]) -> Result<u64> {
+        let record = ToonRecord::parse(line)?;
+        self.schemas.read().check(&record)?;
+
+        self.put(line)
+    }
+
+    /// Fail with `Error::TooManyKeys` unless `new_rows` more live rows fit
+    fn check_max_keys(&self, new_rows: usize) -> Result<()> {
+        match self.config.max_keys {
+            Some(max_keys) if *self.live_rows.read() + new_rows > max_keys => {
+                Err(Error::TooManyKeys(max_keys))
+            }
+            _ => Ok(()),
         }
+    }
 
-        if line.len() > MAX_VALUE_SIZE {
+    /// Put a row created at `created_at`, or now if `None`
+    ///
+    /// A row `replacing` another one that is deleted right after is not
+    /// held to `max_keys`.
+    fn put_row(
+        &self,
+        line: &[u8],
+        tag: u8,
+        max_value_size: usize,
+        created_at: Option<u64>,
+        replacing: bool,
+    ) -> Result<u64> {
+        self.writable()?;
+
+        if line.len() > max_value_size.min(MAX_VALUE_SIZE_LIMIT) {
             return Err(Error::ValueTooLarge(line.len()));
         }
 
+        let compressed = self.compress(line);
+        let stored = compressed.as_deref().unwrap_or(line);
         let mut db_size = self.db_size.write();
-        if *db_size + line.len() as u64 + 1 > MAX_DB_SIZE {
+        if *db_size + row_size(stored) > self.max_db_size() {
             return Err(Error::DatabaseFull(*db_size));
         }
+        // Every put holds the size lock, so two cannot both take the last slot
+        if !replacing {
+            self.check_max_keys(1)?;
+        }
 
-        let mut data_file = self.data_file.write();
-        let mut index = self.index.write();
+        let row_meta = RowMeta::written(created_at);
+        let (data_file, idx_file, meta_file) = match &*self.backend {
+            Backend::File {
+                data_file,
+                idx_file,
+                meta_file,
+                ..
+            } => (data_file, idx_file, meta_file),
+            Backend::Memory(rows) => {
+                let mut rows = rows.write();
+                let mut index = self.index.write();
+
+                let row_id = index.len() as u64;
+                index.push(Some(*db_size | (tag as u64) << TAG_SHIFT));
+                self.lengths.write().push(line.len() as u32);
+                self.meta.write().push(row_meta);
+                rows.push(line.to_vec());
+                *self.live_rows.write() += 1;
+                *db_size += row_size(line);
+
+                return Ok(row_id);
+            }
+        };
 
-        // Get current offset
-        let offset = data_file.seek(SeekFrom::End(0))?;
+        let mut data_file = data_file.write();
+        let mut index = self.index.write();
 
-        // Write line + newline
-        data_file.write_all(line)?;
-        data_file.write_all(b"\n")?;
-        data_file.flush()?; // Flush data to disk
+        // Write length + line in one go
+        let offset = *db_size;
+        let mut row = Vec::with_capacity(row_size(stored) as usize);
+        push_row(&mut row, stored);
+        write_step()?;
+        append_at(&mut data_file, offset, &row)?;
+        // Publish the size now: if a later step fails the index still holds
+        // the row, and the next put must not overwrite it
+        *db_size = offset + row.len() as u64;
 
         // Update index
         let row_id = index.len() as u64;
-        index.push(Some(offset));
+        let mut entry = offset | (tag as u64) << TAG_SHIFT;
+        if compressed.is_some() {
+            entry |= COMPRESSED_BIT;
+        }
+        index.push(Some(entry));
+        self.lengths.write().push(line.len() as u32);
+        self.meta.write().push(row_meta);
+        *self.live_rows.write() += 1;
 
         // Write index entry to disk immediately
-        let mut idx_file = self.idx_file.write();
+        let mut idx_file = idx_file.write();
+
+        // The new offset goes where the checksum was, the checksum moves after it
+        let checksum_pos = IDX_HEADER_LEN + row_id * 8;
+        let checksum =
+            read_u64_at(&mut idx_file, checksum_pos)?.wrapping_add(entry_checksum(row_id, entry));
+        idx_file.seek(SeekFrom::Start(checksum_pos))?;
+        write_step()?;
+        idx_file.write_all(&entry.to_le_bytes())?;
+        write_step()?;
+        idx_file.write_all(&checksum.to_le_bytes())?;
 
         // Update count at start of index file
         idx_file.seek(SeekFrom::Start(TOON_IDX_MAGIC.len() as u64))?;
+        write_step()?;
         idx_file.write_all(&(index.len() as u32).to_le_bytes())?;
-
-        // Append new offset
-        idx_file.seek(SeekFrom::End(0))?;
-        idx_file.write_all(&offset.to_le_bytes())?;
         idx_file.flush()?; // Flush index to disk
+        write_step()?;
+        meta::write(&mut meta_file.write(), row_id, &[row_meta])?;
 
-        // Update size
-        *db_size = offset + line.len() as u64 + 1;
+        // Header count last, so after a crash data rows >= index entries >= header count
+        data_file.seek(SeekFrom::Start(TOON_MAGIC.len() as u64 + 4))?;
+        write_step()?;
+        data_file.write_all(&(index.len() as u32).to_le_bytes())?;
+        data_file.flush()?;
+        self.sync_written(&[&data_file, &idx_file, &meta_file.read()])?;
 
         Ok(row_id)
     }
 
+    /// Put many TOON lines at once, returning their row IDs in order
+    ///
+    /// Takes every lock once and writes the rows, index entries and header
+    /// in one go, which is much faster than calling `put` in a loop for
+    /// large imports. The size limits are checked for every line, and
+    /// `max_keys` for the whole batch, before anything is written: if one
+    /// fails, the error is returned and none of the lines are stored.
+    pub fn bulk_put<'a, I>(&self, lines: I) -> Result<Vec<u64>>
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        self.writable()?;
+
+        let mut db_size = self.db_size.write();
+        let max_db_size = self.max_db_size();
+
+        // Stage every row first so a limit hit mid-batch leaves nothing behind
+        let mut batch = Vec::new();
+        let mut lengths = Vec::new();
+        // Stored length and index entry flags of every row
+        let mut stored_rows = Vec::new();
+        for line in lines {
+            if line.len() > MAX_VALUE_SIZE {
+                return Err(Error::ValueTooLarge(line.len()));
+            }
+            let compressed = self.compress(line);
+            let stored = compressed.as_deref().unwrap_or(line);
+            let size = *db_size + batch.len() as u64;
+            if size + row_size(stored) > max_db_size {
+                return Err(Error::DatabaseFull(size));
+            }
+            push_row(&mut batch, stored);
+            lengths.push(line.len() as u32);
+            let flags = if compressed.is_some() {
+                COMPRESSED_BIT
+            } else {
+                0
+            };
+            stored_rows.push((stored.len() as u64, flags));
+        }
+
+        if lengths.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.check_max_keys(lengths.len())?;
+
+        let row_meta = vec![RowMeta::written(None); lengths.len()];
+        let (data_file, idx_file, meta_file) = match &*self.backend {
+            Backend::File {
+                data_file,
+                idx_file,
+                meta_file,
+                ..
+            } => (data_file, idx_file, meta_file),
+            Backend::Memory(rows) => {
+                let mut rows = rows.write();
+                let mut index = self.index.write();
+
+                // Memory rows are never compressed, so they are stored as given
+                let first_row = index.len() as u64;
+                let mut offset = *db_size;
+                for &(len, _) in &stored_rows {
+                    let start = (offset - *db_size + ROW_PREFIX_LEN) as usize;
+                    rows.push(batch[start..start + len as usize].to_vec());
+                    index.push(Some(offset));
+                    offset += ROW_PREFIX_LEN + len;
+                }
+                self.lengths.write().extend_from_slice(&lengths);
+                self.meta.write().extend_from_slice(&row_meta);
+                *self.live_rows.write() += lengths.len();
+                *db_size = offset;
+
+                return Ok((first_row..first_row + lengths.len() as u64).collect());
+            }
+        };
+
+        let mut data_file = data_file.write();
+        let mut index = self.index.write();
+
+        let first_offset = *db_size;
+        write_step()?;
+        append_at(&mut data_file, first_offset, &batch)?;
+        // As in `put_row`, the rows are taken even if a later step fails
+        *db_size = first_offset + batch.len() as u64;
+
+        let first_row = index.len() as u64;
+        let mut entries = Vec::with_capacity(lengths.len() * 8 + 8);
+        let mut checksum_delta = 0u64;
+        let mut offset = first_offset;
+        for (i, &(len, flags)) in stored_rows.iter().enumerate() {
+            let entry = offset | flags;
+            index.push(Some(entry));
+            entries.extend_from_slice(&entry.to_le_bytes());
+            checksum_delta =
+                checksum_delta.wrapping_add(entry_checksum(first_row + i as u64, entry));
+            offset += ROW_PREFIX_LEN + len;
+        }
+        self.lengths.write().extend_from_slice(&lengths);
+        self.meta.write().extend_from_slice(&row_meta);
+        *self.live_rows.write() += lengths.len();
+
+        // New entries go where the checksum was, followed by the updated checksum
+        let mut idx_file = idx_file.write();
+        let checksum_pos = IDX_HEADER_LEN + first_row * 8;
+        let checksum = read_u64_at(&mut idx_file, checksum_pos)?.wrapping_add(checksum_delta);
+        entries.extend_from_slice(&checksum.to_le_bytes());
+        idx_file.seek(SeekFrom::Start(checksum_pos))?;
+        write_step()?;
+        idx_file.write_all(&entries)?;
+
+        idx_file.seek(SeekFrom::Start(TOON_IDX_MAGIC.len() as u64))?;
+        write_step()?;
+        idx_file.write_all(&(index.len() as u32).to_le_bytes())?;
+        idx_file.flush()?;
+        write_step()?;
+        meta::write(&mut meta_file.write(), first_row, &row_meta)?;
+
+        data_file.seek(SeekFrom::Start(TOON_MAGIC.len() as u64 + 4))?;
+        write_step()?;
+        data_file.write_all(&(index.len() as u32).to_le_bytes())?;
+        data_file.flush()?;
+        self.sync_written(&[&data_file, &idx_file, &meta_file.read()])?;
+
+        Ok((first_row..index.len() as u64).collect())
+    }
+
     /// Get a TOON line by row ID
     ///
     /// # Arguments
@@ -216,91 +1133,436 @@ impl ToonStore {
             return Err(Error::Closed);
         }
 
+        let data_file = match &*self.backend {
+            Backend::File { data_file, .. } => data_file,
+            Backend::Memory(rows) => {
+                let rows = rows.read();
+                return match self.index.read().get(row_id as usize) {
+                    Some(Some(_)) => Ok(rows[row_id as usize].clone()),
+                    _ => Err(Error::NotFound), // Missing or deleted
+                };
+            }
+        };
+
         let index = self.index.read();
 
         if row_id >= index.len() as u64 {
             return Err(Error::NotFound);
         }
 
-        let offset = match index[row_id as usize] {
-            Some(offset) => offset,
+        let entry = match index[row_id as usize] {
+            Some(entry) => entry,
             None => return Err(Error::NotFound), // Deleted
         };
         drop(index);
 
-        let mut data_file = self.data_file.write();
-        data_file.seek(SeekFrom::Start(offset))?;
+        let row = read_row_at(&mut data_file.write(), entry & OFFSET_MASK)?;
+        if entry & COMPRESSED_BIT != 0 {
+            return decompress(&row);
+        }
+        Ok(row)
+    }
 
-        // Read in chunks for better performance
-        let mut line = Vec::with_capacity(1024);
-        let mut buffer = [0u8; 4096];
+    /// Get several TOON lines at once, in the order given
+    ///
+    /// The data file and index are locked once for the whole batch rather
+    /// than once per row, and every row is read from the same state of the
+    /// store. Each row succeeds or fails on its own.
+    pub fn get_many(&self, row_ids: &[u64]) -> Vec<Result<Vec<u8>>> {
+        if *self.closed.read() {
+            return row_ids.iter().map(|_| Err(Error::Closed)).collect();
+        }
 
-        loop {
-            let n = data_file.read(&mut buffer)?;
-            if n == 0 {
-                break;
+        let data_file = match &*self.backend {
+            Backend::File { data_file, .. } => data_file,
+            Backend::Memory(rows) => {
+                let rows = rows.read();
+                let index = self.index.read();
+                return row_ids
+                    .iter()
+                    .map(|&row_id| match index.get(row_id as usize) {
+                        Some(Some(_)) => Ok(rows[row_id as usize].clone()),
+                        _ => Err(Error::NotFound), // Missing or deleted
+                    })
+                    .collect();
             }
+        };
 
-            // Find newline in buffer
-            if let Some(pos) = buffer[..n].iter().position(|&b| b == b'\n') {
-                line.extend_from_slice(&buffer[..pos]);
-                break;
-            } else {
-                line.extend_from_slice(&buffer[..n]);
+        let mut data_file = data_file.write();
+        let index = self.index.read();
+        row_ids
+            .iter()
+            .map(|&row_id| {
+                let entry = match index.get(row_id as usize) {
+                    Some(Some(entry)) => *entry,
+                    _ => return Err(Error::NotFound), // Missing or deleted
+                };
+                let row = read_row_at(&mut data_file, entry & OFFSET_MASK)?;
+                if entry & COMPRESSED_BIT != 0 {
+                    return decompress(&row);
+                }
+                Ok(row)
+            })
+            .collect()
+    }
+
+    /// Get `len` bytes of a TOON line starting at byte `start`
+    ///
+    /// The range is cut short at the end of the line, so a range past it
+    /// comes back short or empty. Only the range is read, unless the row is
+    /// compressed.
+    pub fn get_range(&self, row_id: u64, start: usize, len: usize) -> Result<Vec<u8>> {
+        if *self.closed.read() {
+            return Err(Error::Closed);
+        }
+
+        let end = start.saturating_add(len);
+
+        let data_file = match &*self.backend {
+            Backend::File { data_file, .. } => data_file,
+            Backend::Memory(rows) => {
+                let rows = rows.read();
+                return match self.index.read().get(row_id as usize) {
+                    Some(Some(_)) => {
+                        let row = &rows[row_id as usize];
+                        let start = start.min(row.len());
+                        Ok(row[start..end.min(row.len())].to_vec())
+                    }
+                    _ => Err(Error::NotFound), // Missing or deleted
+                };
             }
+        };
+
+        let (entry, row_len) = match self.index.read().get(row_id as usize) {
+            Some(Some(entry)) => (*entry, self.lengths.read()[row_id as usize] as usize),
+            _ => return Err(Error::NotFound), // Missing or deleted
+        };
+        if entry & COMPRESSED_BIT != 0 {
+            let row = self.get(row_id)?;
+            let start = start.min(row.len());
+            return Ok(row[start..end.min(row.len())].to_vec());
         }
+        let offset = entry & OFFSET_MASK;
 
-        Ok(line)
-    }
+        let start = start.min(row_len);
+        let mut range = vec![0u8; end.min(row_len) - start];
 
-    /// Get the number of rows in the database
-    pub fn len(&self) -> usize {
-        self.index.read().len()
-    }
+        let mut data_file = data_file.write();
+        data_file.seek(SeekFrom::Start(offset + ROW_PREFIX_LEN + start as u64))?;
+        data_file.read_exact(&mut range)?;
 
-    /// Check if the database is empty
-    pub fn is_empty(&self) -> bool {
-        self.index.read().is_empty()
+        Ok(range)
     }
 
-    /// Delete a TOON line by row ID (soft delete - marks as deleted)
+    /// Get the byte length of a TOON line without reading it
     ///
-    /// # Arguments
+    /// Answered from lengths kept in memory, so no I/O is done.
+    pub fn value_len(&self, row_id: u64) -> Result<usize> {
+        if *self.closed.read() {
+            return Err(Error::Closed);
+        }
+
+        match self.index.read().get(row_id as usize) {
+            Some(Some(_)) => Ok(self.lengths.read()[row_id as usize] as usize),
+            _ => Err(Error::NotFound), // Missing or deleted
+        }
+    }
+
+    /// Get the byte length a row takes up in the data file
+    ///
+    /// The same as `value_len` unless the row is compressed, in which case
+    /// the compressed length is read from the data file. The length and
+    /// checksum in front of every row are not counted.
+    pub fn stored_len(&self, row_id: u64) -> Result<usize> {
+        if *self.closed.read() {
+            return Err(Error::Closed);
+        }
+
+        let entry = match self.index.read().get(row_id as usize) {
+            Some(Some(entry)) if entry & COMPRESSED_BIT != 0 => *entry,
+            Some(Some(_)) => return Ok(self.lengths.read()[row_id as usize] as usize),
+            _ => return Err(Error::NotFound), // Missing or deleted
+        };
+        // Memory rows are never compressed
+        let Backend::File { data_file, .. } = &*self.backend else {
+            return Err(Error::NotFound);
+        };
+
+        let mut len = [0u8; 4];
+        let mut data_file = data_file.write();
+        data_file.seek(SeekFrom::Start(entry & OFFSET_MASK))?;
+        data_file.read_exact(&mut len)?;
+        Ok(u32::from_le_bytes(len) as usize)
+    }
+
+    /// Count live values by length in power-of-two buckets
+    ///
+    /// Lengths come from memory, so no I/O is done; they are the lengths
+    /// of the values, not of compressed rows. With `sample`, only about
+    /// that many rows spread evenly over the store are counted.
+    pub fn size_histogram(&self, sample: Option<usize>) -> Result<SizeHistogram> {
+        if *self.closed.read() {
+            return Err(Error::Closed);
+        }
+
+        let index = self.index.read();
+        let lengths = self.lengths.read();
+        let live = *self.live_rows.read();
+        let step = match sample {
+            Some(rows) => live.div_ceil(rows.max(1)).max(1),
+            None => 1,
+        };
+
+        let mut histogram: SizeHistogram = index
+            .iter()
+            .zip(lengths.iter())
+            .filter(|(entry, _)| entry.is_some())
+            .map(|(_, &len)| len as u64)
+            .step_by(step)
+            .collect();
+        histogram.sampled = step > 1;
+        Ok(histogram)
+    }
+
+    /// Get the type tag a row was stored with (0 if untagged)
+    pub fn row_tag(&self, row_id: u64) -> Result<u8> {
+        if *self.closed.read() {
+            return Err(Error::Closed);
+        }
+
+        match self.index.read().get(row_id as usize) {
+            Some(Some(entry)) => Ok((entry >> TAG_SHIFT) as u8),
+            _ => Err(Error::NotFound), // Missing or deleted
+        }
+    }
+
+    /// Get the number of rows in the database
+    pub fn len(&self) -> usize {
+        self.index.read().len()
+    }
+
+    /// Check if the database is empty
+    pub fn is_empty(&self) -> bool {
+        self.index.read().is_empty()
+    }
+
+    /// Get the number of rows that have not been deleted
+    ///
+    /// Unlike `len()`, soft-deleted rows are not counted. Maintained
+    /// incrementally, so this is O(1).
+    pub fn live_len(&self) -> usize {
+        *self.live_rows.read()
+    }
+
+    /// Get the memory allocated to the in-memory index in bytes
+    pub fn index_bytes(&self) -> usize {
+        self.index.read().capacity() * std::mem::size_of::<Option<u64>>()
+    }
+
+    /// Get the current size of the data file in bytes (header included)
+    pub fn db_size(&self) -> u64 {
+        *self.db_size.read()
+    }
+
+    /// Get the size limit for the data file in bytes
+    pub fn max_db_size(&self) -> u64 {
+        *self.max_db_size.read()
+    }
+
+    /// Set the size limit for the data file, capped at `MAX_DB_SIZE`
+    ///
+    /// Writes that would grow the data file past it fail with
+    /// `Error::DatabaseFull`. Lowering it below the current size keeps the
+    /// existing rows; only new writes are refused.
+    pub fn set_max_db_size(&self, bytes: u64) {
+        *self.max_db_size.write() = bytes.min(MAX_DB_SIZE);
+    }
+
+    /// Delete a TOON line by row ID (soft delete - marks as deleted)
+    ///
+    /// # Arguments
     /// * `row_id` - Row ID to delete
     ///
     /// # Returns
     /// * `Result<()>` - Ok if deleted, Err if not found or already deleted
     pub fn delete(&self, row_id: u64) -> Result<()> {
-        if *self.closed.read() {
-            return Err(Error::Closed);
-        }
+        self.writable()?;
 
+        let mut rows = match &*self.backend {
+            Backend::Memory(rows) => Some(rows.write()),
+            Backend::File { .. } => None,
+        };
         let mut index = self.index.write();
 
         if row_id >= index.len() as u64 {
             return Err(Error::NotFound);
         }
 
-        if index[row_id as usize].is_none() {
+        let Some(old_entry) = index[row_id as usize] else {
             return Err(Error::NotFound); // Already deleted
-        }
+        };
 
         // Mark as deleted
         index[row_id as usize] = None;
+        self.meta.write()[row_id as usize] = RowMeta::default();
+        *self.live_rows.write() -= 1;
+
+        let (idx_file, meta_file) = match &*self.backend {
+            Backend::File {
+                idx_file,
+                meta_file,
+                ..
+            } => (idx_file, meta_file),
+            Backend::Memory(_) => {
+                // Free the row, its ID stays reserved
+                if let Some(rows) = rows.as_mut() {
+                    rows[row_id as usize] = Vec::new();
+                }
+                return Ok(());
+            }
+        };
 
         // Update index file immediately
-        let mut idx_file = self.idx_file.write();
+        let mut idx_file = idx_file.write();
 
         // Seek to the offset for this row_id in the index file
-        // Index file format: TOONIDX1 (8 bytes) + count (4 bytes) + offsets (8 bytes each)
-        let offset_pos = TOON_IDX_MAGIC.len() as u64 + 4 + (row_id * 8);
+        // Index file format: TOONIDX2 (8 bytes) + count (4 bytes) + offsets (8 bytes each)
+        // + checksum (8 bytes)
+        let offset_pos = IDX_HEADER_LEN + (row_id * 8);
         idx_file.seek(SeekFrom::Start(offset_pos))?;
+        write_step()?;
         idx_file.write_all(&0u64.to_le_bytes())?; // 0 means deleted
+
+        let checksum_pos = IDX_HEADER_LEN + index.len() as u64 * 8;
+        let checksum = read_u64_at(&mut idx_file, checksum_pos)?
+            .wrapping_sub(entry_checksum(row_id, old_entry))
+            .wrapping_add(entry_checksum(row_id, 0));
+        idx_file.seek(SeekFrom::Start(checksum_pos))?;
+        write_step()?;
+        idx_file.write_all(&checksum.to_le_bytes())?;
         idx_file.flush()?;
+        write_step()?;
+        meta::write(&mut meta_file.write(), row_id, &[RowMeta::default()])?;
+        self.sync_written(&[&idx_file, &meta_file.read()])?;
 
         Ok(())
     }
 
+    /// Remove every row, truncating the files back to their headers
+    ///
+    /// Row IDs start again from 0 afterwards.
+    pub fn clear(&self) -> Result<()> {
+        self.writable()?;
+
+        let mut db_size = self.db_size.write();
+
+        let (path, data_file, idx_file, meta_file, compaction) = match &*self.backend {
+            Backend::File {
+                path,
+                data_file,
+                idx_file,
+                meta_file,
+                compaction,
+                ..
+            } => (path, data_file, idx_file, meta_file, compaction),
+            Backend::Memory(rows) => {
+                let mut rows = rows.write();
+                let mut index = self.index.write();
+                rows.clear();
+                index.clear();
+                self.lengths.write().clear();
+                self.meta.write().clear();
+                *self.live_rows.write() = 0;
+                *db_size = 0;
+                return Ok(());
+            }
+        };
+
+        let mut data_file = data_file.write();
+        Self::abort_compaction(path, &mut compaction.write())?;
+        let mut index = self.index.write();
+        let mut idx_file = idx_file.write();
+
+        let header = create_header(DATA_VERSION, 0);
+        data_file.set_len(0)?;
+        data_file.seek(SeekFrom::Start(0))?;
+        data_file.write_all(&header)?;
+        data_file.sync_all()?;
+
+        idx_file.set_len(0)?;
+        idx_file.seek(SeekFrom::Start(0))?;
+        idx_file.write_all(TOON_IDX_MAGIC)?;
+        idx_file.write_all(&0u32.to_le_bytes())?; // count = 0
+        idx_file.write_all(&0u64.to_le_bytes())?; // checksum of no entries
+        idx_file.sync_all()?;
+        meta::reset(&mut meta_file.write())?;
+
+        index.clear();
+        self.lengths.write().clear();
+        self.meta.write().clear();
+        *self.live_rows.write() = 0;
+        *db_size = header.len() as u64;
+
+        Ok(())
+    }
+
+    /// Update some fields of a TOON record, keeping the rest
+    ///
+    /// The record is re-serialized and replaces the old row (see
+    /// `replace`). Callers that map keys to row IDs must remap the key to
+    /// the returned ID.
+    ///
+    /// # Arguments
+    /// * `row_id` - Row ID of the record to update
+    /// * `updates` - (field, value) pairs; every field must be in the record's schema
+    ///
+    /// # Returns
+    /// * `Result<u64>` - Row ID of the updated record
+    pub fn update_fields(&self, row_id: u64, updates: &[(&str, &[u8])]) -> Result<u64> {
+        let mut record = ToonRecord::parse(&self.get(row_id)?)?;
+        for (field, value) in updates {
+            record.set(field, value)?;
+        }
+
+        self.replace(
+            row_id,
+            &record.serialize(),
+            self.row_tag(row_id)?,
+            MAX_VALUE_SIZE,
+        )
+    }
+
+    /// Overwrite a row: put `line` as a new row, then delete `row_id`
+    ///
+    /// Rows are never rewritten in place, so this is how a value changes
+    /// while keeping its history: the new row is created when `row_id` was
+    /// and updated now. Fails with `Error::NotFound` and writes nothing if
+    /// `row_id` is not a live row.
+    pub fn replace(&self, row_id: u64, line: &[u8], tag: u8, max_value_size: usize) -> Result<u64> {
+        let created_at = match self.index.read().get(row_id as usize) {
+            Some(Some(_)) => Some(self.meta.read()[row_id as usize])
+                .filter(RowMeta::is_known)
+                .map(|meta| meta.created_at),
+            _ => return Err(Error::NotFound),
+        };
+
+        let new_row_id = self.put_row(line, tag, max_value_size, created_at, true)?;
+        self.delete(row_id)?;
+
+        Ok(new_row_id)
+    }
+
+    /// Creation and last update time of a row
+    ///
+    /// `None` for deleted rows and rows written before metadata was kept.
+    pub fn row_metadata(&self, row_id: u64) -> Option<RowMeta> {
+        let index = self.index.read();
+        match index.get(row_id as usize) {
+            Some(Some(_)) => Some(self.meta.read()[row_id as usize]).filter(RowMeta::is_known),
+            _ => None,
+        }
+    }
+
     /// Scan all non-deleted rows
     ///
     /// Returns an iterator over (row_id, line) pairs
@@ -312,39 +1574,556 @@ impl ToonStore {
         }
     }
 
-    /// Close the database and fsync all changes
-    pub fn close(&mut self) -> Result<()> {
+    /// Find live rows from row ID `cursor` on, looking at `count` IDs at most
+    ///
+    /// Returns the matching row IDs and the cursor to pass next time, 0 once
+    /// the end of the index is reached. With a `tag`, only rows stored with
+    /// it match; the rows skipped still count towards `count`, so a call
+    /// may return nothing before the end.
+    ///
+    /// The cursor only moves forward over row IDs, and a row keeps its ID
+    /// for as long as it lives: deletes leave a hole, `replace` and new rows
+    /// take higher IDs, and compaction moves data but not IDs. So every row
+    /// live for the whole scan is returned exactly once, and rows added
+    /// during it may be too. Only `clear` renumbers rows, starting again
+    /// from 0; a scan across it sees just the rows added since.
+    pub fn scan_cursor(
+        &self,
+        cursor: u64,
+        count: usize,
+        tag: Option<u8>,
+    ) -> Result<(Vec<u64>, u64)> {
         if *self.closed.read() {
-            return Ok(());
+            return Err(Error::Closed);
         }
 
-        // Update data file header with current row count
         let index = self.index.read();
+        let start = (cursor as usize).min(index.len());
+        let end = start.saturating_add(count.max(1)).min(index.len());
+
+        let rows = index[start..end]
+            .iter()
+            .enumerate()
+            .filter_map(|(i, entry)| {
+                let entry = (*entry)?;
+                match tag {
+                    Some(tag) if (entry >> TAG_SHIFT) as u8 != tag => None,
+                    _ => Some((start + i) as u64),
+                }
+            })
+            .collect();
+        let next = if end < index.len() { end as u64 } else { 0 };
+
+        Ok((rows, next))
+    }
+
+    /// Like `scan_cursor`, but return each live row's data with its ID
+    ///
+    /// Rows deleted between finding and reading them are left out, so a
+    /// page can hold fewer rows than `scan_cursor` would return.
+    pub fn scan_with_values(&self, cursor: u64, count: usize) -> Result<ValuePage> {
+        let (row_ids, next) = self.scan_cursor(cursor, count, None)?;
+
+        let mut rows = Vec::with_capacity(row_ids.len());
+        for row_id in row_ids {
+            match self.get(row_id) {
+                Ok(data) => rows.push((row_id, data)),
+                Err(Error::NotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok((rows, next))
+    }
+
+    /// Find live rows holding a TOON record whose `field` is `value`
+    ///
+    /// There is no secondary index, so every row is read. Rows that are not
+    /// TOON records, or lack the field, never match.
+    pub fn find_by(&self, field: &str, value: &[u8]) -> Result<Vec<u64>> {
+        let mut rows = Vec::new();
+        for row in self.scan() {
+            let (row_id, line) = row?;
+            if field_matches(&line, field, value) {
+                rows.push(row_id);
+            }
+        }
+        Ok(rows)
+    }
+
+    /// Count the rows `find_by` would return, without collecting them
+    pub fn count_by(&self, field: &str, value: &[u8]) -> Result<usize> {
+        let mut count = 0;
+        for row in self.scan() {
+            let (_, line) = row?;
+            if field_matches(&line, field, value) {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Write the header and full index, then fsync every file
+    ///
+    /// The index is rewritten into a temporary file that is renamed over the
+    /// real one. A no-op for in-memory stores.
+    pub fn sync(&self) -> Result<()> {
+        self.writable()?;
+
+        if let Backend::File {
+            path,
+            data_file,
+            idx_file,
+            meta_file,
+            ..
+        } = &*self.backend
+        {
+            let mut data_file = data_file.write();
+            let index = self.index.read();
+            let mut idx_file = idx_file.write();
+            *idx_file = Self::sync_files(path, &mut data_file, &index)?;
+            meta_file.write().sync_all()?;
+        }
+
+        Ok(())
+    }
+
+    /// Update the data file header, rewrite the index and return its new handle
+    fn sync_files(path: &Path, data_file: &mut File, index: &[Option<u64>]) -> Result<File> {
+        // Update data file header with current row count
         let row_count = index.len() as u32;
 
-        let mut data_file = self.data_file.write();
         data_file.seek(SeekFrom::Start(TOON_MAGIC.len() as u64 + 4))?;
         data_file.write_all(&row_count.to_le_bytes())?;
         data_file.sync_all()?;
 
-        // Update index file
-        let mut idx_file = self.idx_file.write();
-        idx_file.seek(SeekFrom::Start(TOON_IDX_MAGIC.len() as u64))?;
-        idx_file.write_all(&row_count.to_le_bytes())?;
+        Self::write_index(&path.join("db.toon.idx"), index)
+    }
+
+    /// Atomically replace the index file and return a handle to the new one
+    fn write_index(idx_path: &Path, index: &[Option<u64>]) -> Result<File> {
+        let tmp_path = idx_path.with_extension("idx.tmp");
+        Self::write_index_file(&tmp_path, index)?;
+        std::fs::rename(&tmp_path, idx_path)?;
+
+        Ok(OpenOptions::new().read(true).write(true).open(idx_path)?)
+    }
+
+    /// Write a complete index file and fsync it
+    fn write_index_file(path: &Path, index: &[Option<u64>]) -> Result<()> {
+        let mut bytes = Vec::with_capacity(IDX_HEADER_LEN as usize + index.len() * 8 + 8);
+        bytes.extend_from_slice(TOON_IDX_MAGIC);
+        bytes.extend_from_slice(&(index.len() as u32).to_le_bytes());
 
         // Write all offsets (0 for deleted rows)
         for offset in index.iter() {
-            let offset_bytes = offset.unwrap_or(0).to_le_bytes();
-            idx_file.write_all(&offset_bytes)?;
+            bytes.extend_from_slice(&offset.unwrap_or(0).to_le_bytes());
+        }
+        bytes.extend_from_slice(&index_checksum(index).to_le_bytes());
+
+        let mut file = File::create(path)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Compact up to `budget_rows` rows, reclaiming the space of deleted rows
+    ///
+    /// Each call copies the next rows into the compacted file, live rows in
+    /// full and deleted rows as an empty row, so row IDs never change. Locks
+    /// are held for one call only; reads and writes carry on against the
+    /// current data file in between. The call that copies the last row swaps
+    /// the compacted file in and reports `done` with the bytes reclaimed.
+    /// Call it until `done` is set. A no-op for in-memory stores, which
+    /// already free deleted rows.
+    pub fn compact_incremental(&self, budget_rows: usize) -> Result<CompactProgress> {
+        self.writable()?;
+
+        let Backend::File {
+            path,
+            data_file,
+            idx_file,
+            compaction,
+            ..
+        } = &*self.backend
+        else {
+            return Ok(CompactProgress {
+                rows_processed: 0,
+                rows_remaining: 0,
+                bytes_reclaimed: 0,
+                done: true,
+            });
+        };
+
+        let mut db_size = self.db_size.write();
+        let mut data_file = data_file.write();
+        let mut compaction = compaction.write();
+
+        let pass = match compaction.as_mut() {
+            Some(pass) => pass,
+            None => {
+                let header = create_header(DATA_VERSION, 0);
+                let mut file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(path.join("db.toon.compact"))?;
+                file.write_all(&header)?;
+                compaction.insert(Compaction {
+                    file,
+                    offsets: Vec::new(),
+                    size: header.len() as u64,
+                })
+            }
+        };
+
+        let mut index = self.index.write();
+
+        // Copy the next rows in row ID order
+        let start = pass.offsets.len();
+        let end = start.saturating_add(budget_rows).min(index.len());
+        let mut batch = Vec::new();
+        for entry in &index[start..end] {
+            let new_offset = pass.size + batch.len() as u64;
+            match entry {
+                Some(entry) => {
+                    let row = read_row_at(&mut data_file, entry & OFFSET_MASK)?;
+                    push_row(&mut batch, &row);
+                    pass.offsets.push(new_offset);
+                }
+                None => {
+                    push_row(&mut batch, &[]);
+                    pass.offsets.push(0);
+                }
+            }
+        }
+        pass.file.seek(SeekFrom::End(0))?;
+        pass.file.write_all(&batch)?;
+        pass.size += batch.len() as u64;
+
+        let mut progress = CompactProgress {
+            rows_processed: end - start,
+            rows_remaining: index.len() - end,
+            bytes_reclaimed: 0,
+            done: false,
+        };
+        if progress.rows_remaining > 0 {
+            return Ok(progress);
+        }
+
+        // Every row is copied: swap the compacted files in
+        let mut pass = compaction.take().expect("compaction pass in progress");
+        pass.file
+            .seek(SeekFrom::Start(TOON_MAGIC.len() as u64 + 4))?;
+        pass.file.write_all(&(index.len() as u32).to_le_bytes())?;
+        pass.file.sync_all()?;
+        drop(pass.file);
+
+        let new_index: Vec<Option<u64>> = index
+            .iter()
+            .zip(&pass.offsets)
+            .map(|(entry, &offset)| entry.map(|entry| offset | (entry & !OFFSET_MASK)))
+            .collect();
+        let mut idx_file = idx_file.write();
+        Self::write_index_file(&path.join("db.toon.idx.compact"), &new_index)?;
+
+        // The data rename is the commit point, see `recover_compaction`
+        let data_path = path.join("db.toon");
+        let idx_path = path.join("db.toon.idx");
+        std::fs::rename(path.join("db.toon.compact"), &data_path)?;
+        std::fs::rename(path.join("db.toon.idx.compact"), &idx_path)?;
+
+        *data_file = OpenOptions::new().read(true).write(true).open(&data_path)?;
+        *idx_file = OpenOptions::new().read(true).write(true).open(&idx_path)?;
+        *index = new_index;
+
+        progress.bytes_reclaimed = db_size.saturating_sub(pass.size);
+        progress.done = true;
+        *db_size = pass.size;
+
+        Ok(progress)
+    }
+
+    /// Stop a compaction pass and delete its file
+    fn abort_compaction(path: &Path, compaction: &mut Option<Compaction>) -> Result<()> {
+        if compaction.take().is_some() {
+            std::fs::remove_file(path.join("db.toon.compact"))?;
+        }
+        Ok(())
+    }
+
+    /// Clean up after a compaction pass that a crash interrupted
+    ///
+    /// If the compacted data file was already renamed into place, only the
+    /// index rename is left to finish. Otherwise the pass is dropped and the
+    /// original files, which it never touched, are used as they are.
+    fn recover_compaction(path: &Path) -> Result<()> {
+        let compact_data = path.join("db.toon.compact");
+        let compact_idx = path.join("db.toon.idx.compact");
+
+        if compact_idx.exists() && !compact_data.exists() {
+            std::fs::rename(&compact_idx, path.join("db.toon.idx"))?;
+            return Ok(());
+        }
+        for file in [compact_data, compact_idx] {
+            if file.exists() {
+                std::fs::remove_file(file)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sync to disk, then reopen the files and rebuild the in-memory index from them
+    ///
+    /// Verifies that what is on disk matches what was written, without
+    /// closing the handle. Concurrent operations wait until the reload is done.
+    /// A read-only handle only rereads the files, picking up what a writer
+    /// has added. A no-op for in-memory stores.
+    pub fn reload(&self) -> Result<()> {
+        if *self.closed.read() {
+            return Err(Error::Closed);
+        }
+
+        let Backend::File {
+            path,
+            data_file,
+            idx_file,
+            meta_file,
+            compaction,
+            ..
+        } = &*self.backend
+        else {
+            return Ok(());
+        };
+
+        let mut db_size = self.db_size.write();
+        let mut data_file = data_file.write();
+        if !self.read_only {
+            Self::abort_compaction(path, &mut compaction.write())?;
+        }
+        let mut index = self.index.write();
+        let mut idx_file = idx_file.write();
+
+        let idx_path = if self.read_only {
+            Self::read_only_index_path(path)
+        } else {
+            *idx_file = Self::sync_files(path, &mut data_file, &index)?;
+            path.join("db.toon.idx")
+        };
+
+        let data_path = path.join("db.toon");
+        let (new_data, new_idx, new_index, new_lengths, new_size) =
+            Self::open_existing(&data_path, &idx_path, false, self.read_only)?;
+        let mut meta_file = meta_file.write();
+        if !self.read_only {
+            meta_file.sync_all()?;
+        }
+        let (new_meta_file, new_meta) = meta::open(path, new_index.len(), self.read_only)?;
+
+        *self.lengths.write() = new_lengths;
+        *self.meta.write() = new_meta;
+        *meta_file = new_meta_file;
+        *self.live_rows.write() = new_index.iter().filter(|offset| offset.is_some()).count();
+        *db_size = new_size;
+        *data_file = new_data;
+        *idx_file = new_idx;
+        *index = new_index;
+
+        Ok(())
+    }
+
+    /// Close the database and fsync all changes
+    ///
+    /// Also unlocks the directory, so it can be opened again right away.
+    /// Fails with `Error::ReadOnly` on a read-only handle, which has
+    /// nothing to sync; drop it instead.
+    pub fn close(&mut self) -> Result<()> {
+        if *self.closed.read() {
+            return Ok(());
+        }
+        if self.read_only {
+            return Err(Error::ReadOnly);
         }
-        idx_file.sync_all()?;
 
+        self.sync()?;
         *self.closed.write() = true;
+        if let Backend::File {
+            lock: Some(lock), ..
+        } = &*self.backend
+        {
+            lock.unlock()?;
+        }
 
         Ok(())
     }
 }
 
+/// Checksum contribution of one index entry
+///
+/// Entries are mixed with their row ID and summed, so one entry can be
+/// swapped in or out without rereading the rest of the index.
+fn entry_checksum(row_id: u64, offset: u64) -> u64 {
+    // splitmix64 finalizer
+    let mut x = offset ^ row_id.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+/// Checksum over every offset of an index, deleted rows included
+fn index_checksum(index: &[Option<u64>]) -> u64 {
+    index
+        .iter()
+        .enumerate()
+        .fold(0u64, |sum, (row_id, offset)| {
+            sum.wrapping_add(entry_checksum(row_id as u64, offset.unwrap_or(0)))
+        })
+}
+
+/// Bytes a line takes in the data file, length included
+fn row_size(line: &[u8]) -> u64 {
+    ROW_PREFIX_LEN + line.len() as u64
+}
+
+#[cfg(test)]
+thread_local! {
+    /// File writes a put or delete may still make before `write_step` fails it
+    static WRITES_BEFORE_CRASH: std::cell::Cell<Option<usize>> = const { std::cell::Cell::new(None) };
+    /// Fsyncs `sync_written` made on this thread
+    static SYNCS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Called before each file write of a put or delete; tests make it fail
+/// part way through, to leave the files as a crash would
+fn write_step() -> Result<()> {
+    #[cfg(test)]
+    {
+        let crash = WRITES_BEFORE_CRASH.with(|left| match left.get() {
+            Some(0) => true,
+            Some(n) => {
+                left.set(Some(n - 1));
+                false
+            }
+            None => false,
+        });
+        if crash {
+            return Err(Error::Io(std::io::Error::other("simulated crash")));
+        }
+    }
+    Ok(())
+}
+
+/// Append a line to a data file buffer, length and checksum first
+fn push_row(buf: &mut Vec<u8>, line: &[u8]) {
+    buf.extend_from_slice(&(line.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32(line).to_le_bytes());
+    buf.extend_from_slice(line);
+}
+
+/// Inflate a compressed row back into the value it holds
+fn decompress(row: &[u8]) -> Result<Vec<u8>> {
+    let corrupt = || Error::Corrupt("compressed row does not inflate to its length".to_string());
+    let prefix = row.get(..COMPRESSED_LEN_PREFIX).ok_or_else(corrupt)?;
+    let len = u32::from_le_bytes(prefix.try_into().unwrap()) as usize;
+    if len > MAX_VALUE_SIZE_LIMIT {
+        return Err(corrupt());
+    }
+
+    let mut value = Vec::with_capacity(len);
+    DeflateDecoder::new(&row[COMPRESSED_LEN_PREFIX..])
+        .take(len as u64 + 1)
+        .read_to_end(&mut value)
+        .map_err(|_| corrupt())?;
+    if value.len() != len {
+        return Err(corrupt());
+    }
+    Ok(value)
+}
+
+/// Whether `line` parses as a TOON record with `field` set to `value`
+fn field_matches(line: &[u8], field: &str, value: &[u8]) -> bool {
+    ToonRecord::parse(line).is_ok_and(|record| record.get(field) == Some(value))
+}
+
+/// Read the row starting at `offset`, checking it against its checksum
+fn read_row_at(file: &mut File, offset: u64) -> Result<Vec<u8>> {
+    let mut prefix = [0u8; ROW_PREFIX_LEN as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut prefix)?;
+
+    let len = u32::from_le_bytes(prefix[..4].try_into().unwrap()) as usize;
+    if len > MAX_VALUE_SIZE_LIMIT {
+        return Err(Error::Corrupt(format!(
+            "row at offset {} claims {} bytes",
+            offset, len
+        )));
+    }
+
+    let mut row = vec![0u8; len];
+    file.read_exact(&mut row)?;
+    if crc32(&row) != u32::from_le_bytes(prefix[4..].try_into().unwrap()) {
+        return Err(Error::Corrupt(format!(
+            "row at offset {} fails its checksum",
+            offset
+        )));
+    }
+    Ok(row)
+}
+
+/// CRC-32 lookup table (IEEE polynomial, reflected)
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32 of a row, as used by zlib and gzip
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &b| {
+        CRC32_TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Write `bytes` at `end`, the published end of the data, and flush
+///
+/// On failure the file is cut back to `end`, so a half-written row never
+/// sits in front of the next append.
+fn append_at(file: &mut File, end: u64, bytes: &[u8]) -> Result<()> {
+    let written = file
+        .seek(SeekFrom::Start(end))
+        .and_then(|_| file.write_all(bytes))
+        .and_then(|_| file.flush());
+    if let Err(e) = written {
+        let _ = file.set_len(end);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+fn read_u64_at(file: &mut File, pos: u64) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    file.seek(SeekFrom::Start(pos))?;
+    file.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
 impl Drop for ToonStore {
     fn drop(&mut self) {
         let _ = self.close();
@@ -388,6 +2167,88 @@ mod tests {
     use super::*;
     use tempfile::TempDir;
 
+    /// Drop `db` without syncing or closing, as a crash would
+    ///
+    /// The lock is released by hand, as the OS does for a dead process.
+    fn crash(db: ToonStore) {
+        if let Backend::File {
+            lock: Some(lock), ..
+        } = &*db.backend
+        {
+            lock.unlock().unwrap();
+        }
+        std::mem::forget(db);
+    }
+
+    #[test]
+    fn test_directory_locked_while_open() {
+        let dir = TempDir::new().unwrap();
+        let mut db = ToonStore::open(dir.path()).unwrap();
+        db.put(b"row").unwrap();
+
+        match ToonStore::open(dir.path()) {
+            Err(Error::Locked(path)) => assert_eq!(path, dir.path()),
+            other => panic!("expected Locked, got {:?}", other.map(|_| ())),
+        }
+
+        // Closing unlocks, as does dropping
+        db.close().unwrap();
+        let db = ToonStore::open(dir.path()).unwrap();
+        assert_eq!(db.get(0).unwrap(), b"row");
+        assert!(ToonStore::open(dir.path()).is_err());
+        drop(db);
+        ToonStore::open(dir.path()).unwrap();
+    }
+
+    #[test]
+    fn test_read_only_handles() {
+        let dir = TempDir::new().unwrap();
+        assert!(ToonStore::open_read_only(dir.path()).is_err());
+
+        let writer = ToonStore::open(dir.path()).unwrap();
+        writer.put(b"row 0").unwrap();
+        writer.put(b"row 1").unwrap();
+        writer.delete(1).unwrap();
+        writer.sync().unwrap();
+
+        // Any number of readers alongside the one writer
+        let mut reader = ToonStore::open_read_only(dir.path()).unwrap();
+        let other = ToonStore::open_read_only(dir.path()).unwrap();
+        for db in [&reader, &other] {
+            assert_eq!(db.get(0).unwrap(), b"row 0");
+            assert!(matches!(db.get(1), Err(Error::NotFound)));
+            assert_eq!(db.scan().count(), 1);
+            assert!(db.row_metadata(0).is_some());
+        }
+
+        assert!(matches!(reader.put(b"x"), Err(Error::ReadOnly)));
+        assert!(matches!(reader.bulk_put([&b"x"[..]]), Err(Error::ReadOnly)));
+        assert!(matches!(reader.delete(0), Err(Error::ReadOnly)));
+        assert!(matches!(
+            reader.replace(0, b"x", 0, MAX_VALUE_SIZE),
+            Err(Error::ReadOnly)
+        ));
+        assert!(matches!(reader.clear(), Err(Error::ReadOnly)));
+        assert!(matches!(
+            reader.compact_incremental(10),
+            Err(Error::ReadOnly)
+        ));
+        assert!(matches!(reader.close(), Err(Error::ReadOnly)));
+        assert_eq!(reader.get(0).unwrap(), b"row 0");
+
+        // Readers see the rows as of their open until they reload
+        let id = writer.put(b"row 2").unwrap();
+        assert!(matches!(reader.get(id), Err(Error::NotFound)));
+        reader.reload().unwrap();
+        assert_eq!(reader.get(id).unwrap(), b"row 2");
+        drop(writer);
+
+        // Readers do not hold the lock; nothing they did reached the files
+        let writer = ToonStore::open(dir.path()).unwrap();
+        assert_eq!(writer.live_len(), 2);
+        assert!(!dir.path().join("db.toon.idx.tmp").exists());
+    }
+
     #[test]
     fn test_create_and_open() {
         let dir = TempDir::new().unwrap();
@@ -431,18 +2292,601 @@ mod tests {
             b"users[1]{id,name}: 3,Charlie".to_vec(),
         ];
 
-        for line in &lines {
-            db.put(line).unwrap();
+        for line in &lines {
+            db.put(line).unwrap();
+        }
+
+        assert_eq!(db.len(), 3);
+
+        for (i, line) in lines.iter().enumerate() {
+            let retrieved = db.get(i as u64).unwrap();
+            assert_eq!(&retrieved, line);
+        }
+
+        db.close().unwrap();
+    }
+
+    #[test]
+    fn test_get_range() {
+        let dir = TempDir::new().unwrap();
+        let db = ToonStore::open(dir.path()).unwrap();
+
+        let long: Vec<u8> = (0..10_000).map(|i| b'a' + (i % 26) as u8).collect();
+        let id = db.put(&long).unwrap();
+        db.put(b"next row").unwrap();
+
+        assert_eq!(db.get_range(id, 0, 3).unwrap(), b"abc");
+        assert_eq!(db.get_range(id, 5000, 6000).unwrap(), &long[5000..]);
+        assert_eq!(db.get_range(id, 4090, 10).unwrap(), &long[4090..4100]);
+        // Never runs into the next row
+        assert!(db.get_range(id, 20_000, 5).unwrap().is_empty());
+        assert!(matches!(db.get_range(9, 0, 1), Err(Error::NotFound)));
+
+        let mem = ToonStore::open_memory();
+        let id = mem.put(b"hello world").unwrap();
+        assert_eq!(mem.get_range(id, 6, 100).unwrap(), b"world");
+        assert!(mem.get_range(id, 50, 1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_binary_values() {
+        let dir = TempDir::new().unwrap();
+        let value = b"line 1\nline 2\n\0\xff\r\n".to_vec();
+        {
+            let db = ToonStore::open(dir.path()).unwrap();
+            db.put(b"before").unwrap();
+            assert_eq!(db.put(&value).unwrap(), 1);
+            db.bulk_put([&b"\n"[..], b"after\n"]).unwrap();
+            assert_eq!(db.get(1).unwrap(), value);
+            assert_eq!(db.get_range(1, 5, 4).unwrap(), b"1\nli");
+        }
+
+        let db = ToonStore::open(dir.path()).unwrap();
+        assert_eq!(db.len(), 4);
+        assert_eq!(db.get(1).unwrap(), value);
+        assert_eq!(db.get(2).unwrap(), b"\n");
+        assert_eq!(db.value_len(3).unwrap(), 6);
+
+        db.delete(0).unwrap();
+        compact_all(&db, 2);
+        assert_eq!(db.get(1).unwrap(), value);
+        assert_eq!(db.get(3).unwrap(), b"after\n");
+    }
+
+    #[test]
+    fn test_data_v1_upgraded() {
+        let dir = TempDir::new().unwrap();
+
+        // Version 1: newline-terminated rows, index entries point at them
+        let mut data = create_header(1, 3);
+        let mut index = Vec::new();
+        for row in [&b"row 0"[..], b"dropped", b"row 2"] {
+            index.push(Some(data.len() as u64));
+            data.extend_from_slice(row);
+            data.push(b'\n');
+        }
+        index[1] = None;
+        std::fs::write(dir.path().join("db.toon"), &data).unwrap();
+        ToonStore::write_index_file(&dir.path().join("db.toon.idx"), &index).unwrap();
+
+        let err = ToonStore::open_read_only(dir.path()).err().unwrap();
+        assert!(matches!(err, Error::NeedsUpgrade(1)));
+        assert!(err.to_string().contains("open the store writable once"));
+
+        let db = ToonStore::open(dir.path()).unwrap();
+        assert_eq!(db.len(), 3);
+        assert_eq!(db.get(0).unwrap(), b"row 0");
+        assert!(matches!(db.get(1), Err(Error::NotFound)));
+        assert_eq!(db.get(2).unwrap(), b"row 2");
+        assert_eq!(db.put(b"new\nrow").unwrap(), 3);
+        drop(db);
+
+        let header = std::fs::read(dir.path().join("db.toon")).unwrap();
+        assert_eq!(parse_header(&header).unwrap().version, DATA_VERSION);
+        assert!(!dir.path().join("db.toon.compact").exists());
+        let db = ToonStore::open(dir.path()).unwrap();
+        assert_eq!(db.get(3).unwrap(), b"new\nrow");
+    }
+
+    #[test]
+    fn test_data_v2_upgraded() {
+        let dir = TempDir::new().unwrap();
+
+        // Version 2: length-prefixed rows without checksums
+        let mut data = create_header(2, 3);
+        let mut index = Vec::new();
+        for (tag, row) in [(0u64, &b"row 0"[..]), (0, b"dropped"), (1, b"row\n2")] {
+            index.push(Some(data.len() as u64 | (tag << TAG_SHIFT)));
+            data.extend_from_slice(&(row.len() as u32).to_le_bytes());
+            data.extend_from_slice(row);
+        }
+        index[1] = None;
+        std::fs::write(dir.path().join("db.toon"), &data).unwrap();
+        ToonStore::write_index_file(&dir.path().join("db.toon.idx"), &index).unwrap();
+
+        let (db, corrupt) = ToonStore::open_verified(dir.path(), VerifyLevel::Full).unwrap();
+        assert!(corrupt.is_empty());
+        assert_eq!(db.get(0).unwrap(), b"row 0");
+        assert!(matches!(db.get(1), Err(Error::NotFound)));
+        assert_eq!(db.get(2).unwrap(), b"row\n2");
+        assert_eq!(db.row_tag(2).unwrap(), 1);
+        drop(db);
+
+        let header = std::fs::read(dir.path().join("db.toon")).unwrap();
+        assert_eq!(parse_header(&header).unwrap().version, DATA_VERSION);
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_verify_reports_corrupt_rows() {
+        let dir = TempDir::new().unwrap();
+        let damaged = {
+            let db = ToonStore::open(dir.path()).unwrap();
+            for i in 0..10 {
+                db.put(format!("row {}", i).as_bytes()).unwrap();
+            }
+            db.delete(3).unwrap();
+            let offset = db.index.read()[6].unwrap() & OFFSET_MASK;
+            offset
+        };
+
+        // Flip a byte inside row 6, leaving its length intact
+        let data_path = dir.path().join("db.toon");
+        let mut data = std::fs::read(&data_path).unwrap();
+        data[(damaged + ROW_PREFIX_LEN) as usize] ^= 0xFF;
+        std::fs::write(&data_path, &data).unwrap();
+
+        let (db, corrupt) = ToonStore::open_verified(dir.path(), VerifyLevel::None).unwrap();
+        assert!(corrupt.is_empty());
+        assert!(matches!(db.get(6), Err(Error::Corrupt(_))));
+        assert_eq!(db.get(7).unwrap(), b"row 7");
+
+        assert_eq!(db.verify(VerifyLevel::Full).unwrap(), vec![6]);
+        // Fewer rows than the sample, so quick checks them all too
+        assert_eq!(db.verify(VerifyLevel::Quick).unwrap(), vec![6]);
+        assert!(ToonStore::open_memory()
+            .verify(VerifyLevel::Full)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_size_histogram() {
+        let dir = TempDir::new().unwrap();
+        let db = ToonStore::open(dir.path()).unwrap();
+        for len in [3, 4, 10, 10, 16, 1000] {
+            db.put(&vec![b'x'; len]).unwrap();
+        }
+        let gone = db.put(&[b'x'; 5000]).unwrap();
+        db.delete(gone).unwrap();
+
+        let histogram = db.size_histogram(None).unwrap();
+        let counts: Vec<(u64, u64)> = histogram
+            .buckets
+            .iter()
+            .copied()
+            .filter(|&(_, rows)| rows > 0)
+            .collect();
+        assert_eq!(counts, vec![(4, 2), (16, 3), (1024, 1)]);
+        assert_eq!(histogram.buckets.last(), Some(&(1024, 1)));
+        assert_eq!((histogram.rows, histogram.min, histogram.max), (6, 3, 1000));
+        assert_eq!(histogram.total_bytes, 1043);
+        assert!(!histogram.sampled);
+
+        // A sample counts rows spread over the store
+        let sample = db.size_histogram(Some(3)).unwrap();
+        assert_eq!(sample.rows, 3);
+        assert!(sample.sampled);
+        assert_eq!(db.size_histogram(Some(100)).unwrap(), histogram);
+
+        let mem = ToonStore::open_memory();
+        assert_eq!(mem.size_histogram(None).unwrap().rows, 0);
+    }
+
+    #[test]
+    fn test_get_many() {
+        let dir = TempDir::new().unwrap();
+        let db = ToonStore::open(dir.path())
+            .unwrap()
+            .with_config(StoreConfig {
+                compress_threshold: Some(16),
+                ..StoreConfig::default()
+            });
+        let a = db.put(b"alpha").unwrap();
+        let b = db.put(&[b'b'; 100]).unwrap();
+        let gone = db.put(b"gone").unwrap();
+        db.delete(gone).unwrap();
+
+        let rows = db.get_many(&[b, 99, a, gone, b]);
+        assert_eq!(rows.len(), 5);
+        assert_eq!(rows[0].as_ref().unwrap(), &vec![b'b'; 100]);
+        assert!(matches!(rows[1], Err(Error::NotFound)));
+        assert_eq!(rows[2].as_ref().unwrap(), b"alpha");
+        assert!(matches!(rows[3], Err(Error::NotFound)));
+        assert_eq!(rows[4].as_ref().unwrap(), &vec![b'b'; 100]);
+        assert!(db.get_many(&[]).is_empty());
+
+        let mem = ToonStore::open_memory();
+        let m = mem.put(b"mem").unwrap();
+        let rows = mem.get_many(&[m, m + 1]);
+        assert_eq!(rows[0].as_ref().unwrap(), b"mem");
+        assert!(matches!(rows[1], Err(Error::NotFound)));
+    }
+
+    #[test]
+    fn test_value_len() {
+        let dir = TempDir::new().unwrap();
+        let db = ToonStore::open(dir.path()).unwrap();
+
+        let id0 = db.put(b"").unwrap();
+        let id1 = db.put(b"hello").unwrap();
+        assert_eq!(db.value_len(id0).unwrap(), 0);
+        assert_eq!(db.value_len(id1).unwrap(), 5);
+
+        db.delete(id1).unwrap();
+        assert!(matches!(db.value_len(id1), Err(Error::NotFound)));
+        assert!(matches!(db.value_len(9), Err(Error::NotFound)));
+
+        // Rebuilt from the data file on open
+        let id2 = db.put(b"longer row").unwrap();
+        drop(db);
+        let db = ToonStore::open(dir.path()).unwrap();
+        assert_eq!(db.value_len(id0).unwrap(), 0);
+        assert_eq!(db.value_len(id2).unwrap(), 10);
+    }
+
+    #[test]
+    fn test_max_db_size() {
+        let db = ToonStore::open_memory();
+        assert_eq!(db.max_db_size(), MAX_DB_SIZE);
+        db.set_max_db_size(u64::MAX);
+        assert_eq!(db.max_db_size(), MAX_DB_SIZE);
+
+        // 9 bytes plus the length and checksum
+        db.set_max_db_size(17);
+        db.put(b"123456789").unwrap();
+        assert!(matches!(db.put(b"x"), Err(Error::DatabaseFull(17))));
+        assert!(matches!(
+            db.bulk_put([&b""[..]]),
+            Err(Error::DatabaseFull(17))
+        ));
+        assert_eq!(db.len(), 1);
+    }
+
+    #[test]
+    fn test_readers_never_see_torn_rows() {
+        let dir = TempDir::new().unwrap();
+        let db = ToonStore::open(dir.path()).unwrap();
+        let done = std::sync::atomic::AtomicBool::new(false);
+
+        // Every value says how long it is, so a torn read cannot pass
+        let value = |i: usize| format!("{}:{}", i % 300, "x".repeat(i % 300)).into_bytes();
+        let complete = |data: &[u8]| {
+            let text = std::str::from_utf8(data).unwrap();
+            let (len, rest) = text.split_once(':').unwrap();
+            rest.len() == len.parse::<usize>().unwrap() && rest.bytes().all(|b| b == b'x')
+        };
+
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    while !done.load(std::sync::atomic::Ordering::Acquire) {
+                        let (rows, _) = db.scan_with_values(0, usize::MAX).unwrap();
+                        for (_, data) in rows {
+                            assert!(complete(&data), "torn row {:?}", data);
+                        }
+                    }
+                });
+            }
+
+            for i in 0..2000 {
+                db.put(&value(i)).unwrap();
+            }
+            done.store(true, std::sync::atomic::Ordering::Release);
+        });
+
+        assert_eq!(db.live_len(), 2000);
+    }
+
+    #[test]
+    fn test_append_ignores_torn_tail() {
+        let dir = TempDir::new().unwrap();
+        let data_path = dir.path().join("db.toon");
+
+        {
+            let mut db = ToonStore::open(dir.path()).unwrap();
+            db.put(b"first").unwrap();
+
+            // Half a row that was never published, as a failed write leaves
+            let mut file = OpenOptions::new().append(true).open(&data_path).unwrap();
+            file.write_all(&[0xFF; 5]).unwrap();
+            drop(file);
+
+            db.put(b"second").unwrap();
+            assert_eq!(std::fs::metadata(&data_path).unwrap().len(), db.db_size());
+            db.close().unwrap();
+        }
+
+        let db = ToonStore::open(dir.path()).unwrap();
+        assert_eq!(db.get(0).unwrap(), b"first");
+        assert_eq!(db.get(1).unwrap(), b"second");
+    }
+
+    #[test]
+    fn test_bulk_put() {
+        let dir = TempDir::new().unwrap();
+        let db = ToonStore::open(dir.path()).unwrap();
+        db.put(b"first").unwrap();
+
+        let lines: Vec<Vec<u8>> = (0..100)
+            .map(|i| format!("row {}", i).into_bytes())
+            .collect();
+        let ids = db.bulk_put(lines.iter().map(|l| l.as_slice())).unwrap();
+        assert_eq!(ids, (1..101).collect::<Vec<u64>>());
+        assert_eq!(db.get(50).unwrap(), b"row 49");
+        assert_eq!(db.value_len(100).unwrap(), 6);
+        assert!(db.bulk_put(std::iter::empty()).unwrap().is_empty());
+
+        // A line over the limit mid-batch stores nothing
+        let size = db.db_size();
+        let large = vec![b'x'; MAX_VALUE_SIZE + 1];
+        let batch: Vec<&[u8]> = vec![b"ok", &large, b"also ok"];
+        assert!(matches!(db.bulk_put(batch), Err(Error::ValueTooLarge(_))));
+        assert_eq!(db.len(), 101);
+        assert_eq!(db.db_size(), size);
+
+        // Durable without a close, and appends keep working afterwards
+        crash(db);
+        let db = ToonStore::open(dir.path()).unwrap();
+        assert_eq!(db.len(), 101);
+        assert_eq!(db.get(100).unwrap(), b"row 99");
+        assert_eq!(db.put(b"after").unwrap(), 101);
+
+        let mem = ToonStore::open_memory();
+        let ids = mem.bulk_put([b"a".as_slice(), b"bc"]).unwrap();
+        assert_eq!(ids, vec![0, 1]);
+        assert_eq!(mem.get(1).unwrap(), b"bc");
+        assert_eq!(mem.db_size(), 19);
+    }
+
+    #[test]
+    fn test_row_tags() {
+        let dir = TempDir::new().unwrap();
+        let db = ToonStore::open(dir.path()).unwrap();
+
+        let plain = db.put(b"plain").unwrap();
+        let tagged = db.put_tagged(b"hash{a}: 1", 3).unwrap();
+        assert_eq!(db.row_tag(plain).unwrap(), 0);
+        assert_eq!(db.row_tag(tagged).unwrap(), 3);
+        assert_eq!(db.get(tagged).unwrap(), b"hash{a}: 1");
+
+        // Tags follow updates and compaction, and survive a crash
+        let updated = db.update_fields(tagged, &[("a", b"2")]).unwrap();
+        assert_eq!(db.row_tag(updated).unwrap(), 3);
+        while !db.compact_incremental(10).unwrap().done {}
+        assert_eq!(db.row_tag(updated).unwrap(), 3);
+        assert!(matches!(db.row_tag(tagged), Err(Error::NotFound)));
+        db.put_tagged(b"late", 7).unwrap();
+        crash(db);
+
+        let db = ToonStore::open(dir.path()).unwrap();
+        assert_eq!(db.row_tag(updated).unwrap(), 3);
+        assert_eq!(db.row_tag(3).unwrap(), 7);
+        assert_eq!(db.get(3).unwrap(), b"late");
+
+        let mem = ToonStore::open_memory();
+        let id = mem.put_tagged(b"x", 1).unwrap();
+        assert_eq!(mem.row_tag(id).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_schema_validation() {
+        let dir = TempDir::new().unwrap();
+        let db = ToonStore::open(dir.path()).unwrap();
+        let fields = vec!["id".to_string(), "name".to_string()];
+        db.register_schema("user", fields.clone()).unwrap();
+
+        let id = db.put_validated(b"user:1{id,name}: 1,Alice").unwrap();
+        assert_eq!(db.get(id).unwrap(), b"user:1{id,name}: 1,Alice");
+        // Other collections are not checked
+        db.put_validated(b"order{id,total}: 7,9.5").unwrap();
+
+        for (line, problem) in [
+            (&b"user:2{id,name,age}: 2,Bob,30"[..], "unexpected age"),
+            (b"user:3{id,nick}: 3,Carol", "missing name, unexpected nick"),
+            (b"user{name,id}: Dave,4", "fields out of order"),
+        ] {
+            match db.put_validated(line) {
+                Err(Error::SchemaViolation(msg)) => {
+                    assert!(msg.contains("collection user expects {id,name}"), "{}", msg);
+                    assert!(msg.contains(problem), "{}", msg);
+                }
+                other => panic!("expected a schema violation, got {:?}", other),
+            }
+        }
+        assert!(matches!(
+            db.put_validated(b"not toon"),
+            Err(Error::Parse(_))
+        ));
+        assert!(db
+            .register_schema("bad", vec!["a".into(), "a".into()])
+            .is_err());
+        assert!(db.register_schema("bad", vec!["a,b".into()]).is_err());
+        assert_eq!(db.len(), 2);
+
+        // Schemas survive a reopen
+        drop(db);
+        let db = ToonStore::open(dir.path()).unwrap();
+        assert_eq!(db.schema("user"), Some(fields));
+        assert_eq!(db.schema("bad"), None);
+        assert!(db.put_validated(b"user:5{id}: 5").is_err());
+
+        std::fs::write(dir.path().join("db.toon.schema"), b"{not json").unwrap();
+        drop(db);
+        assert!(matches!(
+            ToonStore::open(dir.path()),
+            Err(Error::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn test_max_keys() {
+        let dir = TempDir::new().unwrap();
+        let config = StoreConfig {
+            max_keys: Some(3),
+            ..StoreConfig::default()
+        };
+        let db = ToonStore::open(dir.path()).unwrap().with_config(config);
+
+        for i in 0..3u8 {
+            db.put(&[i]).unwrap();
+        }
+        assert!(matches!(db.put(b"x"), Err(Error::TooManyKeys(3))));
+        assert!(matches!(
+            db.bulk_put([&b"x"[..]]),
+            Err(Error::TooManyKeys(3))
+        ));
+        assert_eq!(db.len(), 3);
+
+        // Replacing a row adds none, so it works at the limit
+        let replaced = db.replace(2, b"two", 0, MAX_VALUE_SIZE).unwrap();
+        assert_eq!(db.get(replaced).unwrap(), b"two");
+
+        // Deleted rows still take an index slot but not a key
+        db.delete(0).unwrap();
+        let id = db.put(b"y").unwrap();
+        assert_eq!(db.get(id).unwrap(), b"y");
+        assert!(matches!(db.put(b"z"), Err(Error::TooManyKeys(3))));
+
+        // A batch either fits as a whole or is not stored at all
+        db.delete(1).unwrap();
+        db.delete(id).unwrap();
+        assert!(matches!(
+            db.bulk_put([&b"a"[..], b"b", b"c"]),
+            Err(Error::TooManyKeys(3))
+        ));
+        assert_eq!(db.bulk_put([&b"a"[..], b"b"]).unwrap().len(), 2);
+        assert_eq!(db.live_len(), 3);
+
+        let mem = ToonStore::open_memory().with_config(StoreConfig {
+            max_keys: Some(0),
+            ..StoreConfig::default()
+        });
+        assert!(matches!(mem.put(b"x"), Err(Error::TooManyKeys(0))));
+    }
+
+    #[test]
+    fn test_compressed_values() {
+        let dir = TempDir::new().unwrap();
+        let large: Vec<u8> = (0..2000)
+            .flat_map(|i| {
+                format!("users[1]{{id,name,role}}: {},user{},member\n", i, i % 7).into_bytes()
+            })
+            .collect();
+        // The same bytes over and over, but too short to be worth compressing
+        let small = b"tiny tiny tiny tiny".to_vec();
+        let config = StoreConfig {
+            compress_threshold: Some(16),
+            ..StoreConfig::default()
+        };
+        {
+            let db = ToonStore::open(dir.path()).unwrap();
+            let plain = db.put(&large).unwrap();
+            assert_eq!(plain, 0);
+        }
+
+        let db = ToonStore::open(dir.path())
+            .unwrap()
+            .with_config(config.clone());
+        let before = db.db_size();
+        let id = db.put(&large).unwrap();
+        let stored = db.db_size() - before;
+        assert!(stored * 2 < large.len() as u64, "{} bytes stored", stored);
+        let bulk = db.bulk_put([&large[..], &small[..]]).unwrap();
+        assert_eq!(db.stored_len(id).unwrap() as u64, stored - ROW_PREFIX_LEN);
+        assert_eq!(db.stored_len(0).unwrap(), large.len());
+
+        for row_id in [0, id, bulk[0]] {
+            assert_eq!(db.get(row_id).unwrap(), large);
+            assert_eq!(db.value_len(row_id).unwrap(), large.len());
+            assert_eq!(db.get_range(row_id, 40, 30).unwrap(), &large[40..70]);
         }
+        assert_eq!(db.get(bulk[1]).unwrap(), small);
+        drop(db);
 
-        assert_eq!(db.len(), 3);
+        // Compressed and plain rows reopen and compact side by side, with no config
+        let db = ToonStore::open(dir.path()).unwrap();
+        assert_eq!(db.verify(VerifyLevel::Full).unwrap(), Vec::<u64>::new());
+        db.delete(0).unwrap();
+        compact_all(&db, 2);
+        drop(db);
+        let db = ToonStore::open(dir.path()).unwrap();
+        assert_eq!(db.get(id).unwrap(), large);
+        assert_eq!(db.value_len(bulk[0]).unwrap(), large.len());
+        assert_eq!(db.get(bulk[1]).unwrap(), small);
+        assert_eq!(db.scan().count(), 3);
+
+        // In memory, values are kept as they are
+        let mem = ToonStore::open_memory().with_config(config);
+        mem.put(&large).unwrap();
+        assert_eq!(mem.db_size(), row_size(&large));
+    }
 
-        for (i, line) in lines.iter().enumerate() {
-            let retrieved = db.get(i as u64).unwrap();
-            assert_eq!(&retrieved, line);
-        }
+    #[test]
+    fn test_row_metadata() {
+        let dir = TempDir::new().unwrap();
+        let db = ToonStore::open(dir.path()).unwrap();
 
-        db.close().unwrap();
+        let id = db.put(b"record{a}: 1").unwrap();
+        let created = db.row_metadata(id).unwrap();
+        assert!(created.created_at > 0);
+        assert_eq!(created.created_at, created.updated_at);
+
+        // An overwrite moves updated_at only
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let updated_id = db.update_fields(id, &[("a", b"2")]).unwrap();
+        let updated = db.row_metadata(updated_id).unwrap();
+        assert_eq!(updated.created_at, created.created_at);
+        assert!(updated.updated_at > created.updated_at);
+        assert_eq!(db.row_metadata(id), None);
+        assert!(matches!(
+            db.replace(id, b"x", 0, MAX_VALUE_SIZE),
+            Err(Error::NotFound)
+        ));
+
+        // Survives compaction, a crash and a reload
+        let bulk = db.bulk_put([&b"one"[..], b"two"]).unwrap();
+        while !db.compact_incremental(10).unwrap().done {}
+        crash(db);
+        let db = ToonStore::open(dir.path()).unwrap();
+        assert_eq!(db.row_metadata(updated_id), Some(updated));
+        assert!(db.row_metadata(bulk[1]).is_some());
+        db.reload().unwrap();
+        assert_eq!(db.row_metadata(updated_id), Some(updated));
+        assert_eq!(db.row_metadata(id), None);
+
+        // Rows from before the sidecar existed have no metadata
+        drop(db);
+        std::fs::remove_file(dir.path().join("db.toon.meta")).unwrap();
+        let db = ToonStore::open(dir.path()).unwrap();
+        assert_eq!(db.row_metadata(updated_id), None);
+        let new_id = db.put(b"new").unwrap();
+        assert!(db.row_metadata(new_id).is_some());
+        db.clear().unwrap();
+        assert_eq!(db.row_metadata(0), None);
+
+        let mem = ToonStore::open_memory();
+        let id = mem.put(b"x").unwrap();
+        let created_at = mem.row_metadata(id).unwrap().created_at;
+        let replaced = mem.replace(id, b"y", 0, MAX_VALUE_SIZE).unwrap();
+        assert_eq!(mem.row_metadata(replaced).unwrap().created_at, created_at);
+        assert_eq!(mem.get(replaced).unwrap(), b"y");
+        assert_eq!(mem.row_metadata(id), None);
     }
 
     #[test]
@@ -553,6 +2997,509 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_live_len() {
+        let dir = TempDir::new().unwrap();
+
+        {
+            let mut db = ToonStore::open(dir.path()).unwrap();
+            for i in 0..5 {
+                db.put(format!("line {}", i).as_bytes()).unwrap();
+            }
+            db.delete(1).unwrap();
+            db.delete(3).unwrap();
+
+            // Deleting twice must not decrement again
+            assert!(db.delete(3).is_err());
+
+            assert_eq!(db.len(), 5);
+            assert_eq!(db.live_len(), 3);
+            db.close().unwrap();
+        }
+
+        // Live count is rebuilt from the index on reopen
+        let db = ToonStore::open(dir.path()).unwrap();
+        assert_eq!(db.len(), 5);
+        assert_eq!(db.live_len(), 3);
+    }
+
+    #[test]
+    fn test_update_fields() {
+        let dir = TempDir::new().unwrap();
+        let db = ToonStore::open(dir.path()).unwrap();
+
+        let row_id = db
+            .put(b"user:1{id,name,email}: user:1,John Doe,john@example.com")
+            .unwrap();
+        let new_row_id = db
+            .update_fields(row_id, &[("email", b"john@new.example")])
+            .unwrap();
+
+        assert_ne!(new_row_id, row_id);
+        assert!(matches!(db.get(row_id), Err(Error::NotFound)));
+        assert_eq!(
+            db.get(new_row_id).unwrap(),
+            b"user:1{id,name,email}: user:1,John Doe,john@new.example"
+        );
+        assert_eq!(db.live_len(), 1);
+
+        // Unknown fields leave the record untouched
+        assert!(matches!(
+            db.update_fields(new_row_id, &[("phone", b"555")]),
+            Err(Error::UnknownField(_))
+        ));
+        assert!(db.get(new_row_id).is_ok());
+    }
+
+    #[test]
+    fn test_clear() {
+        let dir = TempDir::new().unwrap();
+        let empty_size = ToonStore::open(dir.path()).unwrap().db_size();
+
+        let db = ToonStore::open(dir.path()).unwrap();
+        db.put(b"row 0").unwrap();
+        db.put(b"row 1").unwrap();
+
+        db.clear().unwrap();
+        assert!(db.is_empty());
+        assert_eq!(db.live_len(), 0);
+        assert_eq!(db.db_size(), empty_size);
+        assert_eq!(db.scan().count(), 0);
+
+        // Row IDs restart and the cleared state survives a reopen
+        assert_eq!(db.put(b"fresh").unwrap(), 0);
+        drop(db);
+        let db = ToonStore::open(dir.path()).unwrap();
+        assert_eq!(db.len(), 1);
+        assert_eq!(db.get(0).unwrap(), b"fresh");
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = TempDir::new().unwrap();
+        let db = ToonStore::open(dir.path()).unwrap();
+
+        let id0 = db.put(b"row 0").unwrap();
+        let id1 = db.put(b"row 1").unwrap();
+        db.delete(id0).unwrap();
+
+        db.reload().unwrap();
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.live_len(), 1);
+        assert!(db.get(id0).is_err());
+        assert_eq!(db.get(id1).unwrap(), b"row 1");
+
+        // Writes keep working against the reopened files
+        let id2 = db.put(b"row 2").unwrap();
+        assert_eq!(db.get(id2).unwrap(), b"row 2");
+    }
+
+    #[test]
+    fn test_index_checksum_kept_without_close() {
+        let dir = TempDir::new().unwrap();
+        let db = ToonStore::open(dir.path()).unwrap();
+        db.put(b"row 0").unwrap();
+        db.put(b"row 1").unwrap();
+        db.put(b"row 2").unwrap();
+        db.delete(1).unwrap();
+
+        // Simulate a crash: no sync, no close
+        crash(db);
+
+        let db = ToonStore::open(dir.path()).unwrap();
+        assert_eq!(db.len(), 3);
+        assert_eq!(db.live_len(), 2);
+        assert_eq!(db.get(2).unwrap(), b"row 2");
+    }
+
+    #[test]
+    fn test_failure_after_append_keeps_row_space() {
+        let dir = TempDir::new().unwrap();
+        let db = ToonStore::open(dir.path()).unwrap();
+        db.put(b"row 0").unwrap();
+
+        // A truncated index file fails the put after its row is appended
+        let idx = OpenOptions::new()
+            .write(true)
+            .open(dir.path().join("db.toon.idx"))
+            .unwrap();
+        idx.set_len(0).unwrap();
+        assert!(db.put(b"row 1").is_err());
+        assert!(db.bulk_put([&b"row 2"[..], b"row 3"]).is_err());
+
+        // The rows the index kept are not overwritten by later puts
+        db.sync().unwrap();
+        let id = db.put(b"row 4").unwrap();
+        assert_eq!(db.get(1).unwrap(), b"row 1");
+        assert_eq!(db.get(2).unwrap(), b"row 2");
+        assert_eq!(db.get(3).unwrap(), b"row 3");
+        assert_eq!(db.get(id).unwrap(), b"row 4");
+    }
+
+    /// A write to interrupt, see `crash_during`
+    type Interrupted = dyn Fn(&ToonStore) -> Result<()>;
+
+    /// Run `write` on a store holding rows 0..3, killing it after `steps` file writes
+    fn crash_during(dir: &Path, steps: usize, write: &Interrupted) -> bool {
+        let db = ToonStore::open(dir).unwrap();
+        db.bulk_put([&b"row 0"[..], b"row 1", b"row 2"]).unwrap();
+        WRITES_BEFORE_CRASH.with(|left| left.set(Some(steps)));
+        let finished = write(&db).is_ok();
+        WRITES_BEFORE_CRASH.with(|left| left.set(None));
+        crash(db);
+        finished
+    }
+
+    #[test]
+    fn test_crash_between_index_writes() {
+        let puts: [&Interrupted; 2] = [&|db| db.put(b"row 3").map(|_| ()), &|db| {
+            db.bulk_put([&b"row 3"[..], b"row 4"]).map(|_| ())
+        }];
+        for (which, put) in puts.iter().enumerate() {
+            for steps in 0.. {
+                let dir = TempDir::new().unwrap();
+                let finished = crash_during(dir.path(), steps, *put);
+
+                // The rows written before are kept; the interrupted ones may be lost
+                let db = ToonStore::open_with_repair(dir.path(), true)
+                    .unwrap_or_else(|e| panic!("put {} step {}: {}", which, steps, e));
+                assert!(db.len() >= 3);
+                for row_id in 0..3u64 {
+                    assert_eq!(
+                        db.get(row_id).unwrap(),
+                        format!("row {}", row_id).as_bytes()
+                    );
+                }
+                drop(db);
+                assert!(ToonStore::open(dir.path()).is_ok());
+                if finished {
+                    break;
+                }
+            }
+        }
+
+        for steps in 0.. {
+            let dir = TempDir::new().unwrap();
+            let finished = crash_during(dir.path(), steps, &|db| db.delete(1));
+
+            // The deleted row is either still there or gone, the others untouched
+            let db = ToonStore::open_with_repair(dir.path(), true)
+                .unwrap_or_else(|e| panic!("delete step {}: {}", steps, e));
+            assert_eq!(db.len(), 3);
+            assert_eq!(db.get(0).unwrap(), b"row 0");
+            assert_eq!(db.get(2).unwrap(), b"row 2");
+            assert!(matches!(db.get(1), Ok(_) | Err(Error::NotFound)));
+            drop(db);
+            assert!(ToonStore::open(dir.path()).is_ok());
+            if finished {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_torn_index_tail_is_dropped() {
+        let dir = TempDir::new().unwrap();
+        let mut db = ToonStore::open(dir.path()).unwrap();
+        db.bulk_put([&b"row 0"[..], b"row 1"]).unwrap();
+        db.close().unwrap();
+
+        // Half of a word a crash cut short after the checksum
+        let idx_path = dir.path().join("db.toon.idx");
+        let mut idx = std::fs::read(&idx_path).unwrap();
+        idx.extend_from_slice(&[1, 2, 3]);
+        std::fs::write(&idx_path, &idx).unwrap();
+
+        let db = ToonStore::open(dir.path()).unwrap();
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.get(1).unwrap(), b"row 1");
+    }
+
+    #[test]
+    fn test_fsync_always_persists_without_close() {
+        let dir = TempDir::new().unwrap();
+        let config = StoreConfig {
+            fsync: FsyncPolicy::Always,
+            ..StoreConfig::default()
+        };
+        let db = ToonStore::open(dir.path()).unwrap().with_config(config);
+        SYNCS.with(|syncs| syncs.set(0));
+        db.put(b"row 0").unwrap();
+        let after_put = SYNCS.with(|syncs| syncs.get());
+        assert!(after_put > 0);
+        db.bulk_put([&b"row 1"[..], b"row 2"]).unwrap();
+        db.delete(1).unwrap();
+        assert!(SYNCS.with(|syncs| syncs.get()) > after_put);
+        crash(db);
+
+        // The crash above keeps the page cache, so this only checks what was written
+        let db = ToonStore::open(dir.path()).unwrap();
+        assert_eq!(db.len(), 3);
+        assert_eq!(db.live_len(), 2);
+        assert_eq!(db.get(0).unwrap(), b"row 0");
+        assert_eq!(db.get(2).unwrap(), b"row 2");
+
+        // Nothing is fsynced per write under the other policies
+        SYNCS.with(|syncs| syncs.set(0));
+        db.put(b"row 3").unwrap();
+        assert_eq!(SYNCS.with(|syncs| syncs.get()), 0);
+    }
+
+    #[test]
+    fn test_fsync_every_sec_follows_config() {
+        let dir = TempDir::new().unwrap();
+        let config = StoreConfig {
+            fsync: FsyncPolicy::EverySec,
+            ..StoreConfig::default()
+        };
+        // Each running thread holds a reference to the generation
+        let threads = |db: &ToonStore| Arc::strong_count(&db.sync_generation) - 1;
+        let db = ToonStore::open(dir.path())
+            .unwrap()
+            .with_config(config.clone());
+        assert_eq!(threads(&db), 1);
+        db.put(b"row 0").unwrap();
+
+        // Configuring another policy stops the thread, as closing does, and
+        // switching back within the second leaves one thread, not two
+        let db = db.with_config(StoreConfig::default());
+        let mut db = db.with_config(config);
+        std::thread::sleep(Duration::from_millis(1500));
+        assert_eq!(threads(&db), 1);
+        db.close().unwrap();
+        drop(db);
+
+        let db = ToonStore::open(dir.path()).unwrap();
+        assert_eq!(db.get(0).unwrap(), b"row 0");
+    }
+
+    #[test]
+    fn test_index_corruption_detected() {
+        let dir = TempDir::new().unwrap();
+        let idx_path = dir.path().join("db.toon.idx");
+        {
+            let db = ToonStore::open(dir.path()).unwrap();
+            db.put(b"row 0").unwrap();
+            db.put(b"row 1").unwrap();
+        }
+        let good = std::fs::read(&idx_path).unwrap();
+
+        // Flipped bit in an offset
+        let mut bad = good.clone();
+        bad[IDX_HEADER_LEN as usize] ^= 1;
+        std::fs::write(&idx_path, &bad).unwrap();
+        assert!(matches!(
+            ToonStore::open(dir.path()),
+            Err(Error::Corrupt(_))
+        ));
+
+        // Index torn part way through a rewrite
+        std::fs::write(&idx_path, &good[..good.len() - 5]).unwrap();
+        assert!(matches!(
+            ToonStore::open(dir.path()),
+            Err(Error::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn test_index_rewrite_is_atomic() {
+        let dir = TempDir::new().unwrap();
+        {
+            let db = ToonStore::open(dir.path()).unwrap();
+            db.put(b"row 0").unwrap();
+        }
+
+        // A rewrite that died before the rename leaves only a partial temp file
+        let tmp_path = dir.path().join("db.toon.idx.tmp");
+        std::fs::write(&tmp_path, b"TOONIDX2\x05").unwrap();
+
+        let db = ToonStore::open(dir.path()).unwrap();
+        assert_eq!(db.get(0).unwrap(), b"row 0");
+        db.sync().unwrap();
+        assert!(!tmp_path.exists());
+    }
+
+    #[test]
+    fn test_index_v1_upgraded() {
+        let dir = TempDir::new().unwrap();
+        let idx_path = dir.path().join("db.toon.idx");
+        {
+            let db = ToonStore::open(dir.path()).unwrap();
+            db.put(b"row 0").unwrap();
+            db.put(b"row 1").unwrap();
+        }
+
+        // Rewrite the index in the old format: same layout, no checksum
+        let mut v1 = std::fs::read(&idx_path).unwrap();
+        v1.truncate(v1.len() - 8);
+        v1[..TOON_IDX_MAGIC_V1.len()].copy_from_slice(TOON_IDX_MAGIC_V1);
+        std::fs::write(&idx_path, &v1).unwrap();
+
+        let db = ToonStore::open(dir.path()).unwrap();
+        assert_eq!(db.get(1).unwrap(), b"row 1");
+        assert!(std::fs::read(&idx_path)
+            .unwrap()
+            .starts_with(TOON_IDX_MAGIC));
+        db.put(b"row 2").unwrap();
+        crash(db);
+
+        let db = ToonStore::open(dir.path()).unwrap();
+        assert_eq!(db.get(2).unwrap(), b"row 2");
+    }
+
+    #[test]
+    fn test_row_count_mismatch() {
+        let dir = TempDir::new().unwrap();
+        let data_path = dir.path().join("db.toon");
+        let size = {
+            let db = ToonStore::open(dir.path()).unwrap();
+            db.put(b"row 0").unwrap();
+            db.put(b"row 1").unwrap();
+            db.db_size()
+        };
+
+        // A row that reached the data file but never the index
+        let mut data = std::fs::read(&data_path).unwrap();
+        data.extend_from_slice(&6u32.to_le_bytes());
+        data.extend_from_slice(&crc32(b"orphan").to_le_bytes());
+        data.extend_from_slice(b"orphan");
+        std::fs::write(&data_path, &data).unwrap();
+
+        match ToonStore::open(dir.path()) {
+            Err(Error::Parse(msg)) => assert!(msg.contains("data file has 3"), "{}", msg),
+            other => panic!("expected a row count mismatch, got {:?}", other.map(|_| ())),
+        }
+
+        let db = ToonStore::open_with_repair(dir.path(), true).unwrap();
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.db_size(), size);
+        assert_eq!(db.put(b"row 2").unwrap(), 2);
+        assert_eq!(db.get(2).unwrap(), b"row 2");
+        drop(db);
+
+        let db = ToonStore::open(dir.path()).unwrap();
+        assert_eq!(db.len(), 3);
+    }
+
+    /// Run compaction passes of `budget` rows until one finishes
+    fn compact_all(db: &ToonStore, budget: usize) -> CompactProgress {
+        loop {
+            let progress = db.compact_incremental(budget).unwrap();
+            if progress.done {
+                return progress;
+            }
+        }
+    }
+
+    #[test]
+    fn test_compact_incremental() {
+        let dir = TempDir::new().unwrap();
+        let db = ToonStore::open(dir.path()).unwrap();
+
+        let big = vec![b'x'; 1000];
+        for i in 0..10u8 {
+            let mut row = big.clone();
+            row[0] = b'0' + i;
+            db.put(&row).unwrap();
+        }
+        for row_id in (0..10).step_by(2) {
+            db.delete(row_id).unwrap();
+        }
+        let size = db.db_size();
+
+        let progress = db.compact_incremental(4).unwrap();
+        assert_eq!(progress.rows_processed, 4);
+        assert_eq!(progress.rows_remaining, 6);
+        assert!(!progress.done);
+
+        let progress = compact_all(&db, 4);
+        assert_eq!(progress.bytes_reclaimed, size - db.db_size());
+        assert!(progress.bytes_reclaimed >= 5 * 1000);
+
+        // Row IDs are unchanged
+        assert_eq!(db.len(), 10);
+        assert_eq!(db.live_len(), 5);
+        assert!(matches!(db.get(4), Err(Error::NotFound)));
+        assert_eq!(db.get(5).unwrap()[0], b'5');
+        assert_eq!(db.put(b"after").unwrap(), 10);
+        drop(db);
+
+        let db = ToonStore::open(dir.path()).unwrap();
+        assert_eq!(db.get(9).unwrap()[0], b'9');
+        assert_eq!(db.get(10).unwrap(), b"after");
+        assert!(!dir.path().join("db.toon.compact").exists());
+    }
+
+    #[test]
+    fn test_compact_with_concurrent_writes() {
+        let dir = TempDir::new().unwrap();
+        let db = ToonStore::open(dir.path()).unwrap();
+        for i in 0..6 {
+            db.put(format!("row {}", i).as_bytes()).unwrap();
+        }
+
+        db.compact_incremental(3).unwrap();
+        // A copied row is deleted, an uncopied one deleted, a new one added
+        db.delete(1).unwrap();
+        db.delete(4).unwrap();
+        let id = db.put(b"new row").unwrap();
+        assert_eq!(db.get(id).unwrap(), b"new row");
+
+        let progress = compact_all(&db, 3);
+        assert!(progress.done);
+        assert_eq!(db.live_len(), 5);
+        assert!(matches!(db.get(1), Err(Error::NotFound)));
+        assert!(matches!(db.get(4), Err(Error::NotFound)));
+        assert_eq!(db.get(5).unwrap(), b"row 5");
+        assert_eq!(db.get(id).unwrap(), b"new row");
+
+        // Clearing mid-pass drops the pass
+        db.compact_incremental(1).unwrap();
+        db.clear().unwrap();
+        assert!(!dir.path().join("db.toon.compact").exists());
+        assert!(compact_all(&db, 1).done);
+    }
+
+    #[test]
+    fn test_compact_crash_recovery() {
+        let dir = TempDir::new().unwrap();
+        let idx_path = dir.path().join("db.toon.idx");
+
+        // Crash mid-pass: the original files are untouched
+        let db = ToonStore::open(dir.path()).unwrap();
+        db.put(b"row 0").unwrap();
+        db.put(b"row 1").unwrap();
+        db.put(b"row 2").unwrap();
+        db.delete(0).unwrap();
+        db.sync().unwrap();
+        let old_index = std::fs::read(&idx_path).unwrap();
+        db.compact_incremental(1).unwrap();
+        crash(db);
+
+        let db = ToonStore::open(dir.path()).unwrap();
+        assert!(!dir.path().join("db.toon.compact").exists());
+        assert_eq!(db.get(2).unwrap(), b"row 2");
+
+        // Crash between the two renames: open finishes the swap
+        compact_all(&db, 10);
+        drop(db);
+        std::fs::rename(&idx_path, dir.path().join("db.toon.idx.compact")).unwrap();
+        std::fs::write(&idx_path, &old_index).unwrap();
+
+        let db = ToonStore::open(dir.path()).unwrap();
+        assert_eq!(db.get(1).unwrap(), b"row 1");
+        assert_eq!(db.get(2).unwrap(), b"row 2");
+        assert!(matches!(db.get(0), Err(Error::NotFound)));
+    }
+
+    #[test]
+    fn test_compact_memory_is_noop() {
+        let db = ToonStore::open_memory();
+        db.put(b"row").unwrap();
+        assert!(db.compact_incremental(10).unwrap().done);
+        assert_eq!(db.get(0).unwrap(), b"row");
+    }
+
     #[test]
     fn test_scan() {
         let dir = TempDir::new().unwrap();
@@ -596,6 +3543,120 @@ mod tests {
         assert_eq!(results[1].as_ref().unwrap().1, b"line 2");
     }
 
+    #[test]
+    fn test_scan_cursor() {
+        let db = ToonStore::open_memory();
+        for i in 0..5u8 {
+            db.put_tagged(&[i], i % 2).unwrap();
+        }
+        db.delete(2).unwrap();
+
+        assert_eq!(db.scan_cursor(0, 3, None).unwrap(), (vec![0, 1], 3));
+        assert_eq!(db.scan_cursor(3, 3, None).unwrap(), (vec![3, 4], 0));
+        assert_eq!(db.scan_cursor(0, 2, Some(1)).unwrap(), (vec![1], 2));
+        assert_eq!(db.scan_cursor(2, 2, Some(1)).unwrap(), (vec![3], 4));
+        assert_eq!(db.scan_cursor(4, 2, Some(1)).unwrap(), (vec![], 0));
+        assert_eq!(db.scan_cursor(99, 2, None).unwrap(), (vec![], 0));
+    }
+
+    #[test]
+    fn test_scan_cursor_under_concurrent_writes() {
+        use std::collections::{HashSet, VecDeque};
+
+        let dir = TempDir::new().unwrap();
+        let db = ToonStore::open(dir.path()).unwrap();
+        let done = std::sync::atomic::AtomicBool::new(false);
+
+        // Rows live for the whole test, between rows that come and go
+        let mut stable = HashSet::new();
+        let mut churn = VecDeque::new();
+        for i in 0..500 {
+            stable.insert(db.put(format!("stable {}", i).as_bytes()).unwrap());
+            churn.push_back(db.put(&[b'c'; 100]).unwrap());
+        }
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut churn = churn;
+                while !done.load(std::sync::atomic::Ordering::Acquire) {
+                    for _ in 0..50 {
+                        db.delete(churn.pop_front().unwrap()).unwrap();
+                        churn.push_back(db.put(&[b'c'; 100]).unwrap());
+                    }
+                    db.compact_incremental(100).unwrap();
+                }
+            });
+
+            for _ in 0..20 {
+                let mut seen = Vec::new();
+                let mut cursor = 0;
+                loop {
+                    let (rows, next) = db.scan_cursor(cursor, 7, None).unwrap();
+                    seen.extend(rows);
+                    if next == 0 {
+                        break;
+                    }
+                    cursor = next;
+                }
+
+                let unique: HashSet<u64> = seen.iter().copied().collect();
+                assert_eq!(unique.len(), seen.len(), "a row was returned twice");
+                assert!(unique.is_superset(&stable), "a stable row was skipped");
+            }
+            done.store(true, std::sync::atomic::Ordering::Release);
+        });
+
+        assert_eq!(db.live_len(), 1000);
+    }
+
+    #[test]
+    fn test_scan_with_values() {
+        let dir = TempDir::new().unwrap();
+        let db = ToonStore::open(dir.path()).unwrap();
+        for i in 0..1000 {
+            db.put(format!("row {}", i).as_bytes()).unwrap();
+        }
+        for row_id in (0..1000).step_by(7) {
+            db.delete(row_id).unwrap();
+        }
+
+        let mut dumped = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let (rows, next) = db.scan_with_values(cursor, 64).unwrap();
+            assert!(rows.len() <= 64);
+            dumped.extend(rows);
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+
+        let expected: Vec<_> = db.scan().map(|row| row.unwrap()).collect();
+        assert_eq!(dumped.len(), 1000 - 143);
+        assert_eq!(dumped, expected);
+    }
+
+    #[test]
+    fn test_count_by() {
+        let db = ToonStore::open_memory();
+        for (id, city) in [(1, "Paris"), (2, "Oslo"), (3, "Paris"), (4, "Lima")] {
+            let line = format!("user:{}{{id,city}}: {},{}", id, id, city);
+            db.put(line.as_bytes()).unwrap();
+        }
+        db.put(b"city: Paris, not a record").unwrap();
+        db.put(b"user:5{id,city}: 5,Paris").unwrap();
+        db.delete(5).unwrap();
+
+        for city in ["Paris", "Oslo", "Lima", "Rome"] {
+            let rows = db.find_by("city", city.as_bytes()).unwrap();
+            assert_eq!(db.count_by("city", city.as_bytes()).unwrap(), rows.len());
+        }
+        assert_eq!(db.find_by("city", b"Paris").unwrap(), vec![0, 2]);
+        assert_eq!(db.count_by("city", b"Rome").unwrap(), 0);
+        assert_eq!(db.count_by("country", b"Paris").unwrap(), 0);
+    }
+
     #[test]
     fn test_scan_empty() {
         let dir = TempDir::new().unwrap();
@@ -615,4 +3676,94 @@ mod tests {
         // Try to delete non-existent row
         assert!(matches!(db.delete(5), Err(Error::NotFound)));
     }
+
+    #[test]
+    fn test_memory_put_and_get() {
+        let db = ToonStore::open_memory();
+
+        let id0 = db.put(b"users[1]{id,name}:").unwrap();
+        let id1 = db.put(b"  1,Alice").unwrap();
+        assert_eq!(id0, 0);
+        assert_eq!(id1, 1);
+        assert_eq!(db.len(), 2);
+
+        assert_eq!(db.get(id0).unwrap(), b"users[1]{id,name}:");
+        assert_eq!(db.get(id1).unwrap(), b"  1,Alice");
+        assert!(matches!(db.get(2), Err(Error::NotFound)));
+    }
+
+    #[test]
+    fn test_memory_delete() {
+        let db = ToonStore::open_memory();
+
+        db.put(b"line 0").unwrap();
+        db.put(b"line 1").unwrap();
+        db.put(b"line 2").unwrap();
+        db.put(b"line 3").unwrap();
+
+        db.delete(1).unwrap();
+        db.delete(3).unwrap();
+        assert!(matches!(db.get(1), Err(Error::NotFound)));
+        assert!(matches!(db.delete(1), Err(Error::NotFound)));
+        assert!(matches!(db.delete(5), Err(Error::NotFound)));
+        assert_eq!(db.len(), 4);
+        assert_eq!(db.live_len(), 2);
+
+        let results: Vec<_> = db.scan().map(|r| r.unwrap()).collect();
+        assert_eq!(
+            results,
+            vec![(0, b"line 0".to_vec()), (2, b"line 2".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_put_tagged_limited() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = ToonStore::open(temp_dir.path()).unwrap();
+
+        let large = vec![b'x'; 8 * MAX_VALUE_SIZE];
+        assert!(matches!(db.put(&large), Err(Error::ValueTooLarge(_))));
+        let row_id = db.put_tagged_limited(&large, 0, large.len()).unwrap();
+        assert!(matches!(
+            db.put_tagged_limited(b"12345", 0, 4),
+            Err(Error::ValueTooLarge(5))
+        ));
+        drop(db);
+
+        // Rows above the default limit read back after a reopen
+        let db = ToonStore::open(temp_dir.path()).unwrap();
+        assert_eq!(db.get(row_id).unwrap(), large);
+    }
+
+    #[test]
+    fn test_memory_limits_and_close() {
+        let mut db = ToonStore::open_memory();
+
+        let large = vec![b'x'; MAX_VALUE_SIZE + 1];
+        assert!(matches!(db.put(&large), Err(Error::ValueTooLarge(_))));
+
+        db.put(b"12345").unwrap();
+        assert_eq!(db.db_size(), 13);
+        db.sync().unwrap();
+        db.reload().unwrap();
+        assert_eq!(db.get(0).unwrap(), b"12345");
+
+        db.close().unwrap();
+        assert!(matches!(db.put(b"more"), Err(Error::Closed)));
+    }
+
+    #[test]
+    fn test_memory_update_fields_and_clear() {
+        let db = ToonStore::open_memory();
+
+        let row_id = db.put(b"user:1{id,name}: user:1,John").unwrap();
+        let new_row_id = db.update_fields(row_id, &[("name", b"Jane")]).unwrap();
+        assert!(matches!(db.get(row_id), Err(Error::NotFound)));
+        assert_eq!(db.get(new_row_id).unwrap(), b"user:1{id,name}: user:1,Jane");
+
+        db.clear().unwrap();
+        assert!(db.is_empty());
+        assert_eq!(db.db_size(), 0);
+        assert_eq!(db.put(b"fresh").unwrap(), 0);
+    }
 }
@@ -6,9 +6,14 @@
 use anyhow::{Context, Result};
 use bcrypt::{hash, verify, DEFAULT_COST};
 use std::fs;
+use std::net::IpAddr;
 use std::path::Path;
 use tracing::{info, warn};
 
+use crate::pubsub::Subscriber;
+use crate::ratelimit::{LoginBackoff, TokenBucket};
+use crate::resp::RespValue;
+
 /// Authentication configuration
 #[derive(Clone)]
 pub struct AuthConfig {
@@ -56,13 +61,13 @@ impl AuthConfig {
         })
     }
 
-    /// Create auth config from plain password
-    pub fn from_password(password: &str) -> Result<Self> {
+    /// Create auth config from plain password, hashed at bcrypt `cost`
+    pub fn from_password(password: &str, cost: u32) -> Result<Self> {
         if password.is_empty() {
             return Ok(Self::disabled());
         }
 
-        let password_hash = hash(password, DEFAULT_COST).context("Failed to hash password")?;
+        let password_hash = hash(password, cost).context("Failed to hash password")?;
 
         info!("Authentication enabled with provided password");
         Ok(Self {
@@ -91,6 +96,32 @@ pub struct SessionState {
     pub authenticated: bool,
     pub username: Option<String>,
     pub user_role: Option<crate::users::UserRole>,
+    /// Commands queued since MULTI (None when not in a transaction)
+    pub multi_queue: Option<Vec<RespValue>>,
+    /// Set when a command failed to queue, EXEC then aborts
+    pub multi_failed: bool,
+    /// Keys under WATCH with their version at WATCH time
+    pub watched: Vec<(String, u64)>,
+    /// This connection's pub/sub message queue (None outside a live connection)
+    pub subscriber: Option<Subscriber>,
+    /// Channels this connection is subscribed to
+    pub channels: Vec<String>,
+    /// Replies to send ahead of the command's own reply (one per channel for SUBSCRIBE)
+    pub extra_replies: Vec<RespValue>,
+    /// Set by HELLO 3: the client reads RESP3 replies and pushes
+    pub resp3: bool,
+    /// Set by CLIENT TRACKING ON: keys read are tracked for invalidation
+    pub tracking: bool,
+    /// Name set with CLIENT SETNAME
+    pub client_name: Option<String>,
+    /// The authenticated user's value size limit (None = the store default)
+    pub max_value_size: Option<usize>,
+    /// Commands allowed under --max-commands-per-sec
+    pub rate_limit: TokenBucket,
+    /// Failed AUTH attempts on this connection, under --auth-max-failures
+    pub login_backoff: LoginBackoff,
+    /// Address the client connected from (None over a UNIX socket or outside a connection)
+    pub peer: Option<IpAddr>,
 }
 
 impl SessionState {
@@ -108,6 +139,19 @@ impl SessionState {
             } else {
                 None
             },
+            multi_queue: None,
+            multi_failed: false,
+            watched: Vec::new(),
+            subscriber: None,
+            channels: Vec::new(),
+            extra_replies: Vec::new(),
+            resp3: false,
+            tracking: false,
+            client_name: None,
+            max_value_size: None,
+            rate_limit: TokenBucket::new(),
+            login_backoff: LoginBackoff::new(),
+            peer: None,
         }
     }
 
@@ -115,6 +159,12 @@ impl SessionState {
         self.authenticated = true;
         self.username = Some(username);
         self.user_role = Some(role);
+        self.max_value_size = None;
+    }
+
+    /// Largest value this session may write
+    pub fn value_limit(&self) -> usize {
+        self.max_value_size.unwrap_or(toonstoredb::MAX_VALUE_SIZE)
     }
 
     pub fn is_authenticated(&self) -> bool {
@@ -131,6 +181,62 @@ impl SessionState {
     pub fn username(&self) -> &str {
         self.username.as_deref().unwrap_or("anonymous")
     }
+
+    /// Check if the session is subscribed to at least one channel
+    pub fn in_subscribe_mode(&self) -> bool {
+        !self.channels.is_empty()
+    }
+
+    /// Check if the session is inside MULTI
+    pub fn in_multi(&self) -> bool {
+        self.multi_queue.is_some()
+    }
+
+    /// Start queuing commands for EXEC
+    pub fn start_multi(&mut self) {
+        self.multi_queue = Some(Vec::new());
+        self.multi_failed = false;
+    }
+
+    /// Queue a command for the current transaction
+    pub fn queue_command(&mut self, cmd: RespValue) {
+        if let Some(queue) = &mut self.multi_queue {
+            queue.push(cmd);
+        }
+    }
+
+    /// Mark the current transaction as failed (no-op outside MULTI)
+    pub fn flag_multi_error(&mut self) {
+        if self.in_multi() {
+            self.multi_failed = true;
+        }
+    }
+
+    /// Watch a key at the given version (the first WATCH of a key wins),
+    /// false if it was already watched
+    pub fn watch(&mut self, key: String, version: u64) -> bool {
+        if self.watched.iter().any(|(k, _)| *k == key) {
+            return false;
+        }
+        self.watched.push((key, version));
+        true
+    }
+
+    /// Forget all watched keys, returning them
+    pub fn unwatch(&mut self) -> Vec<(String, u64)> {
+        std::mem::take(&mut self.watched)
+    }
+
+    /// Take the replies queued ahead of the current command's reply
+    pub fn take_extra_replies(&mut self) -> Vec<RespValue> {
+        std::mem::take(&mut self.extra_replies)
+    }
+
+    /// Leave MULTI, returning the queued commands and whether queuing failed
+    pub fn take_multi(&mut self) -> Option<(Vec<RespValue>, bool)> {
+        let failed = std::mem::take(&mut self.multi_failed);
+        self.multi_queue.take().map(|queue| (queue, failed))
+    }
 }
 
 /// Helper to create a password hash for the password file
@@ -161,7 +267,7 @@ mod tests {
 
     #[test]
     fn test_auth_with_password() {
-        let auth = AuthConfig::from_password("mysecret").unwrap();
+        let auth = AuthConfig::from_password("mysecret", 4).unwrap();
         assert!(auth.is_required());
         assert!(auth.verify("mysecret"));
         assert!(!auth.verify("wrongpassword"));
@@ -7,13 +7,59 @@ use flate2::write::GzEncoder;
 use flate2::Compression;
 use std::fs::{self, File};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tar::{Archive, Builder};
-use tracing::info;
+use tracing::{error, info, warn};
+
+/// Scratch directory a restore extracts into
+const RESTORE_TEMP_DIR: &str = ".restore_temp";
+
+/// Where a restore keeps the data it replaced
+const OLD_BACKUP_DIR: &str = ".old_backup";
+
+/// Prefix of the directories a backup copies the data into before archiving
+const SNAPSHOT_DIR_PREFIX: &str = ".snapshot_";
 
 /// Backup configuration
 pub struct BackupConfig {
     pub data_dir: PathBuf,
     pub backup_dir: PathBuf,
+    /// Backups younger than this are never removed by cleanup
+    pub min_age: Duration,
+    /// Gzip level, 0 (store only) to 9 (smallest)
+    pub compression: u32,
+    /// Unix time of the newest successful backup (0 = none)
+    last_save: AtomicI64,
+    /// Numbers the snapshot directories, so concurrent backups do not share one
+    snapshots: AtomicU64,
+}
+
+/// A copy of the data directory taken by `BackupConfig::snapshot`, removed on drop
+pub struct Snapshot {
+    dir: PathBuf,
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            warn!("Failed to remove snapshot directory {:?}: {}", self.dir, e);
+        }
+    }
+}
+
+/// Copy a file, or a directory and everything under it
+fn copy_all(from: &Path, to: &Path) -> std::io::Result<()> {
+    if from.is_dir() {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_all(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else if from.is_file() {
+        fs::copy(from, to)?;
+    }
+    Ok(())
 }
 
 impl BackupConfig {
@@ -23,14 +69,88 @@ impl BackupConfig {
             .map(|p| p.as_ref().to_path_buf())
             .unwrap_or_else(|| data_dir.join("backups"));
 
-        Self {
+        let config = Self {
             data_dir,
             backup_dir,
+            min_age: Duration::ZERO,
+            compression: Compression::default().level(),
+            last_save: AtomicI64::new(0),
+            snapshots: AtomicU64::new(0),
+        };
+
+        // Backups left by earlier runs count, as Redis counts its last dump
+        let newest = config
+            .list_backups()
+            .ok()
+            .and_then(|backups| backups.first().and_then(|b| b.modified))
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok());
+        if let Some(newest) = newest {
+            config
+                .last_save
+                .store(newest.as_secs() as i64, Ordering::Relaxed);
         }
+        config
+    }
+
+    /// Unix time of the newest successful backup, 0 if there is none
+    pub fn last_save(&self) -> i64 {
+        self.last_save.load(Ordering::Relaxed)
     }
 
     /// Create a backup of the database
+    ///
+    /// The files are copied one by one, so a write that lands meanwhile can
+    /// leave the backup torn; a server takes `snapshot` with writes held off
+    /// and archives it with `archive` instead.
     pub fn create_backup(&self, name: Option<&str>) -> Result<PathBuf> {
+        let snapshot = self.snapshot()?;
+        self.archive(&snapshot, name)
+    }
+
+    /// Copy the data directory aside, as it stands at one point in time
+    ///
+    /// The caller holds off writes until this returns; archiving the copy
+    /// can then run alongside them.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let id = self.snapshots.fetch_add(1, Ordering::Relaxed);
+        let dir = self.data_dir.join(format!("{}{}", SNAPSHOT_DIR_PREFIX, id));
+        if dir.exists() {
+            fs::remove_dir_all(&dir).context("Failed to clean up old snapshot directory")?;
+        }
+        fs::create_dir_all(&dir).context("Failed to create snapshot directory")?;
+        let snapshot = Snapshot { dir };
+
+        let data_dir_entries =
+            fs::read_dir(&self.data_dir).context("Failed to read data directory")?;
+
+        for entry in data_dir_entries {
+            let entry = entry.context("Failed to read directory entry")?;
+            let path = entry.path();
+
+            // Skip backup directory itself, what a restore or another backup left
+            // behind, and the open store's lock
+            let is_snapshot = entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.starts_with(SNAPSHOT_DIR_PREFIX));
+            if path.starts_with(&self.backup_dir)
+                || is_snapshot
+                || path.ends_with(RESTORE_TEMP_DIR)
+                || path.ends_with(OLD_BACKUP_DIR)
+                || path.ends_with(toonstoredb::LOCK_FILE)
+            {
+                continue;
+            }
+
+            copy_all(&path, &snapshot.dir.join(entry.file_name()))
+                .context(format!("Failed to copy {:?} into snapshot", path))?;
+        }
+
+        Ok(snapshot)
+    }
+
+    /// Archive a snapshot into the backup directory
+    pub fn archive(&self, snapshot: &Snapshot, name: Option<&str>) -> Result<PathBuf> {
         // Create backup directory if it doesn't exist
         fs::create_dir_all(&self.backup_dir).context("Failed to create backup directory")?;
 
@@ -44,24 +164,19 @@ impl BackupConfig {
 
         // Create tar.gz archive
         let tar_gz = File::create(&backup_path).context("Failed to create backup file")?;
-        let enc = GzEncoder::new(tar_gz, Compression::default());
+        let enc = GzEncoder::new(tar_gz, Compression::new(self.compression.min(9)));
         let mut tar = Builder::new(enc);
 
-        // Add all files from data directory
-        let data_dir_entries =
-            fs::read_dir(&self.data_dir).context("Failed to read data directory")?;
+        // Add all files from the snapshot
+        let snapshot_entries =
+            fs::read_dir(&snapshot.dir).context("Failed to read snapshot directory")?;
 
-        for entry in data_dir_entries {
+        for entry in snapshot_entries {
             let entry = entry.context("Failed to read directory entry")?;
             let path = entry.path();
 
-            // Skip backup directory itself
-            if path.starts_with(&self.backup_dir) {
-                continue;
-            }
-
             // Get relative path for archive
-            let relative_path = path.strip_prefix(&self.data_dir).unwrap_or(&path);
+            let relative_path = path.strip_prefix(&snapshot.dir).unwrap_or(&path);
 
             if path.is_file() {
                 info!("Adding file to backup: {:?}", relative_path);
@@ -80,7 +195,13 @@ impl BackupConfig {
             }
         }
 
-        tar.finish().context("Failed to finalize backup archive")?;
+        // Only report success once the archive is on disk
+        tar.into_inner()
+            .and_then(|enc| enc.finish())
+            .and_then(|file| file.sync_all())
+            .context("Failed to finalize backup archive")?;
+        self.last_save
+            .fetch_max(Utc::now().timestamp(), Ordering::Relaxed);
 
         let metadata = fs::metadata(&backup_path)?;
         info!(
@@ -103,7 +224,7 @@ impl BackupConfig {
         info!("Restoring backup from: {:?}", backup_path);
 
         // Create a temporary directory for extraction
-        let temp_dir = self.data_dir.join(".restore_temp");
+        let temp_dir = self.data_dir.join(RESTORE_TEMP_DIR);
         if temp_dir.exists() {
             fs::remove_dir_all(&temp_dir)
                 .context("Failed to clean up temporary restore directory")?;
@@ -122,26 +243,59 @@ impl BackupConfig {
         info!("Backup extracted to temporary directory");
 
         // Move current data to backup (if exists)
-        let old_backup_dir = self.data_dir.join(".old_backup");
+        let old_backup_dir = self.data_dir.join(OLD_BACKUP_DIR);
         if old_backup_dir.exists() {
             fs::remove_dir_all(&old_backup_dir).context("Failed to remove old backup directory")?;
         }
 
-        // Move existing data files to .old_backup
+        // Swap the data files for the restored ones, undoing every move if
+        // any fails so the data directory is never left half-restored
+        let mut moved_out = Vec::new();
+        let mut moved_in = Vec::new();
+        let swapped = self
+            .move_aside(&temp_dir, &old_backup_dir, &mut moved_out)
+            .and_then(|_| self.move_in(&temp_dir, &mut moved_in));
+        if let Err(e) = swapped {
+            self.roll_back(&moved_in, &moved_out, &old_backup_dir);
+            let _ = fs::remove_dir_all(&temp_dir);
+            return Err(e);
+        }
+
+        // Clean up temporary directory
+        fs::remove_dir_all(&temp_dir).context("Failed to remove temporary directory")?;
+
+        info!("Backup restored successfully");
+        info!("Previous data backed up to: {:?}", old_backup_dir);
+
+        Ok(())
+    }
+
+    /// Move the current data files into `old_backup_dir`, recording each
+    /// original path in `moved`
+    fn move_aside(
+        &self,
+        temp_dir: &Path,
+        old_backup_dir: &Path,
+        moved: &mut Vec<PathBuf>,
+    ) -> Result<()> {
         let data_entries = fs::read_dir(&self.data_dir).context("Failed to read data directory")?;
 
         for entry in data_entries {
             let entry = entry.context("Failed to read directory entry")?;
             let path = entry.path();
 
-            // Skip special directories
-            if path == temp_dir || path == old_backup_dir || path.starts_with(&self.backup_dir) {
+            // Skip special directories, and the lock the running store still holds
+            if path == temp_dir
+                || path == old_backup_dir
+                || path.starts_with(&self.backup_dir)
+                || path.ends_with(toonstoredb::LOCK_FILE)
+            {
                 continue;
             }
 
             // Create old_backup dir on first file
             if !old_backup_dir.exists() {
-                fs::create_dir_all(&old_backup_dir)
+                fs::create_dir_all(old_backup_dir)
                     .context("Failed to create old backup directory")?;
             }
 
@@ -150,10 +304,16 @@ impl BackupConfig {
 
             fs::rename(&path, &dest)
                 .context(format!("Failed to backup existing file: {:?}", path))?;
+            moved.push(path);
         }
 
-        // Move restored files to data directory
-        let temp_entries = fs::read_dir(&temp_dir).context("Failed to read temporary directory")?;
+        Ok(())
+    }
+
+    /// Move the extracted files into the data directory, recording each
+    /// destination in `moved`
+    fn move_in(&self, temp_dir: &Path, moved: &mut Vec<PathBuf>) -> Result<()> {
+        let temp_entries = fs::read_dir(temp_dir).context("Failed to read temporary directory")?;
 
         for entry in temp_entries {
             let entry = entry.context("Failed to read directory entry")?;
@@ -162,15 +322,36 @@ impl BackupConfig {
             let dest = self.data_dir.join(filename);
 
             fs::rename(&path, &dest).context(format!("Failed to restore file: {:?}", path))?;
+            moved.push(dest);
         }
 
-        // Clean up temporary directory
-        fs::remove_dir_all(&temp_dir).context("Failed to remove temporary directory")?;
+        Ok(())
+    }
 
-        info!("Backup restored successfully");
-        info!("Previous data backed up to: {:?}", old_backup_dir);
+    /// Undo a failed restore: drop the restored files and put the data
+    /// files moved to `old_backup_dir` back where they were
+    fn roll_back(&self, moved_in: &[PathBuf], moved_out: &[PathBuf], old_backup_dir: &Path) {
+        warn!("Restore failed, rolling back to the previous data");
+
+        for path in moved_in {
+            let removed = if path.is_dir() {
+                fs::remove_dir_all(path)
+            } else {
+                fs::remove_file(path)
+            };
+            if let Err(e) = removed {
+                error!("Failed to remove restored file {:?}: {}", path, e);
+            }
+        }
 
-        Ok(())
+        for path in moved_out {
+            let saved = old_backup_dir.join(path.file_name().unwrap());
+            if let Err(e) = fs::rename(&saved, path) {
+                error!("Failed to put back {:?} (kept at {:?}): {}", path, saved, e);
+            }
+        }
+
+        let _ = fs::remove_dir(old_backup_dir);
     }
 
     /// List available backups
@@ -204,21 +385,42 @@ impl BackupConfig {
         }
 
         // Sort by modification time (newest first)
-        backups.sort_by(|a, b| b.modified.cmp(&a.modified));
+        backups.sort_by_key(|b| std::cmp::Reverse(b.modified));
 
         Ok(backups)
     }
 
-    /// Delete old backups, keeping only the N most recent
-    pub fn cleanup_old_backups(&self, keep_count: usize) -> Result<usize> {
-        let backups = self.list_backups()?;
+    /// Backups `cleanup_old_backups` would delete, without deleting them
+    ///
+    /// Everything past the `keep_count` most recent, except backups younger
+    /// than `min_age` (or of unknown age).
+    pub fn cleanup_old_backups_dry_run(&self, keep_count: usize) -> Result<Vec<BackupInfo>> {
+        let now = SystemTime::now();
+        let old_enough = |backup: &BackupInfo| {
+            backup
+                .modified
+                .and_then(|modified| now.duration_since(modified).ok())
+                .is_some_and(|age| age >= self.min_age)
+        };
+
+        Ok(self
+            .list_backups()?
+            .into_iter()
+            .skip(keep_count)
+            .filter(old_enough)
+            .collect())
+    }
 
-        if backups.len() <= keep_count {
+    /// Delete old backups, keeping only the N most recent (and any younger
+    /// than `min_age`)
+    pub fn cleanup_old_backups(&self, keep_count: usize) -> Result<usize> {
+        let backups = self.cleanup_old_backups_dry_run(keep_count)?;
+        if backups.is_empty() {
             return Ok(0);
         }
 
         let mut deleted = 0;
-        for backup in backups.iter().skip(keep_count) {
+        for backup in &backups {
             info!("Deleting old backup: {:?}", backup.path);
             fs::remove_file(&backup.path)
                 .context(format!("Failed to delete backup: {:?}", backup.path))?;
@@ -269,4 +471,120 @@ mod tests {
         let content = fs::read_to_string(data_dir.join("test.txt")).unwrap();
         assert_eq!(content, "test data");
     }
+
+    #[test]
+    fn test_compression_levels() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path().join("data");
+        let content = "row ".repeat(1000);
+        fs::create_dir_all(&data_dir).unwrap();
+
+        let mut config = BackupConfig::new(&data_dir, None::<&str>);
+        for level in [0, 9] {
+            fs::write(data_dir.join("test.txt"), &content).unwrap();
+            config.compression = level;
+            let backup_path = config
+                .create_backup(Some(&format!("level{}", level)))
+                .unwrap();
+
+            fs::write(data_dir.join("test.txt"), "modified data").unwrap();
+            config.restore_backup(&backup_path).unwrap();
+            assert_eq!(
+                fs::read_to_string(data_dir.join("test.txt")).unwrap(),
+                content
+            );
+        }
+    }
+
+    #[test]
+    fn test_restore_rolls_back_on_failure() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path().join("data");
+        let config = BackupConfig::new(&data_dir, None::<&str>);
+
+        fs::create_dir_all(&data_dir).unwrap();
+        fs::write(data_dir.join("a.txt"), "original a").unwrap();
+        fs::write(data_dir.join("b.txt"), "original b").unwrap();
+        config.create_backup(Some("keep")).unwrap();
+
+        // A backup whose "backups" file cannot replace the backup directory
+        let bad_path = config.backup_dir.join("bad.tar.gz");
+        let enc = GzEncoder::new(File::create(&bad_path).unwrap(), Compression::default());
+        let mut tar = Builder::new(enc);
+        for (name, content) in [
+            ("a.txt", "restored a"),
+            ("c.txt", "restored c"),
+            ("backups", "x"),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, name, content.as_bytes())
+                .unwrap();
+        }
+        tar.into_inner().unwrap().finish().unwrap();
+
+        assert!(config.restore_backup(&bad_path).is_err());
+
+        assert_eq!(
+            fs::read_to_string(data_dir.join("a.txt")).unwrap(),
+            "original a"
+        );
+        assert_eq!(
+            fs::read_to_string(data_dir.join("b.txt")).unwrap(),
+            "original b"
+        );
+        assert!(!data_dir.join("c.txt").exists());
+        assert!(!data_dir.join(OLD_BACKUP_DIR).exists());
+        assert!(!data_dir.join(RESTORE_TEMP_DIR).exists());
+        assert_eq!(config.list_backups().unwrap().len(), 2);
+    }
+
+    /// Write a fake backup archive last modified `age` ago
+    fn fake_backup(dir: &Path, name: &str, age: Duration) {
+        let path = dir.join(name);
+        let file = File::create(&path).unwrap();
+        file.set_modified(SystemTime::now() - age).unwrap();
+    }
+
+    #[test]
+    fn test_cleanup_dry_run() {
+        let temp = TempDir::new().unwrap();
+        let backup_dir = temp.path().join("backups");
+        fs::create_dir_all(&backup_dir).unwrap();
+        let hour = Duration::from_secs(3600);
+        for (name, hours) in [
+            ("a.tar.gz", 1),
+            ("b.tar.gz", 2),
+            ("c.tar.gz", 3),
+            ("d.tar.gz", 4),
+        ] {
+            fake_backup(&backup_dir, name, hour * hours);
+        }
+
+        let mut config = BackupConfig::new(temp.path(), Some(&backup_dir));
+        let names = |backups: Vec<BackupInfo>| -> Vec<String> {
+            backups.into_iter().map(|b| b.filename).collect()
+        };
+        assert_eq!(
+            names(config.cleanup_old_backups_dry_run(2).unwrap()),
+            ["c.tar.gz", "d.tar.gz"]
+        );
+        assert_eq!(config.list_backups().unwrap().len(), 4);
+
+        // The 3 hour old backup is too recent to prune
+        config.min_age = hour * 3 + Duration::from_secs(60);
+        assert_eq!(
+            names(config.cleanup_old_backups_dry_run(1).unwrap()),
+            ["d.tar.gz"]
+        );
+
+        assert_eq!(config.cleanup_old_backups(1).unwrap(), 1);
+        assert_eq!(
+            names(config.list_backups().unwrap()),
+            ["a.tar.gz", "b.tar.gz", "c.tar.gz"]
+        );
+        assert!(config.cleanup_old_backups_dry_run(1).unwrap().is_empty());
+    }
 }
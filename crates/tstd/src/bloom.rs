@@ -0,0 +1,87 @@
+//! Bloom filter over stored keys
+//!
+//! Lets GET answer for keys that were never stored without a key map
+//! lookup. Keys are added before they go into the key map or a list, so
+//! the filter never rules out a key that exists; it lets through some keys
+//! that do not, and deleted keys until it is rebuilt. Bits are atomics, so
+//! adding a key only needs a shared reference, and positions are hashed
+//! with a random per-process seed so clients cannot aim for collisions.
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Filter bits per expected key, about 1% false positives at `HASHES`
+const BITS_PER_KEY: usize = 10;
+
+/// Bit positions set per key
+const HASHES: u64 = 7;
+
+/// Bloom filter of key names
+pub struct BloomFilter {
+    bits: Vec<AtomicU64>,
+    hasher: RandomState,
+}
+
+impl BloomFilter {
+    /// Create a filter sized for `expected_keys`; more keys raise the false positive rate
+    pub fn new(expected_keys: usize) -> Self {
+        let words = expected_keys
+            .max(1)
+            .saturating_mul(BITS_PER_KEY)
+            .div_ceil(64);
+        Self {
+            bits: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Bit positions of `key`, by double hashing one 64-bit hash
+    fn positions(&self, key: &str) -> impl Iterator<Item = usize> {
+        let hash = self.hasher.hash_one(key);
+        let step = hash.rotate_left(32) | 1;
+        let len = self.bits.len() as u64 * 64;
+        (0..HASHES).map(move |i| (hash.wrapping_add(i.wrapping_mul(step)) % len) as usize)
+    }
+
+    pub fn insert(&self, key: &str) {
+        for bit in self.positions(key) {
+            self.bits[bit / 64].fetch_or(1 << (bit % 64), Ordering::Relaxed);
+        }
+    }
+
+    /// False only if `key` was never inserted since the last clear
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.positions(key)
+            .all(|bit| self.bits[bit / 64].load(Ordering::Relaxed) & (1 << (bit % 64)) != 0)
+    }
+
+    pub fn clear(&self) {
+        for word in &self.bits {
+            word.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_false_negatives() {
+        let filter = BloomFilter::new(1000);
+        for i in 0..1000 {
+            filter.insert(&format!("key:{}", i));
+        }
+        assert!((0..1000).all(|i| filter.may_contain(&format!("key:{}", i))));
+
+        // Sized for the keys, most absent keys are ruled out
+        let false_positives = (0..1000)
+            .filter(|i| filter.may_contain(&format!("absent:{}", i)))
+            .count();
+        assert!(false_positives < 50, "{} false positives", false_positives);
+
+        filter.clear();
+        assert!(!filter.may_contain("key:1"));
+    }
+}
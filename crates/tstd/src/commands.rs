@@ -0,0 +1,649 @@
+//! Static command table used by COMMAND and transaction queueing
+//!
+//! Arity follows Redis conventions: a positive value is the exact number of
+//! arguments including the command name, a negative value is the minimum.
+//! Key positions drive `command_keys`, the one place that works out which
+//! arguments of a command are keys.
+
+use crate::keys;
+use crate::resp::RespValue;
+
+/// Specification of a supported command
+#[derive(Debug, Clone, Copy)]
+pub struct CommandSpec {
+    /// Command name (uppercase)
+    pub name: &'static str,
+    /// Argument count including the command name (negative = minimum)
+    pub arity: i64,
+    /// Redis-style flags such as `readonly`, `write`, `fast`; `slow`
+    /// commands run off the event loop, under the command timeout
+    pub flags: &'static [&'static str],
+    /// Position of the first key argument (0 = no keys)
+    pub first_key: i64,
+    /// Position of the last key argument (-1 = last argument)
+    pub last_key: i64,
+    /// Step between key arguments
+    pub step: i64,
+    /// Command group for COMMAND DOCS
+    pub group: &'static str,
+    /// One-line description for COMMAND DOCS
+    pub summary: &'static str,
+}
+
+impl CommandSpec {
+    /// Check whether `argc` (including the command name) satisfies the arity
+    pub fn arity_ok(&self, argc: usize) -> bool {
+        let argc = argc as i64;
+        if self.arity >= 0 {
+            argc == self.arity
+        } else {
+            argc >= -self.arity
+        }
+    }
+}
+
+const fn spec(
+    name: &'static str,
+    arity: i64,
+    flags: &'static [&'static str],
+    keys: (i64, i64, i64),
+    group: &'static str,
+    summary: &'static str,
+) -> CommandSpec {
+    CommandSpec {
+        name,
+        arity,
+        flags,
+        first_key: keys.0,
+        last_key: keys.1,
+        step: keys.2,
+        group,
+        summary,
+    }
+}
+
+const NO_KEYS: (i64, i64, i64) = (0, 0, 0);
+const ONE_KEY: (i64, i64, i64) = (1, 1, 1);
+const ALL_KEYS: (i64, i64, i64) = (1, -1, 1);
+
+/// Every command the server implements
+pub const COMMAND_TABLE: &[CommandSpec] = &[
+    spec(
+        "PING",
+        -1,
+        &["fast"],
+        NO_KEYS,
+        "connection",
+        "Return PONG or the given message",
+    ),
+    spec(
+        "ECHO",
+        2,
+        &["fast"],
+        NO_KEYS,
+        "connection",
+        "Return the given string",
+    ),
+    spec(
+        "HELLO",
+        -1,
+        &["fast", "noscript", "loading", "stale"],
+        NO_KEYS,
+        "connection",
+        "Switch the connection's protocol version and describe the server",
+    ),
+    spec(
+        "AUTH",
+        -2,
+        &["noscript", "loading", "stale", "fast"],
+        NO_KEYS,
+        "connection",
+        "Authenticate the connection",
+    ),
+    spec(
+        "QUIT",
+        -1,
+        &["fast"],
+        NO_KEYS,
+        "connection",
+        "Close the connection",
+    ),
+    spec(
+        "GET",
+        2,
+        &["readonly", "fast"],
+        ONE_KEY,
+        "string",
+        "Get the value of a key",
+    ),
+    spec(
+        "GETEX",
+        -2,
+        &["write", "fast"],
+        ONE_KEY,
+        "string",
+        "Get the value of a key and set or clear its expiration",
+    ),
+    spec(
+        "MGET",
+        -2,
+        &["readonly", "fast"],
+        ALL_KEYS,
+        "string",
+        "Get the values of several keys",
+    ),
+    spec(
+        "SET",
+        -3,
+        &["write", "denyoom"],
+        ONE_KEY,
+        "string",
+        "Set the value of a key",
+    ),
+    spec(
+        "GETRANGE",
+        4,
+        &["readonly"],
+        ONE_KEY,
+        "string",
+        "Get a substring of the value of a key",
+    ),
+    spec(
+        "SETRANGE",
+        4,
+        &["write", "denyoom"],
+        ONE_KEY,
+        "string",
+        "Overwrite part of a value starting at an offset",
+    ),
+    spec(
+        "STRLEN",
+        2,
+        &["readonly", "fast"],
+        ONE_KEY,
+        "string",
+        "Get the length of the value of a key",
+    ),
+    spec(
+        "HSET",
+        -4,
+        &["write", "denyoom", "fast"],
+        ONE_KEY,
+        "hash",
+        "Set the value of one or more hash fields",
+    ),
+    spec(
+        "HGET",
+        3,
+        &["readonly", "fast"],
+        ONE_KEY,
+        "hash",
+        "Get the value of a hash field",
+    ),
+    spec(
+        "HGETALL",
+        2,
+        &["readonly"],
+        ONE_KEY,
+        "hash",
+        "Get all fields and values of a hash",
+    ),
+    spec(
+        "HDEL",
+        -3,
+        &["write", "fast"],
+        ONE_KEY,
+        "hash",
+        "Delete one or more hash fields",
+    ),
+    spec(
+        "HLEN",
+        2,
+        &["readonly", "fast"],
+        ONE_KEY,
+        "hash",
+        "Count the fields of a hash",
+    ),
+    spec(
+        "LPUSH",
+        -3,
+        &["write", "denyoom", "fast"],
+        ONE_KEY,
+        "list",
+        "Prepend one or more elements to a list",
+    ),
+    spec(
+        "RPUSH",
+        -3,
+        &["write", "denyoom", "fast"],
+        ONE_KEY,
+        "list",
+        "Append one or more elements to a list",
+    ),
+    spec(
+        "LPOP",
+        -2,
+        &["write", "fast"],
+        ONE_KEY,
+        "list",
+        "Remove and return elements from the head of a list",
+    ),
+    spec(
+        "RPOP",
+        -2,
+        &["write", "fast"],
+        ONE_KEY,
+        "list",
+        "Remove and return elements from the tail of a list",
+    ),
+    spec(
+        "LLEN",
+        2,
+        &["readonly", "fast"],
+        ONE_KEY,
+        "list",
+        "Return the length of a list",
+    ),
+    spec(
+        "LRANGE",
+        4,
+        &["readonly"],
+        ONE_KEY,
+        "list",
+        "Return a range of elements from a list",
+    ),
+    spec(
+        "DEL",
+        -2,
+        &["write"],
+        ALL_KEYS,
+        "generic",
+        "Delete one or more keys",
+    ),
+    spec(
+        "UNLINK",
+        -2,
+        &["write", "fast"],
+        ALL_KEYS,
+        "generic",
+        "Delete one or more keys without blocking",
+    ),
+    spec(
+        "EXISTS",
+        -2,
+        &["readonly", "fast"],
+        ALL_KEYS,
+        "generic",
+        "Count how many of the given keys exist",
+    ),
+    spec(
+        "KEYS",
+        -1,
+        &["readonly", "slow"],
+        NO_KEYS,
+        "generic",
+        "Find all keys matching a glob pattern",
+    ),
+    spec(
+        "COUNTKEYS",
+        2,
+        &["readonly", "slow"],
+        NO_KEYS,
+        "generic",
+        "Count the keys matching a glob pattern",
+    ),
+    spec(
+        "SCAN",
+        -2,
+        &["readonly", "slow"],
+        NO_KEYS,
+        "generic",
+        "Incrementally iterate over the keys",
+    ),
+    spec(
+        "DUMPALL",
+        -2,
+        &["admin", "readonly", "slow"],
+        NO_KEYS,
+        "server",
+        "Incrementally iterate over the raw rows and their data",
+    ),
+    spec(
+        "COUNTBY",
+        3,
+        &["readonly", "slow"],
+        NO_KEYS,
+        "generic",
+        "Count the rows whose TOON field holds a value",
+    ),
+    spec(
+        "DBSIZE",
+        1,
+        &["readonly", "fast"],
+        NO_KEYS,
+        "server",
+        "Return the number of keys",
+    ),
+    spec(
+        "FLUSHDB",
+        -1,
+        &["write", "slow"],
+        NO_KEYS,
+        "server",
+        "Remove all keys",
+    ),
+    spec(
+        "INFO",
+        -1,
+        &["loading", "stale"],
+        NO_KEYS,
+        "server",
+        "Return server information and statistics",
+    ),
+    spec(
+        "COMMAND",
+        -1,
+        &["loading", "stale"],
+        NO_KEYS,
+        "server",
+        "Describe the supported commands",
+    ),
+    spec(
+        "TIME",
+        1,
+        &["loading", "stale", "fast"],
+        NO_KEYS,
+        "server",
+        "Return the server time as seconds and microseconds",
+    ),
+    spec(
+        "CONFIG",
+        -2,
+        &["admin", "loading", "stale"],
+        NO_KEYS,
+        "server",
+        "Get or set runtime configuration",
+    ),
+    spec(
+        "SAVE",
+        -1,
+        &["admin", "slow"],
+        NO_KEYS,
+        "server",
+        "Create a backup of the data directory",
+    ),
+    spec(
+        "BGSAVE",
+        -1,
+        &["admin", "slow"],
+        NO_KEYS,
+        "server",
+        "Create a backup of the data directory",
+    ),
+    spec(
+        "BGREWRITEAOF",
+        -1,
+        &["admin", "slow"],
+        NO_KEYS,
+        "server",
+        "Create a named backup",
+    ),
+    spec(
+        "BACKUP",
+        -1,
+        &["admin", "slow"],
+        NO_KEYS,
+        "server",
+        "Create a named backup, or list backups with BACKUP LIST",
+    ),
+    spec(
+        "RESTORE",
+        -2,
+        &["write", "admin", "slow"],
+        NO_KEYS,
+        "server",
+        "Restore the data directory from a backup",
+    ),
+    spec(
+        "LASTSAVE",
+        1,
+        &["fast"],
+        NO_KEYS,
+        "server",
+        "Return the Unix time of the last successful backup",
+    ),
+    spec(
+        "USER",
+        -2,
+        &["admin"],
+        NO_KEYS,
+        "server",
+        "Manage users in multi-user mode",
+    ),
+    spec(
+        "ACL",
+        -2,
+        &["admin", "noscript", "loading", "stale"],
+        NO_KEYS,
+        "server",
+        "Manage users and their permissions",
+    ),
+    spec(
+        "USERSTATS",
+        2,
+        &["admin"],
+        NO_KEYS,
+        "server",
+        "Per-command execution counts for a user",
+    ),
+    spec(
+        "HOTKEYS",
+        -1,
+        &["admin"],
+        NO_KEYS,
+        "server",
+        "List the most frequently read keys with their read counts",
+    ),
+    spec(
+        "SIZEHISTOGRAM",
+        -1,
+        &["admin", "readonly", "slow"],
+        NO_KEYS,
+        "server",
+        "Count stored values by length in power-of-two buckets",
+    ),
+    spec(
+        "OBJECT",
+        -2,
+        &["readonly"],
+        (2, 2, 1),
+        "generic",
+        "Inspect the internals of a key",
+    ),
+    spec(
+        "MEMORY",
+        -2,
+        &["readonly"],
+        (2, 2, 1),
+        "server",
+        "Estimate the memory a key uses",
+    ),
+    spec(
+        "CLIENT",
+        -2,
+        &["noscript", "loading", "stale"],
+        NO_KEYS,
+        "connection",
+        "Get or set the name of the connection",
+    ),
+    spec(
+        "DEBUG",
+        -2,
+        &["admin", "noscript", "loading", "stale", "slow"],
+        NO_KEYS,
+        "server",
+        "Debugging and persistence testing commands",
+    ),
+    spec(
+        "WAIT",
+        3,
+        &[],
+        NO_KEYS,
+        "generic",
+        "Wait for writes to reach replicas (always 0, replicas do not acknowledge)",
+    ),
+    spec(
+        "SYNC",
+        1,
+        &["admin", "noscript"],
+        NO_KEYS,
+        "server",
+        "Attach as a replica: a snapshot of the dataset, then every write",
+    ),
+    spec(
+        "EVAL",
+        -3,
+        &["noscript"],
+        NO_KEYS,
+        "scripting",
+        "Run a list of commands atomically, with KEYS[n] and ARGV[n] substituted",
+    ),
+    spec(
+        "MULTI",
+        1,
+        &["fast"],
+        NO_KEYS,
+        "transactions",
+        "Start a transaction",
+    ),
+    spec(
+        "EXEC",
+        1,
+        &[],
+        NO_KEYS,
+        "transactions",
+        "Execute the queued transaction",
+    ),
+    spec(
+        "DISCARD",
+        1,
+        &["fast"],
+        NO_KEYS,
+        "transactions",
+        "Discard the queued transaction",
+    ),
+    spec(
+        "WATCH",
+        -2,
+        &["fast"],
+        ALL_KEYS,
+        "transactions",
+        "Abort the next EXEC if any of the keys change",
+    ),
+    spec(
+        "UNWATCH",
+        1,
+        &["fast"],
+        NO_KEYS,
+        "transactions",
+        "Forget all watched keys",
+    ),
+];
+
+/// Look up a command by name (case-insensitive)
+pub fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    COMMAND_TABLE
+        .iter()
+        .find(|spec| spec.name.eq_ignore_ascii_case(name))
+}
+
+/// Key arguments of `command` (name first), at its table entry's key positions
+///
+/// Keys come back decoded with `keys::from_bytes`, the form the key map
+/// stores them in. Unknown commands and commands without keys have none.
+pub fn command_keys(command: &[RespValue]) -> Vec<String> {
+    let spec = match command.first() {
+        Some(RespValue::BulkString(Some(name))) => {
+            match std::str::from_utf8(name).ok().and_then(lookup) {
+                Some(spec) => spec,
+                None => return Vec::new(),
+            }
+        }
+        _ => return Vec::new(),
+    };
+    if spec.first_key <= 0 {
+        return Vec::new();
+    }
+
+    let last = if spec.last_key < 0 {
+        command.len() as i64 + spec.last_key
+    } else {
+        spec.last_key.min(command.len() as i64 - 1)
+    };
+
+    let mut keys = Vec::new();
+    let mut pos = spec.first_key;
+    while pos <= last {
+        if let RespValue::BulkString(Some(k)) = &command[pos as usize] {
+            keys.push(keys::from_bytes(k));
+        }
+        pos += spec.step.max(1);
+    }
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_case_insensitive() {
+        assert_eq!(lookup("get").unwrap().name, "GET");
+        assert!(lookup("NOSUCHCMD").is_none());
+    }
+
+    #[test]
+    fn test_arity() {
+        let get = lookup("GET").unwrap();
+        assert!(get.arity_ok(2));
+        assert!(!get.arity_ok(3));
+
+        let set = lookup("SET").unwrap();
+        assert!(!set.arity_ok(2));
+        assert!(set.arity_ok(3));
+        assert!(set.arity_ok(5));
+    }
+
+    #[test]
+    fn test_command_keys() {
+        let command = |words: &[&[u8]]| -> Vec<RespValue> {
+            words
+                .iter()
+                .map(|w| RespValue::BulkString(Some(w.to_vec())))
+                .collect()
+        };
+
+        assert_eq!(command_keys(&command(&[b"get", b"k"])), ["k"]);
+        assert_eq!(command_keys(&command(&[b"DEL", b"a", b"b"])), ["a", "b"]);
+        assert_eq!(
+            command_keys(&command(&[b"SET", b"k", b"v", b"EX", b"5"])),
+            ["k"]
+        );
+        assert_eq!(command_keys(&command(&[b"MEMORY", b"USAGE", b"k"])), ["k"]);
+        assert_eq!(
+            command_keys(&command(&[b"WATCH", b"\xff"])),
+            [keys::from_bytes(b"\xff")]
+        );
+        assert!(command_keys(&command(&[b"PING"])).is_empty());
+        assert!(command_keys(&command(&[b"NOSUCHCMD", b"k"])).is_empty());
+    }
+
+    #[test]
+    fn test_names_unique() {
+        for (i, a) in COMMAND_TABLE.iter().enumerate() {
+            for b in &COMMAND_TABLE[i + 1..] {
+                assert_ne!(a.name, b.name);
+            }
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//! Runtime server configuration
+//!
+//! Holds the tunables that can be read and changed while the server runs
+//! via `CONFIG GET` / `CONFIG SET`.
+
+use anyhow::Result;
+use std::collections::HashMap;
+
+/// Parameters understood by `CONFIG GET` / `CONFIG SET`
+pub const CONFIG_PARAMS: &[&str] = &["capacity", "cache-max-value-size", "maxmemory"];
+
+/// Default cap on a connection's unparsed input, matching Redis' client-query-buffer-limit
+pub const DEFAULT_MAX_REQUEST_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// Default size of the pieces a reply is written to the connection in
+pub const DEFAULT_REPLY_CHUNK_BYTES: u64 = 64 * 1024;
+
+/// Default number of failed AUTH attempts in a row allowed before a lockout
+pub const DEFAULT_AUTH_MAX_FAILURES: u32 = 5;
+
+/// Default cap on the keys CLIENT TRACKING remembers, matching Redis' tracking-table-max-keys
+pub const DEFAULT_TRACKING_TABLE_MAX_KEYS: usize = 1_000_000;
+
+/// Default interval of the background sweep for expired keys
+pub const DEFAULT_ACTIVE_EXPIRE_CYCLE_MS: u64 = 100;
+
+/// Bounds on the output pending for one connection: the pub/sub messages,
+/// invalidations and replicated writes queued for it, not the replies to its
+/// own commands (0 = no bound)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OutputBufferLimit {
+    /// Queued bytes past which the connection is closed at once
+    pub hard: u64,
+    /// Queued bytes the connection may stay over for `soft_seconds` before it is closed
+    pub soft: u64,
+    pub soft_seconds: u64,
+}
+
+impl OutputBufferLimit {
+    /// Parse `<hard> <soft> <soft-seconds>`, the sizes as for `parse_memory`
+    pub fn parse(value: &str) -> Result<Self> {
+        let parts: Vec<&str> = value.split_whitespace().collect();
+        let [hard, soft, soft_seconds] = parts[..] else {
+            anyhow::bail!("expected <hard> <soft> <soft-seconds>, got: {}", value);
+        };
+        Ok(Self {
+            hard: parse_memory(hard)?,
+            soft: parse_memory(soft)?,
+            soft_seconds: soft_seconds
+                .parse()
+                .map_err(|_| anyhow::anyhow!("invalid number of seconds: {}", soft_seconds))?,
+        })
+    }
+}
+
+/// Mutable server configuration shared by all connections
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Maximum data file size in bytes before writes are refused (0 = unlimited)
+    pub maxmemory: u64,
+    /// Publish `__keyevent@0__:<event>` messages when keys expire
+    pub notify_keyspace_events: bool,
+    /// Largest unparsed request a connection may buffer before it is closed
+    pub max_request_bytes: u64,
+    /// Replies are serialized and written this many bytes at a time
+    pub reply_chunk_bytes: u64,
+    /// Time a `slow` command may run before its client gets an error (0 = no limit)
+    pub command_timeout_ms: u64,
+    /// Commands a connection may run per second, AUTH and PING aside (0 = unlimited)
+    pub max_commands_per_sec: u64,
+    /// Failed AUTH attempts in a row before further ones are locked out (0 = never)
+    pub auth_max_failures: u32,
+    /// Also count failed AUTH attempts per username, across connections
+    pub auth_lockout_per_user: bool,
+    /// Keys to size the GET bloom filter for (0 = no filter)
+    pub bloom_filter_keys: usize,
+    /// Bound on each connection's queued messages, see `OutputBufferLimit`
+    pub client_output_buffer_limit: OutputBufferLimit,
+    /// Keys CLIENT TRACKING remembers before it forgets the oldest (0 = no limit)
+    pub tracking_table_max_keys: usize,
+    /// How often the background sweep looks for expired keys (0 = no sweep)
+    pub active_expire_cycle_ms: u64,
+    /// Original command name -> name clients must use instead ("" = disabled)
+    pub renamed_commands: HashMap<String, String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            maxmemory: 0,
+            notify_keyspace_events: false,
+            max_request_bytes: DEFAULT_MAX_REQUEST_BYTES,
+            reply_chunk_bytes: DEFAULT_REPLY_CHUNK_BYTES,
+            command_timeout_ms: 0,
+            max_commands_per_sec: 0,
+            auth_max_failures: DEFAULT_AUTH_MAX_FAILURES,
+            auth_lockout_per_user: false,
+            bloom_filter_keys: 0,
+            client_output_buffer_limit: OutputBufferLimit::default(),
+            tracking_table_max_keys: DEFAULT_TRACKING_TABLE_MAX_KEYS,
+            active_expire_cycle_ms: DEFAULT_ACTIVE_EXPIRE_CYCLE_MS,
+            renamed_commands: HashMap::new(),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn new(maxmemory: u64) -> Self {
+        Self {
+            maxmemory,
+            ..Self::default()
+        }
+    }
+
+    /// Map the name a client sent to the command it runs, None if disabled or renamed away
+    pub fn resolve_command(&self, name: &str) -> Option<String> {
+        if let Some((original, _)) = self
+            .renamed_commands
+            .iter()
+            .find(|(_, new)| !new.is_empty() && new.as_str() == name)
+        {
+            return Some(original.clone());
+        }
+
+        if self.renamed_commands.contains_key(name) {
+            return None;
+        }
+
+        Some(name.to_string())
+    }
+
+    /// Check whether a write would be refused given the current data size
+    pub fn is_over_maxmemory(&self, used_bytes: u64) -> bool {
+        self.maxmemory > 0 && used_bytes >= self.maxmemory
+    }
+}
+
+/// Parse a memory amount such as `1048576`, `512kb`, `64mb` or `1gb`
+pub fn parse_memory(value: &str) -> Result<u64> {
+    let lower = value.trim().to_lowercase();
+    let (digits, multiplier) = if let Some(n) = lower.strip_suffix("gb") {
+        (n, 1024 * 1024 * 1024)
+    } else if let Some(n) = lower.strip_suffix("mb") {
+        (n, 1024 * 1024)
+    } else if let Some(n) = lower.strip_suffix("kb") {
+        (n, 1024)
+    } else if let Some(n) = lower.strip_suffix('b') {
+        (n, 1)
+    } else {
+        (lower.as_str(), 1)
+    };
+
+    let amount = digits
+        .parse::<u64>()
+        .map_err(|_| anyhow::anyhow!("invalid memory amount: {}", value))?;
+
+    amount
+        .checked_mul(multiplier)
+        .ok_or_else(|| anyhow::anyhow!("memory amount out of range: {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_memory() {
+        assert_eq!(parse_memory("100").unwrap(), 100);
+        assert_eq!(parse_memory("10b").unwrap(), 10);
+        assert_eq!(parse_memory("2kb").unwrap(), 2048);
+        assert_eq!(parse_memory("64MB").unwrap(), 64 * 1024 * 1024);
+        assert_eq!(parse_memory("1gb").unwrap(), 1024 * 1024 * 1024);
+        assert!(parse_memory("lots").is_err());
+        assert!(parse_memory("-1").is_err());
+    }
+
+    #[test]
+    fn test_parse_output_buffer_limit() {
+        assert_eq!(
+            OutputBufferLimit::parse("32mb 8mb 60").unwrap(),
+            OutputBufferLimit {
+                hard: 32 * 1024 * 1024,
+                soft: 8 * 1024 * 1024,
+                soft_seconds: 60,
+            }
+        );
+        assert_eq!(
+            OutputBufferLimit::parse("0 0 0").unwrap(),
+            OutputBufferLimit::default()
+        );
+        assert!(OutputBufferLimit::parse("32mb 8mb").is_err());
+        assert!(OutputBufferLimit::parse("32mb 8mb soon").is_err());
+    }
+
+    #[test]
+    fn test_resolve_command() {
+        let mut config = ServerConfig::default();
+        config
+            .renamed_commands
+            .insert("FLUSHDB".to_string(), String::new());
+        config
+            .renamed_commands
+            .insert("CONFIG".to_string(), "SECRETCONFIG".to_string());
+
+        assert_eq!(config.resolve_command("GET"), Some("GET".to_string()));
+        assert_eq!(config.resolve_command("FLUSHDB"), None);
+        assert_eq!(config.resolve_command("CONFIG"), None);
+        assert_eq!(
+            config.resolve_command("SECRETCONFIG"),
+            Some("CONFIG".to_string())
+        );
+    }
+
+    #[test]
+    fn test_maxmemory_disabled_by_default() {
+        let config = ServerConfig::default();
+        assert!(!config.is_over_maxmemory(u64::MAX));
+
+        let config = ServerConfig::new(100);
+        assert!(!config.is_over_maxmemory(99));
+        assert!(config.is_over_maxmemory(100));
+    }
+}
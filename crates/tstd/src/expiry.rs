@@ -0,0 +1,162 @@
+//! Expiry deadlines of keys with a TTL
+//!
+//! Deadlines are kept by key for TTL lookups, and ordered by deadline so
+//! the keys that are due can be taken from the front without looking at
+//! the ones that are not. Keys also sit in a dense list, so a random one
+//! can be picked without walking the map. The three share one allocation
+//! of each key name. Deadlines are milliseconds since the UNIX epoch.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+/// Expiry deadline per key, with an index of keys by deadline
+#[derive(Debug, Default)]
+pub struct Expires {
+    /// Deadline of each key and its slot in `keys`
+    deadlines: HashMap<Arc<str>, (u64, usize)>,
+    by_deadline: BTreeSet<(u64, Arc<str>)>,
+    keys: Vec<Arc<str>>,
+}
+
+impl Expires {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the deadline of `key`, returning the one it replaces
+    pub fn insert(&mut self, key: String, deadline: u64) -> Option<u64> {
+        if let Some((name, entry)) = self.deadlines.get_key_value(key.as_str()) {
+            let (old, slot) = *entry;
+            let name = Arc::clone(name);
+            if old != deadline {
+                self.by_deadline.remove(&(old, Arc::clone(&name)));
+                self.by_deadline.insert((deadline, Arc::clone(&name)));
+                self.deadlines.insert(name, (deadline, slot));
+            }
+            return Some(old);
+        }
+
+        let name: Arc<str> = Arc::from(key);
+        self.by_deadline.insert((deadline, Arc::clone(&name)));
+        self.deadlines
+            .insert(Arc::clone(&name), (deadline, self.keys.len()));
+        self.keys.push(name);
+        None
+    }
+
+    /// Clear the deadline of `key`, returning it
+    pub fn remove(&mut self, key: &str) -> Option<u64> {
+        let (name, (deadline, slot)) = self.deadlines.remove_entry(key)?;
+        self.by_deadline.remove(&(deadline, name));
+        self.keys.swap_remove(slot);
+        if let Some(moved) = self.keys.get(slot) {
+            self.deadlines
+                .get_mut(moved)
+                .expect("listed key has a deadline")
+                .1 = slot;
+        }
+        Some(deadline)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&u64> {
+        self.deadlines.get(key).map(|(deadline, _)| deadline)
+    }
+
+    pub fn clear(&mut self) {
+        self.deadlines.clear();
+        self.by_deadline.clear();
+        self.keys.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.deadlines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deadlines.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &u64)> {
+        self.deadlines
+            .iter()
+            .map(|(key, (deadline, _))| (&**key, deadline))
+    }
+
+    /// Up to `limit` keys whose deadline is at or before `now`, earliest first
+    pub fn due(&self, now: u64, limit: usize) -> Vec<String> {
+        self.by_deadline
+            .iter()
+            .take_while(|(deadline, _)| *deadline <= now)
+            .take(limit)
+            .map(|(_, key)| key.to_string())
+            .collect()
+    }
+
+    /// Deadline of the key at `index` of the dense list, wrapped to its length
+    ///
+    /// Picking uniformly random indexes samples keys uniformly.
+    pub fn nth_deadline(&self, index: usize) -> Option<u64> {
+        if self.keys.is_empty() {
+            return None;
+        }
+        let key = &self.keys[index % self.keys.len()];
+        self.deadlines.get(key).map(|&(deadline, _)| deadline)
+    }
+}
+
+impl From<HashMap<String, u64>> for Expires {
+    fn from(deadlines: HashMap<String, u64>) -> Self {
+        let mut expires = Expires::new();
+        for (key, deadline) in deadlines {
+            expires.insert(key, deadline);
+        }
+        expires
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_due_keys_in_deadline_order() {
+        let mut expires = Expires::new();
+        expires.insert("late".to_string(), 300);
+        expires.insert("early".to_string(), 100);
+        expires.insert("middle".to_string(), 50);
+        // Renewing a key moves it in the deadline order
+        assert_eq!(expires.insert("middle".to_string(), 200), Some(50));
+
+        assert_eq!(expires.due(99, 10), Vec::<String>::new());
+        assert_eq!(expires.due(250, 10), vec!["early", "middle"]);
+        assert_eq!(expires.due(1000, 2), vec!["early", "middle"]);
+
+        assert_eq!(expires.remove("early"), Some(100));
+        assert_eq!(expires.remove("early"), None);
+        assert_eq!(expires.due(1000, 10), vec!["middle", "late"]);
+        assert_eq!(expires.len(), 2);
+
+        let reloaded = Expires::from(HashMap::from([("k".to_string(), 5)]));
+        assert_eq!(reloaded.due(5, 10), vec!["k"]);
+        assert_eq!(reloaded.get("k"), Some(&5));
+    }
+
+    #[test]
+    fn test_nth_deadline_covers_every_key() {
+        let mut expires = Expires::new();
+        for (i, key) in ["a", "b", "c", "d"].iter().enumerate() {
+            expires.insert(key.to_string(), i as u64);
+        }
+        // Removing from the middle moves the last key into the hole
+        expires.remove("b");
+        expires.insert("c".to_string(), 20);
+
+        let mut deadlines: Vec<u64> = (0..3).filter_map(|i| expires.nth_deadline(i)).collect();
+        deadlines.sort();
+        assert_eq!(deadlines, vec![0, 3, 20]);
+        assert_eq!(expires.nth_deadline(3), expires.nth_deadline(0));
+
+        expires.clear();
+        assert_eq!(expires.nth_deadline(0), None);
+    }
+}
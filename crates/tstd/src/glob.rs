@@ -0,0 +1,160 @@
+//! Glob patterns for KEYS, SCAN MATCH and CONFIG GET
+//!
+//! Same syntax as Redis' `stringmatchlen`, over bytes: `*` matches any run,
+//! `?` any one byte, `[abc]`, `[^abc]` and `[a-z]` a byte from (or not from)
+//! a set, and `\` makes the next byte literal. An unterminated set runs to
+//! the end of the pattern.
+//!
+//! Redis tries every split of the string at each `*` recursively, which is
+//! exponential on patterns like `a*a*a*b`. Here every other token matches
+//! exactly one byte, so only the most recent `*` ever needs to be retried:
+//! an earlier one could only take bytes the later one can take as well.
+//! Matching is iterative and takes at most pattern length × string length
+//! steps.
+
+/// Whether `string` matches the glob `pattern`
+pub fn stringmatch(pattern: &[u8], string: &[u8]) -> bool {
+    let mut p = 0;
+    let mut s = 0;
+    // Pattern position after the last `*` and the string position it resumes from
+    let mut retry: Option<(usize, usize)> = None;
+
+    while s < string.len() {
+        if pattern.get(p) == Some(&b'*') {
+            while pattern.get(p) == Some(&b'*') {
+                p += 1;
+            }
+            if p == pattern.len() {
+                return true;
+            }
+            retry = Some((p, s));
+            continue;
+        }
+
+        if p < pattern.len() {
+            let (matched, next) = match_token(pattern, p, string[s]);
+            if matched {
+                p = next;
+                s += 1;
+                continue;
+            }
+        }
+
+        // Let the last `*` take one more byte, or fail without one
+        let Some((star_p, star_s)) = retry else {
+            return false;
+        };
+        p = star_p;
+        s = star_s + 1;
+        retry = Some((star_p, s));
+    }
+
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+/// Match the token at `p` against one byte, returning whether it matched
+/// and where the next token starts
+fn match_token(pattern: &[u8], p: usize, byte: u8) -> (bool, usize) {
+    match pattern[p] {
+        b'?' => (true, p + 1),
+        b'[' => match_set(pattern, p + 1, byte),
+        b'\\' if p + 1 < pattern.len() => (pattern[p + 1] == byte, p + 2),
+        literal => (literal == byte, p + 1),
+    }
+}
+
+/// Match a `[...]` set starting just after its `[`
+fn match_set(pattern: &[u8], mut p: usize, byte: u8) -> (bool, usize) {
+    let negate = pattern.get(p) == Some(&b'^');
+    if negate {
+        p += 1;
+    }
+
+    let mut matched = false;
+    loop {
+        match pattern.get(p) {
+            None => break,
+            Some(b']') => {
+                p += 1;
+                break;
+            }
+            Some(b'\\') if p + 1 < pattern.len() => {
+                matched |= pattern[p + 1] == byte;
+                p += 2;
+            }
+            Some(&start) if p + 2 < pattern.len() && pattern[p + 1] == b'-' => {
+                let end = pattern[p + 2];
+                let (low, high) = if start <= end {
+                    (start, end)
+                } else {
+                    (end, start)
+                };
+                matched |= (low..=high).contains(&byte);
+                p += 3;
+            }
+            Some(&literal) => {
+                matched |= literal == byte;
+                p += 1;
+            }
+        }
+    }
+    (matched != negate, p)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn matches(pattern: &str, string: &str) -> bool {
+        stringmatch(pattern.as_bytes(), string.as_bytes())
+    }
+
+    #[test]
+    fn test_wildcards() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "anything"));
+        assert!(matches("user:*", "user:1"));
+        assert!(matches("user:*", "user:"));
+        assert!(!matches("user:*", "session:1"));
+        assert!(matches("*:1", "user:1"));
+        assert!(matches("u*r:*1", "user:101"));
+        assert!(!matches("u*r:*1", "user:10"));
+        assert!(matches("h?llo", "hello"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(matches("**a**", "banana"));
+        assert!(matches("", ""));
+        assert!(!matches("", "a"));
+    }
+
+    #[test]
+    fn test_sets_and_escapes() {
+        assert!(matches("h[ae]llo", "hello"));
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("h[a-b]llo", "hbllo"));
+        assert!(matches("h[b-a]llo", "hallo"));
+        assert!(!matches("h[a-b]llo", "hcllo"));
+        assert!(matches("key[\\]]", "key]"));
+        assert!(matches("a\\*b", "a*b"));
+        assert!(!matches("a\\*b", "axb"));
+        assert!(matches("a\\", "a\\"));
+        // An unterminated set runs to the end of the pattern
+        assert!(matches("a[bc", "ab"));
+        assert!(!matches("a[bc", "abc"));
+    }
+
+    #[test]
+    fn test_pathological_patterns_are_fast() {
+        let string = "a".repeat(10_000);
+        let pattern = format!("{}b", "a*".repeat(50));
+
+        let start = Instant::now();
+        assert!(!matches(&pattern, &string));
+        assert!(matches(&pattern, &format!("{}b", string)));
+        assert!(!matches(&"*?".repeat(100), &"x".repeat(50)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
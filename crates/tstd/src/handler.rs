@@ -2,22 +2,202 @@
 
 use crate::auth::{AuthConfig, SessionState};
 use crate::backup::BackupConfig;
+use crate::bloom::BloomFilter;
+use crate::commands::{self, command_keys, CommandSpec, COMMAND_TABLE};
+use crate::config::{parse_memory, ServerConfig, CONFIG_PARAMS};
+use crate::expiry::Expires;
+use crate::glob;
+use crate::help;
+use crate::keymap::{KeyMap, Lists};
+use crate::keys;
+use crate::pubsub::PubSub;
+use crate::ratelimit::LoginBackoff;
+use crate::replication::Replication;
 use crate::resp::RespValue;
-use crate::users::{UserManager, UserRole};
-use std::collections::HashMap;
+use crate::script;
+use crate::stats::CommandStats;
+use crate::tracking::Tracking;
+use crate::users::{UserManager, UserRole, UserStats, ANONYMOUS_USER};
+use std::collections::{HashMap, VecDeque};
 use std::fs::{File, OpenOptions};
+use std::hash::Hash;
 use std::io::{BufRead, BufReader, BufWriter, Write};
-use std::sync::{Arc, RwLock};
-use tooncache::ToonCache;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tooncache::{LruCache, ToonCache};
+use toonstoredb::ToonRecord;
 use tracing::{error, info, warn};
 
+/// INFO sections in report order
+const INFO_SECTIONS: &[&str] = &["server", "clients", "stats", "memory", "keyspace"];
+
+/// Record name of rows holding a hash, one TOON field per hash field
+const HASH_RECORD: &str = "hash";
+
+/// Row type tag of plain string values (the storage default)
+const STRING_TAG: u8 = 0;
+
+/// Row type tag of rows holding a hash
+const HASH_TAG: u8 = 1;
+
+/// Row type tag of list elements
+const LIST_TAG: u8 = 2;
+
+/// Most `SET ... IDEMPOTENT` tokens remembered at once, least recent dropped first
+const IDEMPOTENCY_TOKENS: usize = 10_000;
+
+/// Most usernames with failed AUTH attempts remembered at once, least recent dropped first
+const LOGIN_FAILURE_USERS: usize = 10_000;
+
+/// Most client addresses with failed AUTH attempts remembered at once, least recent dropped first
+const LOGIN_FAILURE_PEERS: usize = 10_000;
+
+/// How long a `SET ... IDEMPOTENT` token keeps its reply
+const IDEMPOTENCY_TTL: Duration = Duration::from_secs(300);
+
+/// Due keys one round of the background expiry sweep reaps
+const ACTIVE_EXPIRE_KEYS_PER_ROUND: usize = 20;
+
+/// Value of the row `DEBUG SELFTEST` writes and deletes again
+const SELFTEST_VALUE: &[u8] = b"__tstd_selftest__";
+
+/// Prefix of the scratch store directories `DEBUG SELFTEST` writes to
+const SELFTEST_DIR_PREFIX: &str = ".selftest_";
+
+/// Bytes MEMORY USAGE adds per key for its key map entry
+const MEMORY_KEY_OVERHEAD: usize = 48;
+
+/// Bytes MEMORY USAGE adds per row for its storage index entry
+const MEMORY_ROW_OVERHEAD: usize = 16;
+
+/// Keys with a TTL that `INFO keyspace` samples for `avg_ttl`
+const KEYSPACE_TTL_SAMPLE: usize = 100;
+
 pub struct CommandHandler {
     cache: Arc<ToonCache>,
-    key_map: Arc<RwLock<HashMap<String, u64>>>,
+    key_map: Arc<RwLock<KeyMap>>,
     keymap_path: String,
     auth_config: Arc<AuthConfig>,
     backup_config: Arc<BackupConfig>,
+    /// Set while a BGSAVE backup is being written
+    bgsave_running: Arc<AtomicBool>,
     user_manager: Option<Arc<UserManager>>,
+    config: Arc<RwLock<ServerConfig>>,
+    /// Shared by every command, held exclusively by EXEC so transactions never interleave
+    txn_lock: RwLock<()>,
+    /// Last modification version of each key some connection WATCHes; keys
+    /// nobody watches are left out, so the map only grows with the watches
+    key_versions: RwLock<HashMap<String, WatchedKey>>,
+    /// Source of unique, increasing key versions
+    next_version: AtomicU64,
+    /// Expiry deadline per key, in milliseconds since the UNIX epoch
+    expires: RwLock<Expires>,
+    pubsub: Arc<PubSub>,
+    /// Keys read by CLIENT TRACKING connections
+    tracking: Tracking,
+    /// Replicas attached with SYNC, fed every applied write
+    replication: Replication,
+    user_stats: Arc<UserStats>,
+    /// Row IDs of each list's elements, head first
+    lists: RwLock<Lists>,
+    lists_path: String,
+    /// Every stored key, when --bloom-filter is set; only changed under the key map lock
+    bloom: Option<BloomFilter>,
+    /// Open client connections
+    connected_clients: AtomicUsize,
+    /// Throughput and latency of handled commands
+    command_stats: CommandStats,
+    /// Reply and time of each recent `SET ... IDEMPOTENT` token, by user and
+    /// key; no reply yet while the write is in flight
+    idempotency: Mutex<LruCache<IdempotencyKey, (Instant, Option<RespValue>)>>,
+    /// Signalled when an in-flight `SET ... IDEMPOTENT` write finishes
+    idempotency_done: Condvar,
+    /// Numbers the `DEBUG SELFTEST` scratch stores, so concurrent runs do not share one
+    selftests: AtomicU64,
+    /// Failed AUTH attempts per existing username, under --auth-lockout-per-user
+    login_failures: Mutex<LruCache<String, LoginBackoff>>,
+    /// Failed AUTH attempts per client address, so reconnecting does not start afresh
+    peer_login_failures: Mutex<LruCache<IpAddr, LoginBackoff>>,
+    /// Whether the background expiry sweep runs, toggled by DEBUG SET-ACTIVE-EXPIRE
+    active_expire: AtomicBool,
+    /// Random ID of this server process, reported as INFO run_id
+    run_id: String,
+    /// When the handler was created, for INFO uptime
+    started: Instant,
+}
+
+/// Key counts for `INFO keyspace`, see `CommandHandler::keyspace_info`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyspaceInfo {
+    pub keys: usize,
+    /// Keys with a TTL
+    pub expires: usize,
+    /// Mean remaining TTL in milliseconds over the sampled keys, 0 without any
+    pub avg_ttl: u64,
+}
+
+/// Counts a client connection for as long as it is alive
+pub struct ConnectionGuard<'a>(&'a AtomicUsize);
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A WATCHed key: how many connections watch it, and its version
+struct WatchedKey {
+    watchers: usize,
+    /// 0 until the key is modified while watched
+    version: u64,
+}
+
+/// A `SET ... IDEMPOTENT` token, remembered per user and key
+type IdempotencyKey = (String, String, Vec<u8>);
+
+/// Marks a `SET ... IDEMPOTENT` write in flight; dropped unfinished, as by
+/// a panic, it forgets the token so the retries waiting on it can write
+struct InFlight<'a> {
+    handler: &'a CommandHandler,
+    id: Option<IdempotencyKey>,
+}
+
+impl InFlight<'_> {
+    /// Remember the write's reply for its retries, unless it failed
+    fn finish(mut self, reply: &RespValue) {
+        if let Some(id) = self.id.take() {
+            let mut seen = self.handler.idempotency.lock().unwrap();
+            if matches!(reply, RespValue::Error(_)) {
+                seen.remove(&id);
+            } else {
+                seen.put(id, (Instant::now(), Some(reply.clone())));
+            }
+            self.handler.idempotency_done.notify_all();
+        }
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id.take() {
+            if let Ok(mut seen) = self.handler.idempotency.lock() {
+                seen.remove(&id);
+            }
+            self.handler.idempotency_done.notify_all();
+        }
+    }
+}
+
+/// Clears the BGSAVE flag when the background job ends, even by panicking
+struct BgsaveRunning(Arc<AtomicBool>);
+
+impl Drop for BgsaveRunning {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
 }
 
 impl CommandHandler {
@@ -27,9 +207,15 @@ impl CommandHandler {
         auth_config: Arc<AuthConfig>,
         backup_config: Arc<BackupConfig>,
         user_manager: Option<Arc<UserManager>>,
+        config: ServerConfig,
     ) -> Self {
         let keymap_path = format!("{}/keymap.txt", data_dir);
-        let mut key_map = Self::load_keymap(&keymap_path);
+        let lists_path = format!("{}/lists.txt", data_dir);
+        let (lists, list_deadlines) = Self::load_lists(&lists_path);
+        let (mut key_map, mut expires) = Self::load_keymap(&keymap_path);
+        for (key, deadline) in list_deadlines {
+            expires.insert(key, deadline);
+        }
 
         // If keymap is empty, rebuild it from the database
         if key_map.is_empty() {
@@ -39,39 +225,164 @@ impl CommandHandler {
 
             // Save the rebuilt keymap
             if !key_map.is_empty() {
-                Self::save_keymap_static(&keymap_path, &key_map);
+                Self::save_keymap_static(&keymap_path, &key_map, &Expires::new());
             }
         } else {
             info!("Loaded {} keys from persistent storage", key_map.len());
         }
 
+        let bloom = (config.bloom_filter_keys > 0).then(|| {
+            let bloom = BloomFilter::new(config.bloom_filter_keys);
+            for key in key_map.keys().chain(lists.keys()) {
+                bloom.insert(key);
+            }
+            bloom
+        });
+        let tracking = Tracking::new(config.tracking_table_max_keys);
+        let pubsub = PubSub::with_limit(config.client_output_buffer_limit);
+
         Self {
             cache,
-            key_map: Arc::new(RwLock::new(key_map)),
+            key_map: Arc::new(RwLock::new(KeyMap::from(key_map))),
             keymap_path,
             auth_config,
             backup_config,
+            bgsave_running: Arc::new(AtomicBool::new(false)),
             user_manager,
+            config: Arc::new(RwLock::new(config)),
+            txn_lock: RwLock::new(()),
+            key_versions: RwLock::new(HashMap::new()),
+            next_version: AtomicU64::new(1),
+            expires: RwLock::new(expires),
+            pubsub: Arc::new(pubsub),
+            tracking,
+            replication: Replication::new(),
+            user_stats: Arc::new(UserStats::new()),
+            lists: RwLock::new(Lists::from(lists)),
+            lists_path,
+            bloom,
+            connected_clients: AtomicUsize::new(0),
+            command_stats: CommandStats::new(),
+            idempotency: Mutex::new(LruCache::new(IDEMPOTENCY_TOKENS)),
+            idempotency_done: Condvar::new(),
+            selftests: AtomicU64::new(0),
+            login_failures: Mutex::new(LruCache::new(LOGIN_FAILURE_USERS)),
+            peer_login_failures: Mutex::new(LruCache::new(LOGIN_FAILURE_PEERS)),
+            active_expire: AtomicBool::new(true),
+            run_id: generate_run_id(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Largest unparsed request a connection may buffer
+    pub fn max_request_bytes(&self) -> u64 {
+        self.config.read().unwrap().max_request_bytes
+    }
+
+    /// Size of the pieces replies are written in
+    pub fn reply_chunk_bytes(&self) -> usize {
+        self.config.read().unwrap().reply_chunk_bytes as usize
+    }
+
+    /// Commands one connection may run per second (0 = unlimited)
+    pub fn max_commands_per_sec(&self) -> u64 {
+        self.config.read().unwrap().max_commands_per_sec
+    }
+
+    /// How long a `slow` command may run, None for no limit
+    pub fn command_timeout(&self) -> Option<Duration> {
+        match self.config.read().unwrap().command_timeout_ms {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    /// Interval of the background expiry sweep, None if there is none
+    pub fn active_expire_cycle(&self) -> Option<Duration> {
+        match self.config.read().unwrap().active_expire_cycle_ms {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    /// Whether `cmd` names a command flagged `slow`, after renames
+    pub fn is_slow(&self, cmd: &RespValue) -> bool {
+        let name = match cmd {
+            RespValue::Array(Some(arr)) => match arr.first() {
+                Some(RespValue::BulkString(Some(name))) => {
+                    String::from_utf8_lossy(name).to_uppercase()
+                }
+                _ => return false,
+            },
+            _ => return false,
+        };
+
+        self.config
+            .read()
+            .unwrap()
+            .resolve_command(&name)
+            .and_then(|name| commands::lookup(&name))
+            .is_some_and(|spec| spec.flags.contains(&"slow"))
+    }
+
+    /// Count a new connection until the returned guard is dropped
+    pub fn track_connection(&self) -> ConnectionGuard<'_> {
+        self.connected_clients.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(&self.connected_clients)
+    }
+
+    /// Number of open client connections
+    pub fn connected_clients(&self) -> usize {
+        self.connected_clients.load(Ordering::Relaxed)
+    }
+
+    /// Cache and storage shared by all connections
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn cache(&self) -> &Arc<ToonCache> {
+        &self.cache
+    }
+
+    /// Channel registry shared by all connections
+    pub fn pubsub(&self) -> &Arc<PubSub> {
+        &self.pubsub
+    }
+
+    /// Drop a closing connection's channel subscriptions, tracked and watched keys
+    pub fn disconnect(&self, session: &mut SessionState) {
+        self.unwatch(session);
+        if let Some(subscriber) = &session.subscriber {
+            for channel in session.channels.drain(..) {
+                self.pubsub.unsubscribe(&channel, subscriber.id);
+            }
+            if std::mem::take(&mut session.tracking) {
+                self.tracking.disable(subscriber.id);
+            }
         }
     }
 
-    /// Load key mapping from disk
-    fn load_keymap(path: &str) -> HashMap<String, u64> {
+    /// Load key mapping and expiry deadlines from disk
+    ///
+    /// Each line is `key<TAB>row_id`, with a third `<TAB>deadline_ms` column for keys with a TTL
+    fn load_keymap(path: &str) -> (HashMap<String, u64>, Expires) {
         let mut map = HashMap::new();
+        let mut expires = HashMap::new();
 
         if let Ok(file) = File::open(path) {
             let reader = BufReader::new(file);
             for line in reader.lines().map_while(Result::ok) {
                 let parts: Vec<&str> = line.split('\t').collect();
-                if parts.len() == 2 {
+                if parts.len() == 2 || parts.len() == 3 {
                     if let Ok(row_id) = parts[1].parse::<u64>() {
                         map.insert(parts[0].to_string(), row_id);
+                        if let Some(Ok(deadline)) = parts.get(2).map(|d| d.parse::<u64>()) {
+                            expires.insert(parts[0].to_string(), deadline);
+                        }
                     }
                 }
             }
         }
 
-        map
+        (map, Expires::from(expires))
     }
 
     /// Rebuild keymap by scanning the database
@@ -105,7 +416,7 @@ impl CommandHandler {
     }
 
     /// Save keymap to disk (static version for use without self)
-    fn save_keymap_static(path: &str, key_map: &HashMap<String, u64>) {
+    fn save_keymap_static(path: &str, key_map: &HashMap<String, u64>, expires: &Expires) {
         match OpenOptions::new()
             .write(true)
             .create(true)
@@ -115,7 +426,11 @@ impl CommandHandler {
             Ok(file) => {
                 let mut writer = BufWriter::new(file);
                 for (key, row_id) in key_map.iter() {
-                    if let Err(e) = writeln!(writer, "{}\t{}", key, row_id) {
+                    let result = match expires.get(key) {
+                        Some(deadline) => writeln!(writer, "{}\t{}\t{}", key, row_id, deadline),
+                        None => writeln!(writer, "{}\t{}", key, row_id),
+                    };
+                    if let Err(e) = result {
                         error!("Failed to write keymap entry: {}", e);
                     }
                 }
@@ -130,31 +445,101 @@ impl CommandHandler {
     /// Save key mapping to disk
     fn save_keymap(&self) {
         let key_map = self.key_map.read().unwrap();
+        let expires = self.expires.read().unwrap();
+        Self::save_keymap_static(&self.keymap_path, &key_map, &expires);
+    }
+
+    /// Load list membership and the deadlines of lists with a TTL from disk
+    ///
+    /// Each line is `key<TAB>row_id,row_id,...`, with a third `<TAB>deadline_ms`
+    /// column for lists with a TTL
+    fn load_lists(path: &str) -> (HashMap<String, VecDeque<u64>>, HashMap<String, u64>) {
+        let mut lists = HashMap::new();
+        let mut deadlines = HashMap::new();
+
+        if let Ok(file) = File::open(path) {
+            let reader = BufReader::new(file);
+            for line in reader.lines().map_while(Result::ok) {
+                let parts: Vec<&str> = line.split('\t').collect();
+                let (key, rows, deadline) = match parts[..] {
+                    [key, rows] => (key, rows, None),
+                    [key, rows, deadline] => (key, rows, deadline.parse::<u64>().ok()),
+                    _ => continue,
+                };
+                let rows: Result<VecDeque<u64>, _> =
+                    rows.split(',').map(|r| r.parse::<u64>()).collect();
+                match rows {
+                    Ok(rows) if !rows.is_empty() => {
+                        lists.insert(key.to_string(), rows);
+                        if let Some(deadline) = deadline {
+                            deadlines.insert(key.to_string(), deadline);
+                        }
+                    }
+                    _ => warn!("Skipping malformed list entry for '{}'", key),
+                }
+            }
+        }
+
+        (lists, deadlines)
+    }
 
+    /// Save list membership to disk (static version for use without self)
+    fn save_lists_static(path: &str, lists: &HashMap<String, VecDeque<u64>>, expires: &Expires) {
         match OpenOptions::new()
             .write(true)
             .create(true)
             .truncate(true)
-            .open(&self.keymap_path)
+            .open(path)
         {
             Ok(file) => {
                 let mut writer = BufWriter::new(file);
-                for (key, row_id) in key_map.iter() {
-                    if let Err(e) = writeln!(writer, "{}\t{}", key, row_id) {
-                        error!("Failed to write keymap entry: {}", e);
+                for (key, rows) in lists.iter() {
+                    let rows: Vec<String> = rows.iter().map(|r| r.to_string()).collect();
+                    let result = match expires.get(key) {
+                        Some(deadline) => {
+                            writeln!(writer, "{}\t{}\t{}", key, rows.join(","), deadline)
+                        }
+                        None => writeln!(writer, "{}\t{}", key, rows.join(",")),
+                    };
+                    if let Err(e) = result {
+                        error!("Failed to write list entry: {}", e);
                     }
                 }
                 if let Err(e) = writer.flush() {
-                    error!("Failed to flush keymap: {}", e);
+                    error!("Failed to flush lists: {}", e);
                 }
             }
-            Err(e) => error!("Failed to open keymap file: {}", e),
+            Err(e) => error!("Failed to open lists file: {}", e),
+        }
+    }
+
+    /// Save list membership to disk
+    fn save_lists(&self) {
+        let lists = self.lists.read().unwrap();
+        let expires = self.expires.read().unwrap();
+        Self::save_lists_static(&self.lists_path, &lists, &expires);
+    }
+
+    /// Persist a TTL change to the file `key` is saved in
+    fn save_ttl(&self, key: &str) {
+        if self.lists.read().unwrap().contains_key(key) {
+            self.save_lists();
+        } else {
+            self.save_keymap();
         }
     }
 
+    /// Handle one command, timing it for INFO stats
     pub fn handle(&self, cmd: RespValue, session: &mut SessionState) -> RespValue {
+        let start = Instant::now();
+        let reply = self.handle_untimed(cmd, session);
+        self.command_stats.record(start.elapsed());
+        reply
+    }
+
+    fn handle_untimed(&self, cmd: RespValue, session: &mut SessionState) -> RespValue {
         info!("Handler received command: {:?}", cmd);
-        let arr = match cmd {
+        let mut arr = match cmd {
             RespValue::Array(Some(arr)) if !arr.is_empty() => arr,
             _ => return RespValue::Error("ERR invalid command format".to_string()),
         };
@@ -163,6 +548,26 @@ impl CommandHandler {
             RespValue::BulkString(Some(cmd)) => String::from_utf8_lossy(cmd).to_uppercase(),
             _ => return RespValue::Error("ERR invalid command".to_string()),
         };
+
+        // --rename-command: disabled or renamed-away names are unknown, new names map back
+        let command = match self.config.read().unwrap().resolve_command(&command) {
+            Some(resolved) => resolved,
+            None => {
+                session.flag_multi_error();
+                return RespValue::Error(format!("ERR unknown command '{}'", command));
+            }
+        };
+        // --max-commands-per-sec: AUTH and PING stay allowed so clients can keep the connection up
+        if !matches!(command.as_str(), "AUTH" | "PING")
+            && !session
+                .rate_limit
+                .try_acquire(self.max_commands_per_sec(), Instant::now())
+        {
+            session.flag_multi_error();
+            return RespValue::Error("ERR rate limit exceeded".to_string());
+        }
+        // Queued commands are re-parsed by EXEC, so store them under the real name
+        arr[0] = RespValue::BulkString(Some(command.as_bytes().to_vec()));
         info!(
             "Executing command: {} (user: {})",
             command,
@@ -176,11 +581,14 @@ impl CommandHandler {
 
         // Check authentication for all other commands
         if self.auth_config.is_required() && !session.is_authenticated() {
+            self.user_stats.record(ANONYMOUS_USER, &command);
             return RespValue::Error("NOAUTH Authentication required".to_string());
         }
 
         // Check role-based permissions
         if !session.can_execute(&command) {
+            // A refused command inside MULTI also dooms the transaction
+            session.flag_multi_error();
             return RespValue::Error(format!(
                 "NOPERM User '{}' does not have permission to execute '{}'",
                 session.username(),
@@ -188,759 +596,6756 @@ impl CommandHandler {
             ));
         }
 
+        self.user_stats.record(
+            session.username.as_deref().unwrap_or(ANONYMOUS_USER),
+            &command,
+        );
+
+        // A subscribed connection only accepts commands that manage the subscription
+        if session.in_subscribe_mode()
+            && !matches!(
+                command.as_str(),
+                "SUBSCRIBE" | "UNSUBSCRIBE" | "PING" | "QUIT"
+            )
+        {
+            return RespValue::Error(format!(
+                "ERR Can't execute '{}': only SUBSCRIBE / UNSUBSCRIBE / PING / QUIT are allowed in this context",
+                command.to_lowercase()
+            ));
+        }
+
+        // Transaction control commands are never queued
         match command.as_str() {
-            "PING" => self.handle_ping(&arr[1..]),
+            "MULTI" => return self.handle_multi(session),
+            "EXEC" => return self.handle_exec(session),
+            "DISCARD" => return self.handle_discard(session),
+            "WATCH" => return self.handle_watch(&arr, session),
+            "UNWATCH" => {
+                self.unwatch(session);
+                return RespValue::SimpleString("OK".to_string());
+            }
+            _ => {}
+        }
+
+        if session.in_multi() && command != "QUIT" {
+            match commands::lookup(&command) {
+                None => {
+                    session.flag_multi_error();
+                    return RespValue::Error(format!("ERR unknown command '{}'", command));
+                }
+                Some(spec) if !spec.arity_ok(arr.len()) => {
+                    session.flag_multi_error();
+                    return RespValue::Error(format!(
+                        "ERR wrong number of arguments for '{}' command",
+                        command.to_lowercase()
+                    ));
+                }
+                Some(_) => {}
+            }
+            session.queue_command(RespValue::Array(Some(arr)));
+            return RespValue::SimpleString("QUEUED".to_string());
+        }
+
+        // A script runs alone, like EXEC; queued in MULTI it runs under EXEC's lock.
+        // SYNC and the backups take their snapshots alone too, so no write lands
+        // half in them.
+        if matches!(
+            command.as_str(),
+            "EVAL" | "SYNC" | "SAVE" | "BGSAVE" | "BGREWRITEAOF" | "BACKUP"
+        ) {
+            let _txn = self.txn_lock.write().unwrap();
+            return self.dispatch(&command, &arr, session);
+        }
+
+        let _txn = self.txn_lock.read().unwrap();
+        self.dispatch(&command, &arr, session)
+    }
+
+    /// Run a single parsed command (auth, permissions and queueing already handled)
+    ///
+    /// Applied writes are forwarded to replicas. RESTORE is not: it swaps
+    /// the data directory for one a replica does not have, so replicas need
+    /// a new SYNC afterwards.
+    fn dispatch(&self, command: &str, arr: &[RespValue], session: &mut SessionState) -> RespValue {
+        let spec = commands::lookup(command);
+        // Lazy expiry: keys this command touches are reaped before it sees them
+        if let Some(spec) = spec {
+            self.expire_command_keys(spec, arr);
+        }
+        if let Some(reply) = help_reply(command, arr) {
+            return reply;
+        }
+
+        let replicated = spec
+            .is_some_and(|spec| spec.flags.contains(&"write") && !spec.flags.contains(&"admin"));
+        let _order = if replicated {
+            self.replication.order()
+        } else {
+            None
+        };
+        let reply = self.dispatch_command(command, arr, session);
+        if replicated && !matches!(reply, RespValue::Error(_)) {
+            self.replication.broadcast(arr);
+        }
+        if let (true, Some(spec), Some(subscriber)) = (session.tracking, spec, &session.subscriber)
+        {
+            if spec.flags.contains(&"readonly") && !matches!(reply, RespValue::Error(_)) {
+                for key in command_keys(arr) {
+                    self.tracking.track(&key, subscriber);
+                }
+            }
+        }
+        reply
+    }
+
+    fn dispatch_command(
+        &self,
+        command: &str,
+        arr: &[RespValue],
+        session: &mut SessionState,
+    ) -> RespValue {
+        match command {
+            "PING" => self.handle_ping(&arr[1..], session),
             "ECHO" => self.handle_echo(&arr[1..]),
+            "HELLO" => self.handle_hello(&arr[1..], session),
             "GET" => self.handle_get(&arr[1..]),
+            "GETEX" => self.handle_getex(&arr[1..]),
             "MGET" => self.handle_mget(&arr[1..]),
-            "SET" => self.handle_set(&arr[1..]),
-            "DEL" => self.handle_del(&arr[1..]),
+            "SET" => self.handle_set(&arr[1..], session),
+            "GETRANGE" => self.handle_getrange(&arr[1..]),
+            "SETRANGE" => self.handle_setrange(&arr[1..], session.value_limit()),
+            "STRLEN" => self.handle_strlen(&arr[1..]),
+            // Deletes are cheap soft deletes, so UNLINK has nothing to defer
+            "DEL" | "UNLINK" => self.handle_del(&arr[1..]),
             "EXISTS" => self.handle_exists(&arr[1..]),
             "KEYS" => self.handle_keys(&arr[1..]),
+            "COUNTKEYS" => self.handle_countkeys(&arr[1..]),
+            "SCAN" => self.handle_scan(&arr[1..]),
+            "DUMPALL" => self.handle_dumpall(&arr[1..]),
+            "COUNTBY" => self.handle_countby(&arr[1..]),
+            "EVAL" => self.handle_eval(&arr[1..], session),
             "DBSIZE" => self.handle_dbsize(),
-            "FLUSHDB" => self.handle_flushdb(),
+            "FLUSHDB" => self.handle_flushdb(&arr[1..]),
             "INFO" => self.handle_info(&arr[1..]),
             "COMMAND" => self.handle_command(&arr[1..]),
-            "SAVE" | "BGSAVE" => self.handle_save(&arr[1..]),
-            "BGREWRITEAOF" | "BACKUP" => self.handle_backup(&arr[1..]),
+            "SAVE" => self.handle_save(&arr[1..]),
+            "BGSAVE" => self.handle_bgsave(&arr[1..]),
+            "BGREWRITEAOF" | "BACKUP" => self.handle_backup(&arr[1..], session),
             "RESTORE" => self.handle_restore(&arr[1..]),
             "LASTSAVE" => self.handle_lastsave(),
             "USER" => self.handle_user(&arr[1..], session),
+            "ACL" => self.handle_acl(&arr[1..]),
+            "CONFIG" => self.handle_config(&arr[1..]),
+            "EXPIRE" => self.handle_expire(&arr[1..], 1000, "expire"),
+            "PEXPIRE" => self.handle_expire(&arr[1..], 1, "pexpire"),
+            "TTL" => self.handle_ttl(&arr[1..], false),
+            "PTTL" => self.handle_ttl(&arr[1..], true),
+            "PERSIST" => self.handle_persist(&arr[1..]),
+            "SUBSCRIBE" => self.handle_subscribe(&arr[1..], session),
+            "UNSUBSCRIBE" => self.handle_unsubscribe(&arr[1..], session),
+            "PUBLISH" => self.handle_publish(&arr[1..]),
+            "USERSTATS" => self.handle_userstats(&arr[1..]),
+            "HOTKEYS" => self.handle_hotkeys(&arr[1..]),
+            "SIZEHISTOGRAM" => self.handle_sizehistogram(&arr[1..]),
+            "HSET" => self.handle_hset(&arr[1..], session.value_limit()),
+            "HGET" => self.handle_hget(&arr[1..]),
+            "HGETALL" => self.handle_hgetall(&arr[1..]),
+            "HDEL" => self.handle_hdel(&arr[1..]),
+            "HLEN" => self.handle_hlen(&arr[1..]),
+            "DEBUG" => self.handle_debug(&arr[1..]),
+            "OBJECT" => self.handle_object(&arr[1..]),
+            "MEMORY" => self.handle_memory(&arr[1..]),
+            "CLIENT" => self.handle_client(&arr[1..], session),
+            "WAIT" => self.handle_wait(&arr[1..]),
+            "TIME" => self.handle_time(),
+            "SYNC" => self.handle_sync(&arr[1..], session),
+            "LPUSH" => self.handle_push(&arr[1..], true, "lpush", session.value_limit()),
+            "RPUSH" => self.handle_push(&arr[1..], false, "rpush", session.value_limit()),
+            "LPOP" => self.handle_pop(&arr[1..], true, "lpop"),
+            "RPOP" => self.handle_pop(&arr[1..], false, "rpop"),
+            "LLEN" => self.handle_llen(&arr[1..]),
+            "LRANGE" => self.handle_lrange(&arr[1..]),
             "QUIT" => RespValue::SimpleString("OK".to_string()),
             _ => RespValue::Error(format!("ERR unknown command '{}'", command)),
         }
     }
 
-    fn handle_ping(&self, args: &[RespValue]) -> RespValue {
-        if args.is_empty() {
-            RespValue::SimpleString("PONG".to_string())
-        } else if args.len() == 1 {
-            // PING with message
-            args[0].clone()
-        } else {
-            RespValue::Error("ERR wrong number of arguments for 'ping' command".to_string())
+    fn handle_multi(&self, session: &mut SessionState) -> RespValue {
+        if session.in_multi() {
+            return RespValue::Error("ERR MULTI calls can not be nested".to_string());
         }
+        session.start_multi();
+        RespValue::SimpleString("OK".to_string())
     }
 
-    fn handle_echo(&self, args: &[RespValue]) -> RespValue {
-        if args.len() != 1 {
+    fn handle_exec(&self, session: &mut SessionState) -> RespValue {
+        let (queued, failed) = match session.take_multi() {
+            Some(txn) => txn,
+            None => return RespValue::Error("ERR EXEC without MULTI".to_string()),
+        };
+        let watched = session.unwatch();
+
+        if failed {
+            self.release_watches(&watched);
             return RespValue::Error(
-                "ERR wrong number of arguments for 'echo' command".to_string(),
+                "EXECABORT Transaction discarded because of previous errors.".to_string(),
             );
         }
-        args[0].clone()
-    }
 
-    fn handle_get(&self, args: &[RespValue]) -> RespValue {
-        if args.len() != 1 {
-            return RespValue::Error("ERR wrong number of arguments for 'get' command".to_string());
+        // Exclusive lock: no other connection runs a command until the whole queue is applied
+        let _txn = self.txn_lock.write().unwrap();
+
+        // Optimistic locking: abort if any watched key changed since WATCH.
+        // Checked before the watches are released, which may forget the versions.
+        let changed = watched
+            .iter()
+            .any(|(key, version)| self.key_version(key) != *version);
+        self.release_watches(&watched);
+        if changed {
+            info!("EXEC: watched key modified, transaction aborted");
+            return RespValue::Array(None);
         }
 
-        let key = match &args[0] {
-            RespValue::BulkString(Some(k)) => match String::from_utf8(k.clone()) {
-                Ok(s) => s,
-                Err(_) => return RespValue::Error("ERR invalid key".to_string()),
-            },
-            _ => return RespValue::Error("ERR invalid key type".to_string()),
-        };
+        info!("EXEC: applying {} queued commands", queued.len());
 
-        // Look up row_id from key_map
-        let key_map = self.key_map.read().unwrap();
-        info!(
-            "GET: Looking for key '{}', keymap has {} keys",
-            key,
-            key_map.len()
-        );
-        let row_id = match key_map.get(&key) {
-            Some(id) => {
-                info!("GET: Found key '{}' -> row_id {}", key, id);
-                *id
+        let mut replies = Vec::with_capacity(queued.len());
+        for cmd in queued {
+            if let RespValue::Array(Some(arr)) = cmd {
+                let command = match &arr[0] {
+                    RespValue::BulkString(Some(c)) => String::from_utf8_lossy(c).to_uppercase(),
+                    _ => continue,
+                };
+                replies.push(self.dispatch(&command, &arr, session));
             }
+        }
+
+        RespValue::Array(Some(replies))
+    }
+
+    /// EVAL script numkeys [key ...] [arg ...]: run a list of commands atomically
+    ///
+    /// See `script` for the syntax. Every statement is checked like a
+    /// queued MULTI command before any runs; then they run in order and the
+    /// last reply is returned. An error reply stops the script, without
+    /// undoing the statements before it. Callers hold the exclusive
+    /// transaction lock.
+    fn handle_eval(&self, args: &[RespValue], session: &mut SessionState) -> RespValue {
+        let Some(RespValue::BulkString(Some(source))) = args.first() else {
+            return RespValue::Error(
+                "ERR wrong number of arguments for 'eval' command".to_string(),
+            );
+        };
+        let rest: Vec<Vec<u8>> = args[2.min(args.len())..]
+            .iter()
+            .filter_map(|arg| match arg {
+                RespValue::BulkString(Some(b)) => Some(b.clone()),
+                _ => None,
+            })
+            .collect();
+        let numkeys = match args.get(1).and_then(bulk_to_i64) {
+            Some(n) if n >= 0 && n as usize <= rest.len() => n as usize,
+            Some(n) if n >= 0 => {
+                return RespValue::Error(
+                    "ERR Number of keys can't be greater than number of args".to_string(),
+                )
+            }
+            Some(_) => return RespValue::Error("ERR Number of keys can't be negative".to_string()),
             None => {
-                info!("GET: Key '{}' not found in keymap", key);
-                return RespValue::BulkString(None);
-            } // Key not found
+                return RespValue::Error("ERR value is not an integer or out of range".to_string())
+            }
         };
+        let (keys, argv) = rest.split_at(numkeys);
 
-        match self.cache.get(row_id) {
-            Ok(data) => {
-                info!("GET: Successfully retrieved data for row_id {}", row_id);
-                RespValue::BulkString(Some(data))
+        let statements = match script::parse(source, keys, argv) {
+            Ok(statements) if !statements.is_empty() => statements,
+            Ok(_) => {
+                return RespValue::Error("ERR Error compiling script: no commands".to_string())
             }
-            Err(e) => {
-                error!("GET: Failed to retrieve data for row_id {}: {}", row_id, e);
-                RespValue::BulkString(None)
+            Err(e) => return RespValue::Error(format!("ERR Error compiling script: {}", e)),
+        };
+
+        let mut commands = Vec::with_capacity(statements.len());
+        for mut words in statements {
+            let requested = String::from_utf8_lossy(&words[0]).to_uppercase();
+            let Some(name) = self.config.read().unwrap().resolve_command(&requested) else {
+                return RespValue::Error(format!("ERR unknown command '{}' in script", requested));
+            };
+            if matches!(
+                name.as_str(),
+                "EVAL"
+                    | "MULTI"
+                    | "EXEC"
+                    | "DISCARD"
+                    | "WATCH"
+                    | "UNWATCH"
+                    | "AUTH"
+                    | "QUIT"
+                    | "SUBSCRIBE"
+                    | "UNSUBSCRIBE"
+                    | "SYNC"
+            ) {
+                return RespValue::Error(format!(
+                    "ERR '{}' is not allowed in scripts",
+                    name.to_lowercase()
+                ));
             }
+            match commands::lookup(&name) {
+                None => {
+                    return RespValue::Error(format!("ERR unknown command '{}' in script", name))
+                }
+                Some(spec) if !spec.arity_ok(words.len()) => {
+                    return RespValue::Error(format!(
+                        "ERR wrong number of arguments for '{}' command",
+                        name.to_lowercase()
+                    ))
+                }
+                Some(_) => {}
+            }
+            if !session.can_execute(&name) {
+                return RespValue::Error(format!(
+                    "NOPERM User '{}' does not have permission to execute '{}'",
+                    session.username(),
+                    name
+                ));
+            }
+            words[0] = name.as_bytes().to_vec();
+            commands.push((name, words));
         }
+
+        let mut reply = RespValue::BulkString(None);
+        for (name, words) in commands {
+            let arr: Vec<RespValue> = words
+                .into_iter()
+                .map(|word| RespValue::BulkString(Some(word)))
+                .collect();
+            reply = self.dispatch(&name, &arr, session);
+            if matches!(reply, RespValue::Error(_)) {
+                break;
+            }
+        }
+        reply
     }
 
-    fn handle_mget(&self, args: &[RespValue]) -> RespValue {
-        if args.is_empty() {
+    fn handle_discard(&self, session: &mut SessionState) -> RespValue {
+        match session.take_multi() {
+            Some(_) => {
+                self.unwatch(session);
+                RespValue::SimpleString("OK".to_string())
+            }
+            None => RespValue::Error("ERR DISCARD without MULTI".to_string()),
+        }
+    }
+
+    fn handle_watch(&self, arr: &[RespValue], session: &mut SessionState) -> RespValue {
+        if arr.len() < 2 {
             return RespValue::Error(
-                "ERR wrong number of arguments for 'mget' command".to_string(),
+                "ERR wrong number of arguments for 'watch' command".to_string(),
             );
         }
 
-        let mut results = Vec::with_capacity(args.len());
-        let key_map = self.key_map.read().unwrap();
-
-        for arg in args {
-            let key = match arg {
-                RespValue::BulkString(Some(k)) => match String::from_utf8(k.clone()) {
-                    Ok(s) => s,
-                    Err(_) => {
-                        results.push(RespValue::BulkString(None));
-                        continue;
-                    }
-                },
-                _ => {
-                    results.push(RespValue::BulkString(None));
-                    continue;
-                }
-            };
+        if session.in_multi() {
+            return RespValue::Error("ERR WATCH inside MULTI is not allowed".to_string());
+        }
 
-            // Look up row_id from key_map
-            match key_map.get(&key) {
-                Some(&row_id) => match self.cache.get(row_id) {
-                    Ok(data) => results.push(RespValue::BulkString(Some(data))),
-                    Err(_) => results.push(RespValue::BulkString(None)),
-                },
-                None => results.push(RespValue::BulkString(None)),
+        let mut versions = self.key_versions.write().unwrap();
+        for key in command_keys(arr) {
+            let version = versions.get(&key).map_or(0, |watched| watched.version);
+            if session.watch(key.clone(), version) {
+                versions
+                    .entry(key)
+                    .or_insert(WatchedKey {
+                        watchers: 0,
+                        version,
+                    })
+                    .watchers += 1;
             }
         }
 
-        RespValue::Array(Some(results))
+        RespValue::SimpleString("OK".to_string())
     }
 
-    fn handle_set(&self, args: &[RespValue]) -> RespValue {
-        info!("SET command called with {} args", args.len());
-        if args.len() < 2 {
-            return RespValue::Error("ERR wrong number of arguments for 'set' command".to_string());
-        }
-
-        let key = match &args[0] {
-            RespValue::BulkString(Some(k)) => match String::from_utf8(k.clone()) {
-                Ok(s) => s,
-                Err(_) => return RespValue::Error("ERR invalid key".to_string()),
-            },
-            _ => return RespValue::Error("ERR invalid key type".to_string()),
-        };
-
-        let value = match &args[1] {
-            RespValue::BulkString(Some(v)) => v,
-            _ => return RespValue::Error("ERR invalid value type".to_string()),
-        };
+    /// Current modification version of a watched key (0 if it was not
+    /// modified since it was first watched)
+    fn key_version(&self, key: &str) -> u64 {
+        self.key_versions
+            .read()
+            .unwrap()
+            .get(key)
+            .map_or(0, |watched| watched.version)
+    }
 
-        // Check if key already exists
-        let mut key_map = self.key_map.write().unwrap();
+    /// Forget the keys a connection watches
+    fn unwatch(&self, session: &mut SessionState) {
+        self.release_watches(&session.unwatch());
+    }
 
-        if let Some(&existing_row_id) = key_map.get(&key) {
-            // Update existing key - delete old value first
-            let _ = self.cache.delete(existing_row_id);
+    /// Drop one watcher from each of `watched`, forgetting the versions of
+    /// keys nobody watches any more
+    fn release_watches(&self, watched: &[(String, u64)]) {
+        if watched.is_empty() {
+            return;
         }
-
-        // Insert new value and map key to row_id
-        match self.cache.put(value) {
-            Ok(row_id) => {
-                key_map.insert(key, row_id);
-                drop(key_map); // Release lock before save
-                self.save_keymap(); // Persist to disk
-                RespValue::SimpleString("OK".to_string())
+        let mut versions = self.key_versions.write().unwrap();
+        for (key, _) in watched {
+            if let Some(entry) = versions.get_mut(key) {
+                entry.watchers -= 1;
+                if entry.watchers == 0 {
+                    versions.remove(key);
+                }
             }
-            Err(e) => RespValue::Error(format!("ERR {}", e)),
         }
     }
 
-    fn handle_del(&self, args: &[RespValue]) -> RespValue {
-        if args.is_empty() {
-            return RespValue::Error("ERR wrong number of arguments for 'del' command".to_string());
+    /// Record a modification of a key so that WATCHers see it as changed
+    /// and tracking connections that read it get an invalidation
+    fn touch_key(&self, key: &str) {
+        if let Some(watched) = self.key_versions.write().unwrap().get_mut(key) {
+            watched.version = self.next_version.fetch_add(1, Ordering::Relaxed);
         }
+        self.tracking.invalidate(key);
+    }
 
-        let mut deleted = 0i64;
-        let mut key_map = self.key_map.write().unwrap();
+    /// Reap the command's key arguments that are past their deadline
+    fn expire_command_keys(&self, spec: &CommandSpec, arr: &[RespValue]) {
+        if spec.first_key <= 0 || self.expires.read().unwrap().is_empty() {
+            return;
+        }
+        self.remove_expired(command_keys(arr));
+    }
 
-        for arg in args {
-            if let RespValue::BulkString(Some(k)) = arg {
-                if let Ok(key) = String::from_utf8(k.clone()) {
-                    if let Some(row_id) = key_map.remove(&key) {
-                        if self.cache.delete(row_id).is_ok() {
-                            deleted += 1;
-                        }
-                    }
-                }
-            }
+    /// Reap every key that is past its deadline, returning how many were removed
+    fn reap_expired(&self) -> usize {
+        let due = self.expires.read().unwrap().due(now_ms(), usize::MAX);
+        self.remove_expired(due)
+    }
+
+    /// Add a key about to be stored to the bloom filter, if there is one
+    fn bloom_insert(&self, key: &str) {
+        if let Some(bloom) = &self.bloom {
+            bloom.insert(key);
         }
+    }
 
-        drop(key_map); // Release lock
-        if deleted > 0 {
-            self.save_keymap(); // Persist to disk
+    /// Refill the bloom filter with exactly the stored keys, dropping deleted ones
+    fn rebuild_bloom(
+        &self,
+        key_map: &HashMap<String, u64>,
+        lists: &HashMap<String, VecDeque<u64>>,
+    ) {
+        if let Some(bloom) = &self.bloom {
+            bloom.clear();
+            for key in key_map.keys().chain(lists.keys()) {
+                bloom.insert(key);
+            }
         }
+    }
 
-        RespValue::Integer(deleted)
+    /// Whether the background expiry sweep should run (DEBUG SET-ACTIVE-EXPIRE)
+    pub fn active_expire_enabled(&self) -> bool {
+        self.active_expire.load(Ordering::Relaxed)
     }
 
-    fn handle_exists(&self, args: &[RespValue]) -> RespValue {
-        if args.is_empty() {
-            return RespValue::Error(
-                "ERR wrong number of arguments for 'exists' command".to_string(),
-            );
+    /// Background expiry sweep, safe to call from outside a command
+    ///
+    /// Reaps due keys in rounds of `ACTIVE_EXPIRE_KEYS_PER_ROUND`, each under
+    /// its own shared transaction lock, until none are left or `budget` is
+    /// spent; the rest wait for the next sweep or a command that touches
+    /// them. Returns how many keys were removed.
+    pub fn sweep_expired(&self, budget: Duration) -> usize {
+        let start = Instant::now();
+        let mut removed = 0;
+        loop {
+            let _txn = self.txn_lock.read().unwrap();
+            let due = self
+                .expires
+                .read()
+                .unwrap()
+                .due(now_ms(), ACTIVE_EXPIRE_KEYS_PER_ROUND);
+            let more = due.len() == ACTIVE_EXPIRE_KEYS_PER_ROUND;
+            removed += self.remove_expired(due);
+            if !more || start.elapsed() >= budget {
+                return removed;
+            }
         }
+    }
 
-        let mut count = 0i64;
-        let key_map = self.key_map.read().unwrap();
+    /// Delete the given keys if they are (still) past their deadline
+    fn remove_expired(&self, keys: Vec<String>) -> usize {
+        if keys.is_empty() {
+            return 0;
+        }
 
-        for arg in args {
-            if let RespValue::BulkString(Some(k)) = arg {
-                if let Ok(key) = String::from_utf8(k.clone()) {
-                    if key_map.contains_key(&key) {
-                        count += 1;
+        let now = now_ms();
+        let mut removed = Vec::new();
+        let mut lists_removed = false;
+        {
+            let mut key_map = self.key_map.write().unwrap();
+            let mut lists = self.lists.write().unwrap();
+            let mut expires = self.expires.write().unwrap();
+
+            for key in keys {
+                // Re-check under the write lock, another caller may have reaped or renewed it
+                if !matches!(expires.get(&key), Some(&deadline) if deadline <= now) {
+                    continue;
+                }
+                expires.remove(&key);
+                if let Some(row_id) = key_map.remove(&key) {
+                    let _ = self.cache.delete(row_id);
+                } else if let Some(rows) = lists.remove(&key) {
+                    for row_id in rows {
+                        let _ = self.cache.delete(row_id);
                     }
+                    lists_removed = true;
+                } else {
+                    continue;
                 }
+                self.touch_key(&key);
+                removed.push(key);
             }
         }
 
-        RespValue::Integer(count)
+        if removed.is_empty() {
+            return 0;
+        }
+
+        info!("Expired {} keys", removed.len());
+        self.save_keymap();
+        if lists_removed {
+            self.save_lists();
+        }
+        for key in &removed {
+            self.notify_keyevent("expired", key);
+        }
+
+        removed.len()
     }
 
-    fn handle_keys(&self, args: &[RespValue]) -> RespValue {
-        let pattern = if args.is_empty() {
-            "*".to_string()
-        } else {
-            match &args[0] {
-                RespValue::BulkString(Some(p)) => match String::from_utf8(p.clone()) {
-                    Ok(s) => s,
-                    Err(_) => return RespValue::Error("ERR invalid pattern".to_string()),
-                },
-                _ => return RespValue::Error("ERR invalid pattern type".to_string()),
-            }
+    /// Publish a keyspace event if notifications are enabled
+    fn notify_keyevent(&self, event: &str, key: &str) {
+        if self.config.read().unwrap().notify_keyspace_events {
+            self.pubsub
+                .publish(&format!("__keyevent@0__:{}", event), &keys::to_bytes(key));
+        }
+    }
+
+    fn handle_expire(&self, args: &[RespValue], unit_ms: i64, name: &str) -> RespValue {
+        if args.len() != 2 {
+            return RespValue::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name
+            ));
+        }
+
+        let key = match key_arg(&args[0]) {
+            Ok(key) => key,
+            Err(e) => return e,
         };
 
-        let key_map = self.key_map.read().unwrap();
-        let mut matching_keys = Vec::new();
+        let Some(amount) = bulk_to_i64(&args[1]) else {
+            return RespValue::Error("ERR value is not an integer or out of range".to_string());
+        };
 
-        for key in key_map.keys() {
-            if matches_pattern(key, &pattern) {
-                matching_keys.push(RespValue::BulkString(Some(key.as_bytes().to_vec())));
+        let deadline = match amount
+            .checked_mul(unit_ms)
+            .and_then(|ms| ms.checked_add(now_ms() as i64))
+        {
+            Some(deadline) => deadline.max(0) as u64,
+            None => {
+                return RespValue::Error(format!("ERR invalid expire time in '{}' command", name))
+            }
+        };
+
+        {
+            let key_map = self.key_map.read().unwrap();
+            let lists = self.lists.read().unwrap();
+            if !key_map.contains_key(&key) && !lists.contains_key(&key) {
+                return RespValue::Integer(0);
             }
+            self.expires.write().unwrap().insert(key.clone(), deadline);
         }
 
-        RespValue::Array(Some(matching_keys))
-    }
+        self.touch_key(&key);
+        self.save_ttl(&key);
 
-    fn handle_dbsize(&self) -> RespValue {
-        let key_map = self.key_map.read().unwrap();
-        RespValue::Integer(key_map.len() as i64)
-    }
+        // A deadline already in the past removes the key right away
+        self.remove_expired(vec![key]);
 
-    fn handle_flushdb(&self) -> RespValue {
-        let mut key_map = self.key_map.write().unwrap();
-        key_map.clear();
-        drop(key_map); // Release lock
-        self.cache.clear_cache();
-        self.save_keymap(); // Persist empty keymap
-        RespValue::SimpleString("OK".to_string())
+        RespValue::Integer(1)
     }
 
-    fn handle_info(&self, _args: &[RespValue]) -> RespValue {
-        let stats = self.cache.stats();
-        let key_map = self.key_map.read().unwrap();
-        let info = format!(
-            "# Server\r\n\
-             toonstore_version:0.1.0\r\n\
-             \r\n\
-             # Stats\r\n\
-             total_keys:{}\r\n\
-             cache_size:{}\r\n\
-             cache_capacity:{}\r\n\
-             cache_hits:{}\r\n\
-             cache_misses:{}\r\n\
-             cache_hit_ratio:{:.2}\r\n",
-            key_map.len(),
-            self.cache.cache_len(),
-            self.cache.capacity(),
-            stats.hits(),
-            stats.misses(),
-            stats.hit_ratio(),
-        );
-        RespValue::BulkString(Some(info.into_bytes()))
-    }
+    fn handle_ttl(&self, args: &[RespValue], millis: bool) -> RespValue {
+        if args.len() != 1 {
+            return RespValue::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                if millis { "pttl" } else { "ttl" }
+            ));
+        }
+
+        let key = match key_arg(&args[0]) {
+            Ok(key) => key,
+            Err(e) => return e,
+        };
+
+        if !self.key_map.read().unwrap().contains_key(&key)
+            && !self.lists.read().unwrap().contains_key(&key)
+        {
+            return RespValue::Integer(-2);
+        }
 
-    fn handle_command(&self, _args: &[RespValue]) -> RespValue {
-        // Return empty array for COMMAND (redis-cli compatibility)
-        RespValue::Array(Some(vec![]))
+        match self.expires.read().unwrap().get(&key) {
+            Some(&deadline) => {
+                let remaining = deadline.saturating_sub(now_ms()) as i64;
+                if millis {
+                    RespValue::Integer(remaining)
+                } else {
+                    RespValue::Integer((remaining + 500) / 1000)
+                }
+            }
+            None => RespValue::Integer(-1),
+        }
     }
 
-    fn handle_auth(&self, args: &[RespValue], session: &mut SessionState) -> RespValue {
-        // Support both AUTH password and AUTH username password
-        if args.is_empty() || args.len() > 2 {
+    fn handle_persist(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 1 {
             return RespValue::Error(
-                "ERR wrong number of arguments for 'auth' command".to_string(),
+                "ERR wrong number of arguments for 'persist' command".to_string(),
             );
         }
 
-        // If user manager is enabled, use multi-user authentication
-        if let Some(user_manager) = &self.user_manager {
-            let (username, password) = if args.len() == 2 {
-                // AUTH username password
-                let username = match &args[0] {
-                    RespValue::BulkString(Some(u)) => match String::from_utf8(u.clone()) {
-                        Ok(s) => s,
-                        Err(_) => return RespValue::Error("ERR invalid username".to_string()),
-                    },
-                    _ => return RespValue::Error("ERR invalid username type".to_string()),
-                };
+        let key = match key_arg(&args[0]) {
+            Ok(key) => key,
+            Err(e) => return e,
+        };
 
-                let password = match &args[1] {
-                    RespValue::BulkString(Some(p)) => match String::from_utf8(p.clone()) {
-                        Ok(s) => s,
-                        Err(_) => return RespValue::Error("ERR invalid password".to_string()),
-                    },
-                    _ => return RespValue::Error("ERR invalid password type".to_string()),
-                };
+        if self.expires.write().unwrap().remove(&key).is_none() {
+            return RespValue::Integer(0);
+        }
 
-                (username, password)
-            } else {
-                // AUTH password (use 'admin' as default user)
-                let password = match &args[0] {
-                    RespValue::BulkString(Some(p)) => match String::from_utf8(p.clone()) {
-                        Ok(s) => s,
-                        Err(_) => return RespValue::Error("ERR invalid password".to_string()),
-                    },
-                    _ => return RespValue::Error("ERR invalid password type".to_string()),
-                };
+        self.touch_key(&key);
+        self.save_ttl(&key);
+        RespValue::Integer(1)
+    }
 
-                ("admin".to_string(), password)
-            };
+    fn handle_subscribe(&self, args: &[RespValue], session: &mut SessionState) -> RespValue {
+        if args.is_empty() {
+            return RespValue::Error(
+                "ERR wrong number of arguments for 'subscribe' command".to_string(),
+            );
+        }
 
-            // Authenticate with user manager
-            if let Some(user) = user_manager.authenticate(&username, &password) {
-                session.authenticate(user.username.clone(), user.role);
-                info!("User '{}' authenticated successfully", username);
-                RespValue::SimpleString("OK".to_string())
-            } else {
-                warn!("Failed authentication attempt for user '{}'", username);
-                RespValue::Error("WRONGPASS invalid username-password pair".to_string())
-            }
-        } else {
-            // Fallback to simple password authentication
-            if args.len() != 1 {
+        let subscriber = match &session.subscriber {
+            Some(subscriber) => subscriber.clone(),
+            None => {
                 return RespValue::Error(
-                    "ERR wrong number of arguments for 'auth' command".to_string(),
-                );
+                    "ERR pub/sub is not available on this connection".to_string(),
+                )
             }
+        };
 
-            let password = match &args[0] {
-                RespValue::BulkString(Some(p)) => match String::from_utf8(p.clone()) {
-                    Ok(s) => s,
-                    Err(_) => return RespValue::Error("ERR invalid password".to_string()),
-                },
-                _ => return RespValue::Error("ERR invalid password type".to_string()),
+        let mut replies = Vec::with_capacity(args.len());
+        for arg in args {
+            let channel = match arg {
+                RespValue::BulkString(Some(c)) => String::from_utf8_lossy(c).to_string(),
+                _ => return RespValue::Error("ERR invalid channel".to_string()),
             };
 
-            if !self.auth_config.is_required() {
-                return RespValue::Error(
-                    "ERR Client sent AUTH, but no password is set".to_string(),
-                );
+            self.pubsub.subscribe(&channel, &subscriber);
+            if !session.channels.contains(&channel) {
+                session.channels.push(channel.clone());
             }
+            replies.push(subscription_reply(
+                "subscribe",
+                Some(&channel),
+                session.channels.len(),
+            ));
+        }
 
-            if self.auth_config.verify(&password) {
-                session.authenticate("default".to_string(), UserRole::Admin);
-                RespValue::SimpleString("OK".to_string())
-            } else {
-                RespValue::Error("WRONGPASS invalid username-password pair".to_string())
+        split_replies(replies, session)
+    }
+
+    fn handle_unsubscribe(&self, args: &[RespValue], session: &mut SessionState) -> RespValue {
+        // No arguments means every subscribed channel
+        let channels: Vec<String> = if args.is_empty() {
+            session.channels.clone()
+        } else {
+            args.iter()
+                .filter_map(|arg| match arg {
+                    RespValue::BulkString(Some(c)) => Some(String::from_utf8_lossy(c).to_string()),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        if channels.is_empty() {
+            return subscription_reply("unsubscribe", None, 0);
+        }
+
+        let mut replies = Vec::with_capacity(channels.len());
+        for channel in channels {
+            if let Some(subscriber) = &session.subscriber {
+                self.pubsub.unsubscribe(&channel, subscriber.id);
             }
+            session.channels.retain(|c| *c != channel);
+            replies.push(subscription_reply(
+                "unsubscribe",
+                Some(&channel),
+                session.channels.len(),
+            ));
         }
+
+        split_replies(replies, session)
     }
 
-    fn handle_user(&self, args: &[RespValue], session: &SessionState) -> RespValue {
-        let user_manager = match &self.user_manager {
-            Some(mgr) => mgr,
-            None => return RespValue::Error("ERR user management not enabled".to_string()),
+    fn handle_publish(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 2 {
+            return RespValue::Error(
+                "ERR wrong number of arguments for 'publish' command".to_string(),
+            );
+        }
+
+        let channel = match &args[0] {
+            RespValue::BulkString(Some(c)) => String::from_utf8_lossy(c).to_string(),
+            _ => return RespValue::Error("ERR invalid channel".to_string()),
+        };
+
+        let message = match &args[1] {
+            RespValue::BulkString(Some(m)) => m,
+            _ => return RespValue::Error("ERR invalid message".to_string()),
         };
 
+        RespValue::Integer(self.pubsub.publish(&channel, message) as i64)
+    }
+
+    fn handle_ping(&self, args: &[RespValue], session: &SessionState) -> RespValue {
+        if args.len() > 1 {
+            return RespValue::Error(
+                "ERR wrong number of arguments for 'ping' command".to_string(),
+            );
+        }
+
+        // Subscribed connections get the message form so clients can tell it from a publish
+        if session.in_subscribe_mode() {
+            return RespValue::Array(Some(vec![
+                RespValue::BulkString(Some(b"pong".to_vec())),
+                args.first()
+                    .cloned()
+                    .unwrap_or(RespValue::BulkString(Some(Vec::new()))),
+            ]));
+        }
+
         if args.is_empty() {
+            RespValue::SimpleString("PONG".to_string())
+        } else if args.len() == 1 {
+            // PING with message
+            args[0].clone()
+        } else {
+            RespValue::Error("ERR wrong number of arguments for 'ping' command".to_string())
+        }
+    }
+
+    fn handle_wait(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 2 {
             return RespValue::Error(
-                "ERR wrong number of arguments for 'user' command".to_string(),
+                "ERR wrong number of arguments for 'wait' command".to_string(),
             );
         }
 
-        let subcommand = match &args[0] {
-            RespValue::BulkString(Some(cmd)) => String::from_utf8_lossy(cmd).to_uppercase(),
-            _ => return RespValue::Error("ERR invalid subcommand".to_string()),
+        match (bulk_to_i64(&args[0]), bulk_to_i64(&args[1])) {
+            (Some(replicas), Some(timeout)) if replicas >= 0 && timeout >= 0 => {
+                // Replicas never acknowledge the writes they are sent, so none count
+                RespValue::Integer(0)
+            }
+            (Some(_), Some(_)) => RespValue::Error("ERR timeout is negative".to_string()),
+            _ => RespValue::Error("ERR value is not an integer or out of range".to_string()),
+        }
+    }
+
+    /// SYNC: attach this connection as a replica
+    ///
+    /// The reply is a snapshot of the dataset as the commands that rebuild
+    /// it on an empty store: FLUSHDB, a SET, HSET or RPUSH per key, and a
+    /// PEXPIRE per key with a TTL. From then on the connection is sent each
+    /// applied write as a command array. Callers hold the exclusive
+    /// transaction lock, so no write is missing from both.
+    fn handle_sync(&self, args: &[RespValue], session: &mut SessionState) -> RespValue {
+        if !args.is_empty() {
+            return RespValue::Error(
+                "ERR wrong number of arguments for 'sync' command".to_string(),
+            );
+        }
+        let Some(feed) = session.subscriber.clone() else {
+            return RespValue::Error(
+                "ERR replication is not available on this connection".to_string(),
+            );
         };
 
-        match subcommand.as_str() {
-            "CREATE" => {
-                // USER CREATE username password [role]
-                if args.len() < 3 {
-                    return RespValue::Error(
-                        "ERR USER CREATE requires username and password".to_string(),
-                    );
-                }
+        self.reap_expired();
+        let snapshot = match self.snapshot_commands() {
+            Ok(snapshot) => snapshot,
+            Err(e) => return e,
+        };
+        self.replication.attach(&feed);
+        info!(
+            "SYNC: sent {} snapshot commands, {} replicas attached",
+            snapshot.len(),
+            self.replication.replica_count()
+        );
 
-                let username = match &args[1] {
-                    RespValue::BulkString(Some(u)) => String::from_utf8_lossy(u).to_string(),
-                    _ => return RespValue::Error("ERR invalid username".to_string()),
-                };
+        RespValue::Array(Some(snapshot))
+    }
 
-                let password = match &args[2] {
-                    RespValue::BulkString(Some(p)) => String::from_utf8_lossy(p).to_string(),
-                    _ => return RespValue::Error("ERR invalid password".to_string()),
-                };
+    /// Commands that rebuild the current dataset on an empty store, see `handle_sync`
+    fn snapshot_commands(&self) -> Result<Vec<RespValue>, RespValue> {
+        fn command(words: Vec<Vec<u8>>) -> RespValue {
+            RespValue::Array(Some(
+                words
+                    .into_iter()
+                    .map(|word| RespValue::BulkString(Some(word)))
+                    .collect(),
+            ))
+        }
 
-                let role = if args.len() > 3 {
-                    match &args[3] {
-                        RespValue::BulkString(Some(r)) => {
-                            let role_str = String::from_utf8_lossy(r).to_uppercase();
-                            match role_str.as_str() {
-                                "ADMIN" => UserRole::Admin,
-                                "READWRITE" => UserRole::ReadWrite,
-                                "READONLY" => UserRole::ReadOnly,
-                                _ => return RespValue::Error("ERR invalid role".to_string()),
-                            }
-                        }
-                        _ => return RespValue::Error("ERR invalid role type".to_string()),
-                    }
-                } else {
-                    UserRole::ReadWrite // Default role
-                };
+        let key_map = self.key_map.read().unwrap();
+        let lists = self.lists.read().unwrap();
+        let expires = self.expires.read().unwrap();
 
-                match user_manager.create_user(&username, &password, role) {
-                    Ok(_) => RespValue::SimpleString("OK".to_string()),
-                    Err(e) => RespValue::Error(format!("ERR {}", e)),
+        let mut commands = vec![command(vec![b"FLUSHDB".to_vec()])];
+        for (key, &row_id) in key_map.iter() {
+            let words = if self.cache.row_tag(row_id).ok() == Some(HASH_TAG) {
+                let record = self.load_hash(row_id)?;
+                let mut words = vec![b"HSET".to_vec(), keys::to_bytes(key).into_owned()];
+                for (field, value) in record.fields.into_iter().zip(record.values) {
+                    words.push(field.into_bytes());
+                    words.push(value);
                 }
-            }
-            "DELETE" => {
-                // USER DELETE username
-                if args.len() != 2 {
-                    return RespValue::Error("ERR USER DELETE requires username".to_string());
+                words
+            } else {
+                match self.cache.get(row_id) {
+                    Ok(value) => vec![b"SET".to_vec(), keys::to_bytes(key).into_owned(), value],
+                    // A key whose row is gone reads as missing, so it has nothing to copy
+                    Err(toonstoredb::Error::NotFound) => continue,
+                    Err(e) => return Err(RespValue::Error(format!("ERR {}", e))),
+                }
+            };
+            commands.push(command(words));
+        }
+        for (key, rows) in lists.iter() {
+            let mut words = vec![b"RPUSH".to_vec(), keys::to_bytes(key).into_owned()];
+            for &row_id in rows {
+                match self.cache.get(row_id) {
+                    Ok(value) => words.push(value),
+                    Err(e) => return Err(RespValue::Error(format!("ERR {}", e))),
                 }
+            }
+            commands.push(command(words));
+        }
 
-                let username = match &args[1] {
-                    RespValue::BulkString(Some(u)) => String::from_utf8_lossy(u).to_string(),
-                    _ => return RespValue::Error("ERR invalid username".to_string()),
-                };
+        let now = now_ms();
+        for (key, &deadline) in expires.iter() {
+            if key_map.contains_key(key) || lists.contains_key(key) {
+                let remaining = deadline.saturating_sub(now).max(1);
+                commands.push(command(vec![
+                    b"PEXPIRE".to_vec(),
+                    keys::to_bytes(key).into_owned(),
+                    remaining.to_string().into_bytes(),
+                ]));
+            }
+        }
 
-                match user_manager.delete_user(&username) {
-                    Ok(_) => RespValue::SimpleString("OK".to_string()),
-                    Err(e) => RespValue::Error(format!("ERR {}", e)),
+        Ok(commands)
+    }
+
+    fn handle_time(&self) -> RespValue {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(now.as_secs().to_string().into_bytes())),
+            RespValue::BulkString(Some(now.subsec_micros().to_string().into_bytes())),
+        ]))
+    }
+
+    fn handle_echo(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 1 {
+            return RespValue::Error(
+                "ERR wrong number of arguments for 'echo' command".to_string(),
+            );
+        }
+        args[0].clone()
+    }
+
+    fn handle_get(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 1 {
+            return RespValue::Error("ERR wrong number of arguments for 'get' command".to_string());
+        }
+
+        let key = match key_arg(&args[0]) {
+            Ok(key) => key,
+            Err(e) => return e,
+        };
+
+        // Look up row_id from key_map, unless the bloom filter rules the key out
+        let key_map = self.key_map.read().unwrap();
+        if self
+            .bloom
+            .as_ref()
+            .is_some_and(|bloom| !bloom.may_contain(&key))
+        {
+            return RespValue::BulkString(None);
+        }
+        info!(
+            "GET: Looking for key '{}', keymap has {} keys",
+            key,
+            key_map.len()
+        );
+        let row_id = match key_map.get(&key) {
+            Some(id) => {
+                info!("GET: Found key '{}' -> row_id {}", key, id);
+                *id
+            }
+            None => {
+                info!("GET: Key '{}' not found in keymap", key);
+                if self.lists.read().unwrap().contains_key(&key) {
+                    return wrong_type();
                 }
+                return RespValue::BulkString(None);
+            } // Key not found
+        };
+        if !self.is_string_row(row_id) {
+            return wrong_type();
+        }
+
+        match self.cache.get(row_id) {
+            Ok(data) => {
+                info!("GET: Successfully retrieved data for row_id {}", row_id);
+                RespValue::BulkString(Some(data))
             }
-            "LIST" => {
-                // USER LIST
-                let users = user_manager.list_users();
-                let result: Vec<RespValue> = users
-                    .iter()
-                    .map(|u| RespValue::BulkString(Some(u.as_bytes().to_vec())))
-                    .collect();
-                RespValue::Array(Some(result))
+            Err(e) => {
+                error!("GET: Failed to retrieve data for row_id {}: {}", row_id, e);
+                RespValue::BulkString(None)
             }
-            "SETPASS" => {
-                // USER SETPASS username newpassword
-                if args.len() != 3 {
+        }
+    }
+
+    /// GETEX key [EX seconds | PX milliseconds | PERSIST]
+    ///
+    /// The value is read and the TTL changed under the same key map lock,
+    /// so the key cannot be deleted or replaced in between. Without an
+    /// option this is GET, leaving the TTL as it was.
+    fn handle_getex(&self, args: &[RespValue]) -> RespValue {
+        if args.is_empty() {
+            return RespValue::Error(
+                "ERR wrong number of arguments for 'getex' command".to_string(),
+            );
+        }
+        let key = match key_arg(&args[0]) {
+            Ok(key) => key,
+            Err(e) => return e,
+        };
+
+        // None = leave the TTL alone, Some(None) = PERSIST, Some(Some(deadline)) = expire then
+        let change = match &args[1..] {
+            [] => None,
+            [RespValue::BulkString(Some(option))] if option.eq_ignore_ascii_case(b"PERSIST") => {
+                Some(None)
+            }
+            [RespValue::BulkString(Some(option)), amount] => {
+                let unit_ms = if option.eq_ignore_ascii_case(b"EX") {
+                    1000
+                } else if option.eq_ignore_ascii_case(b"PX") {
+                    1
+                } else {
+                    return RespValue::Error("ERR syntax error".to_string());
+                };
+                let Some(amount) = bulk_to_i64(amount) else {
                     return RespValue::Error(
-                        "ERR USER SETPASS requires username and new password".to_string(),
+                        "ERR value is not an integer or out of range".to_string(),
                     );
+                };
+                match amount
+                    .checked_mul(unit_ms)
+                    .filter(|&ms| ms > 0)
+                    .and_then(|ms| ms.checked_add(now_ms() as i64))
+                {
+                    Some(deadline) => Some(Some(deadline as u64)),
+                    None => {
+                        return RespValue::Error(
+                            "ERR invalid expire time in 'getex' command".to_string(),
+                        )
+                    }
                 }
+            }
+            _ => return RespValue::Error("ERR syntax error".to_string()),
+        };
 
-                let username = match &args[1] {
-                    RespValue::BulkString(Some(u)) => String::from_utf8_lossy(u).to_string(),
-                    _ => return RespValue::Error("ERR invalid username".to_string()),
-                };
+        let data = {
+            let key_map = self.key_map.read().unwrap();
+            let Some(&row_id) = key_map.get(&key) else {
+                if self.lists.read().unwrap().contains_key(&key) {
+                    return wrong_type();
+                }
+                return RespValue::BulkString(None);
+            };
+            if !self.is_string_row(row_id) {
+                return wrong_type();
+            }
+            let data = match self.cache.get(row_id) {
+                Ok(data) => data,
+                Err(e) => return RespValue::Error(format!("ERR {}", e)),
+            };
 
-                let new_password = match &args[2] {
-                    RespValue::BulkString(Some(p)) => String::from_utf8_lossy(p).to_string(),
-                    _ => return RespValue::Error("ERR invalid password".to_string()),
-                };
+            let mut expires = self.expires.write().unwrap();
+            let changed = match change {
+                None => false,
+                Some(None) => expires.remove(&key).is_some(),
+                Some(Some(deadline)) => {
+                    expires.insert(key.clone(), deadline);
+                    true
+                }
+            };
+            if !changed {
+                return RespValue::BulkString(Some(data));
+            }
+            data
+        };
 
-                match user_manager.change_password(&username, &new_password) {
-                    Ok(_) => RespValue::SimpleString("OK".to_string()),
-                    Err(e) => RespValue::Error(format!("ERR {}", e)),
+        self.touch_key(&key);
+        self.save_ttl(&key);
+        RespValue::BulkString(Some(data))
+    }
+
+    fn handle_mget(&self, args: &[RespValue]) -> RespValue {
+        if args.is_empty() {
+            return RespValue::Error(
+                "ERR wrong number of arguments for 'mget' command".to_string(),
+            );
+        }
+
+        let key_map = self.key_map.read().unwrap();
+
+        // Row of each key holding a string, then all of them read in one batch
+        let rows: Vec<Option<u64>> = args
+            .iter()
+            .map(|arg| match arg {
+                RespValue::BulkString(Some(k)) => key_map
+                    .get(&keys::from_bytes(k))
+                    .copied()
+                    .filter(|&row_id| self.is_string_row(row_id)),
+                _ => None,
+            })
+            .collect();
+        let row_ids: Vec<u64> = rows.iter().flatten().copied().collect();
+        let mut values = self.cache.get_batch(&row_ids).into_iter();
+
+        let results = rows
+            .iter()
+            .map(|row| match row.and_then(|_| values.next()) {
+                Some(Ok(data)) => RespValue::BulkString(Some(data)),
+                _ => RespValue::BulkString(None),
+            })
+            .collect();
+        RespValue::Array(Some(results))
+    }
+
+    fn handle_set(&self, args: &[RespValue], session: &SessionState) -> RespValue {
+        let max_value_size = session.value_limit();
+        info!("SET command called with {} args", args.len());
+        if args.len() < 2 {
+            return RespValue::Error("ERR wrong number of arguments for 'set' command".to_string());
+        }
+
+        let key = match key_arg(&args[0]) {
+            Ok(key) => key,
+            Err(e) => return e,
+        };
+
+        let value = match &args[1] {
+            RespValue::BulkString(Some(v)) => v,
+            _ => return RespValue::Error("ERR invalid value type".to_string()),
+        };
+
+        // Other options are accepted and ignored, as before
+        let mut token = None;
+        let mut options = args[2..].iter();
+        while let Some(option) = options.next() {
+            if matches!(option, RespValue::BulkString(Some(name)) if name.eq_ignore_ascii_case(b"IDEMPOTENT"))
+            {
+                match options.next() {
+                    Some(RespValue::BulkString(Some(t))) => token = Some(t.clone()),
+                    _ => return RespValue::Error("ERR syntax error".to_string()),
                 }
             }
-            "WHOAMI" => {
-                // USER WHOAMI
-                RespValue::BulkString(Some(session.username().as_bytes().to_vec()))
+        }
+
+        let Some(token) = token else {
+            return self.set_string(key, value, max_value_size);
+        };
+
+        // A retry racing the original waits for its reply instead of writing
+        // again; the lock itself is only held to look the token up. Failed
+        // writes are not remembered and may be retried with the same token.
+        let id = (session.username().to_string(), key.clone(), token);
+        let mut seen = self.idempotency.lock().unwrap();
+        loop {
+            match seen.get(&id) {
+                Some((at, Some(reply))) if at.elapsed() < IDEMPOTENCY_TTL => return reply.clone(),
+                Some((_, None)) => seen = self.idempotency_done.wait(seen).unwrap(),
+                _ => break,
             }
-            _ => RespValue::Error(format!("ERR unknown USER subcommand '{}'", subcommand)),
         }
+        seen.put(id.clone(), (Instant::now(), None));
+        drop(seen);
+
+        let in_flight = InFlight {
+            handler: self,
+            id: Some(id),
+        };
+        let reply = self.set_string(key, value, max_value_size);
+        in_flight.finish(&reply);
+        reply
     }
 
-    fn handle_save(&self, _args: &[RespValue]) -> RespValue {
-        match self.backup_config.create_backup(Some("manual")) {
-            Ok(path) => {
-                info!("Manual backup created: {:?}", path);
-                RespValue::SimpleString("OK".to_string())
-            }
-            Err(e) => {
-                error!("Failed to create backup: {}", e);
-                RespValue::Error(format!("ERR Failed to create backup: {}", e))
+    /// Store `value` as the string at `key`, replacing whatever it held
+    fn set_string(&self, key: String, value: &[u8], max_value_size: usize) -> RespValue {
+        if self
+            .config
+            .read()
+            .unwrap()
+            .is_over_maxmemory(self.cache.db_size())
+        {
+            return oom();
+        }
+
+        // Check if key already exists
+        let mut key_map = self.key_map.write().unwrap();
+
+        let existing = key_map.get(&key).copied();
+        let mut lists = self.lists.write().unwrap();
+
+        // Insert new value and map key to row_id
+        let row_id = match self.put_value(existing, value, STRING_TAG, max_value_size) {
+            Ok(row_id) => row_id,
+            // The old value, TTL and list are left as they were
+            Err(e) => return write_error(e),
+        };
+
+        // A plain SET discards any previous TTL, and replaces a list
+        self.expires.write().unwrap().remove(&key);
+        let old_list = lists.remove(&key);
+        drop(lists);
+        if let Some(rows) = &old_list {
+            for &row_id in rows {
+                let _ = self.cache.delete(row_id);
             }
         }
+
+        self.touch_key(&key);
+        self.bloom_insert(&key);
+        key_map.insert(key, row_id);
+        drop(key_map); // Release lock before save
+        self.save_keymap(); // Persist to disk
+        if old_list.is_some() {
+            self.save_lists();
+        }
+        RespValue::SimpleString("OK".to_string())
     }
 
-    fn handle_backup(&self, args: &[RespValue]) -> RespValue {
-        let backup_name = if args.is_empty() {
-            "backup"
+    fn handle_getrange(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 3 {
+            return RespValue::Error(
+                "ERR wrong number of arguments for 'getrange' command".to_string(),
+            );
+        }
+
+        let key = match key_arg(&args[0]) {
+            Ok(key) => key,
+            Err(e) => return e,
+        };
+
+        let (Some(start), Some(end)) = (bulk_to_i64(&args[1]), bulk_to_i64(&args[2])) else {
+            return RespValue::Error("ERR value is not an integer or out of range".to_string());
+        };
+
+        let key_map = self.key_map.read().unwrap();
+        let Some(&row_id) = key_map.get(&key) else {
+            if self.lists.read().unwrap().contains_key(&key) {
+                return wrong_type();
+            }
+            return RespValue::BulkString(Some(Vec::new()));
+        };
+        if !self.is_string_row(row_id) {
+            return wrong_type();
+        }
+
+        // Non-negative bounds read just the range; negative ones need the length
+        let result = if start >= 0 && end >= 0 {
+            if start > end {
+                return RespValue::BulkString(Some(Vec::new()));
+            }
+            self.cache
+                .get_range(row_id, start as usize, (end - start) as usize + 1)
         } else {
-            match &args[0] {
-                RespValue::BulkString(Some(n)) => match std::str::from_utf8(n) {
-                    Ok(s) => s,
-                    Err(_) => return RespValue::Error("ERR invalid backup name".to_string()),
-                },
-                _ => return RespValue::Error("ERR invalid backup name type".to_string()),
+            self.cache.get(row_id).map(|value| {
+                let len = value.len() as i64;
+                let start = if start < 0 {
+                    (len + start).max(0)
+                } else {
+                    start
+                };
+                let end = if end < 0 { len + end } else { end.min(len - 1) };
+                if start > end || start >= len {
+                    Vec::new()
+                } else {
+                    value[start as usize..=end as usize].to_vec()
+                }
+            })
+        };
+
+        match result {
+            Ok(range) => RespValue::BulkString(Some(range)),
+            Err(e) => RespValue::Error(format!("ERR {}", e)),
+        }
+    }
+
+    fn handle_setrange(&self, args: &[RespValue], max_value_size: usize) -> RespValue {
+        if args.len() != 3 {
+            return RespValue::Error(
+                "ERR wrong number of arguments for 'setrange' command".to_string(),
+            );
+        }
+
+        let key = match key_arg(&args[0]) {
+            Ok(key) => key,
+            Err(e) => return e,
+        };
+
+        let offset = match bulk_to_i64(&args[1]) {
+            Some(offset) if offset >= 0 => offset as usize,
+            Some(_) => return RespValue::Error("ERR offset is out of range".to_string()),
+            None => {
+                return RespValue::Error("ERR value is not an integer or out of range".to_string())
+            }
+        };
+
+        let patch = match &args[2] {
+            RespValue::BulkString(Some(v)) => v,
+            _ => return RespValue::Error("ERR invalid value type".to_string()),
+        };
+
+        if offset.saturating_add(patch.len()) > max_value_size {
+            return RespValue::Error("ERR string exceeds maximum allowed size".to_string());
+        }
+
+        let mut key_map = self.key_map.write().unwrap();
+        if self.lists.read().unwrap().contains_key(&key) {
+            return wrong_type();
+        }
+
+        let existing = key_map.get(&key).copied();
+        let mut value = match existing {
+            Some(row_id) if !self.is_string_row(row_id) => return wrong_type(),
+            Some(row_id) => match self.cache.get(row_id) {
+                Ok(value) => value,
+                Err(e) => return RespValue::Error(format!("ERR {}", e)),
+            },
+            None => Vec::new(),
+        };
+
+        // Redis leaves a missing key alone for an empty patch
+        if patch.is_empty() {
+            return RespValue::Integer(value.len() as i64);
+        }
+
+        if self
+            .config
+            .read()
+            .unwrap()
+            .is_over_maxmemory(self.cache.db_size())
+        {
+            return oom();
+        }
+
+        if value.len() < offset + patch.len() {
+            value.resize(offset + patch.len(), 0);
+        }
+        value[offset..offset + patch.len()].copy_from_slice(patch);
+
+        // The patched value is a new row; the TTL stays with the key
+        match self.put_value(existing, &value, STRING_TAG, max_value_size) {
+            Ok(row_id) => {
+                self.touch_key(&key);
+                self.bloom_insert(&key);
+                key_map.insert(key, row_id);
+                drop(key_map); // Release lock before save
+                self.save_keymap();
+                RespValue::Integer(value.len() as i64)
             }
+            Err(e) => write_error(e),
+        }
+    }
+
+    fn handle_strlen(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 1 {
+            return RespValue::Error(
+                "ERR wrong number of arguments for 'strlen' command".to_string(),
+            );
+        }
+
+        let key = match key_arg(&args[0]) {
+            Ok(key) => key,
+            Err(e) => return e,
         };
 
-        match self.backup_config.create_backup(Some(backup_name)) {
-            Ok(path) => {
-                info!("Named backup created: {:?}", path);
-                let filename = path
-                    .file_name()
-                    .and_then(|n| n.to_str())
-                    .unwrap_or("unknown");
-                RespValue::BulkString(Some(filename.as_bytes().to_vec()))
-            }
-            Err(e) => {
-                error!("Failed to create backup: {}", e);
-                RespValue::Error(format!("ERR Failed to create backup: {}", e))
-            }
-        }
+        let key_map = self.key_map.read().unwrap();
+        let Some(&row_id) = key_map.get(&key) else {
+            if self.lists.read().unwrap().contains_key(&key) {
+                return wrong_type();
+            }
+            return RespValue::Integer(0);
+        };
+        if !self.is_string_row(row_id) {
+            return wrong_type();
+        }
+
+        // A row deleted under the key counts as missing
+        RespValue::Integer(self.cache.value_len(row_id).unwrap_or(0) as i64)
+    }
+
+    fn handle_del(&self, args: &[RespValue]) -> RespValue {
+        if args.is_empty() {
+            return RespValue::Error("ERR wrong number of arguments for 'del' command".to_string());
+        }
+
+        let mut deleted = 0i64;
+        let mut lists_deleted = false;
+        let mut key_map = self.key_map.write().unwrap();
+        let mut lists = self.lists.write().unwrap();
+
+        for arg in args {
+            if let RespValue::BulkString(Some(k)) = arg {
+                let key = keys::from_bytes(k);
+                if let Some(row_id) = key_map.remove(&key) {
+                    // The key is gone even if its row already was
+                    self.touch_key(&key);
+                    self.expires.write().unwrap().remove(&key);
+                    let _ = self.cache.delete(row_id);
+                    deleted += 1;
+                } else if let Some(rows) = lists.remove(&key) {
+                    self.touch_key(&key);
+                    self.expires.write().unwrap().remove(&key);
+                    for row_id in rows {
+                        let _ = self.cache.delete(row_id);
+                    }
+                    deleted += 1;
+                    lists_deleted = true;
+                }
+            }
+        }
+
+        drop(lists);
+        drop(key_map); // Release lock
+        if deleted > 0 {
+            self.save_keymap(); // Persist to disk
+        }
+        if lists_deleted {
+            self.save_lists();
+        }
+
+        RespValue::Integer(deleted)
+    }
+
+    fn handle_exists(&self, args: &[RespValue]) -> RespValue {
+        if args.is_empty() {
+            return RespValue::Error(
+                "ERR wrong number of arguments for 'exists' command".to_string(),
+            );
+        }
+
+        let mut count = 0i64;
+        let key_map = self.key_map.read().unwrap();
+        let lists = self.lists.read().unwrap();
+
+        for arg in args {
+            if let RespValue::BulkString(Some(k)) = arg {
+                let key = keys::from_bytes(k);
+                if key_map.contains_key(&key) || lists.contains_key(&key) {
+                    count += 1;
+                }
+            }
+        }
+
+        RespValue::Integer(count)
+    }
+
+    fn handle_keys(&self, args: &[RespValue]) -> RespValue {
+        self.reap_expired();
+
+        let pattern = if args.is_empty() {
+            "*".to_string()
+        } else {
+            match &args[0] {
+                RespValue::BulkString(Some(p)) => keys::from_bytes(p),
+                _ => return RespValue::Error("ERR invalid pattern type".to_string()),
+            }
+        };
+
+        let key_map = self.key_map.read().unwrap();
+        let lists = self.lists.read().unwrap();
+        let mut matching_keys = Vec::new();
+
+        for key in key_map.keys().chain(lists.keys()) {
+            if matches_pattern(key, &pattern) {
+                matching_keys.push(RespValue::BulkString(Some(
+                    keys::to_bytes(key).into_owned(),
+                )));
+            }
+        }
+
+        RespValue::Array(Some(matching_keys))
+    }
+
+    /// COUNTKEYS pattern: how many keys KEYS would return, without listing them
+    fn handle_countkeys(&self, args: &[RespValue]) -> RespValue {
+        self.reap_expired();
+
+        let pattern = match args {
+            [RespValue::BulkString(Some(p))] => keys::from_bytes(p),
+            [_] => return RespValue::Error("ERR invalid pattern type".to_string()),
+            _ => {
+                return RespValue::Error(
+                    "ERR wrong number of arguments for 'countkeys' command".to_string(),
+                )
+            }
+        };
+
+        let key_map = self.key_map.read().unwrap();
+        let lists = self.lists.read().unwrap();
+        let count = key_map
+            .keys()
+            .chain(lists.keys())
+            .filter(|key| matches_pattern(key, &pattern))
+            .count();
+
+        RespValue::Integer(count as i64)
+    }
+
+    /// SCAN cursor [MATCH pattern] [COUNT count] [TYPE type] [ORDERED]
+    ///
+    /// The cursor is a row ID: each call looks at the next COUNT rows and
+    /// replies with the keys they hold. A key stands at its row, a list at
+    /// its lowest element row. Keys only ever move to new, higher rows, so
+    /// every key present for the whole scan is returned at least once.
+    /// Keys are looked up by row, so a page costs COUNT lookups however
+    /// many keys there are, and comes in row ID order: a full scan replays
+    /// the order keys were last written in. ORDERED, which used to ask for
+    /// that, is still accepted.
+    fn handle_scan(&self, args: &[RespValue]) -> RespValue {
+        self.reap_expired();
+
+        let Some(cursor) = args.first().and_then(bulk_to_i64).filter(|&c| c >= 0) else {
+            if args.is_empty() {
+                return RespValue::Error(
+                    "ERR wrong number of arguments for 'scan' command".to_string(),
+                );
+            }
+            return RespValue::Error("ERR invalid cursor".to_string());
+        };
+
+        let mut pattern = "*".to_string();
+        let mut count = 10;
+        let mut tag = None;
+        let mut options = args[1..].iter();
+        while let Some(option) = options.next() {
+            let RespValue::BulkString(Some(name)) = option else {
+                return RespValue::Error("ERR syntax error".to_string());
+            };
+            let name = String::from_utf8_lossy(name).to_uppercase();
+            if name == "ORDERED" {
+                continue;
+            }
+            let Some(RespValue::BulkString(Some(value))) = options.next() else {
+                return RespValue::Error("ERR syntax error".to_string());
+            };
+            match name.as_str() {
+                "MATCH" => pattern = keys::from_bytes(value),
+                "COUNT" => match std::str::from_utf8(value).ok().and_then(|v| v.parse().ok()) {
+                    Some(n) if n > 0 => count = n,
+                    Some(_) => return RespValue::Error("ERR syntax error".to_string()),
+                    None => {
+                        return RespValue::Error(
+                            "ERR value is not an integer or out of range".to_string(),
+                        )
+                    }
+                },
+                "TYPE" => {
+                    tag = match String::from_utf8_lossy(value).to_lowercase().as_str() {
+                        "string" => Some(STRING_TAG),
+                        "hash" => Some(HASH_TAG),
+                        "list" => Some(LIST_TAG),
+                        other => {
+                            return RespValue::Error(format!("ERR unknown type name '{}'", other))
+                        }
+                    }
+                }
+                _ => return RespValue::Error("ERR syntax error".to_string()),
+            }
+        }
+
+        let key_map = self.key_map.read().unwrap();
+        let lists = self.lists.read().unwrap();
+        let (rows, next) = match self.cache.scan_cursor(cursor as u64, count, tag) {
+            Ok(scan) => scan,
+            Err(e) => return RespValue::Error(format!("ERR {}", e)),
+        };
+        let keys = rows
+            .into_iter()
+            .filter_map(|row_id| key_map.key_at(row_id).or_else(|| lists.key_at(row_id)))
+            .filter(|key| matches_pattern(key, &pattern))
+            .map(|key| RespValue::BulkString(Some(keys::to_bytes(key).into_owned())))
+            .collect();
+
+        RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(next.to_string().into_bytes())),
+            RespValue::Array(Some(keys)),
+        ]))
+    }
+
+    /// DUMPALL cursor [COUNT count]: page through raw rows as row ID, data pairs
+    ///
+    /// Cursors work as for SCAN. Rows are returned as stored, so a hash is
+    /// its serialized form and each list element is a row of its own.
+    fn handle_dumpall(&self, args: &[RespValue]) -> RespValue {
+        self.reap_expired();
+
+        let Some(cursor) = args.first().and_then(bulk_to_i64).filter(|&c| c >= 0) else {
+            if args.is_empty() {
+                return RespValue::Error(
+                    "ERR wrong number of arguments for 'dumpall' command".to_string(),
+                );
+            }
+            return RespValue::Error("ERR invalid cursor".to_string());
+        };
+
+        let count = match &args[1..] {
+            [] => 10,
+            [RespValue::BulkString(Some(name)), value] if name.eq_ignore_ascii_case(b"COUNT") => {
+                match bulk_to_i64(value) {
+                    Some(n) if n > 0 => n as usize,
+                    Some(_) => return RespValue::Error("ERR syntax error".to_string()),
+                    None => {
+                        return RespValue::Error(
+                            "ERR value is not an integer or out of range".to_string(),
+                        )
+                    }
+                }
+            }
+            _ => return RespValue::Error("ERR syntax error".to_string()),
+        };
+
+        let (rows, next) = match self.cache.scan_with_values(cursor as u64, count) {
+            Ok(scan) => scan,
+            Err(e) => return RespValue::Error(format!("ERR {}", e)),
+        };
+
+        let mut entries = Vec::with_capacity(rows.len() * 2);
+        for (row_id, data) in rows {
+            entries.push(RespValue::Integer(row_id as i64));
+            entries.push(RespValue::BulkString(Some(data)));
+        }
+
+        RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(next.to_string().into_bytes())),
+            RespValue::Array(Some(entries)),
+        ]))
+    }
+
+    /// COUNTBY field value: number of rows holding a TOON record with `field` set to `value`
+    ///
+    /// Hashes are TOON records too, so this counts hashes with the field set.
+    fn handle_countby(&self, args: &[RespValue]) -> RespValue {
+        let [RespValue::BulkString(Some(field)), RespValue::BulkString(Some(value))] = args else {
+            return RespValue::Error(
+                "ERR wrong number of arguments for 'countby' command".to_string(),
+            );
+        };
+        let Ok(field) = std::str::from_utf8(field) else {
+            return RespValue::Error("ERR invalid field".to_string());
+        };
+
+        self.reap_expired();
+        match self.cache.count_by(field, value) {
+            Ok(count) => RespValue::Integer(count as i64),
+            Err(e) => RespValue::Error(format!("ERR {}", e)),
+        }
+    }
+
+    /// Key counts, with `avg_ttl` estimated from up to `sample` random keys
+    ///
+    /// The counts are read off the maps' lengths and the sample is picked by
+    /// index, so each lock is held for O(1) or O(sample), never for a walk
+    /// over the keyspace that would stall writers. With no more keys with a
+    /// TTL than `sample`, every one of them is averaged.
+    pub fn keyspace_info(&self, sample: usize) -> KeyspaceInfo {
+        use std::collections::hash_map::RandomState;
+        use std::hash::BuildHasher;
+
+        let keys = self.key_map.read().unwrap().len() + self.lists.read().unwrap().len();
+        let now = now_ms();
+        let state = RandomState::new();
+        let expires = self.expires.read().unwrap();
+        let count = expires.len();
+        let deadlines: Vec<u64> = if count <= sample {
+            expires.iter().map(|(_, &deadline)| deadline).collect()
+        } else {
+            (0..sample as u64)
+                .filter_map(|i| expires.nth_deadline(state.hash_one(i) as usize))
+                .collect()
+        };
+        let ttls: Vec<u64> = deadlines
+            .into_iter()
+            .map(|deadline| deadline.saturating_sub(now))
+            .collect();
+        drop(expires);
+
+        let avg_ttl = match ttls.len() {
+            0 => 0,
+            n => ttls.iter().sum::<u64>() / n as u64,
+        };
+        KeyspaceInfo {
+            keys,
+            expires: count,
+            avg_ttl,
+        }
+    }
+
+    fn handle_dbsize(&self) -> RespValue {
+        self.reap_expired();
+        let key_map = self.key_map.read().unwrap();
+        let lists = self.lists.read().unwrap();
+        RespValue::Integer((key_map.len() + lists.len()) as i64)
+    }
+
+    fn handle_flushdb(&self, args: &[RespValue]) -> RespValue {
+        // ASYNC and SYNC are both accepted; the flush always completes before replying
+        match args {
+            [] => {}
+            [RespValue::BulkString(Some(mode))]
+                if mode.eq_ignore_ascii_case(b"ASYNC") || mode.eq_ignore_ascii_case(b"SYNC") => {}
+            _ => return RespValue::Error("ERR syntax error".to_string()),
+        }
+
+        let mut key_map = self.key_map.write().unwrap();
+        let mut lists = self.lists.write().unwrap();
+        let mut expires = self.expires.write().unwrap();
+
+        // Row IDs restart from 0, so no key may keep pointing at an old row:
+        // the empty key map and lists reach disk before storage is cleared,
+        // and a crash in between leaves rows without keys, not keys on reused rows
+        Self::save_keymap_static(&self.keymap_path, &HashMap::new(), &expires);
+        Self::save_lists_static(&self.lists_path, &HashMap::new(), &expires);
+        if let Err(e) = self.cache.clear() {
+            error!("FLUSHDB: Failed to clear storage: {}", e);
+            Self::save_keymap_static(&self.keymap_path, &key_map, &expires);
+            Self::save_lists_static(&self.lists_path, &lists, &expires);
+            return RespValue::Error(format!("ERR {}", e));
+        }
+
+        for key in key_map.keys().chain(lists.keys()) {
+            self.touch_key(key);
+        }
+        key_map.clear();
+        lists.clear();
+        if let Some(bloom) = &self.bloom {
+            bloom.clear();
+        }
+        expires.clear();
+        RespValue::SimpleString("OK".to_string())
+    }
+
+    /// Check whether `row_id` holds a plain string
+    ///
+    /// A row deleted under the key counts as a string, so readers keep their
+    /// missing-row behaviour.
+    fn is_string_row(&self, row_id: u64) -> bool {
+        self.cache.row_tag(row_id).unwrap_or(STRING_TAG) == STRING_TAG
+    }
+
+    /// Write a key's new value, replacing its old row if it had one
+    ///
+    /// Going through `replace` keeps the key's creation time (see `OBJECT
+    /// CREATEDAT`); an old row that is already gone is not carried over.
+    fn put_value(
+        &self,
+        old_row_id: Option<u64>,
+        value: &[u8],
+        tag: u8,
+        max_value_size: usize,
+    ) -> toonstoredb::Result<u64> {
+        match old_row_id.map(|row_id| self.cache.replace(row_id, value, tag, max_value_size)) {
+            None | Some(Err(toonstoredb::Error::NotFound)) => {
+                self.cache.put_tagged_limited(value, tag, max_value_size)
+            }
+            Some(replaced) => replaced,
+        }
+    }
+
+    /// Read the hash stored at `row_id`
+    fn load_hash(&self, row_id: u64) -> Result<ToonRecord, RespValue> {
+        if self.cache.row_tag(row_id).ok() != Some(HASH_TAG) {
+            return Err(wrong_type());
+        }
+        let data = self
+            .cache
+            .get(row_id)
+            .map_err(|e| RespValue::Error(format!("ERR {}", e)))?;
+        match ToonRecord::parse(&data) {
+            Ok(record) if record.name == HASH_RECORD => Ok(record),
+            _ => Err(wrong_type()),
+        }
+    }
+
+    /// Store a rewritten hash under `key`, dropping the key once it has no fields
+    ///
+    /// Must be called with the key map write lock held.
+    fn store_hash(
+        &self,
+        key_map: &mut KeyMap,
+        key: &str,
+        old_row_id: Option<u64>,
+        record: &ToonRecord,
+        max_value_size: usize,
+    ) -> Result<(), RespValue> {
+        if record.fields.is_empty() {
+            key_map.remove(key);
+            self.expires.write().unwrap().remove(key);
+            if let Some(old_row_id) = old_row_id {
+                let _ = self.cache.delete(old_row_id);
+            }
+        } else {
+            let row_id = self
+                .put_value(old_row_id, &record.serialize(), HASH_TAG, max_value_size)
+                .map_err(write_error)?;
+            self.bloom_insert(key);
+            key_map.insert(key.to_string(), row_id);
+        }
+        self.touch_key(key);
+        Ok(())
+    }
+
+    fn handle_hset(&self, args: &[RespValue], max_value_size: usize) -> RespValue {
+        if args.len() < 3 || args.len() % 2 != 1 {
+            return RespValue::Error(
+                "ERR wrong number of arguments for 'hset' command".to_string(),
+            );
+        }
+
+        let key = match key_arg(&args[0]) {
+            Ok(key) => key,
+            Err(e) => return e,
+        };
+
+        let mut pairs = Vec::with_capacity(args.len() / 2);
+        for pair in args[1..].chunks(2) {
+            let field = match hash_field(&pair[0]) {
+                Ok(field) => field,
+                Err(e) => return e,
+            };
+            let value = match &pair[1] {
+                RespValue::BulkString(Some(v)) => v.as_slice(),
+                _ => return RespValue::Error("ERR invalid value type".to_string()),
+            };
+            pairs.push((field, value));
+        }
+
+        if self
+            .config
+            .read()
+            .unwrap()
+            .is_over_maxmemory(self.cache.db_size())
+        {
+            return oom();
+        }
+
+        let mut key_map = self.key_map.write().unwrap();
+        if self.lists.read().unwrap().contains_key(&key) {
+            return wrong_type();
+        }
+        let existing = match key_map.get(&key) {
+            Some(&row_id) => match self.load_hash(row_id) {
+                Ok(record) => Some((row_id, record)),
+                Err(e) => return e,
+            },
+            None => None,
+        };
+
+        let added = match existing {
+            // Only existing fields change: the schema stays, update in place
+            Some((row_id, record)) if pairs.iter().all(|(f, _)| record.get(f).is_some()) => {
+                let updates: Vec<(&str, &[u8])> =
+                    pairs.iter().map(|(f, v)| (f.as_str(), *v)).collect();
+                match self.cache.update_fields(row_id, &updates) {
+                    Ok(new_row_id) => {
+                        self.bloom_insert(&key);
+                        key_map.insert(key.clone(), new_row_id);
+                        self.touch_key(&key);
+                    }
+                    Err(e) => return write_error(e),
+                }
+                0
+            }
+            // New fields extend the schema, so the record is rebuilt
+            existing => {
+                let (old_row_id, mut record) = match existing {
+                    Some((row_id, record)) => (Some(row_id), record),
+                    None => (
+                        None,
+                        ToonRecord {
+                            name: HASH_RECORD.to_string(),
+                            fields: Vec::new(),
+                            values: Vec::new(),
+                        },
+                    ),
+                };
+
+                let mut added = 0;
+                for (field, value) in &pairs {
+                    if record.set(field, value).is_err() {
+                        record.fields.push(field.clone());
+                        record.values.push(value.to_vec());
+                        added += 1;
+                    }
+                }
+
+                if let Err(e) =
+                    self.store_hash(&mut key_map, &key, old_row_id, &record, max_value_size)
+                {
+                    return e;
+                }
+                added
+            }
+        };
+
+        drop(key_map); // Release lock before save
+        self.save_keymap();
+        RespValue::Integer(added)
+    }
+
+    fn handle_hget(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 2 {
+            return RespValue::Error(
+                "ERR wrong number of arguments for 'hget' command".to_string(),
+            );
+        }
+
+        let key = match key_arg(&args[0]) {
+            Ok(key) => key,
+            Err(e) => return e,
+        };
+        let field = match hash_field(&args[1]) {
+            Ok(field) => field,
+            Err(e) => return e,
+        };
+
+        let key_map = self.key_map.read().unwrap();
+        let Some(&row_id) = key_map.get(&key) else {
+            return RespValue::BulkString(None);
+        };
+
+        match self.load_hash(row_id) {
+            Ok(record) => RespValue::BulkString(record.get(&field).map(|v| v.to_vec())),
+            Err(e) => e,
+        }
+    }
+
+    fn handle_hgetall(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 1 {
+            return RespValue::Error(
+                "ERR wrong number of arguments for 'hgetall' command".to_string(),
+            );
+        }
+
+        let key = match key_arg(&args[0]) {
+            Ok(key) => key,
+            Err(e) => return e,
+        };
+
+        let key_map = self.key_map.read().unwrap();
+        let Some(&row_id) = key_map.get(&key) else {
+            return RespValue::Array(Some(vec![]));
+        };
+
+        match self.load_hash(row_id) {
+            Ok(record) => {
+                let mut result = Vec::with_capacity(record.fields.len() * 2);
+                for (field, value) in record.fields.into_iter().zip(record.values) {
+                    result.push(RespValue::BulkString(Some(field.into_bytes())));
+                    result.push(RespValue::BulkString(Some(value)));
+                }
+                RespValue::Array(Some(result))
+            }
+            Err(e) => e,
+        }
+    }
+
+    fn handle_hdel(&self, args: &[RespValue]) -> RespValue {
+        if args.len() < 2 {
+            return RespValue::Error(
+                "ERR wrong number of arguments for 'hdel' command".to_string(),
+            );
+        }
+
+        let key = match key_arg(&args[0]) {
+            Ok(key) => key,
+            Err(e) => return e,
+        };
+
+        let mut fields = Vec::with_capacity(args.len() - 1);
+        for arg in &args[1..] {
+            match hash_field(arg) {
+                Ok(field) => fields.push(field),
+                Err(e) => return e,
+            }
+        }
+
+        let mut key_map = self.key_map.write().unwrap();
+        let Some(&row_id) = key_map.get(&key) else {
+            return RespValue::Integer(0);
+        };
+        let mut record = match self.load_hash(row_id) {
+            Ok(record) => record,
+            Err(e) => return e,
+        };
+
+        let mut removed = 0;
+        for field in &fields {
+            if let Some(i) = record.fields.iter().position(|f| f == field) {
+                record.fields.remove(i);
+                record.values.remove(i);
+                removed += 1;
+            }
+        }
+
+        if removed > 0 {
+            // Removing fields only shrinks the hash, so any user may
+            if let Err(e) = self.store_hash(
+                &mut key_map,
+                &key,
+                Some(row_id),
+                &record,
+                toonstoredb::MAX_VALUE_SIZE_LIMIT,
+            ) {
+                return e;
+            }
+            drop(key_map); // Release lock before save
+            self.save_keymap();
+        }
+
+        RespValue::Integer(removed)
+    }
+
+    fn handle_hlen(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 1 {
+            return RespValue::Error(
+                "ERR wrong number of arguments for 'hlen' command".to_string(),
+            );
+        }
+
+        let key = match key_arg(&args[0]) {
+            Ok(key) => key,
+            Err(e) => return e,
+        };
+
+        let key_map = self.key_map.read().unwrap();
+        let Some(&row_id) = key_map.get(&key) else {
+            return RespValue::Integer(0);
+        };
+
+        match self.load_hash(row_id) {
+            Ok(record) => RespValue::Integer(record.fields.len() as i64),
+            Err(e) => e,
+        }
+    }
+
+    fn handle_push(
+        &self,
+        args: &[RespValue],
+        front: bool,
+        name: &str,
+        max_value_size: usize,
+    ) -> RespValue {
+        if args.len() < 2 {
+            return RespValue::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name
+            ));
+        }
+
+        let key = match key_arg(&args[0]) {
+            Ok(key) => key,
+            Err(e) => return e,
+        };
+
+        let mut elements = Vec::with_capacity(args.len() - 1);
+        for arg in &args[1..] {
+            match arg {
+                RespValue::BulkString(Some(v)) => elements.push(v),
+                _ => return RespValue::Error("ERR invalid value type".to_string()),
+            }
+        }
+
+        if self
+            .config
+            .read()
+            .unwrap()
+            .is_over_maxmemory(self.cache.db_size())
+        {
+            return oom();
+        }
+
+        // Held so a concurrent SET cannot claim the key while we push
+        let key_map = self.key_map.read().unwrap();
+        if key_map.contains_key(&key) {
+            return wrong_type();
+        }
+
+        let mut lists = self.lists.write().unwrap();
+        self.bloom_insert(&key);
+        let mut result = Ok(());
+        for element in elements {
+            match self
+                .cache
+                .put_tagged_limited(element, LIST_TAG, max_value_size)
+            {
+                Ok(row_id) => lists.push(&key, row_id, front),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+
+        let len = lists.get(&key).map_or(0, VecDeque::len);
+        if len == 0 {
+            lists.remove(&key);
+        }
+        drop(lists);
+        drop(key_map);
+
+        // Elements pushed before a failure stay, as they would in a pipeline
+        if len > 0 {
+            self.touch_key(&key);
+            self.save_lists();
+        }
+
+        match result {
+            Ok(()) => RespValue::Integer(len as i64),
+            Err(e) => write_error(e),
+        }
+    }
+
+    fn handle_pop(&self, args: &[RespValue], front: bool, name: &str) -> RespValue {
+        if args.is_empty() || args.len() > 2 {
+            return RespValue::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name
+            ));
+        }
+
+        let key = match key_arg(&args[0]) {
+            Ok(key) => key,
+            Err(e) => return e,
+        };
+
+        let count = match args.get(1) {
+            Some(arg) => match bulk_to_i64(arg) {
+                Some(n) if n >= 0 => Some(n as usize),
+                _ => {
+                    return RespValue::Error(
+                        "ERR value is out of range, must be positive".to_string(),
+                    )
+                }
+            },
+            None => None,
+        };
+
+        let key_map = self.key_map.read().unwrap();
+        if key_map.contains_key(&key) {
+            return wrong_type();
+        }
+
+        let mut lists = self.lists.write().unwrap();
+        if !lists.contains_key(&key) {
+            return match count {
+                Some(_) => RespValue::Array(None),
+                None => RespValue::BulkString(None),
+            };
+        }
+
+        let mut popped = Vec::new();
+        for _ in 0..count.unwrap_or(1) {
+            let Some(row_id) = lists.pop(&key, front) else {
+                break;
+            };
+
+            match self.cache.get(row_id) {
+                Ok(data) => popped.push(RespValue::BulkString(Some(data))),
+                Err(e) => error!("POP: Failed to read list element {}: {}", row_id, e),
+            }
+            let _ = self.cache.delete(row_id);
+        }
+
+        if lists.get(&key).is_some_and(VecDeque::is_empty) {
+            // An emptied list is deleted, and its TTL with it
+            lists.remove(&key);
+            self.expires.write().unwrap().remove(&key);
+        }
+        drop(lists);
+        drop(key_map);
+
+        if count != Some(0) {
+            self.touch_key(&key);
+            self.save_lists();
+        }
+
+        match count {
+            Some(_) => RespValue::Array(Some(popped)),
+            None => popped.pop().unwrap_or(RespValue::BulkString(None)),
+        }
+    }
+
+    fn handle_llen(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 1 {
+            return RespValue::Error(
+                "ERR wrong number of arguments for 'llen' command".to_string(),
+            );
+        }
+
+        let key = match key_arg(&args[0]) {
+            Ok(key) => key,
+            Err(e) => return e,
+        };
+
+        let key_map = self.key_map.read().unwrap();
+        if key_map.contains_key(&key) {
+            return wrong_type();
+        }
+
+        let lists = self.lists.read().unwrap();
+        RespValue::Integer(lists.get(&key).map_or(0, |list| list.len() as i64))
+    }
+
+    fn handle_lrange(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 3 {
+            return RespValue::Error(
+                "ERR wrong number of arguments for 'lrange' command".to_string(),
+            );
+        }
+
+        let key = match key_arg(&args[0]) {
+            Ok(key) => key,
+            Err(e) => return e,
+        };
+
+        let (Some(start), Some(stop)) = (bulk_to_i64(&args[1]), bulk_to_i64(&args[2])) else {
+            return RespValue::Error("ERR value is not an integer or out of range".to_string());
+        };
+
+        let key_map = self.key_map.read().unwrap();
+        if key_map.contains_key(&key) {
+            return wrong_type();
+        }
+
+        let lists = self.lists.read().unwrap();
+        let Some(list) = lists.get(&key) else {
+            return RespValue::Array(Some(vec![]));
+        };
+
+        // Negative indices count from the tail; out-of-range ends are clamped
+        let len = list.len() as i64;
+        let start = if start < 0 {
+            (len + start).max(0)
+        } else {
+            start
+        };
+        let stop = if stop < 0 {
+            len + stop
+        } else {
+            stop.min(len - 1)
+        };
+        if start > stop {
+            return RespValue::Array(Some(vec![]));
+        }
+
+        let mut result = Vec::with_capacity((stop - start + 1) as usize);
+        for &row_id in list.range(start as usize..=stop as usize) {
+            match self.cache.get(row_id) {
+                Ok(data) => result.push(RespValue::BulkString(Some(data))),
+                Err(e) => {
+                    error!("LRANGE: Failed to read list element {}: {}", row_id, e);
+                    result.push(RespValue::BulkString(None));
+                }
+            }
+        }
+
+        RespValue::Array(Some(result))
+    }
+
+    fn handle_info(&self, args: &[RespValue]) -> RespValue {
+        let mut sections = Vec::with_capacity(args.len());
+        for arg in args {
+            match arg {
+                RespValue::BulkString(Some(s)) => {
+                    sections.push(String::from_utf8_lossy(s).to_lowercase())
+                }
+                _ => return RespValue::Error("ERR invalid section".to_string()),
+            }
+        }
+
+        // No section, "all", "default" or "everything" selects the full report
+        let all = sections.is_empty()
+            || sections
+                .iter()
+                .any(|s| matches!(s.as_str(), "all" | "default" | "everything"));
+
+        let mut info = String::new();
+        for &name in INFO_SECTIONS {
+            if all || sections.iter().any(|s| s == name) {
+                if !info.is_empty() {
+                    info.push_str("\r\n");
+                }
+                info.push_str(&self.info_section(name));
+            }
+        }
+
+        // Unknown sections contribute nothing, like Redis
+        RespValue::BulkString(Some(info.into_bytes()))
+    }
+
+    /// Render one INFO section, including its `# Title` header
+    fn info_section(&self, name: &str) -> String {
+        match name {
+            "server" => format!(
+                "# Server\r\n\
+                 toonstore_version:0.1.0\r\n\
+                 run_id:{}\r\n\
+                 uptime_in_seconds:{}\r\n",
+                self.run_id,
+                self.started.elapsed().as_secs(),
+            ),
+            "clients" => format!(
+                "# Clients\r\n\
+                 connected_clients:{}\r\n",
+                self.connected_clients(),
+            ),
+            "stats" => {
+                let stats = self.cache.stats();
+                let total_keys =
+                    self.key_map.read().unwrap().len() + self.lists.read().unwrap().len();
+                format!(
+                    "# Stats\r\n\
+                     total_keys:{}\r\n\
+                     live_rows:{}\r\n\
+                     cache_size:{}\r\n\
+                     cache_capacity:{}\r\n\
+                     cache_hits:{}\r\n\
+                     cache_misses:{}\r\n\
+                     cache_hit_ratio:{:.2}\r\n\
+                     cache_recent_hit_ratio:{:.2}\r\n\
+                     total_commands_processed:{}\r\n\
+                     instantaneous_ops_per_sec:{}\r\n\
+                     cmd_latency_us:{:.2}\r\n",
+                    total_keys,
+                    self.cache.live_len(),
+                    self.cache.cache_len(),
+                    self.cache.capacity(),
+                    stats.hits(),
+                    stats.misses(),
+                    stats.hit_ratio(),
+                    stats.recent_hit_ratio(),
+                    self.command_stats.total_commands(),
+                    self.command_stats.ops_per_sec(),
+                    self.command_stats.latency_us(),
+                )
+            }
+            "memory" => {
+                let report = self.cache.memory_report();
+                format!(
+                    "# Memory\r\n\
+                     used_disk_bytes:{}\r\n\
+                     maxmemory:{}\r\n\
+                     index_entries:{}\r\n\
+                     index_bytes:{}\r\n\
+                     deleted_rows:{}\r\n\
+                     cache_entries:{}\r\n\
+                     cache_bytes:{}\r\n\
+                     lru_nodes:{}\r\n\
+                     lru_free_nodes:{}\r\n",
+                    self.cache.db_size(),
+                    self.config.read().unwrap().maxmemory,
+                    report.index_entries,
+                    report.index_bytes,
+                    report.deleted_rows,
+                    report.cache_entries,
+                    report.cache_bytes,
+                    report.lru_nodes,
+                    report.lru_free_nodes,
+                )
+            }
+            "keyspace" => {
+                let info = self.keyspace_info(KEYSPACE_TTL_SAMPLE);
+                // Redis omits empty databases
+                if info.keys == 0 {
+                    "# Keyspace\r\n".to_string()
+                } else {
+                    format!(
+                        "# Keyspace\r\ndb0:keys={},expires={},avg_ttl={}\r\n",
+                        info.keys, info.expires, info.avg_ttl
+                    )
+                }
+            }
+            _ => String::new(),
+        }
+    }
+
+    fn handle_command(&self, args: &[RespValue]) -> RespValue {
+        let Some(RespValue::BulkString(Some(sub))) = args.first() else {
+            // Bare COMMAND lists every spec
+            return RespValue::Array(Some(COMMAND_TABLE.iter().map(command_info).collect()));
+        };
+
+        let names: Vec<String> = args[1..]
+            .iter()
+            .filter_map(|arg| match arg {
+                RespValue::BulkString(Some(b)) => Some(String::from_utf8_lossy(b).to_string()),
+                _ => None,
+            })
+            .collect();
+
+        let subcommand = String::from_utf8_lossy(sub).to_uppercase();
+        match subcommand.as_str() {
+            "COUNT" => RespValue::Integer(COMMAND_TABLE.len() as i64),
+            "INFO" => {
+                let infos = names
+                    .iter()
+                    .map(|name| match commands::lookup(name) {
+                        Some(spec) => command_info(spec),
+                        None => RespValue::Array(None),
+                    })
+                    .collect();
+                RespValue::Array(Some(infos))
+            }
+            "DOCS" => {
+                let specs: Vec<&CommandSpec> = if names.is_empty() {
+                    COMMAND_TABLE.iter().collect()
+                } else {
+                    names
+                        .iter()
+                        .filter_map(|name| commands::lookup(name))
+                        .collect()
+                };
+
+                let mut docs = Vec::with_capacity(specs.len() * 2);
+                for spec in specs {
+                    docs.push(RespValue::BulkString(Some(
+                        spec.name.to_lowercase().into_bytes(),
+                    )));
+                    docs.push(RespValue::Array(Some(vec![
+                        RespValue::BulkString(Some(b"summary".to_vec())),
+                        RespValue::BulkString(Some(spec.summary.as_bytes().to_vec())),
+                        RespValue::BulkString(Some(b"group".to_vec())),
+                        RespValue::BulkString(Some(spec.group.as_bytes().to_vec())),
+                    ])));
+                }
+                RespValue::Array(Some(docs))
+            }
+            _ => unknown_subcommand("COMMAND", &subcommand),
+        }
+    }
+
+    /// AUTH, refused while the connection, its address or the username is
+    /// locked out by failed attempts
+    fn handle_auth(&self, args: &[RespValue], session: &mut SessionState) -> RespValue {
+        let (allowance, per_user) = {
+            let config = self.config.read().unwrap();
+            (config.auth_max_failures, config.auth_lockout_per_user)
+        };
+        // Only user accounts are counted by name, a shared password has no one
+        // to tell apart. Names that do not exist are not counted either, so
+        // spraying made-up names cannot push real ones out of the table.
+        let username = match (per_user, &self.user_manager, args) {
+            (true, Some(users), [RespValue::BulkString(Some(name)), _]) => {
+                let name = String::from_utf8_lossy(name).into_owned();
+                users.get_user(&name).is_some().then_some(name)
+            }
+            (true, Some(_), [_]) => Some("admin".to_string()),
+            _ => None,
+        };
+
+        let now = Instant::now();
+        let user_locked_for = username.as_ref().and_then(|name| {
+            self.login_failures
+                .lock()
+                .unwrap()
+                .get(name)
+                .and_then(|backoff| backoff.locked_for(now))
+        });
+        let peer_locked_for = session.peer.and_then(|peer| {
+            self.peer_login_failures
+                .lock()
+                .unwrap()
+                .get(&peer)
+                .and_then(|backoff| backoff.locked_for(now))
+        });
+        let locked_for = session
+            .login_backoff
+            .locked_for(now)
+            .max(user_locked_for)
+            .max(peer_locked_for);
+        if let Some(wait) = locked_for {
+            warn!(
+                "Refused AUTH from a locked out client (user: {}, address: {})",
+                username.as_deref().unwrap_or("-"),
+                session
+                    .peer
+                    .map_or("-".to_string(), |peer| peer.to_string())
+            );
+            return RespValue::Error(format!(
+                "ERR too many failed AUTH attempts, retry in {} seconds",
+                wait.as_millis().div_ceil(1000)
+            ));
+        }
+
+        let reply = self.verify_auth(args, session);
+        match &reply {
+            RespValue::Error(e) if e.starts_with("WRONGPASS") => {
+                session.login_backoff.record_failure(allowance, now);
+                if let Some(name) = username {
+                    record_login_failure(&self.login_failures, name, allowance, now);
+                }
+                if let Some(peer) = session.peer {
+                    record_login_failure(&self.peer_login_failures, peer, allowance, now);
+                }
+            }
+            RespValue::SimpleString(_) => {
+                session.login_backoff.reset();
+                if let Some(name) = username {
+                    self.login_failures.lock().unwrap().remove(&name);
+                }
+                if let Some(peer) = session.peer {
+                    self.peer_login_failures.lock().unwrap().remove(&peer);
+                }
+            }
+            _ => {}
+        }
+        reply
+    }
+
+    fn verify_auth(&self, args: &[RespValue], session: &mut SessionState) -> RespValue {
+        // Support both AUTH password and AUTH username password
+        if args.is_empty() || args.len() > 2 {
+            return RespValue::Error(
+                "ERR wrong number of arguments for 'auth' command".to_string(),
+            );
+        }
+
+        // If user manager is enabled, use multi-user authentication
+        if let Some(user_manager) = &self.user_manager {
+            let (username, password) = if args.len() == 2 {
+                // AUTH username password
+                let username = match &args[0] {
+                    RespValue::BulkString(Some(u)) => match String::from_utf8(u.clone()) {
+                        Ok(s) => s,
+                        Err(_) => return RespValue::Error("ERR invalid username".to_string()),
+                    },
+                    _ => return RespValue::Error("ERR invalid username type".to_string()),
+                };
+
+                let password = match &args[1] {
+                    RespValue::BulkString(Some(p)) => match String::from_utf8(p.clone()) {
+                        Ok(s) => s,
+                        Err(_) => return RespValue::Error("ERR invalid password".to_string()),
+                    },
+                    _ => return RespValue::Error("ERR invalid password type".to_string()),
+                };
+
+                (username, password)
+            } else {
+                // AUTH password (use 'admin' as default user)
+                let password = match &args[0] {
+                    RespValue::BulkString(Some(p)) => match String::from_utf8(p.clone()) {
+                        Ok(s) => s,
+                        Err(_) => return RespValue::Error("ERR invalid password".to_string()),
+                    },
+                    _ => return RespValue::Error("ERR invalid password type".to_string()),
+                };
+
+                ("admin".to_string(), password)
+            };
+
+            // Authenticate with user manager
+            if let Some(user) = user_manager.authenticate(&username, &password) {
+                session.authenticate(user.username.clone(), user.role);
+                session.max_value_size = user.max_value_size;
+                info!("User '{}' authenticated successfully", username);
+                RespValue::SimpleString("OK".to_string())
+            } else {
+                warn!("Failed authentication attempt for user '{}'", username);
+                RespValue::Error("WRONGPASS invalid username-password pair".to_string())
+            }
+        } else {
+            // Fallback to simple password authentication
+            if args.len() != 1 {
+                return RespValue::Error(
+                    "ERR wrong number of arguments for 'auth' command".to_string(),
+                );
+            }
+
+            let password = match &args[0] {
+                RespValue::BulkString(Some(p)) => match String::from_utf8(p.clone()) {
+                    Ok(s) => s,
+                    Err(_) => return RespValue::Error("ERR invalid password".to_string()),
+                },
+                _ => return RespValue::Error("ERR invalid password type".to_string()),
+            };
+
+            if !self.auth_config.is_required() {
+                return RespValue::Error(
+                    "ERR Client sent AUTH, but no password is set".to_string(),
+                );
+            }
+
+            if self.auth_config.verify(&password) {
+                session.authenticate("default".to_string(), UserRole::Admin);
+                RespValue::SimpleString("OK".to_string())
+            } else {
+                RespValue::Error("WRONGPASS invalid username-password pair".to_string())
+            }
+        }
+    }
+
+    fn handle_user(&self, args: &[RespValue], session: &SessionState) -> RespValue {
+        let user_manager = match &self.user_manager {
+            Some(mgr) => mgr,
+            None => return RespValue::Error("ERR user management not enabled".to_string()),
+        };
+
+        if args.is_empty() {
+            return RespValue::Error(
+                "ERR wrong number of arguments for 'user' command".to_string(),
+            );
+        }
+
+        let subcommand = match &args[0] {
+            RespValue::BulkString(Some(cmd)) => String::from_utf8_lossy(cmd).to_uppercase(),
+            _ => return RespValue::Error("ERR invalid subcommand".to_string()),
+        };
+
+        match subcommand.as_str() {
+            "CREATE" => {
+                // USER CREATE username password [role]
+                if args.len() < 3 {
+                    return RespValue::Error(
+                        "ERR USER CREATE requires username and password".to_string(),
+                    );
+                }
+
+                let username = match &args[1] {
+                    RespValue::BulkString(Some(u)) => String::from_utf8_lossy(u).to_string(),
+                    _ => return RespValue::Error("ERR invalid username".to_string()),
+                };
+
+                let password = match &args[2] {
+                    RespValue::BulkString(Some(p)) => String::from_utf8_lossy(p).to_string(),
+                    _ => return RespValue::Error("ERR invalid password".to_string()),
+                };
+
+                let role = if args.len() > 3 {
+                    match &args[3] {
+                        RespValue::BulkString(Some(r)) => {
+                            let role_str = String::from_utf8_lossy(r).to_uppercase();
+                            match role_str.as_str() {
+                                "ADMIN" => UserRole::Admin,
+                                "READWRITE" => UserRole::ReadWrite,
+                                "READONLY" => UserRole::ReadOnly,
+                                _ => return RespValue::Error("ERR invalid role".to_string()),
+                            }
+                        }
+                        _ => return RespValue::Error("ERR invalid role type".to_string()),
+                    }
+                } else {
+                    UserRole::ReadWrite // Default role
+                };
+
+                match user_manager.create_user(&username, &password, role) {
+                    Ok(_) => RespValue::SimpleString("OK".to_string()),
+                    Err(e) => RespValue::Error(format!("ERR {}", e)),
+                }
+            }
+            "DELETE" => {
+                // USER DELETE username
+                if args.len() != 2 {
+                    return RespValue::Error("ERR USER DELETE requires username".to_string());
+                }
+
+                let username = match &args[1] {
+                    RespValue::BulkString(Some(u)) => String::from_utf8_lossy(u).to_string(),
+                    _ => return RespValue::Error("ERR invalid username".to_string()),
+                };
+
+                match user_manager.delete_user(&username) {
+                    Ok(_) => RespValue::SimpleString("OK".to_string()),
+                    Err(e) => RespValue::Error(format!("ERR {}", e)),
+                }
+            }
+            "LIST" => {
+                // USER LIST
+                let users = user_manager.list_users();
+                let result: Vec<RespValue> = users
+                    .iter()
+                    .map(|u| RespValue::BulkString(Some(u.as_bytes().to_vec())))
+                    .collect();
+                RespValue::Array(Some(result))
+            }
+            "SETPASS" => {
+                // USER SETPASS username newpassword
+                if args.len() != 3 {
+                    return RespValue::Error(
+                        "ERR USER SETPASS requires username and new password".to_string(),
+                    );
+                }
+
+                let username = match &args[1] {
+                    RespValue::BulkString(Some(u)) => String::from_utf8_lossy(u).to_string(),
+                    _ => return RespValue::Error("ERR invalid username".to_string()),
+                };
+
+                let new_password = match &args[2] {
+                    RespValue::BulkString(Some(p)) => String::from_utf8_lossy(p).to_string(),
+                    _ => return RespValue::Error("ERR invalid password".to_string()),
+                };
+
+                match user_manager.change_password(&username, &new_password) {
+                    Ok(_) => RespValue::SimpleString("OK".to_string()),
+                    Err(e) => RespValue::Error(format!("ERR {}", e)),
+                }
+            }
+            "WHOAMI" => {
+                // USER WHOAMI
+                RespValue::BulkString(Some(session.username().as_bytes().to_vec()))
+            }
+            _ => unknown_subcommand("USER", &subcommand),
+        }
+    }
+
+    fn handle_acl(&self, args: &[RespValue]) -> RespValue {
+        let user_manager = match &self.user_manager {
+            Some(mgr) => mgr,
+            None => return RespValue::Error("ERR user management not enabled".to_string()),
+        };
+
+        if args.is_empty() {
+            return RespValue::Error("ERR wrong number of arguments for 'acl' command".to_string());
+        }
+
+        let subcommand = match &args[0] {
+            RespValue::BulkString(Some(cmd)) => String::from_utf8_lossy(cmd).to_uppercase(),
+            _ => return RespValue::Error("ERR invalid subcommand".to_string()),
+        };
+
+        let names: Vec<String> = args[1..]
+            .iter()
+            .filter_map(|arg| match arg {
+                RespValue::BulkString(Some(b)) => Some(String::from_utf8_lossy(b).to_string()),
+                _ => None,
+            })
+            .collect();
+
+        match subcommand.as_str() {
+            "SETUSER" => {
+                // ACL SETUSER username [on|off] [>password] [+@all|+@write|+@read]
+                //     [maxvaluesize=<size>|default]
+                let Some((username, rules)) = names.split_first() else {
+                    return RespValue::Error(
+                        "ERR wrong number of arguments for 'acl|setuser' command".to_string(),
+                    );
+                };
+
+                let mut active = None;
+                let mut password = None;
+                let mut role = None;
+                let mut max_value_size = None;
+                for rule in rules {
+                    if rule.eq_ignore_ascii_case("on") {
+                        active = Some(true);
+                    } else if rule.eq_ignore_ascii_case("off") {
+                        active = Some(false);
+                    } else if let Some(pass) = rule.strip_prefix('>') {
+                        password = Some(pass);
+                    } else if let Some(r) = UserRole::from_acl_rule(rule) {
+                        role = Some(r);
+                    } else if let Some(size) = rule.strip_prefix("maxvaluesize=") {
+                        max_value_size = match size {
+                            "default" => Some(None),
+                            _ => match parse_memory(size) {
+                                Ok(size) => Some(Some(size as usize)),
+                                Err(e) => return RespValue::Error(format!("ERR {}", e)),
+                            },
+                        };
+                    } else {
+                        return RespValue::Error(format!(
+                            "ERR Error in ACL SETUSER modifier '{}': Syntax error",
+                            rule
+                        ));
+                    }
+                }
+
+                let result = if user_manager.get_user(username).is_some() {
+                    password
+                        .map_or(Ok(()), |p| user_manager.change_password(username, p))
+                        .and_then(|_| {
+                            role.map_or(Ok(()), |r| user_manager.update_role(username, r))
+                        })
+                } else {
+                    match password {
+                        Some(p) => user_manager.create_user(
+                            username,
+                            p,
+                            role.unwrap_or(UserRole::ReadWrite),
+                        ),
+                        None => {
+                            return RespValue::Error(
+                                "ERR new users need a password (>password)".to_string(),
+                            )
+                        }
+                    }
+                };
+
+                match result
+                    .and_then(|_| active.map_or(Ok(()), |a| user_manager.set_active(username, a)))
+                    .and_then(|_| {
+                        max_value_size.map_or(Ok(()), |size| {
+                            user_manager.set_max_value_size(username, size)
+                        })
+                    }) {
+                    Ok(_) => RespValue::SimpleString("OK".to_string()),
+                    Err(e) => RespValue::Error(format!("ERR {}", e)),
+                }
+            }
+            "GETUSER" => {
+                // ACL GETUSER username
+                if names.len() != 1 {
+                    return RespValue::Error(
+                        "ERR wrong number of arguments for 'acl|getuser' command".to_string(),
+                    );
+                }
+
+                match user_manager.get_user(&names[0]) {
+                    Some(user) => {
+                        let flag = if user.active { "on" } else { "off" };
+                        RespValue::Array(Some(vec![
+                            RespValue::BulkString(Some(b"flags".to_vec())),
+                            RespValue::Array(Some(vec![RespValue::BulkString(Some(
+                                flag.as_bytes().to_vec(),
+                            ))])),
+                            RespValue::BulkString(Some(b"commands".to_vec())),
+                            RespValue::BulkString(Some(user.role.acl_rule().as_bytes().to_vec())),
+                        ]))
+                    }
+                    None => RespValue::BulkString(None),
+                }
+            }
+            "LIST" => {
+                // ACL LIST
+                let mut usernames = user_manager.list_users();
+                usernames.sort();
+                let result = usernames
+                    .iter()
+                    .filter_map(|name| user_manager.get_user(name))
+                    .map(|user| {
+                        let line = format!(
+                            "user {} {} {}",
+                            user.username,
+                            if user.active { "on" } else { "off" },
+                            user.role.acl_rule()
+                        );
+                        RespValue::BulkString(Some(line.into_bytes()))
+                    })
+                    .collect();
+                RespValue::Array(Some(result))
+            }
+            "DELUSER" => {
+                // ACL DELUSER username [username ...]
+                if names.is_empty() {
+                    return RespValue::Error(
+                        "ERR wrong number of arguments for 'acl|deluser' command".to_string(),
+                    );
+                }
+
+                let mut deleted = 0;
+                for name in &names {
+                    match user_manager.delete_user(name) {
+                        Ok(_) => deleted += 1,
+                        Err(e) => warn!("ACL DELUSER {}: {}", name, e),
+                    }
+                }
+                RespValue::Integer(deleted)
+            }
+            "LOAD" => {
+                // ACL LOAD: re-read users.json, e.g. after editing it by hand
+                if !names.is_empty() {
+                    return RespValue::Error(
+                        "ERR wrong number of arguments for 'acl|load' command".to_string(),
+                    );
+                }
+
+                match user_manager.reload() {
+                    Ok(()) => {
+                        info!("ACL LOAD: users reloaded");
+                        RespValue::SimpleString("OK".to_string())
+                    }
+                    Err(e) => RespValue::Error(format!("ERR Error loading ACLs: {:#}", e)),
+                }
+            }
+            _ => unknown_subcommand("ACL", &subcommand),
+        }
+    }
+
+    fn handle_debug(&self, args: &[RespValue]) -> RespValue {
+        let subcommand = match args.first() {
+            Some(RespValue::BulkString(Some(s))) => String::from_utf8_lossy(s).to_uppercase(),
+            _ => {
+                return RespValue::Error(
+                    "ERR wrong number of arguments for 'debug' command".to_string(),
+                )
+            }
+        };
+
+        match subcommand.as_str() {
+            "RELOAD" => self.debug_reload(),
+            "SELFTEST" => self.debug_selftest(),
+            "SLEEP" => match args.get(1).and_then(bulk_to_f64) {
+                Some(seconds) if args.len() == 2 && seconds >= 0.0 && seconds.is_finite() => {
+                    std::thread::sleep(Duration::from_secs_f64(seconds));
+                    RespValue::SimpleString("OK".to_string())
+                }
+                _ => RespValue::Error("ERR DEBUG SLEEP takes a number of seconds".to_string()),
+            },
+            "OBJECT" => match args {
+                [_, RespValue::BulkString(Some(k))] => self.debug_object(&keys::from_bytes(k)),
+                _ => unknown_subcommand("DEBUG", &subcommand),
+            },
+            "SET-ACTIVE-EXPIRE" => match args.get(1).and_then(bulk_to_i64) {
+                // Nothing to toggle: the server was started without a sweep
+                Some(0 | 1) if args.len() == 2 && self.active_expire_cycle().is_none() => {
+                    RespValue::Error(
+                        "ERR active expiry is off, restart with --active-expire-cycle-ms above 0"
+                            .to_string(),
+                    )
+                }
+                Some(flag @ (0 | 1)) if args.len() == 2 => {
+                    self.active_expire.store(flag == 1, Ordering::Relaxed);
+                    RespValue::SimpleString("OK".to_string())
+                }
+                _ => RespValue::Error("ERR DEBUG SET-ACTIVE-EXPIRE takes 0 or 1".to_string()),
+            },
+            "STRINGMATCH-LEN" => match args {
+                [_, RespValue::BulkString(Some(pattern)), RespValue::BulkString(Some(string))] => {
+                    RespValue::Integer(glob::stringmatch(pattern, string) as i64)
+                }
+                _ => RespValue::Error(
+                    "ERR wrong number of arguments for 'debug|stringmatch-len' command".to_string(),
+                ),
+            },
+            _ => unknown_subcommand("DEBUG", &subcommand),
+        }
+    }
+
+    /// DEBUG OBJECT: a Redis-style line about a key, plus its row ID and whether it is cached
+    ///
+    /// `serializedlength` is what the value takes up in the data file, so a
+    /// compressed value reports its compressed size. A list reports the sum
+    /// over its elements and has no single row.
+    fn debug_object(&self, key: &str) -> RespValue {
+        let row_id = self.key_map.read().unwrap().get(key).copied();
+        let line = match row_id {
+            Some(row_id) => {
+                let encoding = match self.cache.row_tag(row_id) {
+                    Ok(HASH_TAG) => "hashtable",
+                    _ => "raw",
+                };
+                let len = match self.cache.stored_len(row_id) {
+                    Ok(len) => len,
+                    Err(e) => return RespValue::Error(format!("ERR {}", e)),
+                };
+                format!(
+                    "refcount:1 encoding:{} serializedlength:{} row_id:{} cached:{}",
+                    encoding,
+                    len,
+                    row_id,
+                    self.cache.is_cached(row_id) as u8
+                )
+            }
+            None => match self.lists.read().unwrap().get(key) {
+                Some(rows) => {
+                    let len: usize = rows
+                        .iter()
+                        .filter_map(|&row_id| self.cache.stored_len(row_id).ok())
+                        .sum();
+                    format!("refcount:1 encoding:linkedlist serializedlength:{}", len)
+                }
+                None => return RespValue::Error("ERR no such key".to_string()),
+            },
+        };
+        RespValue::SimpleString(line)
+    }
+
+    /// MEMORY USAGE key [SAMPLES count]
+    ///
+    /// An estimate: the key's bytes, its value lengths from the storage
+    /// index, and fixed overheads per key and per row. Values are flat, so
+    /// there is nothing to sample and SAMPLES is only validated.
+    fn handle_memory(&self, args: &[RespValue]) -> RespValue {
+        let subcommand = match args.first() {
+            Some(RespValue::BulkString(Some(s))) => String::from_utf8_lossy(s).to_uppercase(),
+            _ => {
+                return RespValue::Error(
+                    "ERR wrong number of arguments for 'memory' command".to_string(),
+                )
+            }
+        };
+        let (key, options) = match (subcommand.as_str(), &args[1..]) {
+            ("USAGE", [RespValue::BulkString(Some(k)), options @ ..]) => (k, options),
+            _ => return unknown_subcommand("MEMORY", &subcommand),
+        };
+        match options {
+            [] => {}
+            [RespValue::BulkString(Some(name)), count] if name.eq_ignore_ascii_case(b"SAMPLES") => {
+                if bulk_to_i64(count).is_none() {
+                    return RespValue::Error(
+                        "ERR value is not an integer or out of range".to_string(),
+                    );
+                }
+            }
+            _ => return RespValue::Error("ERR syntax error".to_string()),
+        }
+
+        let key_len = key.len() + MEMORY_KEY_OVERHEAD;
+        let key = keys::from_bytes(key);
+        let row_id = self.key_map.read().unwrap().get(&key).copied();
+        let value_len = match row_id {
+            // A row deleted under the key counts as missing
+            Some(row_id) => match self.cache.value_len(row_id) {
+                Ok(len) => len + MEMORY_ROW_OVERHEAD,
+                Err(_) => return RespValue::BulkString(None),
+            },
+            None => match self.lists.read().unwrap().get(&key) {
+                Some(rows) => rows
+                    .iter()
+                    .filter_map(|&row_id| self.cache.value_len(row_id).ok())
+                    .map(|len| len + MEMORY_ROW_OVERHEAD)
+                    .sum(),
+                None => return RespValue::BulkString(None),
+            },
+        };
+        RespValue::Integer((key_len + value_len) as i64)
+    }
+
+    fn handle_object(&self, args: &[RespValue]) -> RespValue {
+        let subcommand = match args.first() {
+            Some(RespValue::BulkString(Some(s))) => String::from_utf8_lossy(s).to_uppercase(),
+            _ => {
+                return RespValue::Error(
+                    "ERR wrong number of arguments for 'object' command".to_string(),
+                )
+            }
+        };
+
+        match (subcommand.as_str(), &args[1..]) {
+            ("ENCODING", [RespValue::BulkString(Some(k))]) => {
+                let key = keys::from_bytes(k);
+                let key_map = self.key_map.read().unwrap();
+                match key_map.get(key.as_str()) {
+                    Some(&row_id) if self.cache.row_tag(row_id).ok() == Some(HASH_TAG) => {
+                        RespValue::BulkString(Some(b"hashtable".to_vec()))
+                    }
+                    Some(&row_id) if self.cache.value_len(row_id).is_ok() => {
+                        RespValue::BulkString(Some(b"raw".to_vec()))
+                    }
+                    Some(_) => RespValue::BulkString(None),
+                    None if self.lists.read().unwrap().contains_key(key.as_str()) => {
+                        RespValue::BulkString(Some(b"linkedlist".to_vec()))
+                    }
+                    None => RespValue::BulkString(None),
+                }
+            }
+            ("FREQ", [RespValue::BulkString(Some(k))]) => {
+                if !self.cache.is_lfu() {
+                    return RespValue::Error(
+                        "ERR An LFU cache policy is not selected, access frequency not tracked"
+                            .to_string(),
+                    );
+                }
+                // Rows that are not cached have not been hit since they were read in
+                let key = keys::from_bytes(k);
+                let frequency = |row_id| self.cache.frequency(row_id).map_or(0, i64::from);
+                if let Some(&row_id) = self.key_map.read().unwrap().get(key.as_str()) {
+                    return RespValue::Integer(frequency(row_id));
+                }
+                match self.lists.read().unwrap().get(key.as_str()) {
+                    Some(rows) => RespValue::Integer(
+                        rows.iter()
+                            .map(|&row_id| frequency(row_id))
+                            .max()
+                            .unwrap_or(0),
+                    ),
+                    None => RespValue::BulkString(None),
+                }
+            }
+            (which @ ("CREATEDAT" | "UPDATEDAT"), [RespValue::BulkString(Some(k))]) => {
+                let key = keys::from_bytes(k);
+                let meta = self
+                    .key_map
+                    .read()
+                    .unwrap()
+                    .get(key.as_str())
+                    .and_then(|&row_id| self.cache.row_metadata(row_id));
+                match meta {
+                    Some(meta) if which == "CREATEDAT" => {
+                        RespValue::Integer(meta.created_at as i64)
+                    }
+                    Some(meta) => RespValue::Integer(meta.updated_at as i64),
+                    None => RespValue::BulkString(None),
+                }
+            }
+            _ => unknown_subcommand("OBJECT", &subcommand),
+        }
+    }
+
+    /// HELLO [protover]: switch the connection between RESP2 and RESP3
+    ///
+    /// Only the protocol version is taken, not the AUTH and SETNAME options.
+    /// The reply describes the server, as a map under RESP3.
+    fn handle_hello(&self, args: &[RespValue], session: &mut SessionState) -> RespValue {
+        match args {
+            [] => {}
+            [version] => match bulk_to_i64(version) {
+                Some(2) if session.tracking => {
+                    return RespValue::Error(
+                        "ERR CLIENT TRACKING needs RESP3, turn it off before HELLO 2".to_string(),
+                    )
+                }
+                Some(2) => session.resp3 = false,
+                Some(3) => session.resp3 = true,
+                Some(_) => {
+                    return RespValue::Error("NOPROTO unsupported protocol version".to_string())
+                }
+                None => {
+                    return RespValue::Error(
+                        "ERR Protocol version is not an integer or out of range".to_string(),
+                    )
+                }
+            },
+            _ => return RespValue::Error("ERR HELLO only takes the protocol version".to_string()),
+        }
+
+        let bulk = |s: &str| RespValue::BulkString(Some(s.as_bytes().to_vec()));
+        let id = session.subscriber.as_ref().map_or(0, |s| s.id as i64);
+        let fields = vec![
+            bulk("server"),
+            bulk("tstd"),
+            bulk("version"),
+            bulk(env!("CARGO_PKG_VERSION")),
+            bulk("proto"),
+            RespValue::Integer(if session.resp3 { 3 } else { 2 }),
+            bulk("id"),
+            RespValue::Integer(id),
+            bulk("mode"),
+            bulk("standalone"),
+            bulk("role"),
+            bulk("master"),
+            bulk("modules"),
+            RespValue::Array(Some(Vec::new())),
+        ];
+        if session.resp3 {
+            RespValue::Map(fields)
+        } else {
+            RespValue::Array(Some(fields))
+        }
+    }
+
+    fn handle_client(&self, args: &[RespValue], session: &mut SessionState) -> RespValue {
+        let subcommand = match args.first() {
+            Some(RespValue::BulkString(Some(s))) => String::from_utf8_lossy(s).to_uppercase(),
+            _ => {
+                return RespValue::Error(
+                    "ERR wrong number of arguments for 'client' command".to_string(),
+                )
+            }
+        };
+
+        match (subcommand.as_str(), &args[1..]) {
+            ("GETNAME", []) => match &session.client_name {
+                Some(name) => RespValue::BulkString(Some(name.as_bytes().to_vec())),
+                None => RespValue::BulkString(None),
+            },
+            ("TRACKING", [RespValue::BulkString(Some(mode))]) => {
+                let on = match String::from_utf8_lossy(mode).to_uppercase().as_str() {
+                    "ON" => true,
+                    "OFF" => false,
+                    _ => return RespValue::Error("ERR syntax error".to_string()),
+                };
+                // Invalidations are pushes, which only RESP3 clients can tell from replies
+                if on && !session.resp3 {
+                    return RespValue::Error(
+                        "ERR CLIENT TRACKING needs RESP3, send HELLO 3 first".to_string(),
+                    );
+                }
+                let Some(subscriber) = &session.subscriber else {
+                    return RespValue::Error(
+                        "ERR CLIENT TRACKING needs a client connection".to_string(),
+                    );
+                };
+                if on != session.tracking {
+                    if on {
+                        self.tracking.enable();
+                    } else {
+                        self.tracking.disable(subscriber.id);
+                    }
+                    session.tracking = on;
+                }
+                RespValue::SimpleString("OK".to_string())
+            }
+            ("SETNAME", [RespValue::BulkString(Some(name))]) => {
+                if name.iter().any(|&b| b <= b' ' || b > b'~') {
+                    return RespValue::Error(
+                        "ERR Client names cannot contain spaces, newlines or special characters."
+                            .to_string(),
+                    );
+                }
+                session.client_name = if name.is_empty() {
+                    None
+                } else {
+                    Some(String::from_utf8_lossy(name).to_string())
+                };
+                RespValue::SimpleString("OK".to_string())
+            }
+            _ => unknown_subcommand("CLIENT", &subcommand),
+        }
+    }
+
+    /// Persist everything, then rebuild the store, key map and lists from disk
+    fn debug_reload(&self) -> RespValue {
+        let mut key_map = self.key_map.write().unwrap();
+        let mut lists = self.lists.write().unwrap();
+        let mut expires = self.expires.write().unwrap();
+
+        Self::save_keymap_static(&self.keymap_path, &key_map, &expires);
+        Self::save_lists_static(&self.lists_path, &lists, &expires);
+
+        if let Err(e) = self.cache.reload() {
+            error!("DEBUG RELOAD failed: {}", e);
+            return RespValue::Error(format!("ERR Error trying to reload: {}", e));
+        }
+
+        let (loaded_keys, loaded_expires) = Self::load_keymap(&self.keymap_path);
+        *key_map = KeyMap::from(loaded_keys);
+        *expires = loaded_expires;
+        let (loaded_lists, list_deadlines) = Self::load_lists(&self.lists_path);
+        *lists = Lists::from(loaded_lists);
+        for (key, deadline) in list_deadlines {
+            expires.insert(key, deadline);
+        }
+        self.rebuild_bloom(&key_map, &lists);
+        info!(
+            "DEBUG RELOAD: reloaded {} keys",
+            key_map.len() + lists.len()
+        );
+
+        RespValue::SimpleString("OK".to_string())
+    }
+
+    /// Put, read back and delete a throwaway row, timing each step
+    ///
+    /// The row goes to a scratch store next to the data, under the same
+    /// size limit, so the steps hit the same disk without taking a row ID
+    /// in the live store; its directory is removed again afterwards.
+    fn debug_selftest(&self) -> RespValue {
+        let failed = |step: &str, e: String| {
+            error!("DEBUG SELFTEST {} failed: {}", step, e);
+            RespValue::Error(format!("ERR selftest {} failed: {}", step, e))
+        };
+
+        let id = self.selftests.fetch_add(1, Ordering::Relaxed);
+        let dir = self
+            .backup_config
+            .data_dir
+            .join(format!("{}{}", SELFTEST_DIR_PREFIX, id));
+        let store = match toonstoredb::ToonStore::open(&dir) {
+            Ok(store) => store,
+            Err(e) => {
+                let _ = std::fs::remove_dir_all(&dir);
+                return failed("open", e.to_string());
+            }
+        };
+        store.set_max_db_size(self.cache.max_db_size());
+        let result = Self::selftest_steps(&store);
+        drop(store);
+        if let Err(e) = std::fs::remove_dir_all(&dir) {
+            warn!("Failed to remove selftest directory {:?}: {}", dir, e);
+        }
+
+        match result {
+            Ok((put, get, delete)) => RespValue::SimpleString(format!(
+                "OK put {}us get {}us delete {}us",
+                put.as_micros(),
+                get.as_micros(),
+                delete.as_micros()
+            )),
+            Err((step, e)) => failed(step, e),
+        }
+    }
+
+    /// Time a put, a read back and a delete of one row in `store`
+    fn selftest_steps(
+        store: &toonstoredb::ToonStore,
+    ) -> Result<(Duration, Duration, Duration), (&'static str, String)> {
+        let started = Instant::now();
+        let row_id = store
+            .put(SELFTEST_VALUE)
+            .map_err(|e| ("put", e.to_string()))?;
+        let put = started.elapsed();
+
+        let started = Instant::now();
+        let read = store.get(row_id).map_err(|e| ("get", e.to_string()))?;
+        let get = started.elapsed();
+
+        let started = Instant::now();
+        store
+            .delete(row_id)
+            .map_err(|e| ("delete", e.to_string()))?;
+        let delete = started.elapsed();
+
+        if read != SELFTEST_VALUE {
+            return Err(("get", "row read back differs".to_string()));
+        }
+        Ok((put, get, delete))
+    }
+
+    fn handle_userstats(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 1 {
+            return RespValue::Error(
+                "ERR wrong number of arguments for 'userstats' command".to_string(),
+            );
+        }
+
+        let username = match &args[0] {
+            RespValue::BulkString(Some(u)) => String::from_utf8_lossy(u).to_string(),
+            _ => return RespValue::Error("ERR invalid username".to_string()),
+        };
+
+        let mut result = Vec::new();
+        for (command, count) in self.user_stats.for_user(&username) {
+            result.push(RespValue::BulkString(Some(command.into_bytes())));
+            result.push(RespValue::Integer(count as i64));
+        }
+        RespValue::Array(Some(result))
+    }
+
+    /// SIZEHISTOGRAM [SAMPLE count]: stored value lengths in power-of-two buckets
+    fn handle_sizehistogram(&self, args: &[RespValue]) -> RespValue {
+        let sample = match args {
+            [] => None,
+            [RespValue::BulkString(Some(option)), count]
+                if option.eq_ignore_ascii_case(b"SAMPLE") =>
+            {
+                match bulk_to_i64(count) {
+                    Some(count) if count > 0 => Some(count as usize),
+                    _ => {
+                        return RespValue::Error(
+                            "ERR value is not an integer or out of range".to_string(),
+                        )
+                    }
+                }
+            }
+            _ => return RespValue::Error("ERR syntax error".to_string()),
+        };
+
+        let histogram = match self.cache.size_histogram(sample) {
+            Ok(histogram) => histogram,
+            Err(e) => return RespValue::Error(format!("ERR {}", e)),
+        };
+        let buckets = histogram
+            .buckets
+            .iter()
+            .flat_map(|&(bound, rows)| {
+                [
+                    RespValue::Integer(bound as i64),
+                    RespValue::Integer(rows as i64),
+                ]
+            })
+            .collect();
+        let field = |name: &str| RespValue::BulkString(Some(name.as_bytes().to_vec()));
+        RespValue::Array(Some(vec![
+            field("rows"),
+            RespValue::Integer(histogram.rows as i64),
+            field("sampled"),
+            RespValue::Integer(histogram.sampled as i64),
+            field("min"),
+            RespValue::Integer(histogram.min as i64),
+            field("max"),
+            RespValue::Integer(histogram.max as i64),
+            field("mean"),
+            RespValue::BulkString(Some(format!("{:.2}", histogram.mean()).into_bytes())),
+            field("buckets"),
+            RespValue::Array(Some(buckets)),
+        ]))
+    }
+
+    /// HOTKEYS [count]: most read keys with their read counts
+    ///
+    /// Counts come from the cache, so only reads of cached rows are seen.
+    /// Every element of a list counts towards the list's key.
+    fn handle_hotkeys(&self, args: &[RespValue]) -> RespValue {
+        let count = match args {
+            [] => 10,
+            [arg] => match bulk_to_i64(arg) {
+                Some(count) if count >= 0 => count as usize,
+                _ => {
+                    return RespValue::Error(
+                        "ERR value is not an integer or out of range".to_string(),
+                    )
+                }
+            },
+            _ => {
+                return RespValue::Error(
+                    "ERR wrong number of arguments for 'hotkeys' command".to_string(),
+                )
+            }
+        };
+
+        let hot: HashMap<u64, u64> = self.cache.hot_keys(count).into_iter().collect();
+        let mut keys: HashMap<&str, u64> = HashMap::new();
+        let key_map = self.key_map.read().unwrap();
+        let lists = self.lists.read().unwrap();
+        for (key, row_id) in key_map.iter() {
+            if let Some(&hits) = hot.get(row_id) {
+                *keys.entry(key.as_str()).or_default() += hits;
+            }
+        }
+        for (key, rows) in lists.iter() {
+            for row_id in rows {
+                if let Some(&hits) = hot.get(row_id) {
+                    *keys.entry(key.as_str()).or_default() += hits;
+                }
+            }
+        }
+
+        let mut keys: Vec<(&str, u64)> = keys.into_iter().collect();
+        keys.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+        let mut result = Vec::new();
+        for (key, hits) in keys {
+            result.push(RespValue::BulkString(Some(
+                keys::to_bytes(key).into_owned(),
+            )));
+            result.push(RespValue::Integer(hits as i64));
+        }
+        RespValue::Array(Some(result))
+    }
+
+    fn handle_config(&self, args: &[RespValue]) -> RespValue {
+        if args.is_empty() {
+            return RespValue::Error(
+                "ERR wrong number of arguments for 'config' command".to_string(),
+            );
+        }
+
+        let subcommand = match &args[0] {
+            RespValue::BulkString(Some(cmd)) => String::from_utf8_lossy(cmd).to_uppercase(),
+            _ => return RespValue::Error("ERR invalid subcommand".to_string()),
+        };
+
+        match subcommand.as_str() {
+            "GET" => {
+                // CONFIG GET pattern
+                if args.len() != 2 {
+                    return RespValue::Error(
+                        "ERR wrong number of arguments for 'config|get' command".to_string(),
+                    );
+                }
+
+                let pattern = match &args[1] {
+                    RespValue::BulkString(Some(p)) => String::from_utf8_lossy(p).to_lowercase(),
+                    _ => return RespValue::Error("ERR invalid parameter".to_string()),
+                };
+
+                let mut result = Vec::new();
+                for param in CONFIG_PARAMS {
+                    if matches_pattern(param, &pattern) {
+                        let value = self.config_value(param);
+                        result.push(RespValue::BulkString(Some(param.as_bytes().to_vec())));
+                        result.push(RespValue::BulkString(Some(value.into_bytes())));
+                    }
+                }
+                RespValue::Array(Some(result))
+            }
+            "SET" => {
+                // CONFIG SET parameter value
+                if args.len() != 3 {
+                    return RespValue::Error(
+                        "ERR wrong number of arguments for 'config|set' command".to_string(),
+                    );
+                }
+
+                let param = match &args[1] {
+                    RespValue::BulkString(Some(p)) => String::from_utf8_lossy(p).to_lowercase(),
+                    _ => return RespValue::Error("ERR invalid parameter".to_string()),
+                };
+
+                let value = match &args[2] {
+                    RespValue::BulkString(Some(v)) => String::from_utf8_lossy(v).to_string(),
+                    _ => return RespValue::Error("ERR invalid value".to_string()),
+                };
+
+                match param.as_str() {
+                    // A cache started with capacity 0 has no LRU to resize
+                    "capacity" if self.cache.capacity() == 0 => RespValue::Error(
+                        "ERR the cache is disabled, restart with a capacity above 0".to_string(),
+                    ),
+                    "capacity" => match value.parse::<usize>() {
+                        Ok(capacity) if capacity > 0 => {
+                            self.cache.set_capacity(capacity);
+                            info!("CONFIG SET capacity {}", capacity);
+                            RespValue::SimpleString("OK".to_string())
+                        }
+                        _ => RespValue::Error(format!(
+                            "ERR Invalid argument '{}' for CONFIG SET 'capacity'",
+                            value
+                        )),
+                    },
+                    "cache-max-value-size" => match parse_memory(&value) {
+                        Ok(bytes) => {
+                            self.cache.set_max_value_size(bytes as usize);
+                            info!("CONFIG SET cache-max-value-size {}", bytes);
+                            RespValue::SimpleString("OK".to_string())
+                        }
+                        Err(e) => RespValue::Error(format!(
+                            "ERR Invalid argument '{}' for CONFIG SET 'cache-max-value-size': {}",
+                            value, e
+                        )),
+                    },
+                    "maxmemory" => match parse_memory(&value) {
+                        Ok(bytes) => {
+                            self.config.write().unwrap().maxmemory = bytes;
+                            info!("CONFIG SET maxmemory {}", bytes);
+                            RespValue::SimpleString("OK".to_string())
+                        }
+                        Err(e) => RespValue::Error(format!(
+                            "ERR Invalid argument '{}' for CONFIG SET 'maxmemory': {}",
+                            value, e
+                        )),
+                    },
+                    _ => RespValue::Error(format!(
+                        "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                        param
+                    )),
+                }
+            }
+            _ => unknown_subcommand("CONFIG", &subcommand),
+        }
+    }
+
+    /// Current value of a CONFIG parameter, formatted for CONFIG GET
+    fn config_value(&self, param: &str) -> String {
+        match param {
+            "capacity" => self.cache.capacity().to_string(),
+            "cache-max-value-size" => self.cache.max_value_size().to_string(),
+            "maxmemory" => self.config.read().unwrap().maxmemory.to_string(),
+            _ => String::new(),
+        }
+    }
+
+    /// Create a backup, replying once the archive is written and fsynced
+    ///
+    /// Runs under the exclusive transaction lock, so it blocks every other
+    /// client until the archive is done, as Redis SAVE does.
+    fn handle_save(&self, _args: &[RespValue]) -> RespValue {
+        match self.backup_config.create_backup(Some("manual")) {
+            Ok(path) => {
+                info!("Manual backup created: {:?}", path);
+                RespValue::SimpleString("OK".to_string())
+            }
+            Err(e) => {
+                error!("Failed to create backup: {}", e);
+                RespValue::Error(format!("ERR Failed to create backup: {}", e))
+            }
+        }
+    }
+
+    /// Create a backup in the background, replying right away
+    ///
+    /// The data directory is copied under the exclusive transaction lock
+    /// before the reply; only the tar and gzip of that copy run afterwards.
+    fn handle_bgsave(&self, _args: &[RespValue]) -> RespValue {
+        if self.bgsave_running.swap(true, Ordering::AcqRel) {
+            return RespValue::Error("ERR Background save already in progress".to_string());
+        }
+        let running = BgsaveRunning(Arc::clone(&self.bgsave_running));
+
+        let snapshot = match self.backup_config.snapshot() {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                error!("Background backup failed: {}", e);
+                return RespValue::Error(format!("ERR Failed to create backup: {}", e));
+            }
+        };
+
+        let backup_config = Arc::clone(&self.backup_config);
+        let job = move || {
+            let _running = running;
+            let archived = backup_config.archive(&snapshot, Some("bgsave"));
+            drop(snapshot);
+            match archived {
+                Ok(path) => info!("Background backup created: {:?}", path),
+                Err(e) => error!("Background backup failed: {}", e),
+            }
+        };
+
+        // Tar and gzip block, so keep them off the async workers
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(job)),
+            Err(_) => drop(std::thread::spawn(job)),
+        }
+        RespValue::SimpleString("Background saving started".to_string())
+    }
+
+    /// Back up the data directory outside any command, as the auto-backup does
+    ///
+    /// The copy is taken under the exclusive transaction lock; the archive is
+    /// written after it is released.
+    pub fn backup(&self, name: &str) -> anyhow::Result<PathBuf> {
+        let snapshot = {
+            let _txn = self.txn_lock.write().unwrap();
+            self.backup_config.snapshot()?
+        };
+        self.backup_config.archive(&snapshot, Some(name))
+    }
+
+    fn handle_backup(&self, args: &[RespValue], session: &SessionState) -> RespValue {
+        if let [RespValue::BulkString(Some(sub))] = args {
+            if sub.eq_ignore_ascii_case(b"LIST") {
+                return self.handle_backup_list(session);
+            }
+        }
+
+        let backup_name = if args.is_empty() {
+            "backup"
+        } else {
+            match &args[0] {
+                RespValue::BulkString(Some(n)) => match std::str::from_utf8(n) {
+                    Ok(s) => s,
+                    Err(_) => return RespValue::Error("ERR invalid backup name".to_string()),
+                },
+                _ => return RespValue::Error("ERR invalid backup name type".to_string()),
+            }
+        };
+
+        match self.backup_config.create_backup(Some(backup_name)) {
+            Ok(path) => {
+                info!("Named backup created: {:?}", path);
+                let filename = path
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .unwrap_or("unknown");
+                RespValue::BulkString(Some(filename.as_bytes().to_vec()))
+            }
+            Err(e) => {
+                error!("Failed to create backup: {}", e);
+                RespValue::Error(format!("ERR Failed to create backup: {}", e))
+            }
+        }
+    }
+
+    /// BACKUP LIST: `[filename, size_bytes, modified_unix_ts]` per backup,
+    /// newest first (admin only; an unknown modification time is 0)
+    fn handle_backup_list(&self, session: &SessionState) -> RespValue {
+        if session.user_role != Some(UserRole::Admin) {
+            return RespValue::Error(format!(
+                "NOPERM User '{}' does not have permission to execute 'backup|list'",
+                session.username()
+            ));
+        }
+
+        match self.backup_config.list_backups() {
+            Ok(backups) => RespValue::Array(Some(
+                backups
+                    .iter()
+                    .map(|backup| {
+                        let modified = backup
+                            .modified
+                            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+                            .map_or(0, |d| d.as_secs());
+                        RespValue::Array(Some(vec![
+                            RespValue::BulkString(Some(backup.filename.as_bytes().to_vec())),
+                            RespValue::Integer(backup.size as i64),
+                            RespValue::Integer(modified as i64),
+                        ]))
+                    })
+                    .collect(),
+            )),
+            Err(e) => {
+                error!("Failed to list backups: {}", e);
+                RespValue::Error(format!("ERR Failed to list backups: {}", e))
+            }
+        }
+    }
+
+    fn handle_restore(&self, args: &[RespValue]) -> RespValue {
+        if args.is_empty() {
+            return RespValue::Error(
+                "ERR wrong number of arguments for 'restore' command".to_string(),
+            );
+        }
+
+        let backup_file = match &args[0] {
+            RespValue::BulkString(Some(f)) => match String::from_utf8(f.clone()) {
+                Ok(s) => s,
+                Err(_) => return RespValue::Error("ERR invalid backup filename".to_string()),
+            },
+            _ => return RespValue::Error("ERR invalid backup filename type".to_string()),
+        };
+
+        // Security: Reject absolute paths to prevent path traversal
+        if std::path::Path::new(&backup_file).is_absolute() {
+            warn!("Rejected absolute path in RESTORE: {}", backup_file);
+            return RespValue::Error("ERR absolute paths not allowed".to_string());
+        }
+
+        // Security: Reject paths with ".." to prevent directory traversal
+        if backup_file.contains("..") {
+            warn!(
+                "Rejected path traversal attempt in RESTORE: {}",
+                backup_file
+            );
+            return RespValue::Error("ERR path traversal not allowed".to_string());
+        }
+
+        let backup_path = self.backup_config.backup_dir.join(&backup_file);
+
+        // Security: Validate the resolved path is within backup directory
+        let canonical = match backup_path.canonicalize() {
+            Ok(path) => path,
+            Err(_) => {
+                return RespValue::Error(format!("ERR Backup file not found: {}", backup_file));
+            }
+        };
+
+        let backup_canonical = match self.backup_config.backup_dir.canonicalize() {
+            Ok(path) => path,
+            Err(_) => {
+                error!("Failed to canonicalize backup directory");
+                return RespValue::Error("ERR backup directory error".to_string());
+            }
+        };
+
+        // Ensure the resolved path is within the backup directory
+        if !canonical.starts_with(&backup_canonical) {
+            warn!(
+                "Path traversal attempt blocked: {} -> {:?}",
+                backup_file, canonical
+            );
+            return RespValue::Error("ERR path traversal attempt blocked".to_string());
+        }
+
+        if !backup_path.exists() {
+            return RespValue::Error(format!("ERR Backup file not found: {:?}", backup_path));
+        }
+
+        warn!("Restoring from backup: {:?}", backup_path);
+
+        match self.backup_config.restore_backup(&backup_path) {
+            Ok(_) => {
+                info!("Database restored successfully from {:?}", backup_path);
+                RespValue::SimpleString("OK - Server restart recommended".to_string())
+            }
+            Err(e) => {
+                error!("Failed to restore backup: {}", e);
+                RespValue::Error(format!("ERR Failed to restore backup: {}", e))
+            }
+        }
+    }
+
+    /// Unix time of the last successful backup (0 if there is none)
+    fn handle_lastsave(&self) -> RespValue {
+        RespValue::Integer(self.backup_config.last_save())
+    }
+}
+
+/// Current time in milliseconds since the UNIX epoch
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// 40 random hex digits identifying this server process
+///
+/// Std's `RandomState` is seeded from the OS, once per process, so hashing
+/// a counter with it gives fresh bits on every start without a rand crate.
+fn generate_run_id() -> String {
+    use std::collections::hash_map::RandomState;
+    use std::hash::BuildHasher;
+
+    let state = RandomState::new();
+    let mut id: String = (0..3u64)
+        .map(|i| format!("{:016x}", state.hash_one(i)))
+        .collect();
+    id.truncate(40);
+    id
+}
+
+/// Reply for `<COMMAND> HELP`, if the command has help text
+fn help_reply(command: &str, arr: &[RespValue]) -> Option<RespValue> {
+    let [_, RespValue::BulkString(Some(sub))] = arr else {
+        return None;
+    };
+    if !sub.eq_ignore_ascii_case(b"HELP") {
+        return None;
+    }
+    let lines = help::lines(command)?;
+    Some(RespValue::Array(Some(
+        lines
+            .iter()
+            .map(|line| RespValue::SimpleString(line.to_string()))
+            .collect(),
+    )))
+}
+
+/// Reply for a subcommand the command does not implement
+fn unknown_subcommand(command: &str, subcommand: &str) -> RespValue {
+    RespValue::Error(format!(
+        "ERR Unknown subcommand or wrong number of arguments for '{}'. Try {} HELP.",
+        subcommand.to_lowercase(),
+        command
+    ))
+}
+
+/// Reply for a command run against a key of another type
+fn wrong_type() -> RespValue {
+    RespValue::Error(
+        "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+    )
+}
+
+/// Reply for a write refused because memory or the database is full
+fn oom() -> RespValue {
+    RespValue::Error("OOM command not allowed when used memory > 'maxmemory'".to_string())
+}
+
+/// Reply for a failed storage write
+///
+/// A full database is reported as OOM, like Redis at maxmemory, so clients
+/// treat it as a retryable condition rather than a bug.
+fn write_error(e: toonstoredb::Error) -> RespValue {
+    match e {
+        toonstoredb::Error::DatabaseFull(_) => oom(),
+        e => RespValue::Error(format!("ERR {}", e)),
+    }
+}
+
+/// Count a failed AUTH attempt against `who` in a table of lockouts
+fn record_login_failure<K: Hash + Eq + Clone>(
+    failures: &Mutex<LruCache<K, LoginBackoff>>,
+    who: K,
+    allowance: u32,
+    now: Instant,
+) {
+    let mut failures = failures.lock().unwrap();
+    let mut backoff = failures.get(&who).cloned().unwrap_or_default();
+    backoff.record_failure(allowance, now);
+    failures.put(who, backoff);
+}
+
+/// Extract a key argument
+fn key_arg(arg: &RespValue) -> Result<String, RespValue> {
+    match arg {
+        RespValue::BulkString(Some(k)) => Ok(keys::from_bytes(k)),
+        _ => Err(RespValue::Error("ERR invalid key type".to_string())),
+    }
+}
+
+/// Parse an integer argument
+fn bulk_to_i64(arg: &RespValue) -> Option<i64> {
+    match arg {
+        RespValue::BulkString(Some(v)) => std::str::from_utf8(v).ok()?.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Parse a floating point argument
+fn bulk_to_f64(arg: &RespValue) -> Option<f64> {
+    match arg {
+        RespValue::BulkString(Some(v)) => std::str::from_utf8(v).ok()?.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Extract a hash field name, which must fit in a TOON schema header
+fn hash_field(arg: &RespValue) -> Result<String, RespValue> {
+    let field = match arg {
+        RespValue::BulkString(Some(f)) => String::from_utf8(f.clone())
+            .map_err(|_| RespValue::Error("ERR invalid hash field".to_string()))?,
+        _ => return Err(RespValue::Error("ERR invalid hash field type".to_string())),
+    };
+
+    if field.is_empty() || field.trim() != field || field.contains([',', '{', '}', '\n', '\r']) {
+        return Err(RespValue::Error(format!(
+            "ERR invalid hash field '{}'",
+            field
+        )));
+    }
+
+    Ok(field)
+}
+
+/// Confirmation sent for each channel of SUBSCRIBE / UNSUBSCRIBE
+fn subscription_reply(kind: &str, channel: Option<&str>, count: usize) -> RespValue {
+    RespValue::Array(Some(vec![
+        RespValue::BulkString(Some(kind.as_bytes().to_vec())),
+        RespValue::BulkString(channel.map(|c| c.as_bytes().to_vec())),
+        RespValue::Integer(count as i64),
+    ]))
+}
+
+/// Return the last reply and queue the others to be sent ahead of it
+fn split_replies(mut replies: Vec<RespValue>, session: &mut SessionState) -> RespValue {
+    let last = replies.pop().unwrap_or(RespValue::Array(Some(vec![])));
+    session.extra_replies.extend(replies);
+    last
+}
+
+/// Build the COMMAND INFO reply for a single command
+fn command_info(spec: &CommandSpec) -> RespValue {
+    let flags = spec
+        .flags
+        .iter()
+        .map(|flag| RespValue::SimpleString(flag.to_string()))
+        .collect();
+
+    RespValue::Array(Some(vec![
+        RespValue::BulkString(Some(spec.name.to_lowercase().into_bytes())),
+        RespValue::Integer(spec.arity),
+        RespValue::Array(Some(flags)),
+        RespValue::Integer(spec.first_key),
+        RespValue::Integer(spec.last_key),
+        RespValue::Integer(spec.step),
+    ]))
+}
+
+/// Glob pattern matching for KEYS, SCAN MATCH and CONFIG GET, see `glob`
+fn matches_pattern(key: &str, pattern: &str) -> bool {
+    pattern == "*" || glob::stringmatch(&keys::to_bytes(pattern), &keys::to_bytes(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pubsub::Message;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_ping() {
+        let dir = TempDir::new().unwrap();
+        let cache = Arc::new(ToonCache::new(dir.path(), 100).unwrap());
+        let auth = Arc::new(AuthConfig::disabled());
+        let backup = Arc::new(BackupConfig::new(dir.path(), None::<&str>));
+        let handler = CommandHandler::new(
+            cache,
+            dir.path().to_str().unwrap(),
+            auth.clone(),
+            backup,
+            None,
+            ServerConfig::default(),
+        );
+        let mut session = SessionState::new(false);
+
+        let cmd = RespValue::Array(Some(vec![RespValue::BulkString(Some(b"PING".to_vec()))]));
+
+        let resp = handler.handle(cmd, &mut session);
+        assert_eq!(resp, RespValue::SimpleString("PONG".to_string()));
+    }
+
+    #[test]
+    fn test_echo() {
+        let dir = TempDir::new().unwrap();
+        let cache = Arc::new(ToonCache::new(dir.path(), 100).unwrap());
+        let auth = Arc::new(AuthConfig::disabled());
+        let backup = Arc::new(BackupConfig::new(dir.path(), None::<&str>));
+        let handler = CommandHandler::new(
+            cache,
+            dir.path().to_str().unwrap(),
+            auth.clone(),
+            backup,
+            None,
+            ServerConfig::default(),
+        );
+        let mut session = SessionState::new(false);
+
+        let cmd = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"ECHO".to_vec())),
+            RespValue::BulkString(Some(b"hello".to_vec())),
+        ]));
+
+        let resp = handler.handle(cmd, &mut session);
+        assert_eq!(resp, RespValue::BulkString(Some(b"hello".to_vec())));
+    }
+
+    #[test]
+    fn test_hello() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+
+        let RespValue::Array(Some(fields)) = handler.handle(command(&[b"HELLO"]), &mut session)
+        else {
+            panic!("expected a flat array under RESP2");
+        };
+        assert_eq!(fields[0], RespValue::BulkString(Some(b"server".to_vec())));
+        assert_eq!(fields[5], RespValue::Integer(2));
+
+        let RespValue::Map(fields) = handler.handle(command(&[b"HELLO", b"3"]), &mut session)
+        else {
+            panic!("expected a map under RESP3");
+        };
+        assert_eq!(fields[5], RespValue::Integer(3));
+        assert!(session.resp3);
+
+        for bad in [
+            &[&b"HELLO"[..], b"4"][..],
+            &[b"HELLO", b"three"],
+            &[b"HELLO", b"3", b"SETNAME", b"app"],
+        ] {
+            let resp = handler.handle(command(bad), &mut session);
+            assert!(matches!(resp, RespValue::Error(_)), "{:?}", resp);
+        }
+        assert!(session.resp3);
+
+        handler.handle(command(&[b"HELLO", b"2"]), &mut session);
+        assert!(!session.resp3);
+    }
+
+    #[test]
+    fn test_set_idempotent() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+
+        let first = handler.handle(
+            command(&[b"SET", b"k", b"a", b"IDEMPOTENT", b"t1"]),
+            &mut session,
+        );
+        assert_eq!(first, RespValue::SimpleString("OK".to_string()));
+        let rows = handler.cache.len();
+
+        // A retry replies the same without writing, even with another value
+        let retry = handler.handle(
+            command(&[b"SET", b"k", b"b", b"IDEMPOTENT", b"t1"]),
+            &mut session,
+        );
+        assert_eq!(retry, first);
+        assert_eq!(handler.cache.len(), rows);
+        let resp = handler.handle(command(&[b"GET", b"k"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(Some(b"a".to_vec())));
+
+        let other = handler.handle(
+            command(&[b"SET", b"k", b"b", b"IDEMPOTENT", b"t2"]),
+            &mut session,
+        );
+        assert_eq!(other, first);
+        assert_eq!(handler.cache.len(), rows + 1);
+        let resp = handler.handle(command(&[b"GET", b"k"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(Some(b"b".to_vec())));
+
+        let resp = handler.handle(command(&[b"SET", b"k", b"c", b"IDEMPOTENT"]), &mut session);
+        assert_eq!(resp, RespValue::Error("ERR syntax error".to_string()));
+
+        // A token only stands for the same user writing the same key
+        let rows = handler.cache.len();
+        handler.handle(
+            command(&[b"SET", b"other", b"x", b"IDEMPOTENT", b"t1"]),
+            &mut session,
+        );
+        let mut bob = SessionState::new(false);
+        bob.username = Some("bob".to_string());
+        handler.handle(
+            command(&[b"SET", b"k", b"bob", b"IDEMPOTENT", b"t1"]),
+            &mut bob,
+        );
+        assert_eq!(handler.cache.len(), rows + 2);
+        let resp = handler.handle(command(&[b"GET", b"k"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(Some(b"bob".to_vec())));
+
+        // Racing retries still write once
+        let rows = handler.cache.len();
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    let mut session = SessionState::new(false);
+                    let resp = handler.handle(
+                        command(&[b"SET", b"race", b"v", b"IDEMPOTENT", b"t3"]),
+                        &mut session,
+                    );
+                    assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+                });
+            }
+        });
+        assert_eq!(handler.cache.len(), rows + 1);
+    }
+
+    #[test]
+    fn test_set_and_get() {
+        let dir = TempDir::new().unwrap();
+        let cache = Arc::new(ToonCache::new(dir.path(), 100).unwrap());
+        let auth = Arc::new(AuthConfig::disabled());
+        let backup = Arc::new(BackupConfig::new(dir.path(), None::<&str>));
+        let handler = CommandHandler::new(
+            cache,
+            dir.path().to_str().unwrap(),
+            auth.clone(),
+            backup,
+            None,
+            ServerConfig::default(),
+        );
+        let mut session = SessionState::new(false);
+
+        // SET key value
+        let set_cmd = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"SET".to_vec())),
+            RespValue::BulkString(Some(b"mykey".to_vec())),
+            RespValue::BulkString(Some(b"myvalue".to_vec())),
+        ]));
+
+        let resp = handler.handle(set_cmd, &mut session);
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+    }
+
+    fn config_handler(dir: &TempDir) -> CommandHandler {
+        let cache = Arc::new(ToonCache::new(dir.path(), 100).unwrap());
+        let auth = Arc::new(AuthConfig::disabled());
+        let backup = Arc::new(BackupConfig::new(dir.path(), None::<&str>));
+        CommandHandler::new(
+            cache,
+            dir.path().to_str().unwrap(),
+            auth,
+            backup,
+            None,
+            ServerConfig::default(),
+        )
+    }
+
+    fn command(parts: &[&[u8]]) -> RespValue {
+        RespValue::Array(Some(
+            parts
+                .iter()
+                .map(|p| RespValue::BulkString(Some(p.to_vec())))
+                .collect(),
+        ))
+    }
+
+    #[test]
+    fn test_command_count_matches_dispatch() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+
+        let mut session = SessionState::new(false);
+        let resp = handler.handle(command(&[b"COMMAND", b"COUNT"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(COMMAND_TABLE.len() as i64));
+
+        // Every command in the table must be implemented
+        for spec in COMMAND_TABLE {
+            let mut session = SessionState::new(false);
+            let resp = handler.handle(command(&[spec.name.as_bytes()]), &mut session);
+            if let RespValue::Error(msg) = resp {
+                assert!(!msg.starts_with("ERR unknown command"), "{}", msg);
+            }
+        }
+    }
+
+    #[test]
+    fn test_command_info_and_docs() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+
+        let resp = handler.handle(
+            command(&[b"COMMAND", b"INFO", b"get", b"nosuch"]),
+            &mut session,
+        );
+        let RespValue::Array(Some(infos)) = resp else {
+            panic!("expected array");
+        };
+        assert_eq!(infos.len(), 2);
+        let RespValue::Array(Some(get)) = &infos[0] else {
+            panic!("expected spec array");
+        };
+        assert_eq!(get[0], RespValue::BulkString(Some(b"get".to_vec())));
+        assert_eq!(get[1], RespValue::Integer(2));
+        assert_eq!(infos[1], RespValue::Array(None));
+
+        let resp = handler.handle(command(&[b"COMMAND", b"DOCS", b"set"]), &mut session);
+        let RespValue::Array(Some(docs)) = resp else {
+            panic!("expected array");
+        };
+        assert_eq!(docs.len(), 2);
+        assert_eq!(docs[0], RespValue::BulkString(Some(b"set".to_vec())));
+    }
+
+    #[test]
+    fn test_multi_queue_checks_arity() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+
+        handler.handle(command(&[b"MULTI"]), &mut session);
+        let resp = handler.handle(command(&[b"GET"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(ref e) if e.contains("wrong number of arguments")));
+
+        let resp = handler.handle(command(&[b"EXEC"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(ref e) if e.starts_with("EXECABORT")));
+    }
+
+    #[test]
+    fn test_config_get() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+
+        let resp = handler.handle(command(&[b"CONFIG", b"GET", b"maxmemory"]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::Array(Some(vec![
+                RespValue::BulkString(Some(b"maxmemory".to_vec())),
+                RespValue::BulkString(Some(b"0".to_vec())),
+            ]))
+        );
+
+        // Unknown parameters yield an empty array rather than an error
+        let resp = handler.handle(command(&[b"CONFIG", b"GET", b"save"]), &mut session);
+        assert_eq!(resp, RespValue::Array(Some(vec![])));
+    }
+
+    #[test]
+    fn test_config_set() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+
+        let resp = handler.handle(
+            command(&[b"CONFIG", b"SET", b"capacity", b"5"]),
+            &mut session,
+        );
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+        assert_eq!(handler.cache.capacity(), 5);
+
+        let resp = handler.handle(
+            command(&[b"CONFIG", b"SET", b"cache-max-value-size", b"1kb"]),
+            &mut session,
+        );
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+        assert_eq!(handler.cache.max_value_size(), 1024);
+        let resp = handler.handle(
+            command(&[b"CONFIG", b"GET", b"cache-max-value-size"]),
+            &mut session,
+        );
+        assert_eq!(
+            resp,
+            RespValue::Array(Some(vec![
+                RespValue::BulkString(Some(b"cache-max-value-size".to_vec())),
+                RespValue::BulkString(Some(b"1024".to_vec())),
+            ]))
+        );
+
+        let resp = handler.handle(
+            command(&[b"CONFIG", b"SET", b"maxmemory", b"1"]),
+            &mut session,
+        );
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+
+        // The data file header alone exceeds 1 byte, so writes are refused
+        let resp = handler.handle(command(&[b"SET", b"k", b"v"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(ref e) if e.starts_with("OOM")));
+
+        let resp = handler.handle(
+            command(&[b"CONFIG", b"SET", b"nosuchparam", b"1"]),
+            &mut session,
+        );
+        assert!(matches!(resp, RespValue::Error(_)));
+    }
+
+    #[test]
+    fn test_database_full_is_oom() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+
+        // maxmemory stays off, so only the store's own limit refuses the write
+        handler.cache.set_max_db_size(handler.cache.db_size() + 16);
+        let resp = handler.handle(command(&[b"SET", b"k", b"v"]), &mut session);
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+        let resp = handler.handle(command(&[b"SET", b"big", &[b'x'; 64]]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::Error("OOM command not allowed when used memory > 'maxmemory'".to_string())
+        );
+        let resp = handler.handle(command(&[b"RPUSH", b"l", &[b'x'; 64]]), &mut session);
+        assert!(matches!(resp, RespValue::Error(ref e) if e.starts_with("OOM")));
+    }
+
+    #[test]
+    fn test_eval() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+        handler.handle(command(&[b"SET", b"mykey", b"hello"]), &mut session);
+
+        let get = handler.handle(command(&[b"GET", b"mykey"]), &mut session);
+        let resp = handler.handle(
+            command(&[b"EVAL", b"GET KEYS[1]", b"1", b"mykey"]),
+            &mut session,
+        );
+        assert_eq!(resp, get);
+
+        let resp = handler.handle(
+            command(&[
+                b"EVAL",
+                b"SET KEYS[1] ARGV[1]; STRLEN KEYS[1]",
+                b"1",
+                b"k",
+                b"four",
+            ]),
+            &mut session,
+        );
+        assert_eq!(resp, RespValue::Integer(4));
+
+        // Statements are all checked before any runs
+        let resp = handler.handle(
+            command(&[b"EVAL", b"SET k changed; NOSUCHCMD", b"0"]),
+            &mut session,
+        );
+        assert!(matches!(resp, RespValue::Error(_)));
+        let resp = handler.handle(command(&[b"GET", b"k"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(Some(b"four".to_vec())));
+
+        for bad in [&b"EVAL x"[..], b"MULTI", b"GET KEYS[2]", b""] {
+            let resp = handler.handle(command(&[b"EVAL", bad, b"1", b"k"]), &mut session);
+            assert!(matches!(resp, RespValue::Error(_)), "{:?}", resp);
+        }
+        let resp = handler.handle(command(&[b"EVAL", b"GET k", b"2", b"k"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(_)));
+
+        // Inside MULTI the script is queued and runs under EXEC
+        handler.handle(command(&[b"MULTI"]), &mut session);
+        handler.handle(command(&[b"EVAL", b"GET k", b"0"]), &mut session);
+        let resp = handler.handle(command(&[b"EXEC"]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::Array(Some(vec![RespValue::BulkString(Some(b"four".to_vec()))]))
+        );
+    }
+
+    #[test]
+    fn test_eval_is_atomic() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut session = SessionState::new(false);
+                for _ in 0..200 {
+                    handler.handle(command(&[b"SET", b"k", b"other"]), &mut session);
+                }
+            });
+            let mut session = SessionState::new(false);
+            for _ in 0..200 {
+                let resp = handler.handle(
+                    command(&[b"EVAL", b"SET KEYS[1] mine; GET KEYS[1]", b"1", b"k"]),
+                    &mut session,
+                );
+                assert_eq!(resp, RespValue::BulkString(Some(b"mine".to_vec())));
+            }
+        });
+    }
+
+    #[test]
+    fn test_countby() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+
+        handler.handle(
+            command(&[b"HSET", b"u1", b"city", b"Paris", b"age", b"30"]),
+            &mut session,
+        );
+        handler.handle(command(&[b"HSET", b"u2", b"city", b"Oslo"]), &mut session);
+        handler.handle(command(&[b"HSET", b"u3", b"city", b"Paris"]), &mut session);
+        handler.handle(command(&[b"SET", b"s", b"Paris"]), &mut session);
+
+        let resp = handler.handle(command(&[b"COUNTBY", b"city", b"Paris"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(2));
+        let resp = handler.handle(command(&[b"COUNTBY", b"city", b"Rome"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(0));
+
+        handler.handle(command(&[b"DEL", b"u1"]), &mut session);
+        let resp = handler.handle(command(&[b"COUNTBY", b"city", b"Paris"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(1));
+
+        let resp = handler.handle(command(&[b"COUNTBY", b"city"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(_)));
+    }
+
+    #[test]
+    fn test_dumpall() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+
+        handler.handle(command(&[b"SET", b"a", b"1"]), &mut session);
+        handler.handle(command(&[b"SET", b"b", b"2"]), &mut session);
+        handler.handle(command(&[b"RPUSH", b"l", b"x"]), &mut session);
+        handler.handle(command(&[b"DEL", b"b"]), &mut session);
+
+        let resp = handler.handle(command(&[b"DUMPALL", b"0", b"COUNT", b"2"]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::Array(Some(vec![
+                RespValue::BulkString(Some(b"2".to_vec())),
+                RespValue::Array(Some(vec![
+                    RespValue::Integer(0),
+                    RespValue::BulkString(Some(b"1".to_vec())),
+                ])),
+            ]))
+        );
+        let resp = handler.handle(command(&[b"DUMPALL", b"2", b"COUNT", b"2"]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::Array(Some(vec![
+                RespValue::BulkString(Some(b"0".to_vec())),
+                RespValue::Array(Some(vec![
+                    RespValue::Integer(2),
+                    RespValue::BulkString(Some(b"x".to_vec())),
+                ])),
+            ]))
+        );
+
+        let resp = handler.handle(command(&[b"DUMPALL", b"x"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(_)));
+        let resp = handler.handle(command(&[b"DUMPALL", b"0", b"COUNT"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(_)));
+    }
+
+    #[test]
+    fn test_scan_type() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+
+        for i in 0..10 {
+            let key = format!("s{}", i);
+            handler.handle(command(&[b"SET", key.as_bytes(), b"v"]), &mut session);
+            let key = format!("h{}", i);
+            handler.handle(
+                command(&[b"HSET", key.as_bytes(), b"f", b"v"]),
+                &mut session,
+            );
+        }
+        handler.handle(command(&[b"RPUSH", b"l", b"a", b"b"]), &mut session);
+
+        let scan_all = |args: &[&[u8]]| {
+            let mut session = SessionState::new(false);
+            let mut cursor = b"0".to_vec();
+            let mut keys = Vec::new();
+            loop {
+                let mut cmd: Vec<&[u8]> = vec![b"SCAN", &cursor];
+                cmd.extend_from_slice(args);
+                let RespValue::Array(Some(reply)) = handler.handle(command(&cmd), &mut session)
+                else {
+                    panic!("SCAN failed");
+                };
+                let [RespValue::BulkString(Some(next)), RespValue::Array(Some(batch))] = &reply[..]
+                else {
+                    panic!("unexpected SCAN reply {:?}", reply);
+                };
+                assert!(batch.len() <= 3);
+                for key in batch {
+                    if let RespValue::BulkString(Some(key)) = key {
+                        keys.push(String::from_utf8(key.clone()).unwrap());
+                    }
+                }
+                cursor = next.clone();
+                if cursor == b"0" {
+                    break;
+                }
+            }
+            keys.sort();
+            keys
+        };
+
+        let hashes: Vec<String> = (0..10).map(|i| format!("h{}", i)).collect();
+        assert_eq!(scan_all(&[b"COUNT", b"3", b"TYPE", b"hash"]), hashes);
+        assert_eq!(scan_all(&[b"COUNT", b"3", b"TYPE", b"list"]), vec!["l"]);
+        assert_eq!(scan_all(&[b"COUNT", b"3"]).len(), 21);
+        assert_eq!(
+            scan_all(&[b"MATCH", b"s1*", b"COUNT", b"3", b"TYPE", b"string"]),
+            vec!["s1"]
+        );
+
+        let resp = handler.handle(command(&[b"SCAN", b"0", b"TYPE", b"zset"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(_)));
+        let resp = handler.handle(command(&[b"SCAN", b"0", b"COUNT"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(_)));
+    }
+
+    #[test]
+    fn test_scan_ordered_is_deterministic() {
+        let scan_ordered = || {
+            let dir = TempDir::new().unwrap();
+            let handler = config_handler(&dir);
+            let mut session = SessionState::new(false);
+            for i in 0..50 {
+                let key = format!("key:{}", i);
+                handler.handle(command(&[b"SET", key.as_bytes(), b"v"]), &mut session);
+            }
+            handler.handle(command(&[b"RPUSH", b"queue", b"a", b"b"]), &mut session);
+            handler.handle(command(&[b"SET", b"key:0", b"moved"]), &mut session);
+
+            let mut cursor = b"0".to_vec();
+            let mut keys = Vec::new();
+            loop {
+                let cmd = command(&[b"SCAN", &cursor, b"COUNT", b"7", b"ORDERED"]);
+                let RespValue::Array(Some(reply)) = handler.handle(cmd, &mut session) else {
+                    panic!("SCAN failed");
+                };
+                let [RespValue::BulkString(Some(next)), RespValue::Array(Some(batch))] = &reply[..]
+                else {
+                    panic!("unexpected SCAN reply {:?}", reply);
+                };
+                keys.extend(batch.iter().cloned());
+                cursor = next.clone();
+                if cursor == b"0" {
+                    break;
+                }
+            }
+            keys
+        };
+
+        // Separate key maps iterate in different orders, ORDERED scans do not
+        let first = scan_ordered();
+        assert_eq!(first, scan_ordered());
+
+        // Keys come back in the order they were last written
+        let mut expected: Vec<String> = (1..50).map(|i| format!("key:{}", i)).collect();
+        expected.push("queue".to_string());
+        expected.push("key:0".to_string());
+        let expected: Vec<RespValue> = expected
+            .into_iter()
+            .map(|k| RespValue::BulkString(Some(k.into_bytes())))
+            .collect();
+        assert_eq!(first, expected);
+    }
+
+    #[test]
+    fn test_keyspace_info_samples_ttls() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+        assert_eq!(
+            handler.keyspace_info(10),
+            KeyspaceInfo {
+                keys: 0,
+                expires: 0,
+                avg_ttl: 0
+            }
+        );
+
+        for i in 0..40 {
+            let key = format!("key:{}", i);
+            handler.handle(command(&[b"SET", key.as_bytes(), b"v"]), &mut session);
+            if i % 4 == 0 {
+                let ttl: &[u8] = if i % 8 == 0 { b"100" } else { b"200" };
+                handler.handle(command(&[b"EXPIRE", key.as_bytes(), ttl]), &mut session);
+            }
+        }
+        handler.handle(command(&[b"RPUSH", b"queue", b"a"]), &mut session);
+        handler.handle(command(&[b"DEL", b"key:0"]), &mut session);
+
+        let info = handler.keyspace_info(5);
+        let resp = handler.handle(command(&[b"DBSIZE"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(info.keys as i64));
+        assert_eq!(info.keys, 40);
+        assert_eq!(info.expires, 9);
+        // Every sampled TTL is 100s or 200s, less the time the test took
+        assert!(
+            (90_000..=200_000).contains(&info.avg_ttl),
+            "{}",
+            info.avg_ttl
+        );
+        // With no more than `sample` TTLs, all nine are averaged: four of
+        // 100s (key:0 is gone) and five of 200s
+        let info = handler.keyspace_info(9);
+        assert!(
+            (150_000..=155_556).contains(&info.avg_ttl),
+            "{}",
+            info.avg_ttl
+        );
+    }
+
+    #[test]
+    fn test_failed_set_keeps_old_list() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+        handler.handle(command(&[b"RPUSH", b"queue", b"a", b"b"]), &mut session);
+
+        let resp = handler.set_string("queue".to_string(), b"too long", 4);
+        assert!(matches!(resp, RespValue::Error(_)), "{:?}", resp);
+        let resp = handler.handle(command(&[b"LRANGE", b"queue", b"0", b"-1"]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::Array(Some(vec![
+                RespValue::BulkString(Some(b"a".to_vec())),
+                RespValue::BulkString(Some(b"b".to_vec())),
+            ]))
+        );
+
+        let resp = handler.handle(command(&[b"SET", b"queue", b"v"]), &mut session);
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+        let resp = handler.handle(command(&[b"GET", b"queue"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(Some(b"v".to_vec())));
+        let resp = handler.handle(command(&[b"LLEN", b"queue"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(ref e) if e.starts_with("WRONGTYPE")));
+    }
+
+    #[test]
+    fn test_countkeys() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+        for i in 0..12 {
+            let key = format!("user:{}", i);
+            handler.handle(command(&[b"SET", key.as_bytes(), b"v"]), &mut session);
+        }
+        handler.handle(
+            command(&[b"HSET", b"user:profile", b"f", b"v"]),
+            &mut session,
+        );
+        handler.handle(command(&[b"RPUSH", b"user:queue", b"a"]), &mut session);
+        handler.handle(command(&[b"SET", b"session:1", b"v"]), &mut session);
+        handler.handle(command(&[b"SET", b"users", b"v"]), &mut session);
+
+        let resp = handler.handle(command(&[b"COUNTKEYS", b"user:*"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(14));
+        let resp = handler.handle(command(&[b"COUNTKEYS", b"user:?"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(10));
+        let resp = handler.handle(command(&[b"COUNTKEYS", b"nothing:*"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(0));
+
+        let all = handler.handle(command(&[b"COUNTKEYS", b"*"]), &mut session);
+        assert_eq!(all, handler.handle(command(&[b"DBSIZE"]), &mut session));
+        assert_eq!(all, RespValue::Integer(16));
+
+        let resp = handler.handle(command(&[b"COUNTKEYS"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(ref e) if e.contains("wrong number")));
+    }
+
+    #[test]
+    fn test_binary_safe_keys() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+        let key: &[u8] = b"bin:\xff\xfe\tkey\n";
+
+        let resp = handler.handle(command(&[b"SET", key, b"v"]), &mut session);
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+        handler.handle(command(&[b"RPUSH", b"list:\xff", b"a"]), &mut session);
+        let resp = handler.handle(command(&[b"GET", key]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(Some(b"v".to_vec())));
+        let resp = handler.handle(command(&[b"GET", b"bin:\xff"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(None));
+
+        // Listed with the bytes they were set with, and matched byte-wise
+        let resp = handler.handle(command(&[b"KEYS", b"bin:\xff*"]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::Array(Some(vec![RespValue::BulkString(Some(key.to_vec()))]))
+        );
+        let resp = handler.handle(command(&[b"KEYS", b"list:?"]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::Array(Some(vec![RespValue::BulkString(Some(
+                b"list:\xff".to_vec()
+            ))]))
+        );
+        let resp = handler.handle(command(&[b"SCAN", b"0", b"ORDERED"]), &mut session);
+        let RespValue::Array(Some(reply)) = resp else {
+            panic!("SCAN failed");
+        };
+        assert_eq!(
+            reply[1],
+            RespValue::Array(Some(vec![
+                RespValue::BulkString(Some(key.to_vec())),
+                RespValue::BulkString(Some(b"list:\xff".to_vec())),
+            ]))
+        );
+
+        // The key map files keep them intact across a restart
+        drop(handler);
+        let handler = config_handler(&dir);
+        let resp = handler.handle(command(&[b"GET", key]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(Some(b"v".to_vec())));
+        let resp = handler.handle(
+            command(&[b"LRANGE", b"list:\xff", b"0", b"-1"]),
+            &mut session,
+        );
+        assert_eq!(
+            resp,
+            RespValue::Array(Some(vec![RespValue::BulkString(Some(b"a".to_vec()))]))
+        );
+        let resp = handler.handle(command(&[b"DEL", key, b"list:\xff"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(2));
+    }
+
+    #[test]
+    fn test_hotkeys() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+
+        handler.handle(command(&[b"SET", b"hot", b"v"]), &mut session);
+        handler.handle(command(&[b"SET", b"cold", b"v"]), &mut session);
+        handler.handle(command(&[b"SET", b"unread", b"v"]), &mut session);
+        handler.handle(command(&[b"GET", b"cold"]), &mut session);
+        for _ in 0..20 {
+            handler.handle(command(&[b"GET", b"hot"]), &mut session);
+        }
+
+        let resp = handler.handle(command(&[b"HOTKEYS", b"2"]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::Array(Some(vec![
+                RespValue::BulkString(Some(b"hot".to_vec())),
+                RespValue::Integer(20),
+                RespValue::BulkString(Some(b"cold".to_vec())),
+                RespValue::Integer(1),
+            ]))
+        );
+
+        let resp = handler.handle(command(&[b"HOTKEYS", b"-1"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(_)));
+    }
+
+    #[test]
+    fn test_bloom_filter_rejects_absent_keys() {
+        let dir = TempDir::new().unwrap();
+        let bloom_handler = |dir: &TempDir| {
+            let config = ServerConfig {
+                bloom_filter_keys: 1000,
+                ..ServerConfig::default()
+            };
+            CommandHandler::new(
+                Arc::new(ToonCache::new(dir.path(), 100).unwrap()),
+                dir.path().to_str().unwrap(),
+                Arc::new(AuthConfig::disabled()),
+                Arc::new(BackupConfig::new(dir.path(), None::<&str>)),
+                None,
+                config,
+            )
+        };
+        let handler = bloom_handler(&dir);
+        let mut session = SessionState::new(false);
+        handler.handle(command(&[b"SET", b"present", b"v"]), &mut session);
+        handler.handle(command(&[b"HSET", b"hash", b"f", b"v"]), &mut session);
+        handler.handle(command(&[b"RPUSH", b"list", b"x"]), &mut session);
+
+        let bloom = handler.bloom.as_ref().unwrap();
+        assert!(!bloom.may_contain("absent"));
+        for key in ["present", "hash", "list"] {
+            assert!(bloom.may_contain(key), "{} ruled out", key);
+        }
+        let resp = handler.handle(command(&[b"GET", b"absent"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(None));
+        let resp = handler.handle(command(&[b"GET", b"present"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(Some(b"v".to_vec())));
+        let resp = handler.handle(command(&[b"GET", b"list"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(ref e) if e.starts_with("WRONGTYPE")));
+
+        // Rebuilt on reload and on restart, cleared by FLUSHDB
+        handler.handle(command(&[b"DEBUG", b"RELOAD"]), &mut session);
+        let resp = handler.handle(command(&[b"GET", b"present"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(Some(b"v".to_vec())));
+        drop(handler);
+        let handler = bloom_handler(&dir);
+        assert!(handler.bloom.as_ref().unwrap().may_contain("present"));
+        handler.handle(command(&[b"FLUSHDB"]), &mut session);
+        assert!(!handler.bloom.as_ref().unwrap().may_contain("present"));
+    }
+
+    #[test]
+    fn test_sizehistogram() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+        handler.handle(command(&[b"SET", b"a", b"abc"]), &mut session);
+        handler.handle(command(&[b"SET", b"b", b"abcd"]), &mut session);
+        handler.handle(command(&[b"SET", b"c", &[b'x'; 7]]), &mut session);
+
+        let bulk = |s: &str| RespValue::BulkString(Some(s.as_bytes().to_vec()));
+        let resp = handler.handle(command(&[b"SIZEHISTOGRAM"]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::Array(Some(vec![
+                bulk("rows"),
+                RespValue::Integer(3),
+                bulk("sampled"),
+                RespValue::Integer(0),
+                bulk("min"),
+                RespValue::Integer(3),
+                bulk("max"),
+                RespValue::Integer(7),
+                bulk("mean"),
+                bulk("4.67"),
+                bulk("buckets"),
+                RespValue::Array(Some(
+                    [1, 0, 2, 0, 4, 2, 8, 1]
+                        .into_iter()
+                        .map(RespValue::Integer)
+                        .collect()
+                )),
+            ]))
+        );
+
+        let resp = handler.handle(command(&[b"SIZEHISTOGRAM", b"sample", b"1"]), &mut session);
+        assert!(
+            matches!(resp, RespValue::Array(Some(ref fields)) if fields[1] == RespValue::Integer(1))
+        );
+        let resp = handler.handle(command(&[b"SIZEHISTOGRAM", b"SAMPLE", b"0"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(_)));
+    }
+
+    #[test]
+    fn test_del_counts_keys_deleted() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+
+        handler.handle(command(&[b"SET", b"s", b"v"]), &mut session);
+        handler.handle(command(&[b"HSET", b"h", b"f", b"v"]), &mut session);
+        handler.handle(command(&[b"RPUSH", b"l", b"a", b"b"]), &mut session);
+        handler.handle(command(&[b"SET", b"u", b"v"]), &mut session);
+
+        // A key named twice is deleted, and counted, once
+        let resp = handler.handle(
+            command(&[b"DEL", b"s", b"missing", b"h", b"s", b"l"]),
+            &mut session,
+        );
+        assert_eq!(resp, RespValue::Integer(3));
+        let resp = handler.handle(command(&[b"EXISTS", b"s", b"h", b"l"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(0));
+        assert_eq!(handler.cache.live_len(), 1);
+
+        let resp = handler.handle(command(&[b"DEL", b"s"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(0));
+        let resp = handler.handle(command(&[b"UNLINK", b"u", b"u", b"s"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(1));
+        let resp = handler.handle(command(&[b"GET", b"u"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(None));
+        let resp = handler.handle(command(&[b"UNLINK"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(ref e) if e.contains("wrong number")));
+    }
+
+    #[test]
+    fn test_dbsize_after_deletes_and_reopen() {
+        let dir = TempDir::new().unwrap();
+
+        {
+            let handler = config_handler(&dir);
+            let mut session = SessionState::new(false);
+            for key in ["a", "b", "c", "d", "e"] {
+                handler.handle(command(&[b"SET", key.as_bytes(), b"v"]), &mut session);
+            }
+            let resp = handler.handle(command(&[b"DEL", b"b", b"d"]), &mut session);
+            assert_eq!(resp, RespValue::Integer(2));
+
+            let resp = handler.handle(command(&[b"DBSIZE"]), &mut session);
+            assert_eq!(resp, RespValue::Integer(3));
+            assert_eq!(handler.cache.live_len(), 3);
+        }
+
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+        let resp = handler.handle(command(&[b"DBSIZE"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(3));
+        assert_eq!(handler.cache.live_len(), 3);
+    }
+
+    #[test]
+    fn test_multi_exec() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+        let queued = RespValue::SimpleString("QUEUED".to_string());
+
+        let resp = handler.handle(command(&[b"MULTI"]), &mut session);
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+
+        let resp = handler.handle(command(&[b"SET", b"a", b"1"]), &mut session);
+        assert_eq!(resp, queued);
+        let resp = handler.handle(command(&[b"SET", b"b", b"2"]), &mut session);
+        assert_eq!(resp, queued);
+        let resp = handler.handle(command(&[b"GET", b"a"]), &mut session);
+        assert_eq!(resp, queued);
+
+        // Nothing is applied until EXEC
+        let mut other = SessionState::new(false);
+        let resp = handler.handle(command(&[b"GET", b"a"]), &mut other);
+        assert_eq!(resp, RespValue::BulkString(None));
+
+        let resp = handler.handle(command(&[b"EXEC"]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::Array(Some(vec![
+                RespValue::SimpleString("OK".to_string()),
+                RespValue::SimpleString("OK".to_string()),
+                RespValue::BulkString(Some(b"1".to_vec())),
+            ]))
+        );
+        assert!(!session.in_multi());
+
+        let resp = handler.handle(command(&[b"GET", b"b"]), &mut other);
+        assert_eq!(resp, RespValue::BulkString(Some(b"2".to_vec())));
+    }
+
+    #[test]
+    fn test_multi_queue_error_aborts_exec() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+
+        handler.handle(command(&[b"MULTI"]), &mut session);
+        handler.handle(command(&[b"SET", b"a", b"1"]), &mut session);
+        let resp = handler.handle(command(&[b"NOSUCHCMD"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(_)));
+
+        let resp = handler.handle(command(&[b"EXEC"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(ref e) if e.starts_with("EXECABORT")));
+
+        // The SET queued before the error was never applied
+        let resp = handler.handle(command(&[b"GET", b"a"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(None));
+    }
+
+    #[test]
+    fn test_multi_discard() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+
+        let resp = handler.handle(command(&[b"EXEC"]), &mut session);
+        assert_eq!(resp, RespValue::Error("ERR EXEC without MULTI".to_string()));
+
+        handler.handle(command(&[b"MULTI"]), &mut session);
+        handler.handle(command(&[b"SET", b"a", b"1"]), &mut session);
+        let resp = handler.handle(command(&[b"DISCARD"]), &mut session);
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+
+        let resp = handler.handle(command(&[b"GET", b"a"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(None));
+    }
+
+    #[test]
+    fn test_watch_aborts_exec_on_concurrent_change() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut watcher = SessionState::new(false);
+        let mut writer = SessionState::new(false);
+
+        handler.handle(command(&[b"SET", b"k", b"original"]), &mut writer);
+
+        let resp = handler.handle(command(&[b"WATCH", b"k"]), &mut watcher);
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+
+        // Another connection modifies the watched key
+        handler.handle(command(&[b"SET", b"k", b"changed"]), &mut writer);
+
+        handler.handle(command(&[b"MULTI"]), &mut watcher);
+        handler.handle(command(&[b"SET", b"k", b"from-txn"]), &mut watcher);
+        handler.handle(command(&[b"SET", b"other", b"from-txn"]), &mut watcher);
+        let resp = handler.handle(command(&[b"EXEC"]), &mut watcher);
+        assert_eq!(resp, RespValue::Array(None));
+
+        // None of the queued writes were applied
+        let resp = handler.handle(command(&[b"GET", b"k"]), &mut writer);
+        assert_eq!(resp, RespValue::BulkString(Some(b"changed".to_vec())));
+        let resp = handler.handle(command(&[b"GET", b"other"]), &mut writer);
+        assert_eq!(resp, RespValue::BulkString(None));
+    }
+
+    #[test]
+    fn test_watch_unchanged_and_unwatch() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut watcher = SessionState::new(false);
+        let mut writer = SessionState::new(false);
+
+        // Unchanged watched key: EXEC applies
+        handler.handle(command(&[b"WATCH", b"k"]), &mut watcher);
+        handler.handle(command(&[b"MULTI"]), &mut watcher);
+        handler.handle(command(&[b"SET", b"k", b"1"]), &mut watcher);
+        let resp = handler.handle(command(&[b"EXEC"]), &mut watcher);
+        assert_eq!(
+            resp,
+            RespValue::Array(Some(vec![RespValue::SimpleString("OK".to_string())]))
+        );
+
+        // UNWATCH forgets the key, so a later change does not abort
+        handler.handle(command(&[b"WATCH", b"k"]), &mut watcher);
+        handler.handle(command(&[b"UNWATCH"]), &mut watcher);
+        handler.handle(command(&[b"DEL", b"k"]), &mut writer);
+        handler.handle(command(&[b"MULTI"]), &mut watcher);
+        handler.handle(command(&[b"SET", b"k", b"2"]), &mut watcher);
+        let resp = handler.handle(command(&[b"EXEC"]), &mut watcher);
+        assert!(matches!(resp, RespValue::Array(Some(_))));
+
+        // Only watched keys are versioned, and only while someone watches them
+        handler.handle(command(&[b"SET", b"other", b"1"]), &mut writer);
+        assert!(handler.key_versions.read().unwrap().is_empty());
+        handler.handle(command(&[b"WATCH", b"k", b"k"]), &mut watcher);
+        handler.handle(command(&[b"WATCH", b"k"]), &mut writer);
+        handler.handle(command(&[b"SET", b"k", b"3"]), &mut writer);
+        handler.handle(command(&[b"UNWATCH"]), &mut writer);
+        assert_eq!(handler.key_versions.read().unwrap().len(), 1);
+        handler.disconnect(&mut watcher);
+        assert!(handler.key_versions.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_expire_ttl_and_persist() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+
+        handler.handle(command(&[b"SET", b"k", b"v"]), &mut session);
+        let resp = handler.handle(command(&[b"TTL", b"k"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(-1));
+        let resp = handler.handle(command(&[b"TTL", b"missing"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(-2));
+
+        let resp = handler.handle(command(&[b"EXPIRE", b"k", b"100"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(1));
+        let resp = handler.handle(command(&[b"TTL", b"k"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(100));
+
+        let resp = handler.handle(command(&[b"PERSIST", b"k"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(1));
+        let resp = handler.handle(command(&[b"TTL", b"k"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(-1));
+
+        // Lazy expiry: a key past its deadline is gone on the next read
+        handler.handle(command(&[b"PEXPIRE", b"k", b"1"]), &mut session);
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let resp = handler.handle(command(&[b"GET", b"k"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(None));
+        let resp = handler.handle(command(&[b"DBSIZE"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(0));
+    }
+
+    #[test]
+    fn test_getex() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+        let value = RespValue::BulkString(Some(b"v".to_vec()));
+
+        handler.handle(command(&[b"SET", b"k", b"v"]), &mut session);
+        handler.handle(command(&[b"EXPIRE", b"k", b"100"]), &mut session);
+
+        // Without options the TTL is left alone
+        let resp = handler.handle(command(&[b"GETEX", b"k"]), &mut session);
+        assert_eq!(resp, value);
+        let resp = handler.handle(command(&[b"TTL", b"k"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(100));
+
+        let resp = handler.handle(command(&[b"GETEX", b"k", b"EX", b"500"]), &mut session);
+        assert_eq!(resp, value);
+        let resp = handler.handle(command(&[b"TTL", b"k"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(500));
+        handler.handle(command(&[b"GETEX", b"k", b"px", b"200000"]), &mut session);
+        let resp = handler.handle(command(&[b"TTL", b"k"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(200));
+
+        let resp = handler.handle(command(&[b"GETEX", b"k", b"PERSIST"]), &mut session);
+        assert_eq!(resp, value);
+        let resp = handler.handle(command(&[b"TTL", b"k"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(-1));
+
+        let resp = handler.handle(command(&[b"GETEX", b"missing", b"EX", b"5"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(None));
+        let resp = handler.handle(command(&[b"TTL", b"missing"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(-2));
+
+        for bad in [
+            &[&b"GETEX"[..], b"k", b"EX", b"0"][..],
+            &[b"GETEX", b"k", b"EX", b"soon"],
+            &[b"GETEX", b"k", b"KEEPTTL"],
+            &[b"GETEX", b"k", b"EX", b"5", b"PERSIST"],
+        ] {
+            let resp = handler.handle(command(bad), &mut session);
+            assert!(matches!(resp, RespValue::Error(_)), "{:?}", resp);
+        }
+
+        handler.handle(command(&[b"HSET", b"h", b"f", b"v"]), &mut session);
+        let resp = handler.handle(command(&[b"GETEX", b"h", b"PERSIST"]), &mut session);
+        assert_eq!(resp, wrong_type());
+    }
+
+    #[test]
+    fn test_ttl_survives_reopen() {
+        let dir = TempDir::new().unwrap();
+        {
+            let handler = config_handler(&dir);
+            let mut session = SessionState::new(false);
+            handler.handle(command(&[b"SET", b"k", b"v"]), &mut session);
+            handler.handle(command(&[b"EXPIRE", b"k", b"100"]), &mut session);
+        }
+
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+        let resp = handler.handle(command(&[b"TTL", b"k"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(100));
+    }
+
+    #[test]
+    fn test_expire_on_list() {
+        let dir = TempDir::new().unwrap();
+        {
+            let handler = config_handler(&dir);
+            let mut session = SessionState::new(false);
+            handler.handle(command(&[b"RPUSH", b"queue", b"a", b"b"]), &mut session);
+            let resp = handler.handle(command(&[b"EXPIRE", b"queue", b"100"]), &mut session);
+            assert_eq!(resp, RespValue::Integer(1));
+            let resp = handler.handle(command(&[b"TTL", b"queue"]), &mut session);
+            assert_eq!(resp, RespValue::Integer(100));
+        }
+
+        // The TTL is saved with the list
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+        let resp = handler.handle(command(&[b"TTL", b"queue"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(100));
+
+        // Popping the last element deletes the list and its TTL
+        handler.handle(command(&[b"RPUSH", b"drained", b"a"]), &mut session);
+        handler.handle(command(&[b"EXPIRE", b"drained", b"100"]), &mut session);
+        handler.handle(command(&[b"LPOP", b"drained"]), &mut session);
+        handler.handle(command(&[b"RPUSH", b"drained", b"a"]), &mut session);
+        let resp = handler.handle(command(&[b"TTL", b"drained"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(-1));
+
+        // The sweep removes an expired list and its elements
+        handler.handle(command(&[b"PEXPIRE", b"queue", b"50"]), &mut session);
+        std::thread::sleep(std::time::Duration::from_millis(80));
+        handler.reap_expired();
+        let resp = handler.handle(command(&[b"LLEN", b"queue"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(0));
+        let resp = handler.handle(command(&[b"TTL", b"queue"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(-2));
+    }
+
+    #[test]
+    fn test_expired_key_notifies_subscribers() {
+        let dir = TempDir::new().unwrap();
+        let config = ServerConfig {
+            notify_keyspace_events: true,
+            ..ServerConfig::default()
+        };
+        let handler = CommandHandler::new(
+            Arc::new(ToonCache::new(dir.path(), 100).unwrap()),
+            dir.path().to_str().unwrap(),
+            Arc::new(AuthConfig::disabled()),
+            Arc::new(BackupConfig::new(dir.path(), None::<&str>)),
+            None,
+            config,
+        );
+
+        let mut listener = SessionState::new(false);
+        let (subscriber, mut messages) = handler.pubsub().register();
+        listener.subscriber = Some(subscriber);
+        let resp = handler.handle(
+            command(&[b"SUBSCRIBE", b"__keyevent@0__:expired"]),
+            &mut listener,
+        );
+        assert_eq!(
+            resp,
+            subscription_reply("subscribe", Some("__keyevent@0__:expired"), 1)
+        );
+
+        let mut writer = SessionState::new(false);
+        handler.handle(command(&[b"SET", b"session:1", b"v"]), &mut writer);
+        // Long enough that PEXPIRE itself, saving the key map, never sees it due
+        handler.handle(command(&[b"PEXPIRE", b"session:1", b"50"]), &mut writer);
+        std::thread::sleep(std::time::Duration::from_millis(60));
+
+        // The background sweep reaps the key without anyone reading it
+        assert_eq!(handler.sweep_expired(Duration::from_secs(1)), 1);
+        assert_eq!(
+            messages.try_recv().unwrap(),
+            Message::Publish {
+                channel: "__keyevent@0__:expired".to_string(),
+                payload: b"session:1".to_vec(),
+            }
+        );
+        assert!(messages.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_rate_limit_throttles_and_refills() {
+        let dir = TempDir::new().unwrap();
+        let config = ServerConfig {
+            max_commands_per_sec: 5,
+            ..ServerConfig::default()
+        };
+        let handler = CommandHandler::new(
+            Arc::new(ToonCache::new(dir.path(), 100).unwrap()),
+            dir.path().to_str().unwrap(),
+            Arc::new(AuthConfig::disabled()),
+            Arc::new(BackupConfig::new(dir.path(), None::<&str>)),
+            None,
+            config,
+        );
+        let mut session = SessionState::new(false);
+
+        for _ in 0..5 {
+            let resp = handler.handle(command(&[b"SET", b"k", b"v"]), &mut session);
+            assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+        }
+        let throttled = RespValue::Error("ERR rate limit exceeded".to_string());
+        assert_eq!(
+            handler.handle(command(&[b"GET", b"k"]), &mut session),
+            throttled
+        );
+        // PING keeps working, and other connections have buckets of their own
+        let resp = handler.handle(command(&[b"PING"]), &mut session);
+        assert_eq!(resp, RespValue::SimpleString("PONG".to_string()));
+        let mut other = SessionState::new(false);
+        let resp = handler.handle(command(&[b"GET", b"k"]), &mut other);
+        assert_eq!(resp, RespValue::BulkString(Some(b"v".to_vec())));
+
+        // A fifth of a second brings back one command
+        std::thread::sleep(std::time::Duration::from_millis(250));
+        let resp = handler.handle(command(&[b"GET", b"k"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(Some(b"v".to_vec())));
+        assert_eq!(
+            handler.handle(command(&[b"GET", b"k"]), &mut session),
+            throttled
+        );
+    }
+
+    #[test]
+    fn test_sweep_expired_is_bounded() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+        for i in 0..50 {
+            let key = format!("k{}", i);
+            handler.handle(command(&[b"SET", key.as_bytes(), b"v"]), &mut session);
+            handler.handle(command(&[b"PEXPIRE", key.as_bytes(), b"100"]), &mut session);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(150));
+
+        // Out of budget after the first round, the rest wait
+        assert_eq!(
+            handler.sweep_expired(Duration::ZERO),
+            ACTIVE_EXPIRE_KEYS_PER_ROUND
+        );
+        assert_eq!(
+            handler.sweep_expired(Duration::from_secs(1)),
+            50 - ACTIVE_EXPIRE_KEYS_PER_ROUND
+        );
+        assert_eq!(handler.sweep_expired(Duration::from_secs(1)), 0);
+        assert_eq!(handler.cache.live_len(), 0);
+
+        // Keys reaped lazily are not reaped again by the sweep
+        handler.handle(command(&[b"SET", b"lazy", b"v"]), &mut session);
+        handler.handle(command(&[b"PEXPIRE", b"lazy", b"1"]), &mut session);
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let resp = handler.handle(command(&[b"DBSIZE"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(0));
+        assert_eq!(handler.sweep_expired(Duration::from_secs(1)), 0);
+    }
+
+    #[test]
+    fn test_userstats_counts_per_user() {
+        let dir = TempDir::new().unwrap();
+        let users = Arc::new(UserManager::new(dir.path().to_str().unwrap(), 4).unwrap());
+        users
+            .create_user("alice", "secret", UserRole::ReadWrite)
+            .unwrap();
+        let handler = CommandHandler::new(
+            Arc::new(ToonCache::new(dir.path(), 100).unwrap()),
+            dir.path().to_str().unwrap(),
+            Arc::new(AuthConfig::disabled()),
+            Arc::new(BackupConfig::new(dir.path(), None::<&str>)),
+            Some(users),
+            ServerConfig::default(),
+        );
+
+        let mut alice = SessionState::new(false);
+        handler.handle(command(&[b"AUTH", b"alice", b"secret"]), &mut alice);
+        handler.handle(command(&[b"GET", b"k"]), &mut alice);
+        handler.handle(command(&[b"SET", b"k", b"v"]), &mut alice);
+        handler.handle(command(&[b"GET", b"k"]), &mut alice);
+
+        let mut admin = SessionState::new(false);
+        handler.handle(command(&[b"AUTH", b"admin", b"admin"]), &mut admin);
+        let resp = handler.handle(command(&[b"USERSTATS", b"alice"]), &mut admin);
+        assert_eq!(
+            resp,
+            RespValue::Array(Some(vec![
+                RespValue::BulkString(Some(b"GET".to_vec())),
+                RespValue::Integer(2),
+                RespValue::BulkString(Some(b"SET".to_vec())),
+                RespValue::Integer(1),
+            ]))
+        );
+
+        // Only admins may read the stats
+        let resp = handler.handle(command(&[b"USERSTATS", b"alice"]), &mut alice);
+        assert!(matches!(resp, RespValue::Error(ref e) if e.starts_with("NOPERM")));
+    }
+
+    #[test]
+    fn test_auth_lockout_after_failures() {
+        let dir = TempDir::new().unwrap();
+        let users = Arc::new(UserManager::new(dir.path().to_str().unwrap(), 4).unwrap());
+        users
+            .create_user("alice", "secret", UserRole::ReadWrite)
+            .unwrap();
+        let config = ServerConfig {
+            auth_max_failures: 3,
+            auth_lockout_per_user: true,
+            ..ServerConfig::default()
+        };
+        let handler = CommandHandler::new(
+            Arc::new(ToonCache::new(dir.path(), 100).unwrap()),
+            dir.path().to_str().unwrap(),
+            Arc::new(AuthConfig::disabled()),
+            Arc::new(BackupConfig::new(dir.path(), None::<&str>)),
+            Some(users),
+            config,
+        );
+        let wrongpass =
+            |resp: &RespValue| matches!(resp, RespValue::Error(e) if e.starts_with("WRONGPASS"));
+        let locked = |resp: &RespValue| matches!(resp, RespValue::Error(e) if e.contains("too many failed AUTH"));
+
+        // Within the allowance the right password still works, and resets the count
+        let mut session = SessionState::new(false);
+        for _ in 0..2 {
+            let resp = handler.handle(command(&[b"AUTH", b"alice", b"wrong"]), &mut session);
+            assert!(wrongpass(&resp));
+        }
+        let resp = handler.handle(command(&[b"AUTH", b"alice", b"secret"]), &mut session);
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+
+        for _ in 0..3 {
+            let resp = handler.handle(command(&[b"AUTH", b"alice", b"wrong"]), &mut session);
+            assert!(wrongpass(&resp));
+        }
+        // Locked out, even with the right password
+        let resp = handler.handle(command(&[b"AUTH", b"alice", b"secret"]), &mut session);
+        assert!(locked(&resp));
+
+        // The username is locked from other connections too, other users are not
+        let mut other = SessionState::new(false);
+        let resp = handler.handle(command(&[b"AUTH", b"alice", b"secret"]), &mut other);
+        assert!(locked(&resp));
+        let resp = handler.handle(command(&[b"AUTH", b"admin", b"admin"]), &mut other);
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+
+        // Names that do not exist are never counted
+        for _ in 0..3 {
+            let mut spray = SessionState::new(false);
+            handler.handle(command(&[b"AUTH", b"mallory", b"wrong"]), &mut spray);
+        }
+        assert!(!handler
+            .login_failures
+            .lock()
+            .unwrap()
+            .contains_key(&"mallory".to_string()));
+
+        // The lockout runs out
+        std::thread::sleep(Duration::from_millis(1100));
+        let resp = handler.handle(command(&[b"AUTH", b"alice", b"secret"]), &mut session);
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+    }
+
+    #[test]
+    fn test_auth_lockout_per_address() {
+        let dir = TempDir::new().unwrap();
+        let config = ServerConfig {
+            auth_max_failures: 3,
+            ..ServerConfig::default()
+        };
+        let handler = CommandHandler::new(
+            Arc::new(ToonCache::new(dir.path(), 100).unwrap()),
+            dir.path().to_str().unwrap(),
+            Arc::new(AuthConfig::from_password("secret", 4).unwrap()),
+            Arc::new(BackupConfig::new(dir.path(), None::<&str>)),
+            None,
+            config,
+        );
+        let connect = |peer: [u8; 4]| {
+            let mut session = SessionState::new(true);
+            session.peer = Some(IpAddr::from(peer));
+            session
+        };
+        let locked = |resp: &RespValue| matches!(resp, RespValue::Error(e) if e.contains("too many failed AUTH"));
+
+        // Reconnecting for every attempt does not start the count afresh
+        for _ in 0..3 {
+            let resp = handler.handle(command(&[b"AUTH", b"wrong"]), &mut connect([10, 0, 0, 1]));
+            assert!(matches!(resp, RespValue::Error(e) if e.starts_with("WRONGPASS")));
+        }
+        let resp = handler.handle(command(&[b"AUTH", b"secret"]), &mut connect([10, 0, 0, 1]));
+        assert!(locked(&resp));
+
+        // Other addresses are not held up
+        let resp = handler.handle(command(&[b"AUTH", b"secret"]), &mut connect([10, 0, 0, 2]));
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+    }
+
+    #[test]
+    fn test_userstats_buckets_unauthenticated() {
+        let dir = TempDir::new().unwrap();
+        let handler = CommandHandler::new(
+            Arc::new(ToonCache::new(dir.path(), 100).unwrap()),
+            dir.path().to_str().unwrap(),
+            Arc::new(AuthConfig::from_password("secret", 4).unwrap()),
+            Arc::new(BackupConfig::new(dir.path(), None::<&str>)),
+            None,
+            ServerConfig::default(),
+        );
+
+        let mut session = SessionState::new(true);
+        let resp = handler.handle(command(&[b"GET", b"k"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(ref e) if e.starts_with("NOAUTH")));
+        assert_eq!(
+            handler.user_stats.for_user(ANONYMOUS_USER),
+            vec![("GET".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn test_user_max_value_size() {
+        let dir = TempDir::new().unwrap();
+        let users = Arc::new(UserManager::new(dir.path().to_str().unwrap(), 4).unwrap());
+        users
+            .create_user("bulk", "secret", UserRole::ReadWrite)
+            .unwrap();
+        users
+            .set_max_value_size("bulk", Some(8 * 1024 * 1024))
+            .unwrap();
+        users
+            .create_user("small", "secret", UserRole::ReadWrite)
+            .unwrap();
+        let handler = CommandHandler::new(
+            Arc::new(ToonCache::new(dir.path(), 100).unwrap()),
+            dir.path().to_str().unwrap(),
+            Arc::new(AuthConfig::disabled()),
+            Arc::new(BackupConfig::new(dir.path(), None::<&str>)),
+            Some(users),
+            ServerConfig::default(),
+        );
+        let blob = vec![b'x'; 2 * 1024 * 1024];
+
+        let mut small = SessionState::new(false);
+        handler.handle(command(&[b"AUTH", b"small", b"secret"]), &mut small);
+        let resp = handler.handle(command(&[b"SET", b"blob", &blob]), &mut small);
+        assert!(matches!(resp, RespValue::Error(ref e) if e.contains("too large")));
+        let resp = handler.handle(command(&[b"RPUSH", b"list", &blob]), &mut small);
+        assert!(matches!(resp, RespValue::Error(ref e) if e.contains("too large")));
+
+        let mut bulk = SessionState::new(false);
+        handler.handle(command(&[b"AUTH", b"bulk", b"secret"]), &mut bulk);
+        let resp = handler.handle(command(&[b"SET", b"blob", &blob]), &mut bulk);
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+        let resp = handler.handle(command(&[b"HSET", b"h", b"f", &blob]), &mut bulk);
+        assert_eq!(resp, RespValue::Integer(1));
+
+        // Anyone may read the value back
+        let resp = handler.handle(command(&[b"GET", b"blob"]), &mut small);
+        assert_eq!(resp, RespValue::BulkString(Some(blob)));
+
+        // The limit is set per user with ACL SETUSER
+        let mut admin = SessionState::new(false);
+        handler.handle(command(&[b"AUTH", b"admin", b"admin"]), &mut admin);
+        let resp = handler.handle(
+            command(&[b"ACL", b"SETUSER", b"bulk", b"maxvaluesize=default"]),
+            &mut admin,
+        );
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+        handler.handle(command(&[b"AUTH", b"bulk", b"secret"]), &mut bulk);
+        assert_eq!(bulk.value_limit(), toonstoredb::MAX_VALUE_SIZE);
+    }
+
+    #[test]
+    fn test_save_and_lastsave() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+        let backups = dir.path().join("backups");
+        let count = || std::fs::read_dir(&backups).map_or(0, |entries| entries.count());
+
+        let resp = handler.handle(command(&[b"LASTSAVE"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(0));
+
+        let before = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let resp = handler.handle(command(&[b"SAVE"]), &mut session);
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+        assert_eq!(count(), 1);
+        let RespValue::Integer(lastsave) = handler.handle(command(&[b"LASTSAVE"]), &mut session)
+        else {
+            panic!("Expected integer");
+        };
+        assert!(lastsave >= before);
+
+        let resp = handler.handle(command(&[b"BGSAVE"]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::SimpleString("Background saving started".to_string())
+        );
+        for _ in 0..500 {
+            if count() == 2 && !handler.bgsave_running.load(Ordering::Acquire) {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(count(), 2);
+        assert!(!handler.bgsave_running.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn test_bgsave_snapshots_before_replying() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+        let backups = dir.path().join("backups");
+
+        handler.handle(command(&[b"SET", b"before", b"1"]), &mut session);
+        let resp = handler.handle(command(&[b"BGSAVE"]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::SimpleString("Background saving started".to_string())
+        );
+        // Lands after the copy, so it must not be in the archive
+        handler.handle(command(&[b"SET", b"after", b"2"]), &mut session);
+
+        for _ in 0..500 {
+            if !handler.bgsave_running.load(Ordering::Acquire) {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(!handler.bgsave_running.load(Ordering::Acquire));
+
+        let archive = std::fs::read_dir(&backups)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(File::open(archive).unwrap()));
+        let keymap = tar
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .find(|entry| entry.path().unwrap().ends_with("keymap.txt"))
+            .map(|mut entry| {
+                let mut text = String::new();
+                std::io::Read::read_to_string(&mut entry, &mut text).unwrap();
+                text
+            })
+            .unwrap();
+        assert!(keymap.contains("before"));
+        assert!(!keymap.contains("after"));
+
+        // The copy is gone once archived
+        let leftovers = std::fs::read_dir(dir.path())
+            .unwrap()
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(".snapshot_")
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+    }
+
+    #[test]
+    fn test_backup_list() {
+        let dir = TempDir::new().unwrap();
+        let users = Arc::new(UserManager::new(dir.path().to_str().unwrap(), 4).unwrap());
+        users
+            .create_user("writer", "secret", UserRole::ReadWrite)
+            .unwrap();
+        let handler = CommandHandler::new(
+            Arc::new(ToonCache::new(dir.path(), 100).unwrap()),
+            dir.path().to_str().unwrap(),
+            Arc::new(AuthConfig::disabled()),
+            Arc::new(BackupConfig::new(dir.path(), None::<&str>)),
+            Some(users),
+            ServerConfig::default(),
+        );
+
+        let mut admin = SessionState::new(false);
+        handler.handle(command(&[b"AUTH", b"admin", b"admin"]), &mut admin);
+        let resp = handler.handle(command(&[b"BACKUP", b"LIST"]), &mut admin);
+        assert_eq!(resp, RespValue::Array(Some(vec![])));
+
+        let mut names = Vec::new();
+        for name in [&b"older"[..], b"newer"] {
+            let RespValue::BulkString(Some(filename)) =
+                handler.handle(command(&[b"BACKUP", name]), &mut admin)
+            else {
+                panic!("BACKUP failed");
+            };
+            names.push(String::from_utf8(filename).unwrap());
+        }
+        let older = File::options()
+            .write(true)
+            .open(dir.path().join("backups").join(&names[0]))
+            .unwrap();
+        older
+            .set_modified(SystemTime::now() - std::time::Duration::from_secs(60))
+            .unwrap();
+
+        let resp = handler.handle(command(&[b"BACKUP", b"list"]), &mut admin);
+        let RespValue::Array(Some(entries)) = resp else {
+            panic!("Expected array, got {:?}", resp);
+        };
+        assert_eq!(entries.len(), 2);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        for (entry, name) in entries.iter().zip(names.iter().rev()) {
+            let RespValue::Array(Some(fields)) = entry else {
+                panic!("Expected array entry, got {:?}", entry);
+            };
+            assert_eq!(
+                fields[0],
+                RespValue::BulkString(Some(name.as_bytes().to_vec()))
+            );
+            assert!(matches!(fields[1], RespValue::Integer(size) if size > 0));
+            assert!(matches!(fields[2], RespValue::Integer(ts) if (now - 120..=now).contains(&ts)));
+        }
+
+        let mut writer = SessionState::new(false);
+        handler.handle(command(&[b"AUTH", b"writer", b"secret"]), &mut writer);
+        let resp = handler.handle(command(&[b"BACKUP", b"LIST"]), &mut writer);
+        assert!(matches!(resp, RespValue::Error(ref e) if e.starts_with("NOPERM")));
+    }
+
+    #[test]
+    fn test_acl_setuser_requires_admin() {
+        let dir = TempDir::new().unwrap();
+        let users = Arc::new(UserManager::new(dir.path().to_str().unwrap(), 4).unwrap());
+        users
+            .create_user("reader", "secret", UserRole::ReadOnly)
+            .unwrap();
+        let handler = CommandHandler::new(
+            Arc::new(ToonCache::new(dir.path(), 100).unwrap()),
+            dir.path().to_str().unwrap(),
+            Arc::new(AuthConfig::disabled()),
+            Arc::new(BackupConfig::new(dir.path(), None::<&str>)),
+            Some(users),
+            ServerConfig::default(),
+        );
+
+        let mut reader = SessionState::new(false);
+        handler.handle(command(&[b"AUTH", b"reader", b"secret"]), &mut reader);
+        let resp = handler.handle(
+            command(&[b"ACL", b"SETUSER", b"bob", b"on", b">pw", b"+@all"]),
+            &mut reader,
+        );
+        assert!(matches!(resp, RespValue::Error(ref e) if e.starts_with("NOPERM")));
+
+        let mut admin = SessionState::new(false);
+        handler.handle(command(&[b"AUTH", b"admin", b"admin"]), &mut admin);
+        let resp = handler.handle(
+            command(&[b"ACL", b"SETUSER", b"bob", b"on", b">pw", b"+@read"]),
+            &mut admin,
+        );
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+
+        let resp = handler.handle(command(&[b"ACL", b"GETUSER", b"bob"]), &mut admin);
+        assert_eq!(
+            resp,
+            RespValue::Array(Some(vec![
+                RespValue::BulkString(Some(b"flags".to_vec())),
+                RespValue::Array(Some(vec![RespValue::BulkString(Some(b"on".to_vec()))])),
+                RespValue::BulkString(Some(b"commands".to_vec())),
+                RespValue::BulkString(Some(b"+@read".to_vec())),
+            ]))
+        );
+
+        // The new user can log in and gets the read-only role
+        let mut bob = SessionState::new(false);
+        let resp = handler.handle(command(&[b"AUTH", b"bob", b"pw"]), &mut bob);
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+        let resp = handler.handle(command(&[b"SET", b"k", b"v"]), &mut bob);
+        assert!(matches!(resp, RespValue::Error(ref e) if e.starts_with("NOPERM")));
+
+        let resp = handler.handle(command(&[b"ACL", b"DELUSER", b"bob", b"admin"]), &mut admin);
+        assert_eq!(resp, RespValue::Integer(1));
+        let resp = handler.handle(command(&[b"ACL", b"LIST"]), &mut admin);
+        assert_eq!(
+            resp,
+            RespValue::Array(Some(vec![
+                RespValue::BulkString(Some(b"user admin on +@all".to_vec())),
+                RespValue::BulkString(Some(b"user reader on +@read".to_vec())),
+            ]))
+        );
+
+        let resp = handler.handle(command(&[b"ACL", b"LOAD"]), &mut reader);
+        assert!(matches!(resp, RespValue::Error(ref e) if e.starts_with("NOPERM")));
+        let resp = handler.handle(command(&[b"ACL", b"LOAD"]), &mut admin);
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+    }
+
+    #[test]
+    fn test_rename_and_disable_commands() {
+        let dir = TempDir::new().unwrap();
+        let mut config = ServerConfig::default();
+        config
+            .renamed_commands
+            .insert("FLUSHDB".to_string(), String::new());
+        config
+            .renamed_commands
+            .insert("DBSIZE".to_string(), "SECRETSIZE".to_string());
+        let handler = CommandHandler::new(
+            Arc::new(ToonCache::new(dir.path(), 100).unwrap()),
+            dir.path().to_str().unwrap(),
+            Arc::new(AuthConfig::disabled()),
+            Arc::new(BackupConfig::new(dir.path(), None::<&str>)),
+            None,
+            config,
+        );
+        let mut session = SessionState::new(false);
+
+        let resp = handler.handle(command(&[b"FLUSHDB"]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::Error("ERR unknown command 'FLUSHDB'".to_string())
+        );
+
+        let resp = handler.handle(command(&[b"DBSIZE"]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::Error("ERR unknown command 'DBSIZE'".to_string())
+        );
+        let resp = handler.handle(command(&[b"secretsize"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(0));
+
+        // Renamed commands also work when queued
+        handler.handle(command(&[b"MULTI"]), &mut session);
+        handler.handle(command(&[b"SECRETSIZE"]), &mut session);
+        let resp = handler.handle(command(&[b"EXEC"]), &mut session);
+        assert_eq!(resp, RespValue::Array(Some(vec![RespValue::Integer(0)])));
+    }
+
+    #[test]
+    fn test_getrange_setrange() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+        let bulk = |v: &[u8]| RespValue::BulkString(Some(v.to_vec()));
+
+        handler.handle(command(&[b"SET", b"blob", b"Hello, World"]), &mut session);
+
+        let resp = handler.handle(command(&[b"GETRANGE", b"blob", b"7", b"9"]), &mut session);
+        assert_eq!(resp, bulk(b"Wor"));
+        let resp = handler.handle(command(&[b"GETRANGE", b"blob", b"-5", b"-1"]), &mut session);
+        assert_eq!(resp, bulk(b"World"));
+        let resp = handler.handle(
+            command(&[b"GETRANGE", b"blob", b"0", b"-100"]),
+            &mut session,
+        );
+        assert_eq!(resp, bulk(b""));
+        let resp = handler.handle(command(&[b"GETRANGE", b"blob", b"7", b"100"]), &mut session);
+        assert_eq!(resp, bulk(b"World"));
+        let resp = handler.handle(command(&[b"GETRANGE", b"nope", b"0", b"3"]), &mut session);
+        assert_eq!(resp, bulk(b""));
+
+        let resp = handler.handle(
+            command(&[b"SETRANGE", b"blob", b"7", b"Rust!"]),
+            &mut session,
+        );
+        assert_eq!(resp, RespValue::Integer(12));
+        let resp = handler.handle(command(&[b"GET", b"blob"]), &mut session);
+        assert_eq!(resp, bulk(b"Hello, Rust!"));
+
+        // Writing past the end zero-pads the gap
+        let resp = handler.handle(command(&[b"SETRANGE", b"blob", b"14", b"ok"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(16));
+        let resp = handler.handle(command(&[b"GET", b"blob"]), &mut session);
+        assert_eq!(resp, bulk(b"Hello, Rust!\0\0ok"));
+
+        let resp = handler.handle(command(&[b"SETRANGE", b"new", b"2", b"x"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(3));
+        let resp = handler.handle(command(&[b"SETRANGE", b"blob", b"-1", b"x"]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::Error("ERR offset is out of range".to_string())
+        );
+    }
+
+    #[test]
+    fn test_strlen() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+
+        handler.handle(command(&[b"SET", b"greeting", b"hello"]), &mut session);
+        let resp = handler.handle(command(&[b"STRLEN", b"greeting"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(5));
+        let resp = handler.handle(command(&[b"STRLEN", b"missing"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(0));
+
+        // The key still mapping to a soft-deleted row reads as missing
+        let row_id = *handler.key_map.read().unwrap().get("greeting").unwrap();
+        handler.cache.delete(row_id).unwrap();
+        let resp = handler.handle(command(&[b"STRLEN", b"greeting"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(0));
+
+        handler.handle(command(&[b"DEL", b"greeting"]), &mut session);
+        let resp = handler.handle(command(&[b"STRLEN", b"greeting"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(0));
+    }
+
+    #[test]
+    fn test_hash_commands() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+        let bulk = |v: &[u8]| RespValue::BulkString(Some(v.to_vec()));
+
+        let resp = handler.handle(
+            command(&[b"HSET", b"user:1", b"name", b"Ada", b"lang", b"en"]),
+            &mut session,
+        );
+        assert_eq!(resp, RespValue::Integer(2));
+
+        // Overwriting an existing field adds nothing; a new field adds one
+        let resp = handler.handle(
+            command(&[b"HSET", b"user:1", b"name", b"Ada, Countess"]),
+            &mut session,
+        );
+        assert_eq!(resp, RespValue::Integer(0));
+        let resp = handler.handle(
+            command(&[b"HSET", b"user:1", b"born", b"1815"]),
+            &mut session,
+        );
+        assert_eq!(resp, RespValue::Integer(1));
+
+        let resp = handler.handle(command(&[b"HGET", b"user:1", b"name"]), &mut session);
+        assert_eq!(resp, bulk(b"Ada, Countess"));
+        let resp = handler.handle(command(&[b"HGET", b"user:1", b"nope"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(None));
+        let resp = handler.handle(command(&[b"HLEN", b"user:1"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(3));
+
+        let resp = handler.handle(command(&[b"HGETALL", b"user:1"]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::Array(Some(vec![
+                bulk(b"name"),
+                bulk(b"Ada, Countess"),
+                bulk(b"lang"),
+                bulk(b"en"),
+                bulk(b"born"),
+                bulk(b"1815"),
+            ]))
+        );
+
+        let resp = handler.handle(
+            command(&[b"HDEL", b"user:1", b"lang", b"nope"]),
+            &mut session,
+        );
+        assert_eq!(resp, RespValue::Integer(1));
+        let resp = handler.handle(command(&[b"HGET", b"user:1", b"lang"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(None));
+
+        // Removing the last fields removes the key
+        handler.handle(
+            command(&[b"HDEL", b"user:1", b"name", b"born"]),
+            &mut session,
+        );
+        let resp = handler.handle(command(&[b"EXISTS", b"user:1"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(0));
+        let resp = handler.handle(command(&[b"HGETALL", b"user:1"]), &mut session);
+        assert_eq!(resp, RespValue::Array(Some(vec![])));
+    }
+
+    #[test]
+    fn test_hash_rejects_strings_and_bad_fields() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+
+        handler.handle(command(&[b"SET", b"plain", b"value"]), &mut session);
+        let resp = handler.handle(command(&[b"HGET", b"plain", b"f"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(ref e) if e.starts_with("WRONGTYPE")));
+
+        let resp = handler.handle(command(&[b"HSET", b"h", b"a,b", b"1"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(_)));
+        let resp = handler.handle(command(&[b"HSET", b"h", b"a"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(_)));
+    }
+
+    #[test]
+    fn test_wrongtype() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+        let wrongtype = |resp: &RespValue| {
+            resp == &RespValue::Error(
+                "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+            )
+        };
+
+        handler.handle(command(&[b"SET", b"s", b"value"]), &mut session);
+        handler.handle(command(&[b"HSET", b"h", b"f", b"v"]), &mut session);
+        handler.handle(command(&[b"RPUSH", b"l", b"x"]), &mut session);
+
+        assert!(wrongtype(
+            &handler.handle(command(&[b"HGET", b"s", b"f"]), &mut session)
+        ));
+        for cmd in [&b"GET"[..], b"STRLEN"] {
+            for key in [&b"h"[..], b"l"] {
+                assert!(wrongtype(
+                    &handler.handle(command(&[cmd, key]), &mut session)
+                ));
+            }
+        }
+        let resp = handler.handle(command(&[b"GETRANGE", b"h", b"0", b"-1"]), &mut session);
+        assert!(wrongtype(&resp));
+        let resp = handler.handle(command(&[b"SETRANGE", b"h", b"0", b"x"]), &mut session);
+        assert!(wrongtype(&resp));
+
+        // MGET never errors: other types read as nil
+        let resp = handler.handle(command(&[b"MGET", b"s", b"h", b"l"]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::Array(Some(vec![
+                RespValue::BulkString(Some(b"value".to_vec())),
+                RespValue::BulkString(None),
+                RespValue::BulkString(None),
+            ]))
+        );
+
+        // The type lives in the row, not the content: a string that looks like
+        // a serialized hash is still a string
+        let record = ToonRecord {
+            name: HASH_RECORD.to_string(),
+            fields: vec!["f".to_string()],
+            values: vec![b"v".to_vec()],
+        };
+        handler.handle(
+            command(&[b"SET", b"fake", &record.serialize()]),
+            &mut session,
+        );
+        let resp = handler.handle(command(&[b"HGET", b"fake", b"f"]), &mut session);
+        assert!(wrongtype(&resp));
+
+        // SET replaces a hash with a string
+        handler.handle(command(&[b"SET", b"h", b"now a string"]), &mut session);
+        let resp = handler.handle(command(&[b"GET", b"h"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(Some(b"now a string".to_vec())));
+    }
+
+    #[test]
+    fn test_list_push_pop_range() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+        let bulk = |v: &[u8]| RespValue::BulkString(Some(v.to_vec()));
+
+        let resp = handler.handle(command(&[b"RPUSH", b"q", b"b", b"c"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(2));
+        let resp = handler.handle(command(&[b"LPUSH", b"q", b"a"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(3));
+        handler.handle(command(&[b"RPUSH", b"q", b"d", b"e"]), &mut session);
+
+        let resp = handler.handle(command(&[b"LRANGE", b"q", b"0", b"-1"]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::Array(Some(vec![
+                bulk(b"a"),
+                bulk(b"b"),
+                bulk(b"c"),
+                bulk(b"d"),
+                bulk(b"e"),
+            ]))
+        );
+        let resp = handler.handle(command(&[b"LRANGE", b"q", b"1", b"2"]), &mut session);
+        assert_eq!(resp, RespValue::Array(Some(vec![bulk(b"b"), bulk(b"c")])));
+        let resp = handler.handle(command(&[b"LRANGE", b"q", b"-2", b"100"]), &mut session);
+        assert_eq!(resp, RespValue::Array(Some(vec![bulk(b"d"), bulk(b"e")])));
+
+        let resp = handler.handle(command(&[b"LPOP", b"q"]), &mut session);
+        assert_eq!(resp, bulk(b"a"));
+        let resp = handler.handle(command(&[b"RPOP", b"q", b"2"]), &mut session);
+        assert_eq!(resp, RespValue::Array(Some(vec![bulk(b"e"), bulk(b"d")])));
+        let resp = handler.handle(command(&[b"LLEN", b"q"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(2));
+
+        // Popping the last elements removes the key
+        handler.handle(command(&[b"RPOP", b"q", b"5"]), &mut session);
+        let resp = handler.handle(command(&[b"EXISTS", b"q"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(0));
+        let resp = handler.handle(command(&[b"LPOP", b"q"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(None));
+
+        handler.handle(command(&[b"SET", b"s", b"v"]), &mut session);
+        let resp = handler.handle(command(&[b"LPUSH", b"s", b"x"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(ref e) if e.starts_with("WRONGTYPE")));
+    }
+
+    #[test]
+    fn test_list_survives_reopen() {
+        let dir = TempDir::new().unwrap();
+        let mut session = SessionState::new(false);
+        {
+            let handler = config_handler(&dir);
+            handler.handle(command(&[b"RPUSH", b"q", b"1", b"2", b"3"]), &mut session);
+            handler.handle(command(&[b"LPOP", b"q"]), &mut session);
+            handler.handle(command(&[b"RPUSH", b"gone", b"x"]), &mut session);
+            handler.handle(command(&[b"DEL", b"gone"]), &mut session);
+        }
+
+        let handler = config_handler(&dir);
+        let resp = handler.handle(command(&[b"LRANGE", b"q", b"0", b"-1"]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::Array(Some(vec![
+                RespValue::BulkString(Some(b"2".to_vec())),
+                RespValue::BulkString(Some(b"3".to_vec())),
+            ]))
+        );
+        let resp = handler.handle(command(&[b"DBSIZE"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(1));
+    }
+
+    #[test]
+    fn test_subscribe_mode_restricts_commands() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+        let (subscriber, _messages) = handler.pubsub().register();
+        session.subscriber = Some(subscriber);
+
+        handler.handle(command(&[b"SUBSCRIBE", b"news"]), &mut session);
+
+        let resp = handler.handle(command(&[b"PING"]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::Array(Some(vec![
+                RespValue::BulkString(Some(b"pong".to_vec())),
+                RespValue::BulkString(Some(b"".to_vec())),
+            ]))
+        );
+        let resp = handler.handle(command(&[b"PING", b"hi"]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::Array(Some(vec![
+                RespValue::BulkString(Some(b"pong".to_vec())),
+                RespValue::BulkString(Some(b"hi".to_vec())),
+            ]))
+        );
+
+        let resp = handler.handle(command(&[b"GET", b"k"]), &mut session);
+        assert!(
+            matches!(resp, RespValue::Error(ref e) if e.starts_with("ERR Can't execute 'get'"))
+        );
+
+        // Leaving the last channel restores normal replies
+        handler.handle(command(&[b"UNSUBSCRIBE"]), &mut session);
+        let resp = handler.handle(command(&[b"PING"]), &mut session);
+        assert_eq!(resp, RespValue::SimpleString("PONG".to_string()));
+        let resp = handler.handle(command(&[b"GET", b"k"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(None));
+    }
+
+    #[test]
+    fn test_wait_reports_no_replicas() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+
+        let resp = handler.handle(command(&[b"WAIT", b"1", b"100"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(0));
+        assert_eq!(resp.serialize(), b":0\r\n");
+
+        let resp = handler.handle(command(&[b"WAIT", b"1"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(ref e) if e.contains("wrong number")));
+        let resp = handler.handle(command(&[b"WAIT", b"x", b"100"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(_)));
+    }
+
+    #[test]
+    fn test_help() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+
+        let resp = handler.handle(command(&[b"OBJECT", b"HELP"]), &mut session);
+        let RespValue::Array(Some(lines)) = resp else {
+            panic!("Expected array, got {:?}", resp);
+        };
+        assert!(!lines.is_empty());
+        assert!(matches!(&lines[0], RespValue::SimpleString(s) if s.contains("OBJECT")));
+
+        // ACL HELP works even without user management
+        for name in ["CONFIG", "CLIENT", "ACL", "DEBUG", "COMMAND"] {
+            let resp = handler.handle(command(&[name.as_bytes(), b"help"]), &mut session);
+            let RespValue::Array(Some(lines)) = resp else {
+                panic!("Expected array for {}, got {:?}", name, resp);
+            };
+            assert!(matches!(&lines[0], RespValue::SimpleString(s) if s.starts_with(name)));
+        }
+    }
+
+    #[test]
+    fn test_object_encoding() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+
+        handler.handle(command(&[b"SET", b"s", b"v"]), &mut session);
+        handler.handle(command(&[b"HSET", b"h", b"f", b"v"]), &mut session);
+        handler.handle(command(&[b"RPUSH", b"l", b"a"]), &mut session);
+        for (key, encoding) in [
+            (&b"s"[..], &b"raw"[..]),
+            (b"h", b"hashtable"),
+            (b"l", b"linkedlist"),
+        ] {
+            let resp = handler.handle(command(&[b"OBJECT", b"ENCODING", key]), &mut session);
+            assert_eq!(resp, RespValue::BulkString(Some(encoding.to_vec())));
+        }
+
+        let resp = handler.handle(command(&[b"OBJECT", b"ENCODING", b"missing"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(None));
+        let resp = handler.handle(command(&[b"OBJECT", b"IDLETIME", b"s"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(ref e) if e.contains("OBJECT HELP")));
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+        handler.handle(command(&[b"SET", b"5byte", &[b'x'; 100]]), &mut session);
+        handler.handle(command(&[b"RPUSH", b"list", b"abc", b"de"]), &mut session);
+
+        let usage = |handler: &CommandHandler, session: &mut SessionState, args: &[&[u8]]| {
+            let mut parts: Vec<&[u8]> = vec![b"MEMORY", b"USAGE"];
+            parts.extend_from_slice(args);
+            handler.handle(command(&parts), session)
+        };
+
+        let overhead = (MEMORY_KEY_OVERHEAD + MEMORY_ROW_OVERHEAD) as i64;
+        let resp = usage(&handler, &mut session, &[b"5byte"]);
+        assert_eq!(resp, RespValue::Integer(105 + overhead));
+        let resp = usage(&handler, &mut session, &[b"5byte", b"SAMPLES", b"0"]);
+        assert_eq!(resp, RespValue::Integer(105 + overhead));
+        let resp = usage(&handler, &mut session, &[b"list"]);
+        let list_overhead = (MEMORY_KEY_OVERHEAD + 2 * MEMORY_ROW_OVERHEAD) as i64;
+        assert_eq!(resp, RespValue::Integer(4 + 5 + list_overhead));
+
+        let resp = usage(&handler, &mut session, &[b"missing"]);
+        assert_eq!(resp, RespValue::BulkString(None));
+        let resp = usage(&handler, &mut session, &[b"5byte", b"SAMPLES", b"many"]);
+        assert!(matches!(resp, RespValue::Error(ref e) if e.contains("not an integer")));
+        let resp = usage(&handler, &mut session, &[b"5byte", b"EXTRA"]);
+        assert_eq!(resp, RespValue::Error("ERR syntax error".to_string()));
+        let resp = handler.handle(command(&[b"MEMORY", b"DOCTOR"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(ref e) if e.contains("MEMORY HELP")));
+    }
+
+    #[test]
+    fn test_object_freq() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+        handler.handle(command(&[b"SET", b"k", b"v"]), &mut session);
+        let resp = handler.handle(command(&[b"OBJECT", b"FREQ", b"k"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(ref e) if e.contains("LFU")));
+
+        let lfu_dir = TempDir::new().unwrap();
+        let cache = Arc::new(ToonCache::with_lfu(lfu_dir.path(), 100, 5).unwrap());
+        let backup = Arc::new(BackupConfig::new(lfu_dir.path(), None::<&str>));
+        let handler = CommandHandler::new(
+            cache,
+            lfu_dir.path().to_str().unwrap(),
+            Arc::new(AuthConfig::disabled()),
+            backup,
+            None,
+            ServerConfig::default(),
+        );
+        handler.handle(command(&[b"SET", b"hot", b"v"]), &mut session);
+        handler.handle(command(&[b"SET", b"cold", b"v"]), &mut session);
+        for _ in 0..1000 {
+            handler.handle(command(&[b"GET", b"hot"]), &mut session);
+        }
+        handler.handle(command(&[b"GET", b"cold"]), &mut session);
+
+        let freq = |key: &[u8], session: &mut SessionState| match handler
+            .handle(command(&[b"OBJECT", b"FREQ", key]), session)
+        {
+            RespValue::Integer(n) => n,
+            other => panic!("unexpected OBJECT FREQ reply {:?}", other),
+        };
+        let (hot, cold) = (freq(b"hot", &mut session), freq(b"cold", &mut session));
+        assert!(hot > cold, "hot {} cold {}", hot, cold);
+        assert!(cold >= i64::from(tooncache::LFU_INIT));
+
+        let resp = handler.handle(command(&[b"OBJECT", b"FREQ", b"missing"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(None));
+    }
+
+    #[test]
+    fn test_object_timestamps() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+        let times = |key: &[u8], session: &mut SessionState| {
+            let created = handler.handle(command(&[b"OBJECT", b"CREATEDAT", key]), session);
+            let updated = handler.handle(command(&[b"OBJECT", b"UPDATEDAT", key]), session);
+            match (created, updated) {
+                (RespValue::Integer(c), RespValue::Integer(u)) => Some((c, u)),
+                (RespValue::BulkString(None), RespValue::BulkString(None)) => None,
+                other => panic!("unexpected reply {:?}", other),
+            }
+        };
+
+        handler.handle(command(&[b"SET", b"s", b"v1"]), &mut session);
+        handler.handle(command(&[b"HSET", b"h", b"f", b"v"]), &mut session);
+        let (s_created, s_updated) = times(b"s", &mut session).unwrap();
+        let (h_created, _) = times(b"h", &mut session).unwrap();
+        assert_eq!(s_created, s_updated);
+
+        // Overwrites keep the creation time
+        std::thread::sleep(Duration::from_millis(5));
+        handler.handle(command(&[b"SET", b"s", b"v2"]), &mut session);
+        handler.handle(command(&[b"SETRANGE", b"s", b"0", b"V"]), &mut session);
+        handler.handle(command(&[b"HSET", b"h", b"g", b"v"]), &mut session);
+        let (created, updated) = times(b"s", &mut session).unwrap();
+        assert_eq!(created, s_created);
+        assert!(updated > s_updated);
+        assert_eq!(times(b"h", &mut session).unwrap().0, h_created);
+
+        handler.handle(command(&[b"DEL", b"s"]), &mut session);
+        assert_eq!(times(b"s", &mut session), None);
+        assert_eq!(times(b"missing", &mut session), None);
+    }
+
+    #[test]
+    fn test_client_name() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+
+        let resp = handler.handle(command(&[b"CLIENT", b"GETNAME"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(None));
+        let resp = handler.handle(command(&[b"CLIENT", b"SETNAME", b"worker-1"]), &mut session);
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+        let resp = handler.handle(command(&[b"CLIENT", b"GETNAME"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(Some(b"worker-1".to_vec())));
+
+        let resp = handler.handle(command(&[b"CLIENT", b"SETNAME", b"a b"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(_)));
+        let resp = handler.handle(command(&[b"CLIENT", b"SETNAME", b""]), &mut session);
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+        let resp = handler.handle(command(&[b"CLIENT", b"GETNAME"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(None));
+    }
+
+    #[test]
+    fn test_client_tracking_invalidates_read_keys() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut reader = SessionState::new(false);
+        let (subscriber, mut messages) = handler.pubsub().register();
+        reader.subscriber = Some(subscriber);
+        let mut writer = SessionState::new(false);
+
+        // Invalidations are pushes, so tracking needs a RESP3 connection
+        handler.handle(command(&[b"SET", b"user:1", b"alice"]), &mut writer);
+        let resp = handler.handle(command(&[b"CLIENT", b"TRACKING", b"ON"]), &mut reader);
+        assert!(matches!(resp, RespValue::Error(_)));
+        let resp = handler.handle(command(&[b"HELLO", b"3"]), &mut reader);
+        assert!(matches!(resp, RespValue::Map(_)));
+        let resp = handler.handle(command(&[b"CLIENT", b"TRACKING", b"ON"]), &mut reader);
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+        let resp = handler.handle(command(&[b"HELLO", b"2"]), &mut reader);
+        assert!(matches!(resp, RespValue::Error(_)));
+        handler.handle(command(&[b"GET", b"user:1"]), &mut reader);
+
+        handler.handle(command(&[b"SET", b"user:1", b"bob"]), &mut writer);
+        let message = messages.try_recv().unwrap();
+        assert_eq!(message, Message::Invalidate("user:1".to_string()));
+        assert_eq!(
+            message.to_resp(true).serialize(),
+            b">2\r\n$10\r\ninvalidate\r\n*1\r\n$6\r\nuser:1\r\n"
+        );
+
+        // One invalidation per read, and none for keys never read
+        handler.handle(command(&[b"SET", b"user:1", b"carol"]), &mut writer);
+        handler.handle(command(&[b"SET", b"user:2", b"dave"]), &mut writer);
+        assert!(messages.try_recv().is_err());
+
+        handler.handle(command(&[b"GET", b"user:1"]), &mut reader);
+        handler.handle(command(&[b"CLIENT", b"TRACKING", b"OFF"]), &mut reader);
+        handler.handle(command(&[b"DEL", b"user:1"]), &mut writer);
+        assert!(messages.try_recv().is_err());
+
+        // Outside a live connection there is no queue to push to
+        handler.handle(command(&[b"HELLO", b"3"]), &mut writer);
+        let resp = handler.handle(command(&[b"CLIENT", b"TRACKING", b"ON"]), &mut writer);
+        assert!(matches!(resp, RespValue::Error(_)));
     }
 
-    fn handle_restore(&self, args: &[RespValue]) -> RespValue {
-        if args.is_empty() {
-            return RespValue::Error(
-                "ERR wrong number of arguments for 'restore' command".to_string(),
-            );
-        }
+    #[test]
+    fn test_time() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
 
-        let backup_file = match &args[0] {
-            RespValue::BulkString(Some(f)) => match String::from_utf8(f.clone()) {
-                Ok(s) => s,
-                Err(_) => return RespValue::Error("ERR invalid backup filename".to_string()),
-            },
-            _ => return RespValue::Error("ERR invalid backup filename type".to_string()),
+        let before = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let resp = handler.handle(command(&[b"TIME"]), &mut session);
+        let RespValue::Array(Some(parts)) = resp else {
+            panic!("Expected array, got {:?}", resp);
         };
+        assert_eq!(parts.len(), 2);
 
-        // Security: Reject absolute paths to prevent path traversal
-        if std::path::Path::new(&backup_file).is_absolute() {
-            warn!("Rejected absolute path in RESTORE: {}", backup_file);
-            return RespValue::Error("ERR absolute paths not allowed".to_string());
-        }
+        let numbers: Vec<u64> = parts
+            .iter()
+            .map(|part| match part {
+                RespValue::BulkString(Some(n)) => std::str::from_utf8(n).unwrap().parse().unwrap(),
+                other => panic!("Expected bulk string, got {:?}", other),
+            })
+            .collect();
+        assert!(numbers[0] >= before && numbers[0] <= before + 5);
+        assert!(numbers[1] < 1_000_000);
+    }
 
-        // Security: Reject paths with ".." to prevent directory traversal
-        if backup_file.contains("..") {
-            warn!(
-                "Rejected path traversal attempt in RESTORE: {}",
-                backup_file
-            );
-            return RespValue::Error("ERR path traversal not allowed".to_string());
-        }
+    #[test]
+    fn test_unknown_subcommands() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
 
-        let backup_path = self.backup_config.backup_dir.join(&backup_file);
+        let resp = handler.handle(command(&[b"CONFIG", b"FOO"]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::Error(
+                "ERR Unknown subcommand or wrong number of arguments for 'foo'. Try CONFIG HELP."
+                    .to_string()
+            )
+        );
 
-        // Security: Validate the resolved path is within backup directory
-        let canonical = match backup_path.canonicalize() {
-            Ok(path) => path,
-            Err(_) => {
-                return RespValue::Error(format!("ERR Backup file not found: {}", backup_file));
-            }
+        let resp = handler.handle(command(&[b"COMMAND", b"BAR"]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::Error(
+                "ERR Unknown subcommand or wrong number of arguments for 'bar'. Try COMMAND HELP."
+                    .to_string()
+            )
+        );
+
+        let resp = handler.handle(command(&[b"DEBUG", b"BAZ"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(ref e) if e.ends_with("Try DEBUG HELP.")));
+    }
+
+    #[test]
+    fn test_info_server_run_id_and_uptime() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+        let server = |handler: &CommandHandler, session: &mut SessionState| match handler
+            .handle(command(&[b"INFO", b"server"]), session)
+        {
+            RespValue::BulkString(Some(text)) => String::from_utf8(text).unwrap(),
+            other => panic!("Expected bulk string, got {:?}", other),
+        };
+        let field = |text: &str, name: &str| -> String {
+            text.lines()
+                .find_map(|line| line.strip_prefix(&format!("{}:", name)))
+                .unwrap_or_else(|| panic!("no {} in {:?}", name, text))
+                .to_string()
         };
 
-        let backup_canonical = match self.backup_config.backup_dir.canonicalize() {
-            Ok(path) => path,
-            Err(_) => {
-                error!("Failed to canonicalize backup directory");
-                return RespValue::Error("ERR backup directory error".to_string());
+        let text = server(&handler, &mut session);
+        let run_id = field(&text, "run_id");
+        assert_eq!(run_id.len(), 40);
+        assert!(run_id.bytes().all(|b| b.is_ascii_hexdigit()), "{}", run_id);
+        assert!(field(&text, "uptime_in_seconds").parse::<u64>().is_ok());
+
+        // Fixed for the life of the server, new for the next one
+        assert_eq!(field(&server(&handler, &mut session), "run_id"), run_id);
+        let other_dir = TempDir::new().unwrap();
+        let other = config_handler(&other_dir);
+        assert_ne!(field(&server(&other, &mut session), "run_id"), run_id);
+    }
+
+    #[test]
+    fn test_info_sections() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+        let info = |handler: &CommandHandler, session: &mut SessionState, args: &[&[u8]]| {
+            let mut parts: Vec<&[u8]> = vec![b"INFO"];
+            parts.extend_from_slice(args);
+            match handler.handle(command(&parts), session) {
+                RespValue::BulkString(Some(text)) => String::from_utf8(text).unwrap(),
+                other => panic!("Expected bulk string, got {:?}", other),
             }
         };
 
-        // Ensure the resolved path is within the backup directory
-        if !canonical.starts_with(&backup_canonical) {
-            warn!(
-                "Path traversal attempt blocked: {} -> {:?}",
-                backup_file, canonical
-            );
-            return RespValue::Error("ERR path traversal attempt blocked".to_string());
-        }
+        handler.handle(command(&[b"SET", b"a", b"1"]), &mut session);
+        handler.handle(command(&[b"SET", b"b", b"2"]), &mut session);
+        handler.handle(command(&[b"EXPIRE", b"a", b"100"]), &mut session);
 
-        if !backup_path.exists() {
-            return RespValue::Error(format!("ERR Backup file not found: {:?}", backup_path));
-        }
+        let stats = info(&handler, &mut session, &[b"stats"]);
+        assert!(stats.starts_with("# Stats\r\n"));
+        assert!(stats.contains("cache_hits:"));
+        assert!(stats.contains("instantaneous_ops_per_sec:"));
+        assert!(stats.contains("cmd_latency_us:"));
+        assert!(!stats.contains("toonstore_version"));
 
-        warn!("Restoring from backup: {:?}", backup_path);
+        // Every handled command counts, an INFO once it has replied
+        assert!(
+            stats.contains("total_commands_processed:3\r\n"),
+            "{}",
+            stats
+        );
+        handler.handle(command(&[b"PING"]), &mut session);
+        handler.handle(command(&[b"NOSUCHCMD"]), &mut session);
+        let stats = info(&handler, &mut session, &[b"stats"]);
+        assert!(
+            stats.contains("total_commands_processed:6\r\n"),
+            "{}",
+            stats
+        );
 
-        match self.backup_config.restore_backup(&backup_path) {
-            Ok(_) => {
-                info!("Database restored successfully from {:?}", backup_path);
-                RespValue::SimpleString("OK - Server restart recommended".to_string())
-            }
-            Err(e) => {
-                error!("Failed to restore backup: {}", e);
-                RespValue::Error(format!("ERR Failed to restore backup: {}", e))
-            }
+        let memory = info(&handler, &mut session, &[b"memory"]);
+        assert!(memory.starts_with("# Memory\r\n"));
+        assert!(memory.contains("index_entries:2\r\n"), "{}", memory);
+        assert!(memory.contains("cache_entries:2\r\n"), "{}", memory);
+        assert!(memory.contains("cache_bytes:2\r\n"), "{}", memory);
+        assert!(memory.contains("lru_free_nodes:0\r\n"), "{}", memory);
+
+        let keyspace = info(&handler, &mut session, &[b"KEYSPACE"]);
+        assert!(
+            keyspace.starts_with("# Keyspace\r\ndb0:keys=2,expires=1,avg_ttl="),
+            "{}",
+            keyspace
+        );
+
+        let full = info(&handler, &mut session, &[]);
+        for header in ["# Server", "# Clients", "# Stats", "# Memory", "# Keyspace"] {
+            assert!(full.contains(header), "missing {}", header);
         }
+        // Same report, apart from the command counters moving on
+        let field_names = |text: &str| -> Vec<String> {
+            text.lines()
+                .map(|line| line.split(':').next().unwrap().to_string())
+                .collect()
+        };
+        let all = info(&handler, &mut session, &[b"all"]);
+        assert_eq!(field_names(&all), field_names(&full));
+        assert_eq!(info(&handler, &mut session, &[b"nosuchsection"]), "");
     }
 
-    fn handle_lastsave(&self) -> RespValue {
-        match self.backup_config.list_backups() {
-            Ok(backups) => {
-                let mut result = Vec::new();
-                result.push(RespValue::BulkString(Some(
-                    "Recent Backups:".as_bytes().to_vec(),
-                )));
-
-                for (i, backup) in backups.iter().take(10).enumerate() {
-                    let info = format!("{}. {} ({} bytes)", i + 1, backup.filename, backup.size);
-                    result.push(RespValue::BulkString(Some(info.as_bytes().to_vec())));
-                }
+    #[test]
+    fn test_connected_clients() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
 
-                if result.len() == 1 {
-                    result.push(RespValue::BulkString(Some(
-                        "No backups found".as_bytes().to_vec(),
-                    )));
-                }
+        let first = handler.track_connection();
+        let second = handler.track_connection();
+        assert_eq!(handler.connected_clients(), 2);
+        drop(first);
+        assert_eq!(handler.connected_clients(), 1);
 
-                RespValue::Array(Some(result))
-            }
-            Err(e) => {
-                error!("Failed to list backups: {}", e);
-                RespValue::Error(format!("ERR Failed to list backups: {}", e))
-            }
-        }
-    }
-}
+        let resp = handler.handle(command(&[b"INFO", b"clients"]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::BulkString(Some(b"# Clients\r\nconnected_clients:1\r\n".to_vec()))
+        );
 
-/// Simple glob pattern matching for Redis KEYS command
-/// Supports: * (matches any sequence), ? (matches single char)
-fn matches_pattern(key: &str, pattern: &str) -> bool {
-    if pattern == "*" {
-        return true;
+        drop(second);
+        assert_eq!(handler.connected_clients(), 0);
     }
 
-    let key_chars: Vec<char> = key.chars().collect();
-    let pattern_chars: Vec<char> = pattern.chars().collect();
+    #[test]
+    fn test_flushdb_clears_storage() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
 
-    let mut key_idx = 0;
-    let mut pattern_idx = 0;
-    let mut star_idx = None;
-    let mut match_idx = 0;
+        handler.handle(command(&[b"SET", b"a", b"1"]), &mut session);
+        handler.handle(command(&[b"RPUSH", b"l", b"x", b"y"]), &mut session);
 
-    while key_idx < key_chars.len() {
-        if pattern_idx < pattern_chars.len() {
-            match pattern_chars[pattern_idx] {
-                '*' => {
-                    star_idx = Some(pattern_idx);
-                    match_idx = key_idx;
-                    pattern_idx += 1;
-                    continue;
-                }
-                '?' => {
-                    key_idx += 1;
-                    pattern_idx += 1;
-                    continue;
-                }
-                c if c == key_chars[key_idx] => {
-                    key_idx += 1;
-                    pattern_idx += 1;
-                    continue;
-                }
-                _ => {}
-            }
+        let resp = handler.handle(command(&[b"FLUSHDB", b"ASYNC"]), &mut session);
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+        assert_eq!(handler.cache.scan().count(), 0);
+        let resp = handler.handle(command(&[b"DBSIZE"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(0));
+        for file in ["keymap.txt", "lists.txt"] {
+            assert_eq!(std::fs::read(dir.path().join(file)).unwrap(), b"");
         }
 
-        // No match, backtrack to last star if exists
-        if let Some(star) = star_idx {
-            pattern_idx = star + 1;
-            match_idx += 1;
-            key_idx = match_idx;
-        } else {
-            return false;
-        }
-    }
+        // New writes reuse row IDs without resurrecting old keys
+        handler.handle(command(&[b"SET", b"b", b"2"]), &mut session);
+        let resp = handler.handle(command(&[b"GET", b"a"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(None));
+        let resp = handler.handle(command(&[b"GET", b"b"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(Some(b"2".to_vec())));
 
-    // Check remaining pattern chars are all stars
-    while pattern_idx < pattern_chars.len() && pattern_chars[pattern_idx] == '*' {
-        pattern_idx += 1;
+        let resp = handler.handle(command(&[b"FLUSHDB", b"SYNC"]), &mut session);
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+        let resp = handler.handle(command(&[b"FLUSHDB", b"LATER"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(_)));
     }
 
-    pattern_idx == pattern_chars.len()
-}
+    #[test]
+    fn test_debug_reload() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::TempDir;
+        handler.handle(command(&[b"SET", b"a", b"alpha"]), &mut session);
+        handler.handle(command(&[b"SET", b"b", b"beta"]), &mut session);
+        handler.handle(command(&[b"DEL", b"b"]), &mut session);
+        handler.handle(command(&[b"HSET", b"h", b"f", b"v"]), &mut session);
+        handler.handle(command(&[b"RPUSH", b"l", b"x", b"y"]), &mut session);
+        handler.handle(command(&[b"EXPIRE", b"a", b"100"]), &mut session);
+
+        let resp = handler.handle(command(&[b"DEBUG", b"RELOAD"]), &mut session);
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+
+        let resp = handler.handle(command(&[b"GET", b"a"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(Some(b"alpha".to_vec())));
+        let resp = handler.handle(command(&[b"GET", b"b"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(None));
+        let resp = handler.handle(command(&[b"HGET", b"h", b"f"]), &mut session);
+        assert_eq!(resp, RespValue::BulkString(Some(b"v".to_vec())));
+        let resp = handler.handle(command(&[b"LLEN", b"l"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(2));
+        let resp = handler.handle(command(&[b"TTL", b"a"]), &mut session);
+        assert!(matches!(resp, RespValue::Integer(ttl) if ttl > 0));
+        let resp = handler.handle(command(&[b"DBSIZE"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(3));
+
+        let resp = handler.handle(command(&[b"DEBUG", b"NOPE"]), &mut session);
+        assert!(matches!(resp, RespValue::Error(_)));
+    }
 
     #[test]
-    fn test_ping() {
+    fn test_debug_selftest() {
         let dir = TempDir::new().unwrap();
-        let cache = Arc::new(ToonCache::new(dir.path(), 100).unwrap());
-        let auth = Arc::new(AuthConfig::disabled());
-        let backup = Arc::new(BackupConfig::new(dir.path(), None::<&str>));
-        let handler = CommandHandler::new(
-            cache,
-            dir.path().to_str().unwrap(),
-            auth.clone(),
-            backup,
-            None,
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+        handler.handle(command(&[b"SET", b"k", b"v"]), &mut session);
+
+        let resp = handler.handle(command(&[b"DEBUG", b"SELFTEST"]), &mut session);
+        match resp {
+            RespValue::SimpleString(s) => {
+                assert!(s.starts_with("OK put "), "{}", s);
+                assert!(s.contains("us get ") && s.ends_with("us"), "{}", s);
+            }
+            other => panic!("unexpected reply {:?}", other),
+        }
+        // The live store never saw the row, and the scratch store is gone
+        let rows = handler.cache.len();
+        handler.handle(command(&[b"DEBUG", b"SELFTEST"]), &mut session);
+        assert_eq!(handler.cache.len(), rows);
+        assert_eq!(handler.cache.live_len(), 1);
+        let resp = handler.handle(command(&[b"DBSIZE"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(1));
+        let leftovers = std::fs::read_dir(dir.path())
+            .unwrap()
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(".selftest_")
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+
+        // A store refusing writes fails the first step
+        handler.cache.set_max_db_size(0);
+        let resp = handler.handle(command(&[b"DEBUG", b"SELFTEST"]), &mut session);
+        assert!(
+            matches!(resp, RespValue::Error(ref e) if e.starts_with("ERR selftest put failed: Database full")),
+            "{:?}",
+            resp
         );
+    }
+
+    #[test]
+    fn test_debug_object() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
         let mut session = SessionState::new(false);
+        handler.handle(command(&[b"SET", b"k", b"hello"]), &mut session);
+        handler.handle(command(&[b"GET", b"k"]), &mut session);
+        handler.handle(command(&[b"RPUSH", b"l", b"ab", b"cde"]), &mut session);
+        let row_id = handler.key_map.read().unwrap()["k"];
 
-        let cmd = RespValue::Array(Some(vec![RespValue::BulkString(Some(b"PING".to_vec()))]));
+        let resp = handler.handle(command(&[b"DEBUG", b"OBJECT", b"k"]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::SimpleString(format!(
+                "refcount:1 encoding:raw serializedlength:5 row_id:{} cached:1",
+                row_id
+            ))
+        );
+        handler.cache.clear_cache();
+        let resp = handler.handle(command(&[b"DEBUG", b"OBJECT", b"k"]), &mut session);
+        assert!(matches!(resp, RespValue::SimpleString(ref s) if s.ends_with("cached:0")));
 
-        let resp = handler.handle(cmd, &mut session);
-        assert_eq!(resp, RespValue::SimpleString("PONG".to_string()));
+        let resp = handler.handle(command(&[b"DEBUG", b"OBJECT", b"l"]), &mut session);
+        assert_eq!(
+            resp,
+            RespValue::SimpleString(
+                "refcount:1 encoding:linkedlist serializedlength:5".to_string()
+            )
+        );
+        let resp = handler.handle(command(&[b"DEBUG", b"OBJECT", b"nope"]), &mut session);
+        assert_eq!(resp, RespValue::Error("ERR no such key".to_string()));
     }
 
     #[test]
-    fn test_echo() {
+    fn test_debug_set_active_expire() {
         let dir = TempDir::new().unwrap();
-        let cache = Arc::new(ToonCache::new(dir.path(), 100).unwrap());
-        let auth = Arc::new(AuthConfig::disabled());
-        let backup = Arc::new(BackupConfig::new(dir.path(), None::<&str>));
+        let handler = config_handler(&dir);
+        let mut session = SessionState::new(false);
+        assert!(handler.active_expire_enabled());
+
+        let resp = handler.handle(
+            command(&[b"DEBUG", b"SET-ACTIVE-EXPIRE", b"0"]),
+            &mut session,
+        );
+        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+        assert!(!handler.active_expire_enabled());
+        handler.handle(
+            command(&[b"DEBUG", b"SET-ACTIVE-EXPIRE", b"1"]),
+            &mut session,
+        );
+        assert!(handler.active_expire_enabled());
+
+        let resp = handler.handle(
+            command(&[b"DEBUG", b"SET-ACTIVE-EXPIRE", b"2"]),
+            &mut session,
+        );
+        assert!(matches!(resp, RespValue::Error(_)));
+
+        // Without a sweep there is nothing to switch on or off
+        let dir = TempDir::new().unwrap();
+        let config = ServerConfig {
+            active_expire_cycle_ms: 0,
+            ..ServerConfig::default()
+        };
         let handler = CommandHandler::new(
-            cache,
+            Arc::new(ToonCache::new(dir.path(), 100).unwrap()),
             dir.path().to_str().unwrap(),
-            auth.clone(),
-            backup,
+            Arc::new(AuthConfig::disabled()),
+            Arc::new(BackupConfig::new(dir.path(), None::<&str>)),
             None,
+            config,
         );
+        let resp = handler.handle(
+            command(&[b"DEBUG", b"SET-ACTIVE-EXPIRE", b"1"]),
+            &mut session,
+        );
+        assert!(matches!(resp, RespValue::Error(e) if e.contains("--active-expire-cycle-ms")));
+    }
+
+    #[test]
+    fn test_debug_stringmatch_len() {
+        let dir = TempDir::new().unwrap();
+        let handler = config_handler(&dir);
         let mut session = SessionState::new(false);
+        let mut stringmatch = |pattern: &[u8], string: &[u8]| {
+            handler.handle(
+                command(&[b"DEBUG", b"STRINGMATCH-LEN", pattern, string]),
+                &mut session,
+            )
+        };
 
+        assert_eq!(
+            stringmatch(b"user:[0-9]*", b"user:42"),
+            RespValue::Integer(1)
+        );
+        assert_eq!(
+            stringmatch(b"user:[^0-9]*", b"user:42"),
+            RespValue::Integer(0)
+        );
+
+        // Backtracking over many stars stays linear
+        let string = vec![b'a'; 5000];
+        let pattern = b"a*a*a*a*a*a*a*a*a*a*a*a*b";
+        assert_eq!(stringmatch(pattern, &string), RespValue::Integer(0));
+
+        let arity = RespValue::Error(
+            "ERR wrong number of arguments for 'debug|stringmatch-len' command".to_string(),
+        );
+        assert_eq!(
+            handler.handle(command(&[b"DEBUG", b"STRINGMATCH-LEN", b"*"]), &mut session),
+            arity
+        );
         let cmd = RespValue::Array(Some(vec![
-            RespValue::BulkString(Some(b"ECHO".to_vec())),
-            RespValue::BulkString(Some(b"hello".to_vec())),
+            RespValue::BulkString(Some(b"DEBUG".to_vec())),
+            RespValue::BulkString(Some(b"STRINGMATCH-LEN".to_vec())),
+            RespValue::BulkString(Some(b"*".to_vec())),
+            RespValue::Integer(1),
         ]));
+        assert_eq!(handler.handle(cmd, &mut session), arity);
+    }
 
-        let resp = handler.handle(cmd, &mut session);
-        assert_eq!(resp, RespValue::BulkString(Some(b"hello".to_vec())));
+    #[test]
+    fn test_sync_replica_matches_master() {
+        let master_dir = TempDir::new().unwrap();
+        let master = config_handler(&master_dir);
+        let mut session = SessionState::new(false);
+        handler_writes(
+            &master,
+            &mut session,
+            &[
+                &[b"SET", b"str", b"before"],
+                &[b"SET", b"gone", b"x"],
+                &[b"HSET", b"h", b"a", b"1", b"b", b"2"],
+                &[b"RPUSH", b"l", b"x", b"y", b"z"],
+                &[b"EXPIRE", b"str", b"100"],
+            ],
+        );
+
+        let mut replica_session = SessionState::new(false);
+        let (feed, mut replicated) = master.pubsub().register();
+        replica_session.subscriber = Some(feed);
+        let RespValue::Array(Some(snapshot)) =
+            master.handle(command(&[b"SYNC"]), &mut replica_session)
+        else {
+            panic!("expected a snapshot");
+        };
+        assert_eq!(master.replication.replica_count(), 1);
+
+        // Writes after the snapshot arrive on the feed, failed ones do not
+        handler_writes(
+            &master,
+            &mut session,
+            &[
+                &[b"SET", b"new", b"after"],
+                &[b"DEL", b"gone"],
+                &[b"HDEL", b"h", b"a"],
+                &[b"LPOP", b"l"],
+                &[b"GET", b"str"],
+            ],
+        );
+        let resp = master.handle(command(&[b"HSET", b"str", b"f", b"v"]), &mut session);
+        assert_eq!(resp, wrong_type());
+        let mut feed = Vec::new();
+        while let Ok(Message::Replicate(cmd)) = replicated.try_recv() {
+            feed.push(cmd);
+        }
+        assert_eq!(feed.len(), 4);
+        assert_eq!(feed[0], command(&[b"SET", b"new", b"after"]));
+
+        // A replica with its own data ends up with exactly the master's
+        let replica_dir = TempDir::new().unwrap();
+        let replica = config_handler(&replica_dir);
+        let mut apply_session = SessionState::new(false);
+        replica.handle(command(&[b"SET", b"stale", b"x"]), &mut apply_session);
+        for cmd in snapshot.into_iter().chain(feed) {
+            let resp = replica.handle(cmd, &mut apply_session);
+            assert!(!matches!(resp, RespValue::Error(_)), "{:?}", resp);
+        }
+
+        let reads: &[&[&[u8]]] = &[
+            &[b"DBSIZE"],
+            &[b"GET", b"str"],
+            &[b"GET", b"new"],
+            &[b"EXISTS", b"gone", b"stale"],
+            &[b"HGETALL", b"h"],
+            &[b"LRANGE", b"l", b"0", b"-1"],
+            &[b"TTL", b"new"],
+        ];
+        for read in reads {
+            assert_eq!(
+                replica.handle(command(read), &mut apply_session),
+                master.handle(command(read), &mut session),
+                "{:?}",
+                read
+            );
+        }
+        let RespValue::Integer(ttl) =
+            replica.handle(command(&[b"TTL", b"str"]), &mut apply_session)
+        else {
+            panic!("expected a TTL");
+        };
+        assert!(ttl > 90 && ttl <= 100, "{}", ttl);
+    }
+
+    fn handler_writes(handler: &CommandHandler, session: &mut SessionState, cmds: &[&[&[u8]]]) {
+        for cmd in cmds {
+            let resp = handler.handle(command(cmd), session);
+            assert!(!matches!(resp, RespValue::Error(_)), "{:?}", resp);
+        }
     }
 
     #[test]
-    fn test_set_and_get() {
+    fn test_exists_counts_repeated_keys() {
         let dir = TempDir::new().unwrap();
-        let cache = Arc::new(ToonCache::new(dir.path(), 100).unwrap());
-        let auth = Arc::new(AuthConfig::disabled());
-        let backup = Arc::new(BackupConfig::new(dir.path(), None::<&str>));
-        let handler = CommandHandler::new(
-            cache,
-            dir.path().to_str().unwrap(),
-            auth.clone(),
-            backup,
-            None,
-        );
+        let handler = config_handler(&dir);
         let mut session = SessionState::new(false);
 
-        // SET key value
-        let set_cmd = RespValue::Array(Some(vec![
-            RespValue::BulkString(Some(b"SET".to_vec())),
-            RespValue::BulkString(Some(b"mykey".to_vec())),
-            RespValue::BulkString(Some(b"myvalue".to_vec())),
-        ]));
+        handler.handle(command(&[b"SET", b"foo", b"1"]), &mut session);
+        handler.handle(command(&[b"RPUSH", b"list", b"x"]), &mut session);
 
-        let resp = handler.handle(set_cmd, &mut session);
-        assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+        let resp = handler.handle(command(&[b"EXISTS", b"foo", b"foo"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(2));
+        let resp = handler.handle(command(&[b"EXISTS", b"nope", b"nope"]), &mut session);
+        assert_eq!(resp, RespValue::Integer(0));
+        let resp = handler.handle(
+            command(&[b"EXISTS", b"foo", b"nope", b"list", b"foo"]),
+            &mut session,
+        );
+        assert_eq!(resp, RespValue::Integer(3));
     }
 }
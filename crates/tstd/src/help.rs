@@ -0,0 +1,115 @@
+//! Static `<COMMAND> HELP` text for commands with subcommands
+//!
+//! Lines follow the Redis layout: a usage line, then each subcommand with
+//! its description indented underneath.
+
+const CONFIG_HELP: &[&str] = &[
+    "CONFIG <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+    "GET <pattern>",
+    "    Return parameters matching the glob-like <pattern> and their values.",
+    "SET <parameter> <value>",
+    "    Set the configuration <parameter> to <value>.",
+    "HELP",
+    "    Print this help.",
+];
+
+const CLIENT_HELP: &[&str] = &[
+    "CLIENT <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+    "GETNAME",
+    "    Return the name of the current connection.",
+    "SETNAME <name>",
+    "    Assign the name <name> to the current connection.",
+    "TRACKING (ON|OFF)",
+    "    Enable or disable invalidation pushes for keys read by this connection.",
+    "    Pushes are RESP3, so the connection must have sent HELLO 3.",
+    "HELP",
+    "    Print this help.",
+];
+
+const MEMORY_HELP: &[&str] = &[
+    "MEMORY <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+    "USAGE <key> [SAMPLES <count>]",
+    "    Return the estimated bytes <key> and its value use, including overhead.",
+    "    SAMPLES is accepted for compatibility and ignored.",
+    "HELP",
+    "    Print this help.",
+];
+
+const OBJECT_HELP: &[&str] = &[
+    "OBJECT <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+    "ENCODING <key>",
+    "    Return the kind of internal representation used to store the value",
+    "    associated with <key>.",
+    "CREATEDAT <key>",
+    "    Return the Unix time in milliseconds at which <key> was first set.",
+    "UPDATEDAT <key>",
+    "    Return the Unix time in milliseconds at which <key> was last written.",
+    "FREQ <key>",
+    "    Return the access frequency counter of <key> (LFU cache only).",
+    "HELP",
+    "    Print this help.",
+];
+
+const ACL_HELP: &[&str] = &[
+    "ACL <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+    "SETUSER <username> [<rule> ...]",
+    "    Create or modify a user: on, off, ><password>, +@all, +@write, +@read",
+    "    or maxvaluesize=<size>|default.",
+    "GETUSER <username>",
+    "    Get the user's details.",
+    "DELUSER <username> [<username> ...]",
+    "    Delete a list of users.",
+    "LIST",
+    "    Show users details in config file format.",
+    "LOAD",
+    "    Reload the users from users.json, e.g. after editing it by hand.",
+    "HELP",
+    "    Print this help.",
+];
+
+const DEBUG_HELP: &[&str] = &[
+    "DEBUG <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+    "RELOAD",
+    "    Save the data to disk and reload it back to memory.",
+    "SLEEP <seconds>",
+    "    Block the command for <seconds>, which may be fractional.",
+    "SELFTEST",
+    "    Write, read back and delete a throwaway row, replying with the time",
+    "    each step took.",
+    "OBJECT <key>",
+    "    Show the encoding, on-disk length, row ID and cache residency of <key>.",
+    "SET-ACTIVE-EXPIRE <0|1>",
+    "    Pause or resume the background sweep for expired keys.",
+    "STRINGMATCH-LEN <pattern> <string>",
+    "    Return 1 if <string> matches the glob <pattern>, as KEYS would, else 0.",
+    "HELP",
+    "    Print this help.",
+];
+
+const COMMAND_HELP: &[&str] = &[
+    "COMMAND <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+    "(no subcommand)",
+    "    Return details about all commands.",
+    "COUNT",
+    "    Return the total number of commands.",
+    "INFO [<command-name> ...]",
+    "    Return details about the given commands.",
+    "DOCS [<command-name> ...]",
+    "    Return documentation details about the given commands, or all of them.",
+    "HELP",
+    "    Print this help.",
+];
+
+/// Help lines for `command` (uppercase), if it has subcommands
+pub fn lines(command: &str) -> Option<&'static [&'static str]> {
+    match command {
+        "CONFIG" => Some(CONFIG_HELP),
+        "CLIENT" => Some(CLIENT_HELP),
+        "OBJECT" => Some(OBJECT_HELP),
+        "MEMORY" => Some(MEMORY_HELP),
+        "ACL" => Some(ACL_HELP),
+        "DEBUG" => Some(DEBUG_HELP),
+        "COMMAND" => Some(COMMAND_HELP),
+        _ => None,
+    }
+}
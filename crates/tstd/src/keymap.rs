@@ -0,0 +1,200 @@
+//! Key to row maps that can also be looked up by row
+//!
+//! SCAN pages through the store's rows by row ID and reports the keys of
+//! the rows in each page, so it needs the key at a row without walking
+//! every key. Both maps keep a second index by row for that. Reads go
+//! through `Deref` to the plain map; writes go through these types so the
+//! row index stays in step.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ops::Deref;
+
+/// Row holding each string or hash key, with the key at each row
+#[derive(Debug, Default)]
+pub struct KeyMap {
+    rows: HashMap<String, u64>,
+    keys: BTreeMap<u64, String>,
+}
+
+impl KeyMap {
+    /// Point `key` at `row_id`, returning the row it pointed at before
+    pub fn insert(&mut self, key: String, row_id: u64) -> Option<u64> {
+        self.keys.insert(row_id, key.clone());
+        let old = self.rows.insert(key, row_id);
+        if let Some(old) = old.filter(|&old| old != row_id) {
+            self.keys.remove(&old);
+        }
+        old
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<u64> {
+        let row_id = self.rows.remove(key)?;
+        self.keys.remove(&row_id);
+        Some(row_id)
+    }
+
+    pub fn clear(&mut self) {
+        self.rows.clear();
+        self.keys.clear();
+    }
+
+    /// Key whose value is the row `row_id`
+    pub fn key_at(&self, row_id: u64) -> Option<&str> {
+        self.keys.get(&row_id).map(String::as_str)
+    }
+}
+
+impl Deref for KeyMap {
+    type Target = HashMap<String, u64>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.rows
+    }
+}
+
+impl From<HashMap<String, u64>> for KeyMap {
+    fn from(rows: HashMap<String, u64>) -> Self {
+        let keys = rows.iter().map(|(key, &row)| (row, key.clone())).collect();
+        Self { rows, keys }
+    }
+}
+
+/// Element rows of each list, front first, with each list at its lowest row
+///
+/// A list is reported by SCAN at its lowest row. Elements are only pushed
+/// and popped at the ends, and a pushed element is a new row, above every
+/// row already in the list. So rows fall from the front to the oldest
+/// element and rise from there to the back, and once the lowest is popped
+/// the new lowest is at one of the ends; nothing has to be searched.
+#[derive(Debug, Default)]
+pub struct Lists {
+    lists: HashMap<String, VecDeque<u64>>,
+    by_lowest: BTreeMap<u64, String>,
+}
+
+impl Lists {
+    pub fn remove(&mut self, key: &str) -> Option<VecDeque<u64>> {
+        let rows = self.lists.remove(key)?;
+        if let Some(lowest) = rows.iter().min() {
+            self.by_lowest.remove(lowest);
+        }
+        Some(rows)
+    }
+
+    pub fn clear(&mut self) {
+        self.lists.clear();
+        self.by_lowest.clear();
+    }
+
+    /// Add `row_id` to the front or back of `key`, creating the list
+    pub fn push(&mut self, key: &str, row_id: u64, front: bool) {
+        if !self.lists.contains_key(key) {
+            self.lists.insert(key.to_string(), VecDeque::new());
+        }
+        let list = self.lists.get_mut(key).expect("list was just created");
+        if list.is_empty() {
+            self.by_lowest.insert(row_id, key.to_string());
+        }
+        if front {
+            list.push_front(row_id);
+        } else {
+            list.push_back(row_id);
+        }
+    }
+
+    /// Take the front or back element of `key`; an emptied list stays, empty
+    pub fn pop(&mut self, key: &str, front: bool) -> Option<u64> {
+        let list = self.lists.get_mut(key)?;
+        let row_id = if front {
+            list.pop_front()
+        } else {
+            list.pop_back()
+        }?;
+        if self
+            .by_lowest
+            .get(&row_id)
+            .is_some_and(|owner| owner == key)
+        {
+            self.by_lowest.remove(&row_id);
+            let ends = list.front().into_iter().chain(list.back());
+            if let Some(&lowest) = ends.min() {
+                self.by_lowest.insert(lowest, key.to_string());
+            }
+        }
+        Some(row_id)
+    }
+
+    /// List whose lowest element is the row `row_id`
+    pub fn key_at(&self, row_id: u64) -> Option<&str> {
+        self.by_lowest.get(&row_id).map(String::as_str)
+    }
+}
+
+impl Deref for Lists {
+    type Target = HashMap<String, VecDeque<u64>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.lists
+    }
+}
+
+impl From<HashMap<String, VecDeque<u64>>> for Lists {
+    fn from(lists: HashMap<String, VecDeque<u64>>) -> Self {
+        let by_lowest = lists
+            .iter()
+            .filter_map(|(key, rows)| rows.iter().min().map(|&row| (row, key.clone())))
+            .collect();
+        Self { lists, by_lowest }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_map_rows() {
+        let mut keys = KeyMap::default();
+        keys.insert("a".to_string(), 1);
+        keys.insert("b".to_string(), 2);
+        // Rewriting a key moves it to its new row
+        assert_eq!(keys.insert("a".to_string(), 3), Some(1));
+        assert_eq!(keys.key_at(1), None);
+        assert_eq!(keys.key_at(3), Some("a"));
+        assert_eq!(keys.get("a"), Some(&3));
+
+        assert_eq!(keys.remove("b"), Some(2));
+        assert_eq!(keys.key_at(2), None);
+        assert_eq!(keys.len(), 1);
+
+        let reloaded = KeyMap::from(HashMap::from([("k".to_string(), 7)]));
+        assert_eq!(reloaded.key_at(7), Some("k"));
+    }
+
+    #[test]
+    fn test_list_lowest_row_follows_pops() {
+        let mut lists = Lists::default();
+        // RPUSH 1, LPUSH 2, RPUSH 3: [2, 1, 3]
+        lists.push("l", 1, false);
+        lists.push("l", 2, true);
+        lists.push("l", 3, false);
+        assert_eq!(lists.key_at(1), Some("l"));
+
+        assert_eq!(lists.pop("l", true), Some(2));
+        assert_eq!(lists.key_at(1), Some("l"));
+        assert_eq!(lists.pop("l", true), Some(1));
+        assert_eq!(lists.key_at(1), None);
+        assert_eq!(lists.key_at(3), Some("l"));
+        assert_eq!(lists.pop("l", false), Some(3));
+        assert_eq!(lists.key_at(3), None);
+        assert_eq!(lists.get("l").map(VecDeque::len), Some(0));
+
+        let mut lists = Lists::from(HashMap::from([(
+            "m".to_string(),
+            VecDeque::from([9, 5, 8]),
+        )]));
+        assert_eq!(lists.key_at(5), Some("m"));
+        assert_eq!(lists.remove("m").map(|rows| rows.len()), Some(3));
+        assert_eq!(lists.key_at(5), None);
+    }
+}
@@ -0,0 +1,100 @@
+//! Binary-safe key names
+//!
+//! Keys are kept as `String`s throughout the handler and in the key map
+//! files, but clients may send any bytes. `from_bytes` maps a key to a
+//! `String` losslessly: valid UTF-8 is kept as it is, and every other byte
+//! becomes the character `ESCAPE_BASE + byte`, from the end of the last
+//! private use plane. Tab, CR and LF are escaped the same way so that the
+//! files' `key<TAB>...` lines stay intact, and so are characters of the
+//! escape range itself, byte by byte, so no two keys ever map to the same
+//! `String`. `to_bytes` turns a key back into the bytes the client sent.
+//!
+//! Key map files written before keys were escaped hold raw key names, and
+//! a name there with characters from `ESCAPE_BASE` up is read back as the
+//! bytes those characters stand for. Such keys have to be renamed before
+//! upgrading; nothing on load can tell them from escaped keys.
+
+use std::borrow::Cow;
+
+/// First character of the escape range; byte `b` is escaped as `ESCAPE_BASE + b`
+const ESCAPE_BASE: u32 = 0x10FF00;
+
+/// Whether a character has to be escaped byte by byte
+fn needs_escape(c: char) -> bool {
+    matches!(c, '\t' | '\n' | '\r') || c as u32 >= ESCAPE_BASE
+}
+
+fn push_escaped(key: &mut String, bytes: &[u8]) {
+    for &b in bytes {
+        key.push(char::from_u32(ESCAPE_BASE + b as u32).expect("escape range is valid"));
+    }
+}
+
+/// Key name for the bytes a client sent
+pub fn from_bytes(bytes: &[u8]) -> String {
+    if let Ok(s) = std::str::from_utf8(bytes) {
+        if !s.chars().any(needs_escape) {
+            return s.to_string();
+        }
+    }
+
+    let mut key = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if needs_escape(c) {
+                push_escaped(&mut key, c.encode_utf8(&mut [0; 4]).as_bytes());
+            } else {
+                key.push(c);
+            }
+        }
+        push_escaped(&mut key, chunk.invalid());
+    }
+    key
+}
+
+/// The bytes a key name stands for, as sent by the client
+pub fn to_bytes(key: &str) -> Cow<'_, [u8]> {
+    if !key.chars().any(|c| c as u32 >= ESCAPE_BASE) {
+        return Cow::Borrowed(key.as_bytes());
+    }
+
+    let mut bytes = Vec::with_capacity(key.len());
+    for c in key.chars() {
+        match (c as u32).checked_sub(ESCAPE_BASE) {
+            Some(b) => bytes.push(b as u8),
+            None => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    Cow::Owned(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let escaped_char = "\u{10FF41}".as_bytes();
+        for bytes in [
+            &b"user:1"[..],
+            "caf\u{e9}".as_bytes(),
+            b"\xff\xfe",
+            b"id:\xff\x00\x01",
+            b"tab\there\r\n",
+            escaped_char,
+            b"",
+        ] {
+            let key = from_bytes(bytes);
+            assert_eq!(to_bytes(&key).as_ref(), bytes, "{:?}", bytes);
+            assert!(!key.contains(['\t', '\n', '\r']));
+        }
+
+        // Plain keys are kept as they are
+        assert_eq!(from_bytes(b"user:1"), "user:1");
+        assert!(matches!(to_bytes("user:1"), Cow::Borrowed(_)));
+
+        // An escaped byte and the character it is escaped as stay apart
+        assert_ne!(from_bytes(b"\x41"), from_bytes(escaped_char));
+        assert_ne!(from_bytes(b"\xff"), from_bytes("\u{10FFFF}".as_bytes()));
+    }
+}
@@ -2,9 +2,25 @@
 
 mod auth;
 mod backup;
+mod bloom;
+mod commands;
+mod config;
+mod expiry;
+mod glob;
 mod handler;
+mod help;
+mod keymap;
+mod keys;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod pubsub;
+mod ratelimit;
+mod replication;
 mod resp;
+mod script;
+mod stats;
 mod tls;
+mod tracking;
 mod users;
 
 use anyhow::Result;
@@ -12,16 +28,21 @@ use auth::{AuthConfig, SessionState};
 use backup::BackupConfig;
 use bytes::BytesMut;
 use clap::Parser;
+use config::ServerConfig;
+use socket2::{SockRef, TcpKeepalive};
+use std::net::IpAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tls::{TlsConfig, TlsMode};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::Semaphore;
 use tooncache::ToonCache;
 use tracing::{error, info, warn};
 
 use crate::handler::CommandHandler;
+use crate::pubsub::{Mailbox, Overflow};
 use crate::resp::RespValue;
 
 /// Maximum concurrent connections - prevents DoS via connection flooding
@@ -38,10 +59,89 @@ struct Args {
     #[arg(short, long, default_value = "./data")]
     data: String,
 
-    /// Cache capacity (number of items)
+    /// Repair the data directory before opening it, after a crash mid-write:
+    /// rows its data file, index and header disagree on are dropped
+    #[arg(long)]
+    repair: bool,
+
+    /// Cache capacity (number of items, 0 = no cache: every read goes to storage)
     #[arg(short, long, default_value_t = 10000)]
     capacity: usize,
 
+    /// Send values larger than this, e.g. 256kb, straight to storage instead
+    /// of caching them (0 = cache values of any size)
+    #[arg(long, default_value = "0", value_parser = config::parse_memory)]
+    cache_max_value_size: u64,
+
+    /// Evict the oldest of this many sampled entries instead of keeping a
+    /// strict LRU, so cache hits need no exclusive lock (0 = strict LRU)
+    #[arg(long, default_value_t = 0)]
+    lru_samples: usize,
+
+    /// Evict the least frequently used of the sampled entries rather than
+    /// the least recently used, sampling --lru-samples entries (5 if unset)
+    #[arg(long)]
+    lfu: bool,
+
+    /// Refuse writes once the data file reaches this size, e.g. 512mb (0 = unlimited)
+    #[arg(long, default_value = "0", value_parser = config::parse_memory)]
+    maxmemory: u64,
+
+    /// Close connections whose unparsed input grows past this size, e.g. 64mb
+    #[arg(long, default_value = "1gb", value_parser = config::parse_memory)]
+    max_request_bytes: u64,
+
+    /// Write replies to the connection in pieces of this size, e.g. 16kb,
+    /// instead of serializing large ones into a single buffer
+    #[arg(long, default_value = "64kb", value_parser = config::parse_memory)]
+    reply_chunk_bytes: u64,
+
+    /// Reply with an error to `slow` commands (KEYS, SCAN, SAVE, ...) still
+    /// running after this many milliseconds (0 = no limit); the command
+    /// itself keeps running and may still complete
+    #[arg(long, default_value_t = 0)]
+    command_timeout_ms: u64,
+
+    /// Refuse commands from a connection beyond this many per second, with
+    /// bursts of up to a second's worth; AUTH and PING are exempt (0 = unlimited)
+    #[arg(long, default_value_t = 0)]
+    max_commands_per_sec: u64,
+
+    /// Sweep for expired keys nobody reads this often, spending at most a
+    /// quarter of each cycle on it (0 = only expire keys when touched)
+    #[arg(long, default_value_t = config::DEFAULT_ACTIVE_EXPIRE_CYCLE_MS)]
+    active_expire_cycle_ms: u64,
+
+    /// Send TCP keepalive probes after this many idle seconds (0 = disabled)
+    #[arg(long, default_value_t = 300)]
+    tcp_keepalive: u64,
+
+    /// Rename a command, or disable it with an empty name (repeatable),
+    /// e.g. --rename-command FLUSHDB "" --rename-command CONFIG mysecretconfig
+    #[arg(long, num_args = 2, value_names = ["COMMAND", "NEW_NAME"])]
+    rename_command: Vec<String>,
+
+    /// Publish `__keyevent@0__:expired` messages when keys expire
+    #[arg(long)]
+    notify_keyspace_events: bool,
+
+    /// Keep a bloom filter of stored keys sized for this many, so GET of a
+    /// key never stored skips the key lookup (0 = no filter)
+    #[arg(long, default_value_t = 0)]
+    bloom_filter: usize,
+
+    /// Close connections whose pending pub/sub messages, invalidations and
+    /// replicated writes pass <hard> bytes, or stay over <soft> bytes for
+    /// <soft-seconds>, e.g. "32mb 8mb 60" (0 = no limit); replies to the
+    /// connection's own commands do not count
+    #[arg(long, default_value = "0 0 0", value_parser = config::OutputBufferLimit::parse)]
+    client_output_buffer_limit: config::OutputBufferLimit,
+
+    /// Most keys CLIENT TRACKING remembers; past it the oldest is forgotten
+    /// and its readers get an invalidation (0 = no limit)
+    #[arg(long, default_value_t = config::DEFAULT_TRACKING_TABLE_MAX_KEYS)]
+    tracking_table_max_keys: usize,
+
     /// Health check mode (for Docker)
     #[arg(long)]
     health: bool,
@@ -54,6 +154,26 @@ struct Args {
     #[arg(long)]
     multi_user: bool,
 
+    /// Lock out AUTH after this many failed attempts in a row on a connection
+    /// or from its client address, for 1s doubling with each further failure
+    /// up to 5 minutes (0 = never)
+    #[arg(long, default_value_t = config::DEFAULT_AUTH_MAX_FAILURES)]
+    auth_max_failures: u32,
+
+    /// Also count failed AUTH attempts per existing username across connections
+    /// (multi-user mode; lets anyone lock a known username out for a while)
+    #[arg(long)]
+    auth_lockout_per_user: bool,
+
+    /// Bcrypt cost for password hashes, 4 to 31; users hashed at a lower
+    /// cost are re-hashed on their next login
+    #[arg(
+        long,
+        default_value_t = bcrypt::DEFAULT_COST,
+        value_parser = clap::value_parser!(u32).range(4..=31)
+    )]
+    bcrypt_cost: u32,
+
     /// TLS/SSL mode: disable, prefer, require
     #[arg(long, default_value = "disable")]
     tls_mode: String,
@@ -73,6 +193,24 @@ struct Args {
     /// Auto-backup interval in minutes (0 to disable)
     #[arg(long, default_value_t = 0)]
     auto_backup: u64,
+
+    /// Never prune auto-backups younger than this many minutes
+    #[arg(long, default_value_t = 0)]
+    backup_min_age: u64,
+
+    /// Backup gzip level, 0 (store only, fastest) to 9 (smallest)
+    #[arg(long, default_value_t = 6, value_parser = clap::value_parser!(u32).range(0..=9))]
+    backup_compression: u32,
+
+    /// Serve Prometheus metrics over HTTP at this address, e.g. 127.0.0.1:9121
+    #[cfg(feature = "metrics")]
+    #[arg(long)]
+    metrics_addr: Option<String>,
+
+    /// Listen on a UNIX domain socket at this path instead of TCP
+    /// (mutually exclusive with TLS)
+    #[arg(long)]
+    unixsocket: Option<PathBuf>,
 }
 
 #[tokio::main]
@@ -90,6 +228,20 @@ async fn main() -> Result<()> {
     // Health check
     if args.health {
         // Try to connect to the server
+        #[cfg(unix)]
+        if let Some(path) = &args.unixsocket {
+            match tokio::net::UnixStream::connect(path).await {
+                Ok(_) => {
+                    println!("OK");
+                    std::process::exit(0);
+                }
+                Err(_) => {
+                    eprintln!("FAILED");
+                    std::process::exit(1);
+                }
+            }
+        }
+
         match TcpStream::connect(&args.bind).await {
             Ok(_) => {
                 println!("OK");
@@ -114,7 +266,7 @@ async fn main() -> Result<()> {
     let (auth_config, user_manager) = if args.multi_user {
         // Multi-user mode
         info!("🔐 Multi-user authentication enabled");
-        let user_manager = match crate::users::UserManager::new(&args.data) {
+        let user_manager = match crate::users::UserManager::new(&args.data, args.bcrypt_cost) {
             Ok(mgr) => Arc::new(mgr),
             Err(e) => {
                 error!("Failed to initialize user manager: {}", e);
@@ -129,7 +281,7 @@ async fn main() -> Result<()> {
                 let path = password.trim_start_matches('@');
                 Arc::new(AuthConfig::from_password_file(path)?)
             } else {
-                Arc::new(AuthConfig::from_password(password)?)
+                Arc::new(AuthConfig::from_password(password, args.bcrypt_cost)?)
             }
         } else {
             Arc::new(AuthConfig::disabled())
@@ -146,6 +298,9 @@ async fn main() -> Result<()> {
 
     // Initialize TLS
     let tls_mode = TlsMode::from_str(&args.tls_mode)?;
+    if tls_mode.is_enabled() && args.unixsocket.is_some() {
+        anyhow::bail!("--unixsocket cannot be combined with TLS (use --tls-mode disable)");
+    }
     let _tls_config = if tls_mode.is_enabled() {
         let cert = args
             .tls_cert
@@ -161,16 +316,60 @@ async fn main() -> Result<()> {
     };
 
     // Initialize backup configuration
-    let backup_config = Arc::new(BackupConfig::new(
-        args.data.as_str(),
-        args.backup_dir.as_deref(),
-    ));
+    let mut backup_config = BackupConfig::new(args.data.as_str(), args.backup_dir.as_deref());
+    backup_config.min_age = Duration::from_secs(args.backup_min_age * 60);
+    backup_config.compression = args.backup_compression;
+    let backup_config = Arc::new(backup_config);
     info!("📦 Backup directory: {:?}", backup_config.backup_dir);
 
+    if args.repair {
+        repair_store(&args.data)?;
+    }
+
     // Initialize cache
-    let cache = Arc::new(ToonCache::new(&args.data, args.capacity)?);
+    let cache = if args.lfu {
+        let samples = if args.lru_samples > 0 {
+            args.lru_samples
+        } else {
+            tooncache::DEFAULT_SAMPLES
+        };
+        info!("LFU: {} samples per eviction", samples);
+        Arc::new(ToonCache::with_lfu(&args.data, args.capacity, samples)?)
+    } else if args.lru_samples > 0 {
+        info!("Approximate LRU: {} samples per eviction", args.lru_samples);
+        Arc::new(ToonCache::with_approx_lru(
+            &args.data,
+            args.capacity,
+            args.lru_samples,
+        )?)
+    } else {
+        Arc::new(ToonCache::new(&args.data, args.capacity)?)
+    };
+    cache.set_max_value_size(args.cache_max_value_size as usize);
     info!("Database opened successfully");
 
+    let mut server_config = ServerConfig::new(args.maxmemory);
+    server_config.notify_keyspace_events = args.notify_keyspace_events;
+    server_config.max_request_bytes = args.max_request_bytes;
+    server_config.reply_chunk_bytes = args.reply_chunk_bytes;
+    server_config.command_timeout_ms = args.command_timeout_ms;
+    server_config.max_commands_per_sec = args.max_commands_per_sec;
+    server_config.auth_max_failures = args.auth_max_failures;
+    server_config.auth_lockout_per_user = args.auth_lockout_per_user;
+    server_config.bloom_filter_keys = args.bloom_filter;
+    server_config.client_output_buffer_limit = args.client_output_buffer_limit;
+    server_config.tracking_table_max_keys = args.tracking_table_max_keys;
+    server_config.active_expire_cycle_ms = args.active_expire_cycle_ms;
+    for pair in args.rename_command.chunks(2) {
+        let (command, new_name) = (pair[0].to_uppercase(), pair[1].to_uppercase());
+        if new_name.is_empty() {
+            info!("Command {} disabled", command);
+        } else {
+            info!("Command {} renamed", command);
+        }
+        server_config.renamed_commands.insert(command, new_name);
+    }
+
     // Initialize shared command handler (loads keymap once)
     let handler = Arc::new(CommandHandler::new(
         cache,
@@ -178,11 +377,18 @@ async fn main() -> Result<()> {
         auth_config.clone(),
         backup_config.clone(),
         user_manager.clone(),
+        server_config,
     ));
 
+    // Active expiry: reap keys past their TTL even if nobody reads them
+    if let Some(cycle) = handler.active_expire_cycle() {
+        spawn_active_expiry(Arc::clone(&handler), cycle);
+    }
+
     // Start auto-backup task if enabled
     if args.auto_backup > 0 {
         let backup_config_clone = backup_config.clone();
+        let backup_handler = Arc::clone(&handler);
         let interval_minutes = args.auto_backup;
         tokio::spawn(async move {
             let mut interval =
@@ -190,7 +396,12 @@ async fn main() -> Result<()> {
             loop {
                 interval.tick().await;
                 info!("Running automatic backup...");
-                match backup_config_clone.create_backup(Some("auto")) {
+                // Waits for the transaction lock, then tars, so keep it off the async workers
+                let handler = Arc::clone(&backup_handler);
+                let backup = tokio::task::spawn_blocking(move || handler.backup("auto"))
+                    .await
+                    .unwrap_or_else(|e| Err(anyhow::anyhow!("backup task failed: {}", e)));
+                match backup {
                     Ok(path) => {
                         info!("Auto-backup created: {:?}", path);
                         if let Err(e) = backup_config_clone.cleanup_old_backups(10) {
@@ -206,9 +417,12 @@ async fn main() -> Result<()> {
         info!("✅ Auto-backup: Every {} minutes", interval_minutes);
     }
 
-    // Bind TCP listener
-    let listener = TcpListener::bind(&args.bind).await?;
-    info!("Server listening on {}", args.bind);
+    #[cfg(feature = "metrics")]
+    if let Some(addr) = &args.metrics_addr {
+        let listener = TcpListener::bind(addr).await?;
+        tokio::spawn(metrics::serve(listener, Arc::clone(&handler)));
+        info!("📈 Metrics: http://{}/metrics", addr);
+    }
 
     // Connection limiter to prevent DoS attacks
     let connection_limiter = Arc::new(Semaphore::new(MAX_CONNECTIONS));
@@ -217,6 +431,22 @@ async fn main() -> Result<()> {
         MAX_CONNECTIONS
     );
 
+    // UNIX socket mode replaces the TCP listener entirely
+    if let Some(path) = &args.unixsocket {
+        #[cfg(unix)]
+        return serve_unix(path, handler, auth_config, connection_limiter).await;
+
+        #[cfg(not(unix))]
+        anyhow::bail!(
+            "--unixsocket is not supported on this platform: {}",
+            path.display()
+        );
+    }
+
+    // Bind TCP listener
+    let listener = TcpListener::bind(&args.bind).await?;
+    info!("Server listening on {}", args.bind);
+
     // Print connection info
     println!("\n╔══════════════════════════════════════════════════════════════╗");
     println!("║          ToonStore Server Ready!                            ║");
@@ -328,10 +558,12 @@ async fn main() -> Result<()> {
     }
 
     println!("\n📦 BACKUP COMMANDS:");
-    println!("   SAVE / BGSAVE      - Create immediate backup");
+    println!("   SAVE               - Create a backup and wait until it is on disk");
+    println!("   BGSAVE             - Create a backup in the background");
     println!("   BACKUP [name]      - Create named backup");
+    println!("   BACKUP LIST        - List backups with size and time");
     println!("   RESTORE <file>     - Restore from backup");
-    println!("   LASTSAVE           - List recent backups");
+    println!("   LASTSAVE           - Unix time of the last successful backup");
 
     println!("\n🛑 Press Ctrl+C to stop\n");
 
@@ -339,6 +571,9 @@ async fn main() -> Result<()> {
         match listener.accept().await {
             Ok((stream, addr)) => {
                 info!("New connection from {}", addr);
+                if let Err(e) = configure_socket(&stream, args.tcp_keepalive) {
+                    warn!("Failed to set socket options for {}: {}", addr, e);
+                }
 
                 // Acquire connection permit (blocks if at limit)
                 let permit = match connection_limiter.clone().try_acquire_owned() {
@@ -359,7 +594,9 @@ async fn main() -> Result<()> {
                     // Permit is automatically released when dropped
                     let _permit = permit;
 
-                    if let Err(e) = handle_client(stream, handler, auth_config).await {
+                    if let Err(e) =
+                        handle_client(stream, handler, auth_config, Some(addr.ip())).await
+                    {
                         error!("Error handling client {}: {}", addr, e);
                     }
                     info!("Connection closed: {}", addr);
@@ -372,17 +609,160 @@ async fn main() -> Result<()> {
     }
 }
 
-async fn handle_client(
-    mut stream: TcpStream,
+/// Disable Nagle's algorithm and, unless `keepalive_secs` is 0, enable
+/// keepalive probes so connections to vanished peers are closed
+fn configure_socket(stream: &TcpStream, keepalive_secs: u64) -> std::io::Result<()> {
+    stream.set_nodelay(true)?;
+    if keepalive_secs > 0 {
+        let idle = Duration::from_secs(keepalive_secs);
+        // Probe every third of the idle time, as Redis does
+        let keepalive = TcpKeepalive::new()
+            .with_time(idle)
+            .with_interval((idle / 3).max(Duration::from_secs(1)));
+        SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+    }
+    Ok(())
+}
+
+/// Removes the UNIX socket file when the listener shuts down
+#[cfg(unix)]
+struct UnixSocketGuard(PathBuf);
+
+#[cfg(unix)]
+impl Drop for UnixSocketGuard {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            warn!("Failed to remove UNIX socket {:?}: {}", self.0, e);
+        }
+    }
+}
+
+/// Bind a UNIX domain socket, replacing a stale socket file left by a previous run
+#[cfg(unix)]
+async fn bind_unix(path: &std::path::Path) -> Result<tokio::net::UnixListener> {
+    if path.exists() {
+        // A live server answers on the socket; a stale file refuses the connection
+        if tokio::net::UnixStream::connect(path).await.is_ok() {
+            anyhow::bail!("UNIX socket {:?} is already in use", path);
+        }
+        warn!("Removing stale UNIX socket {:?}", path);
+        std::fs::remove_file(path)?;
+    }
+
+    Ok(tokio::net::UnixListener::bind(path)?)
+}
+
+/// Accept loop for UNIX socket mode, runs until Ctrl+C
+#[cfg(unix)]
+async fn serve_unix(
+    path: &std::path::Path,
     handler: Arc<CommandHandler>,
     auth_config: Arc<AuthConfig>,
+    connection_limiter: Arc<Semaphore>,
 ) -> Result<()> {
-    let mut buffer = BytesMut::with_capacity(4096);
+    let listener = bind_unix(path).await?;
+    let _guard = UnixSocketGuard(path.to_path_buf());
+    info!("Server listening on unix:{}", path.display());
+
+    println!("\n📡 UNIX SOCKET MODE (Redis-compatible RESP Protocol):");
+    println!("   Socket Path:       {}", path.display());
+    println!("   redis-cli Command: redis-cli -s {}", path.display());
+    println!("\n🛑 Press Ctrl+C to stop\n");
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    info!("New connection on unix:{}", path.display());
+
+                    let permit = match connection_limiter.clone().try_acquire_owned() {
+                        Ok(permit) => permit,
+                        Err(_) => {
+                            warn!("Connection limit reached, rejecting UNIX socket connection");
+                            continue;
+                        }
+                    };
+
+                    let handler = Arc::clone(&handler);
+                    let auth_config = Arc::clone(&auth_config);
+
+                    tokio::spawn(async move {
+                        let _permit = permit;
+
+                        if let Err(e) = handle_client(stream, handler, auth_config, None).await {
+                            error!("Error handling UNIX socket client: {}", e);
+                        }
+                        info!("UNIX socket connection closed");
+                    });
+                }
+                Err(e) => {
+                    error!("Error accepting connection: {}", e);
+                }
+            },
+            _ = tokio::signal::ctrl_c() => {
+                info!("Shutting down, removing {}", path.display());
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Serve one client connection, `peer` being the address it connected from
+/// (None over a UNIX socket)
+async fn handle_client<S>(
+    mut stream: S,
+    handler: Arc<CommandHandler>,
+    auth_config: Arc<AuthConfig>,
+    peer: Option<IpAddr>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Released on every exit path, including errors
+    let _connection = handler.track_connection();
     let mut session = SessionState::new(auth_config.is_required());
+    session.peer = peer;
+    let (subscriber, mut messages) = handler.pubsub().register();
+    session.subscriber = Some(subscriber);
+
+    let result = client_loop(&mut stream, &handler, &mut session, &mut messages).await;
+    handler.disconnect(&mut session);
+    result
+}
+
+async fn client_loop<S>(
+    stream: &mut S,
+    handler: &Arc<CommandHandler>,
+    session: &mut SessionState,
+    messages: &mut Mailbox,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buffer = BytesMut::with_capacity(4096);
+    let overflow = messages.overflow();
+    let mut timed_out = None;
 
     loop {
-        // Read data from client
-        let n = stream.read_buf(&mut buffer).await?;
+        // Read data from client, or deliver a published message or a write
+        // for a replica. Nothing is locked while waiting, so idle subscribers
+        // and replicas never hold up other connections.
+        let n = tokio::select! {
+            read = stream.read_buf(&mut buffer) => read?,
+            Some(message) = messages.recv() => {
+                let message = message.to_resp(session.resp3);
+                let chunk_bytes = handler.reply_chunk_bytes();
+                let _reserved = overflow.reserve(message.serialized_len());
+                if !write_within_limit(stream, &message, chunk_bytes, &overflow).await? {
+                    return Ok(());
+                }
+                continue;
+            }
+            _ = overflow.wait() => {
+                warn!("Closing connection: queued output over the output buffer limit");
+                return Ok(());
+            }
+        };
         info!(
             "Read {} bytes from client, buffer total: {}",
             n,
@@ -401,11 +781,20 @@ async fn handle_client(
                 Ok(Some(cmd)) => {
                     info!("Parsed command: {:?}", cmd);
                     // Handle command with session state
-                    let response = handler.handle(cmd, &mut session);
+                    let response = run_command(handler, cmd, session, &mut timed_out).await;
                     info!("Response: {:?}", response);
 
-                    // Send response
-                    stream.write_all(&response.serialize()).await?;
+                    // Send response, unless the client reads so slowly that
+                    // its output goes past the limit meanwhile
+                    let chunk_bytes = handler.reply_chunk_bytes();
+                    for reply in session.take_extra_replies() {
+                        if !write_within_limit(stream, &reply, chunk_bytes, &overflow).await? {
+                            return Ok(());
+                        }
+                    }
+                    if !write_within_limit(stream, &response, chunk_bytes, &overflow).await? {
+                        return Ok(());
+                    }
 
                     // Check for QUIT command
                     if matches!(response, RespValue::SimpleString(ref s) if s == "OK") {
@@ -414,18 +803,817 @@ async fn handle_client(
                     }
                 }
                 Ok(None) => {
-                    // Need more data
+                    // Need more data, unless the partial request is already too big
                     info!("Need more data, buffer size: {}", buffer.len());
+                    if buffer.len() as u64 > handler.max_request_bytes() {
+                        warn!(
+                            "Closing connection: {} unparsed bytes exceeds max request size",
+                            buffer.len()
+                        );
+                        let error_resp = RespValue::Error("ERR request too large".to_string());
+                        stream.write_all(&error_resp.serialize()).await?;
+                        return Ok(());
+                    }
                     break;
                 }
                 Err(e) => {
-                    warn!("Parse error: {}", e);
-                    let error_resp = RespValue::Error(format!("ERR {}", e));
+                    // The stream is out of step, nothing after this point can be trusted
+                    warn!("Closing connection on protocol error: {}", e);
+                    let error_resp = RespValue::Error(format!("ERR Protocol error: {}", e));
                     stream.write_all(&error_resp.serialize()).await?;
-                    buffer.clear();
-                    break;
+                    return Ok(());
                 }
             }
         }
     }
 }
+
+/// Open the store at `data` once with repair, so the cache then opens it as usual
+fn repair_store(data: &str) -> anyhow::Result<()> {
+    let store = toonstoredb::ToonStore::open_with_repair(data, true)?;
+    info!("Repaired {}: {} rows", data, store.len());
+    Ok(())
+}
+
+/// Sweep for expired keys every `cycle`, each sweep bounded to a quarter of it
+fn spawn_active_expiry(handler: Arc<CommandHandler>, cycle: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(cycle);
+        loop {
+            interval.tick().await;
+            if handler.active_expire_enabled() {
+                handler.sweep_expired(cycle / 4);
+            }
+        }
+    });
+}
+
+/// A slow command still running on the blocking pool after its timeout
+type CommandTask = tokio::task::JoinHandle<(RespValue, SessionState)>;
+
+/// Run a command, moving `slow` ones off the event loop
+///
+/// A slow command runs on the blocking pool with a copy of the session,
+/// which replaces the connection's once it finishes. Past the command
+/// timeout the client gets an error instead of waiting; the command itself
+/// cannot be stopped and finishes in the background, its session changes
+/// dropped. A write it makes still lands, so the error says so. The
+/// connection's next command waits for it in `timed_out`, so the writes of
+/// one connection land in the order it sent them. Commands queued by MULTI
+/// only touch the session and run inline.
+async fn run_command(
+    handler: &Arc<CommandHandler>,
+    cmd: RespValue,
+    session: &mut SessionState,
+    timed_out: &mut Option<CommandTask>,
+) -> RespValue {
+    if let Some(task) = timed_out.take() {
+        if let Err(e) = task.await {
+            error!("Command task failed: {}", e);
+        }
+    }
+
+    if session.multi_queue.is_some() || !handler.is_slow(&cmd) {
+        return handler.handle(cmd, session);
+    }
+
+    let timeout = handler.command_timeout();
+    let task_handler = Arc::clone(handler);
+    let mut task_session = session.clone();
+    let mut task = tokio::task::spawn_blocking(move || {
+        let reply = task_handler.handle(cmd, &mut task_session);
+        (reply, task_session)
+    });
+    let finished = match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, &mut task).await {
+            Ok(finished) => finished,
+            Err(_) => {
+                warn!(
+                    "Command still running after {:?}, replying with an error",
+                    timeout
+                );
+                *timed_out = Some(task);
+                return RespValue::Error(
+                    "ERR command timed out, it may still complete in the background".to_string(),
+                );
+            }
+        },
+        None => task.await,
+    };
+
+    match finished {
+        Ok((reply, task_session)) => {
+            *session = task_session;
+            reply
+        }
+        Err(e) => {
+            error!("Command task failed: {}", e);
+            RespValue::Error("ERR command failed".to_string())
+        }
+    }
+}
+
+/// Write to the client, false if its queued output went past the output
+/// buffer limit first
+///
+/// A client that stops reading blocks the write, and everything published
+/// to it meanwhile piles up in its queue; giving up on the write lets the
+/// connection close instead of waiting on the client forever. A reply to
+/// the client's own command does not count against the limit itself, so a
+/// large one to a client that keeps reading never closes it; a queued
+/// message being written still counts, see `Overflow::reserve`.
+async fn write_within_limit<S>(
+    stream: &mut S,
+    reply: &RespValue,
+    chunk_bytes: usize,
+    overflow: &Overflow,
+) -> Result<bool>
+where
+    S: AsyncWrite + Unpin,
+{
+    tokio::select! {
+        written = write_reply(stream, reply, chunk_bytes) => written.map(|_| true),
+        _ = overflow.wait() => {
+            warn!("Closing connection: queued output over the output buffer limit");
+            Ok(false)
+        }
+    }
+}
+
+/// Write a reply piece by piece, so a large one is never serialized whole
+async fn write_reply<S>(stream: &mut S, reply: &RespValue, chunk_bytes: usize) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    for chunk in reply.chunks(chunk_bytes) {
+        stream.write_all(&chunk).await?;
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use backup::BackupConfig;
+    use tempfile::TempDir;
+    use tokio::net::UnixStream;
+
+    fn test_handler(dir: &TempDir) -> Arc<CommandHandler> {
+        test_handler_with(dir, ServerConfig::default())
+    }
+
+    fn test_handler_with(dir: &TempDir, config: ServerConfig) -> Arc<CommandHandler> {
+        let cache = Arc::new(ToonCache::new(dir.path(), 100).unwrap());
+        let backup = Arc::new(BackupConfig::new(dir.path(), None::<&str>));
+        Arc::new(CommandHandler::new(
+            cache,
+            dir.path().to_str().unwrap(),
+            Arc::new(AuthConfig::disabled()),
+            backup,
+            None,
+            config,
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_configure_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        configure_socket(&stream, 0).unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert!(!SockRef::from(&stream).keepalive().unwrap());
+
+        configure_socket(&stream, 60).unwrap();
+        assert!(SockRef::from(&stream).keepalive().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_unix_socket_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let socket_path = dir.path().join("tstd.sock");
+        let handler = test_handler(&dir);
+        let auth = Arc::new(AuthConfig::disabled());
+
+        let listener = bind_unix(&socket_path).await.unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = handle_client(stream, handler, auth, None).await;
+        });
+
+        let mut client = UnixStream::connect(&socket_path).await.unwrap();
+        client
+            .write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n*2\r\n$3\r\nGET\r\n$1\r\nk\r\n")
+            .await
+            .unwrap();
+
+        let expected = b"+OK\r\n$1\r\nv\r\n";
+        let mut reply = vec![0u8; expected.len()];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, expected);
+    }
+
+    #[tokio::test]
+    async fn test_slow_command_times_out() {
+        let dir = TempDir::new().unwrap();
+        let socket_path = dir.path().join("timeout.sock");
+        let config = ServerConfig {
+            command_timeout_ms: 100,
+            ..ServerConfig::default()
+        };
+        let handler = test_handler_with(&dir, config);
+        let auth = Arc::new(AuthConfig::disabled());
+
+        let listener = bind_unix(&socket_path).await.unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = handle_client(stream, handler, auth, None).await;
+        });
+
+        let mut client = UnixStream::connect(&socket_path).await.unwrap();
+        let started = std::time::Instant::now();
+        client
+            .write_all(b"*3\r\n$5\r\nDEBUG\r\n$5\r\nSLEEP\r\n$1\r\n1\r\n*1\r\n$4\r\nPING\r\n")
+            .await
+            .unwrap();
+
+        // The sleep is cut short for the client; its next command waits for it
+        let expected = b"-ERR command timed out, it may still complete in the background\r\n";
+        let mut reply = vec![0u8; expected.len()];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, expected);
+        assert!(started.elapsed() < Duration::from_millis(900));
+        let mut reply = vec![0u8; 7];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, b"+PONG\r\n");
+        assert!(started.elapsed() >= Duration::from_secs(1));
+
+        // Slow commands within the limit reply as usual
+        client
+            .write_all(b"*3\r\n$5\r\nDEBUG\r\n$5\r\nSLEEP\r\n$1\r\n0\r\n")
+            .await
+            .unwrap();
+        let mut reply = vec![0u8; 5];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, b"+OK\r\n");
+    }
+
+    #[test]
+    fn test_repair_store() {
+        let dir = TempDir::new().unwrap();
+        let data = dir.path().to_str().unwrap();
+        {
+            let cache = ToonCache::new(data, 100).unwrap();
+            cache.put(b"row 0").unwrap();
+            cache.put(b"row 1").unwrap();
+        }
+
+        // Cut off the index checksum, as a crash between two writes would
+        let idx_path = dir.path().join("db.toon.idx");
+        let idx = std::fs::read(&idx_path).unwrap();
+        std::fs::write(&idx_path, &idx[..idx.len() - 8]).unwrap();
+        assert!(ToonCache::new(data, 100).is_err());
+
+        repair_store(data).unwrap();
+        let cache = ToonCache::new(data, 100).unwrap();
+        assert_eq!(cache.get(1).unwrap(), b"row 1");
+    }
+
+    #[tokio::test]
+    async fn test_active_expiry_reaps_unread_keys() {
+        let dir = TempDir::new().unwrap();
+        let handler = test_handler(&dir);
+        let mut session = SessionState::new(false);
+        for cmd in [&[&b"SET"[..], b"k", b"v"][..], &[b"EXPIRE", b"k", b"1"]] {
+            let cmd = cmd
+                .iter()
+                .map(|word| RespValue::BulkString(Some(word.to_vec())))
+                .collect();
+            handler.handle(RespValue::Array(Some(cmd)), &mut session);
+        }
+        assert_eq!(handler.cache().live_len(), 1);
+
+        // Nothing reads the key, the sweep alone removes its row
+        spawn_active_expiry(Arc::clone(&handler), Duration::from_millis(100));
+        tokio::time::sleep(Duration::from_millis(1300)).await;
+        assert_eq!(handler.cache().live_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_set_active_expire_pauses_sweep() {
+        let dir = TempDir::new().unwrap();
+        let handler = test_handler(&dir);
+        let mut session = SessionState::new(false);
+        let mut run = |words: &[&[u8]]| {
+            let cmd = words
+                .iter()
+                .map(|word| RespValue::BulkString(Some(word.to_vec())))
+                .collect();
+            handler.handle(RespValue::Array(Some(cmd)), &mut session)
+        };
+        run(&[b"DEBUG", b"SET-ACTIVE-EXPIRE", b"0"]);
+        run(&[b"SET", b"k", b"v"]);
+        run(&[b"PEXPIRE", b"k", b"50"]);
+        spawn_active_expiry(Arc::clone(&handler), Duration::from_millis(20));
+
+        // Paused, the sweep leaves the expired key's row alone
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(handler.cache().live_len(), 1);
+
+        run(&[b"DEBUG", b"SET-ACTIVE-EXPIRE", b"1"]);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(handler.cache().live_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_sync_streams_snapshot_then_writes() {
+        let dir = TempDir::new().unwrap();
+        let socket_path = dir.path().join("sync.sock");
+        let handler = test_handler(&dir);
+        let auth = Arc::new(AuthConfig::disabled());
+        handler.handle(
+            RespValue::Array(Some(vec![
+                RespValue::BulkString(Some(b"SET".to_vec())),
+                RespValue::BulkString(Some(b"a".to_vec())),
+                RespValue::BulkString(Some(b"1".to_vec())),
+            ])),
+            &mut SessionState::new(false),
+        );
+
+        let listener = bind_unix(&socket_path).await.unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let handler = Arc::clone(&handler);
+                let auth = Arc::clone(&auth);
+                tokio::spawn(async move {
+                    let _ = handle_client(stream, handler, auth, None).await;
+                });
+            }
+        });
+
+        let mut replica = UnixStream::connect(&socket_path).await.unwrap();
+        replica.write_all(b"*1\r\n$4\r\nSYNC\r\n").await.unwrap();
+        let expected = b"*2\r\n*1\r\n$7\r\nFLUSHDB\r\n*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n";
+        let mut reply = vec![0u8; expected.len()];
+        replica.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, expected);
+
+        let mut client = UnixStream::connect(&socket_path).await.unwrap();
+        client
+            .write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n2\r\n")
+            .await
+            .unwrap();
+        let mut reply = vec![0u8; 5];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, b"+OK\r\n");
+
+        let expected = b"*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n2\r\n";
+        let mut forwarded = vec![0u8; expected.len()];
+        tokio::time::timeout(Duration::from_secs(5), replica.read_exact(&mut forwarded))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(forwarded, expected);
+    }
+
+    #[tokio::test]
+    async fn test_large_reply_is_streamed_in_chunks() {
+        let dir = TempDir::new().unwrap();
+        let socket_path = dir.path().join("chunks.sock");
+        let config = ServerConfig {
+            reply_chunk_bytes: 16,
+            ..ServerConfig::default()
+        };
+        let handler = test_handler_with(&dir, config);
+        let auth = Arc::new(AuthConfig::disabled());
+
+        let mut rpush = vec![RespValue::BulkString(Some(b"RPUSH".to_vec()))];
+        rpush.push(RespValue::BulkString(Some(b"list".to_vec())));
+        let elements: Vec<RespValue> = (0..500)
+            .map(|i| RespValue::BulkString(Some(format!("element {}", i).into_bytes())))
+            .collect();
+        rpush.extend(elements.iter().cloned());
+        handler.handle(RespValue::Array(Some(rpush)), &mut SessionState::new(false));
+
+        let listener = bind_unix(&socket_path).await.unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = handle_client(stream, handler, auth, None).await;
+        });
+
+        let mut client = UnixStream::connect(&socket_path).await.unwrap();
+        client
+            .write_all(b"*4\r\n$6\r\nLRANGE\r\n$4\r\nlist\r\n$1\r\n0\r\n$2\r\n-1\r\n")
+            .await
+            .unwrap();
+
+        let expected = RespValue::Array(Some(elements)).serialize();
+        let mut reply = vec![0u8; expected.len()];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, expected);
+    }
+
+    #[tokio::test]
+    async fn test_connection_count_follows_clients() {
+        let dir = TempDir::new().unwrap();
+        let socket_path = dir.path().join("tstd.sock");
+        let handler = test_handler(&dir);
+        let auth = Arc::new(AuthConfig::disabled());
+
+        let listener = bind_unix(&socket_path).await.unwrap();
+        let server_handler = Arc::clone(&handler);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let handler = Arc::clone(&server_handler);
+                let auth = Arc::clone(&auth);
+                tokio::spawn(async move {
+                    let _ = handle_client(stream, handler, auth, None).await;
+                });
+            }
+        });
+
+        let ping = |mut client: UnixStream| async move {
+            client.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+            let mut reply = [0u8; 7];
+            client.read_exact(&mut reply).await.unwrap();
+            client
+        };
+        let first = ping(UnixStream::connect(&socket_path).await.unwrap()).await;
+        let second = ping(UnixStream::connect(&socket_path).await.unwrap()).await;
+        assert_eq!(handler.connected_clients(), 2);
+
+        drop(first);
+        drop(second);
+        // Generous budget: the connection tasks may be starved by other tests
+        for _ in 0..500 {
+            if handler.connected_clients() == 0 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(handler.connected_clients(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_bind_unix_replaces_stale_socket() {
+        let dir = TempDir::new().unwrap();
+        let socket_path = dir.path().join("stale.sock");
+
+        // Leave a socket file behind with nobody listening on it
+        drop(std::os::unix::net::UnixListener::bind(&socket_path).unwrap());
+        assert!(socket_path.exists());
+
+        let _listener = bind_unix(&socket_path).await.unwrap();
+        assert!(UnixStream::connect(&socket_path).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_and_publish_over_socket() {
+        let dir = TempDir::new().unwrap();
+        let socket_path = dir.path().join("pubsub.sock");
+        let handler = test_handler(&dir);
+        let auth = Arc::new(AuthConfig::disabled());
+
+        let listener = bind_unix(&socket_path).await.unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let handler = Arc::clone(&handler);
+                let auth = Arc::clone(&auth);
+                tokio::spawn(async move {
+                    let _ = handle_client(stream, handler, auth, None).await;
+                });
+            }
+        });
+
+        let mut subscriber = UnixStream::connect(&socket_path).await.unwrap();
+        subscriber
+            .write_all(b"*3\r\n$9\r\nSUBSCRIBE\r\n$1\r\na\r\n$1\r\nb\r\n")
+            .await
+            .unwrap();
+        let expected = b"*3\r\n$9\r\nsubscribe\r\n$1\r\na\r\n:1\r\n\
+                         *3\r\n$9\r\nsubscribe\r\n$1\r\nb\r\n:2\r\n";
+        let mut reply = vec![0u8; expected.len()];
+        subscriber.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, expected);
+
+        // After HELLO 3 messages arrive as pushes
+        let mut resp3 = UnixStream::connect(&socket_path).await.unwrap();
+        resp3
+            .write_all(
+                b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n\
+                  *2\r\n$9\r\nSUBSCRIBE\r\n$1\r\nb\r\n",
+            )
+            .await
+            .unwrap();
+        let mut replies = Vec::new();
+        while !replies.ends_with(b"*3\r\n$9\r\nsubscribe\r\n$1\r\nb\r\n:1\r\n") {
+            assert!(resp3.read_buf(&mut replies).await.unwrap() > 0);
+        }
+        assert!(replies.starts_with(b"%"));
+
+        let mut publisher = UnixStream::connect(&socket_path).await.unwrap();
+        publisher
+            .write_all(b"*3\r\n$7\r\nPUBLISH\r\n$1\r\nb\r\n$2\r\nhi\r\n")
+            .await
+            .unwrap();
+        let mut reply = [0u8; 4];
+        publisher.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b":2\r\n");
+
+        let expected = b"*3\r\n$7\r\nmessage\r\n$1\r\nb\r\n$2\r\nhi\r\n";
+        let mut message = vec![0u8; expected.len()];
+        subscriber.read_exact(&mut message).await.unwrap();
+        assert_eq!(message, expected);
+
+        let expected = b">3\r\n$7\r\nmessage\r\n$1\r\nb\r\n$2\r\nhi\r\n";
+        let mut message = vec![0u8; expected.len()];
+        resp3.read_exact(&mut message).await.unwrap();
+        assert_eq!(message, expected);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_subscribers_do_not_block_other_connections() {
+        let dir = TempDir::new().unwrap();
+        let socket_path = dir.path().join("fanout.sock");
+        let handler = test_handler(&dir);
+        let auth = Arc::new(AuthConfig::disabled());
+
+        let listener = bind_unix(&socket_path).await.unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let handler = Arc::clone(&handler);
+                let auth = Arc::clone(&auth);
+                tokio::spawn(async move {
+                    let _ = handle_client(stream, handler, auth, None).await;
+                });
+            }
+        });
+
+        let subscribe = || async {
+            let mut client = UnixStream::connect(&socket_path).await.unwrap();
+            client
+                .write_all(b"*2\r\n$9\r\nSUBSCRIBE\r\n$4\r\nnews\r\n")
+                .await
+                .unwrap();
+            let expected = b"*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n";
+            let mut reply = vec![0u8; expected.len()];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, expected);
+            client
+        };
+        let first = subscribe().await;
+        let second = subscribe().await;
+
+        // Both subscribers sit waiting for messages while another connection works
+        let expected = b"*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$2\r\nhi\r\n";
+        let wait_for_message = |mut client: UnixStream| async move {
+            let mut message = vec![0u8; expected.len()];
+            client.read_exact(&mut message).await.unwrap();
+            message
+        };
+        let first_message = wait_for_message(first);
+        let second_message = wait_for_message(second);
+
+        let other = async {
+            let mut client = UnixStream::connect(&socket_path).await.unwrap();
+            client
+                .write_all(
+                    b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n*2\r\n$3\r\nGET\r\n$1\r\nk\r\n",
+                )
+                .await
+                .unwrap();
+            let expected = b"+OK\r\n$1\r\nv\r\n";
+            let mut reply = vec![0u8; expected.len()];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, expected);
+
+            client
+                .write_all(b"*3\r\n$7\r\nPUBLISH\r\n$4\r\nnews\r\n$2\r\nhi\r\n")
+                .await
+                .unwrap();
+            let mut reply = [0u8; 4];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(&reply, b":2\r\n");
+        };
+
+        let (first_message, second_message, ()) =
+            tokio::time::timeout(std::time::Duration::from_secs(10), async {
+                tokio::join!(first_message, second_message, other)
+            })
+            .await
+            .unwrap();
+        assert_eq!(first_message, expected);
+        assert_eq!(second_message, expected);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_slow_subscriber_is_disconnected() {
+        let dir = TempDir::new().unwrap();
+        let socket_path = dir.path().join("slow.sock");
+        let config = ServerConfig {
+            client_output_buffer_limit: config::OutputBufferLimit {
+                hard: 256 * 1024,
+                soft: 0,
+                soft_seconds: 0,
+            },
+            ..ServerConfig::default()
+        };
+        let handler = test_handler_with(&dir, config);
+        let auth = Arc::new(AuthConfig::disabled());
+
+        let listener = bind_unix(&socket_path).await.unwrap();
+        let server_handler = Arc::clone(&handler);
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = handle_client(stream, server_handler, auth, None).await;
+        });
+
+        let mut subscriber = UnixStream::connect(&socket_path).await.unwrap();
+        subscriber
+            .write_all(b"*2\r\n$9\r\nSUBSCRIBE\r\n$4\r\nnews\r\n")
+            .await
+            .unwrap();
+        let mut reply = vec![0u8; b"*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n".len()];
+        subscriber.read_exact(&mut reply).await.unwrap();
+
+        // The subscriber stops reading; the socket fills, then its queue
+        let payload = vec![b'x'; 16 * 1024];
+        let mut published = 0;
+        while handler.pubsub().publish("news", &payload) == 1 {
+            published += 1;
+            assert!(published < 10_000, "slow subscriber was never cut off");
+            tokio::task::yield_now().await;
+        }
+
+        // Whatever made it into the socket is followed by the connection closing
+        let mut received = Vec::new();
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            subscriber.read_to_end(&mut received),
+        )
+        .await
+        .expect("connection was not closed")
+        .unwrap();
+        assert!(received.len() < published * payload.len());
+    }
+
+    #[tokio::test]
+    async fn test_large_reply_does_not_count_against_output_limit() {
+        let dir = TempDir::new().unwrap();
+        let socket_path = dir.path().join("large.sock");
+        let config = ServerConfig {
+            client_output_buffer_limit: config::OutputBufferLimit {
+                hard: 64 * 1024,
+                soft: 0,
+                soft_seconds: 0,
+            },
+            ..ServerConfig::default()
+        };
+        let handler = test_handler_with(&dir, config);
+        let auth = Arc::new(AuthConfig::disabled());
+        let value = vec![b'x'; 256 * 1024];
+        let set = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"SET".to_vec())),
+            RespValue::BulkString(Some(b"big".to_vec())),
+            RespValue::BulkString(Some(value.clone())),
+        ]));
+        handler.handle(set, &mut SessionState::new(false));
+
+        let listener = bind_unix(&socket_path).await.unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = handle_client(stream, handler, auth, None).await;
+        });
+
+        // Four times the hard limit, read as fast as it comes
+        let mut client = UnixStream::connect(&socket_path).await.unwrap();
+        client
+            .write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nbig\r\n*1\r\n$4\r\nPING\r\n")
+            .await
+            .unwrap();
+        let mut expected = format!("${}\r\n", value.len()).into_bytes();
+        expected.extend_from_slice(&value);
+        expected.extend_from_slice(b"\r\n+PONG\r\n");
+        let mut reply = vec![0u8; expected.len()];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, expected);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_slow_replica_is_disconnected() {
+        let dir = TempDir::new().unwrap();
+        let socket_path = dir.path().join("slow-replica.sock");
+        let config = ServerConfig {
+            client_output_buffer_limit: config::OutputBufferLimit {
+                hard: 256 * 1024,
+                soft: 0,
+                soft_seconds: 0,
+            },
+            ..ServerConfig::default()
+        };
+        let handler = test_handler_with(&dir, config);
+        let auth = Arc::new(AuthConfig::disabled());
+
+        let listener = bind_unix(&socket_path).await.unwrap();
+        let server_handler = Arc::clone(&handler);
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = handle_client(stream, server_handler, auth, None).await;
+        });
+
+        let mut replica = UnixStream::connect(&socket_path).await.unwrap();
+        replica.write_all(b"*1\r\n$4\r\nSYNC\r\n").await.unwrap();
+        let expected = b"*1\r\n*1\r\n$7\r\nFLUSHDB\r\n";
+        let mut reply = vec![0u8; expected.len()];
+        replica.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, expected);
+
+        // The replica stops reading; the socket fills, then its feed
+        let value = vec![b'x'; 16 * 1024];
+        let mut session = SessionState::new(false);
+        let mut written = 0;
+        while handler.connected_clients() == 1 {
+            let set = RespValue::Array(Some(vec![
+                RespValue::BulkString(Some(b"SET".to_vec())),
+                RespValue::BulkString(Some(format!("key:{}", written).into_bytes())),
+                RespValue::BulkString(Some(value.clone())),
+            ]));
+            handler.handle(set, &mut session);
+            written += 1;
+            assert!(written < 10_000, "slow replica was never cut off");
+            tokio::task::yield_now().await;
+        }
+
+        // Whatever made it into the socket is followed by the connection closing
+        let mut received = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), replica.read_to_end(&mut received))
+            .await
+            .expect("connection was not closed")
+            .unwrap();
+        assert!(received.len() < written * value.len());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_request_is_rejected() {
+        let dir = TempDir::new().unwrap();
+        let socket_path = dir.path().join("limit.sock");
+        let config = ServerConfig {
+            max_request_bytes: 64,
+            ..ServerConfig::default()
+        };
+        let handler = test_handler_with(&dir, config);
+        let auth = Arc::new(AuthConfig::disabled());
+
+        let listener = bind_unix(&socket_path).await.unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = handle_client(stream, handler, auth, None).await;
+        });
+
+        // A bulk string that never completes, dribbled in past the limit
+        let mut client = UnixStream::connect(&socket_path).await.unwrap();
+        client.write_all(b"*1\r\n$100000\r\n").await.unwrap();
+        for _ in 0..4 {
+            // The server may hang up before the last chunk
+            let _ = client.write_all(&[b'a'; 32]).await;
+        }
+
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"-ERR request too large\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_protocol_error_closes_connection() {
+        let dir = TempDir::new().unwrap();
+        let socket_path = dir.path().join("garbage.sock");
+        let handler = test_handler(&dir);
+        let auth = Arc::new(AuthConfig::disabled());
+
+        let listener = bind_unix(&socket_path).await.unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = handle_client(stream, handler, auth, None).await;
+        });
+
+        // A valid command, then a garbage byte, then one that must not run
+        let mut client = UnixStream::connect(&socket_path).await.unwrap();
+        client
+            .write_all(b"*1\r\n$4\r\nPING\r\n!*1\r\n$4\r\nPING\r\n")
+            .await
+            .unwrap();
+
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(
+            reply,
+            b"+PONG\r\n-ERR Protocol error: Unknown RESP type: !\r\n"
+        );
+    }
+}
@@ -0,0 +1,195 @@
+//! Prometheus metrics endpoint (`--metrics-addr`, `metrics` feature)
+//!
+//! A minimal HTTP/1.x responder rather than a full server: each connection
+//! gets one response and is closed. `GET /metrics` returns the counters in
+//! Prometheus text format, any other path a 404.
+
+use std::fmt::Write as _;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::warn;
+
+use crate::handler::CommandHandler;
+
+/// Largest request head accepted before the connection is dropped
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+
+/// Time a scraper gets to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Render every metric in Prometheus text exposition format
+pub fn render(handler: &CommandHandler) -> String {
+    let cache = handler.cache();
+    let stats = cache.stats();
+    let metrics: [(&str, &str, &str, u64); 5] = [
+        (
+            "tooncache_hits_total",
+            "counter",
+            "Reads served from the cache",
+            stats.hits(),
+        ),
+        (
+            "tooncache_misses_total",
+            "counter",
+            "Reads that had to go to storage",
+            stats.misses(),
+        ),
+        (
+            "tooncache_evictions_total",
+            "counter",
+            "Entries evicted from the cache",
+            stats.evictions(),
+        ),
+        (
+            "toonstore_rows",
+            "gauge",
+            "Rows in storage that have not been deleted",
+            cache.live_len() as u64,
+        ),
+        (
+            "connected_clients",
+            "gauge",
+            "Open client connections",
+            handler.connected_clients() as u64,
+        ),
+    ];
+
+    let mut text = String::new();
+    for (name, kind, help, value) in metrics {
+        let _ = write!(
+            text,
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+        );
+    }
+    text
+}
+
+/// Answer scrapes on `listener` until the task is dropped
+pub async fn serve(listener: TcpListener, handler: Arc<CommandHandler>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let handler = Arc::clone(&handler);
+                tokio::spawn(async move {
+                    if let Err(e) = respond(stream, &handler).await {
+                        warn!("Metrics request failed: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to accept metrics connection: {}", e),
+        }
+    }
+}
+
+async fn respond(mut stream: TcpStream, handler: &CommandHandler) -> io::Result<()> {
+    let head = match tokio::time::timeout(REQUEST_TIMEOUT, read_head(&mut stream)).await {
+        Ok(head) => head?,
+        Err(_) => return Ok(()), // Idle scraper, just hang up
+    };
+
+    let request_line = head.lines().next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next(), parts.next().map(|p| p.split('?').next()));
+
+    let (status, body) = match (method, path) {
+        (Some("GET"), Some(Some("/metrics"))) => ("200 OK", render(handler)),
+        (Some("GET"), _) => ("404 Not Found", "Not Found\n".to_string()),
+        _ => ("405 Method Not Allowed", "Method Not Allowed\n".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Read up to the blank line ending the request head
+async fn read_head(stream: &mut TcpStream) -> io::Result<String> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_BYTES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request head too large",
+            ));
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break; // Client closed early, answer what we have
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AuthConfig;
+    use crate::backup::BackupConfig;
+    use crate::config::ServerConfig;
+    use tempfile::TempDir;
+    use tooncache::ToonCache;
+
+    async fn get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint() {
+        let dir = TempDir::new().unwrap();
+        let cache = Arc::new(ToonCache::new(dir.path(), 100).unwrap());
+        let row_id = cache.put(b"value").unwrap();
+        cache.put(b"other").unwrap();
+        cache.get(row_id).unwrap();
+        let handler = Arc::new(CommandHandler::new(
+            cache,
+            dir.path().to_str().unwrap(),
+            Arc::new(AuthConfig::disabled()),
+            Arc::new(BackupConfig::new(dir.path(), None::<&str>)),
+            None,
+            ServerConfig::default(),
+        ));
+        let _client = handler.track_connection();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, Arc::clone(&handler)));
+
+        let response = get(addr, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("# TYPE tooncache_hits_total counter\n"));
+        assert!(
+            response.contains("\ntooncache_hits_total 1\n"),
+            "{}",
+            response
+        );
+        assert!(response.contains("\ntooncache_misses_total 0\n"));
+        assert!(response.contains("\ntooncache_evictions_total 0\n"));
+        assert!(response.contains("\ntoonstore_rows 2\n"));
+        assert!(response.contains("\nconnected_clients 1\n"));
+
+        let response = get(addr, "/other").await;
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+            "{}",
+            response
+        );
+    }
+}
@@ -0,0 +1,405 @@
+//! Publish/subscribe channel registry
+//!
+//! Each connection registers once and gets a receiver for its messages.
+//! Publishing only takes the registry read lock and never blocks on a slow
+//! subscriber: delivery goes through a per-connection queue that the
+//! connection loop drains. The same queue carries the write feed of a
+//! replica, see `Replication`. It is only bounded by the output buffer
+//! limit: past it the message is dropped and the connection told to close.
+//! A message counts until it has been written to the connection; replies to
+//! the connection's own commands do not count.
+
+use crate::config::OutputBufferLimit;
+use crate::keys;
+use crate::resp::RespValue;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+#[cfg(test)]
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Notify;
+
+/// A message queued for a connection
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    /// Published to a channel the connection subscribes to
+    Publish { channel: String, payload: Vec<u8> },
+    /// A key the connection tracks was modified, see `Tracking`
+    Invalidate(String),
+    /// A write command forwarded to a replica, see `Replication`
+    Replicate(RespValue),
+}
+
+impl Message {
+    /// RESP form delivered to the connection: `["message", channel, payload]`
+    /// for publishes (a push on a RESP3 connection, an array otherwise), a
+    /// `["invalidate", [key]]` push for invalidations and the command array
+    /// itself for replicated writes
+    pub fn to_resp(&self, resp3: bool) -> RespValue {
+        match self {
+            Message::Publish { channel, payload } => {
+                let items = vec![
+                    RespValue::BulkString(Some(b"message".to_vec())),
+                    RespValue::BulkString(Some(channel.as_bytes().to_vec())),
+                    RespValue::BulkString(Some(payload.clone())),
+                ];
+                if resp3 {
+                    RespValue::Push(items)
+                } else {
+                    RespValue::Array(Some(items))
+                }
+            }
+            Message::Invalidate(key) => RespValue::Push(vec![
+                RespValue::BulkString(Some(b"invalidate".to_vec())),
+                RespValue::Array(Some(vec![RespValue::BulkString(Some(
+                    keys::to_bytes(key).into_owned(),
+                ))])),
+            ]),
+            Message::Replicate(command) => command.clone(),
+        }
+    }
+
+    /// Approximate bytes the message takes up queued and on the wire
+    fn queued_len(&self) -> u64 {
+        let body = match self {
+            Message::Publish { channel, payload } => (channel.len() + payload.len()) as u64,
+            Message::Invalidate(key) => key.len() as u64,
+            Message::Replicate(command) => command.serialized_len(),
+        };
+        body + 48
+    }
+}
+
+/// A connection's queued bytes, shared by its sending and receiving halves
+#[derive(Debug)]
+struct Backlog {
+    limit: OutputBufferLimit,
+    bytes: AtomicU64,
+    /// When the backlog last went over the soft limit (None while under it)
+    soft_since: Mutex<Option<Instant>>,
+    overflowed: AtomicBool,
+    overflow: Notify,
+}
+
+impl Backlog {
+    /// Count a queued message, false if that takes the backlog past a limit
+    fn add(&self, len: u64) -> bool {
+        let bytes = self.bytes.fetch_add(len, Ordering::Relaxed) + len;
+        self.check(bytes)
+    }
+
+    fn remove(&self, len: u64) {
+        let bytes = self.bytes.fetch_sub(len, Ordering::Relaxed) - len;
+        if bytes <= self.limit.soft {
+            *self.soft_since.lock().unwrap() = None;
+        } else {
+            // A client draining too slowly to get back under the soft limit
+            // in time is closed even if nothing more is queued
+            self.check(bytes);
+        }
+    }
+
+    /// Flag the backlog as overflowed if `bytes` is past a limit, false if so
+    fn check(&self, bytes: u64) -> bool {
+        let limit = &self.limit;
+        let over = if limit.hard > 0 && bytes > limit.hard {
+            true
+        } else if limit.soft > 0 && bytes > limit.soft {
+            let mut soft_since = self.soft_since.lock().unwrap();
+            let since = *soft_since.get_or_insert_with(Instant::now);
+            since.elapsed() >= Duration::from_secs(limit.soft_seconds)
+        } else {
+            false
+        };
+
+        if over && !self.overflowed.swap(true, Ordering::Relaxed) {
+            self.overflow.notify_one();
+        }
+        !over
+    }
+}
+
+/// Receiving half of a connection's message queue
+pub struct Mailbox {
+    receiver: UnboundedReceiver<Message>,
+    backlog: Arc<Backlog>,
+}
+
+impl Mailbox {
+    pub async fn recv(&mut self) -> Option<Message> {
+        let message = self.receiver.recv().await?;
+        self.backlog.remove(message.queued_len());
+        Some(message)
+    }
+
+    #[cfg(test)]
+    pub fn try_recv(&mut self) -> Result<Message, TryRecvError> {
+        let message = self.receiver.try_recv()?;
+        self.backlog.remove(message.queued_len());
+        Ok(message)
+    }
+
+    /// Signal for the queue going past the output buffer limit
+    pub fn overflow(&self) -> Overflow {
+        Overflow(Arc::clone(&self.backlog))
+    }
+}
+
+/// Watches a connection's queue for going past the output buffer limit
+pub struct Overflow(Arc<Backlog>);
+
+impl Overflow {
+    /// Resolves once the queue has gone past the limit
+    pub async fn wait(&self) {
+        if !self.0.overflowed.load(Ordering::Relaxed) {
+            self.0.overflow.notified().await;
+        }
+    }
+
+    /// Count `len` bytes of output being written against the limit, until
+    /// the returned guard is dropped
+    pub fn reserve(&self, len: u64) -> Reserved {
+        self.0.add(len);
+        Reserved {
+            backlog: Arc::clone(&self.0),
+            len,
+        }
+    }
+}
+
+/// Output counted against a connection's limit while it is written, see `Overflow::reserve`
+pub struct Reserved {
+    backlog: Arc<Backlog>,
+    len: u64,
+}
+
+impl Drop for Reserved {
+    fn drop(&mut self) {
+        self.backlog.remove(self.len);
+    }
+}
+
+/// Sending half of a connection's message queue
+#[derive(Debug, Clone)]
+pub struct Subscriber {
+    pub id: u64,
+    sender: UnboundedSender<Message>,
+    backlog: Arc<Backlog>,
+}
+
+impl Subscriber {
+    /// Queue a message, false if the connection went away or is over its
+    /// output buffer limit
+    pub fn send(&self, message: Message) -> bool {
+        if self.backlog.overflowed.load(Ordering::Relaxed) {
+            return false;
+        }
+        let len = message.queued_len();
+        if !self.backlog.add(len) {
+            self.backlog.remove(len);
+            return false;
+        }
+        if self.sender.send(message).is_err() {
+            self.backlog.remove(len);
+            return false;
+        }
+        true
+    }
+
+    /// Whether the connection went away or is being closed for its output buffer limit
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed() || self.backlog.overflowed.load(Ordering::Relaxed)
+    }
+}
+
+/// Channel name -> subscribed connections
+#[derive(Default)]
+pub struct PubSub {
+    channels: RwLock<HashMap<String, Vec<Subscriber>>>,
+    next_id: AtomicU64,
+    /// Output buffer limit of each connection's queue
+    limit: OutputBufferLimit,
+}
+
+impl PubSub {
+    /// Registry whose connection queues are bounded by `limit`
+    pub fn with_limit(limit: OutputBufferLimit) -> Self {
+        Self {
+            limit,
+            ..Self::default()
+        }
+    }
+
+    /// Create the message queue for a new connection
+    pub fn register(&self) -> (Subscriber, Mailbox) {
+        let (sender, receiver) = unbounded_channel();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let backlog = Arc::new(Backlog {
+            limit: self.limit,
+            bytes: AtomicU64::new(0),
+            soft_since: Mutex::new(None),
+            overflowed: AtomicBool::new(false),
+            overflow: Notify::new(),
+        });
+        let subscriber = Subscriber {
+            id,
+            sender,
+            backlog: Arc::clone(&backlog),
+        };
+        (subscriber, Mailbox { receiver, backlog })
+    }
+
+    /// Subscribe a connection to a channel (no-op if already subscribed)
+    pub fn subscribe(&self, channel: &str, subscriber: &Subscriber) {
+        let mut channels = self.channels.write().unwrap();
+        let subscribers = channels.entry(channel.to_string()).or_default();
+        if !subscribers.iter().any(|s| s.id == subscriber.id) {
+            subscribers.push(subscriber.clone());
+        }
+    }
+
+    /// Unsubscribe a connection from a channel
+    pub fn unsubscribe(&self, channel: &str, id: u64) {
+        let mut channels = self.channels.write().unwrap();
+        if let Some(subscribers) = channels.get_mut(channel) {
+            subscribers.retain(|s| s.id != id);
+            if subscribers.is_empty() {
+                channels.remove(channel);
+            }
+        }
+    }
+
+    /// Publish a message, returning the number of connections it was delivered to
+    pub fn publish(&self, channel: &str, payload: &[u8]) -> usize {
+        let message = Message::Publish {
+            channel: channel.to_string(),
+            payload: payload.to_vec(),
+        };
+
+        let (delivered, stale) = {
+            let channels = self.channels.read().unwrap();
+            let Some(subscribers) = channels.get(channel) else {
+                return 0;
+            };
+
+            let mut delivered = 0;
+            let mut stale = false;
+            for subscriber in subscribers {
+                if subscriber.send(message.clone()) {
+                    delivered += 1;
+                } else {
+                    stale = true;
+                }
+            }
+            (delivered, stale)
+        };
+
+        // Drop subscribers whose connection went away without unsubscribing
+        if stale {
+            let mut channels = self.channels.write().unwrap();
+            if let Some(subscribers) = channels.get_mut(channel) {
+                subscribers.retain(|s| !s.sender.is_closed());
+                if subscribers.is_empty() {
+                    channels.remove(channel);
+                }
+            }
+        }
+
+        delivered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_publish_subscribe() {
+        let pubsub = PubSub::default();
+        let (sub, mut rx) = pubsub.register();
+
+        assert_eq!(pubsub.publish("news", b"ignored"), 0);
+
+        pubsub.subscribe("news", &sub);
+        pubsub.subscribe("news", &sub);
+        assert_eq!(pubsub.publish("news", b"hello"), 1);
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            Message::Publish {
+                channel: "news".to_string(),
+                payload: b"hello".to_vec(),
+            }
+        );
+        assert!(rx.try_recv().is_err());
+
+        pubsub.unsubscribe("news", sub.id);
+        assert_eq!(pubsub.publish("news", b"gone"), 0);
+    }
+
+    #[test]
+    fn test_output_buffer_limit() {
+        let payload = [b'x'; 100];
+        let pubsub = PubSub::with_limit(OutputBufferLimit {
+            hard: 1000,
+            soft: 400,
+            soft_seconds: 60,
+        });
+        let (sub, mut rx) = pubsub.register();
+        pubsub.subscribe("news", &sub);
+
+        // Over the soft limit is fine for a while, and forgotten once drained
+        for _ in 0..3 {
+            assert_eq!(pubsub.publish("news", &payload), 1);
+        }
+        assert!(sub.backlog.soft_since.lock().unwrap().is_some());
+        while rx.try_recv().is_ok() {}
+        assert_eq!(sub.backlog.bytes.load(Ordering::Relaxed), 0);
+        assert!(sub.backlog.soft_since.lock().unwrap().is_none());
+
+        // Past the hard limit the message is dropped and the connection flagged
+        for _ in 0..6 {
+            assert_eq!(pubsub.publish("news", &payload), 1);
+        }
+        assert_eq!(pubsub.publish("news", &payload), 0);
+        assert!(sub.backlog.overflowed.load(Ordering::Relaxed));
+        assert_eq!(pubsub.publish("news", b"small"), 0);
+
+        // Soft limit with no grace period closes as soon as it is passed
+        let pubsub = PubSub::with_limit(OutputBufferLimit {
+            hard: 0,
+            soft: 100,
+            soft_seconds: 0,
+        });
+        let (sub, _rx) = pubsub.register();
+        pubsub.subscribe("news", &sub);
+        assert_eq!(pubsub.publish("news", &payload), 0);
+
+        // Draining too slowly to get back under the soft limit in time closes too
+        let pubsub = PubSub::with_limit(OutputBufferLimit {
+            hard: 0,
+            soft: 400,
+            soft_seconds: 60,
+        });
+        let (sub, mut rx) = pubsub.register();
+        pubsub.subscribe("news", &sub);
+        for _ in 0..6 {
+            assert_eq!(pubsub.publish("news", &payload), 1);
+        }
+        *sub.backlog.soft_since.lock().unwrap() = Some(Instant::now() - Duration::from_secs(61));
+        rx.try_recv().unwrap();
+        assert!(sub.backlog.overflowed.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_publish_prunes_closed_connections() {
+        let pubsub = PubSub::default();
+        let (sub, rx) = pubsub.register();
+        pubsub.subscribe("news", &sub);
+
+        drop(rx);
+        assert_eq!(pubsub.publish("news", b"hello"), 0);
+        assert!(pubsub.channels.read().unwrap().is_empty());
+    }
+}
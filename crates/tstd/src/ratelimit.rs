@@ -0,0 +1,162 @@
+//! Per-connection command rate limiting and failed login backoff
+//!
+//! Each connection has a token bucket holding up to one second's worth of
+//! commands. It starts full, so a new client may burst up to the limit at
+//! once, and refills continuously at the limit. Time comes from the
+//! monotonic clock, so wall clock jumps neither drain nor flood a bucket.
+//!
+//! Failed logins are counted per connection and per client address, and per
+//! username when asked.
+//! Once the failures in a row reach the allowance, AUTH is refused for a
+//! lockout that doubles with every further failure, up to a cap.
+
+use std::time::{Duration, Instant};
+
+/// Lockout after the first failure past the allowance
+pub const LOGIN_LOCKOUT_BASE: Duration = Duration::from_secs(1);
+
+/// Longest lockout a run of failures can earn
+pub const LOGIN_LOCKOUT_MAX: Duration = Duration::from_secs(300);
+
+/// Token bucket of one connection
+#[derive(Debug, Clone, Default)]
+pub struct TokenBucket {
+    tokens: f64,
+    /// When `tokens` was last brought up to date (None = never used, full)
+    refilled: Option<Instant>,
+}
+
+impl TokenBucket {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take a token for one command at `rate` commands per second (0 = unlimited)
+    pub fn try_acquire(&mut self, rate: u64, now: Instant) -> bool {
+        if rate == 0 {
+            return true;
+        }
+
+        let rate = rate as f64;
+        self.tokens = match self.refilled {
+            None => rate,
+            // A lowered limit also caps tokens saved up under the old one
+            Some(last) => {
+                (self.tokens + now.saturating_duration_since(last).as_secs_f64() * rate).min(rate)
+            }
+        };
+        // An earlier `now` refills nothing and must not hand out the same time twice
+        self.refilled = Some(self.refilled.map_or(now, |last| last.max(now)));
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Failed logins in a row and the lockout they earned
+#[derive(Debug, Clone, Default)]
+pub struct LoginBackoff {
+    failures: u32,
+    locked_until: Option<Instant>,
+}
+
+impl LoginBackoff {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Time left before another attempt is allowed (None = allowed now)
+    pub fn locked_for(&self, now: Instant) -> Option<Duration> {
+        self.locked_until
+            .filter(|&until| until > now)
+            .map(|until| until - now)
+    }
+
+    /// Count a failed attempt, locking out once `allowance` failures are reached (0 = never)
+    pub fn record_failure(&mut self, allowance: u32, now: Instant) {
+        self.failures = self.failures.saturating_add(1);
+        if allowance == 0 || self.failures < allowance {
+            return;
+        }
+
+        let doublings = (self.failures - allowance).min(31);
+        let lockout = LOGIN_LOCKOUT_BASE
+            .saturating_mul(1 << doublings)
+            .min(LOGIN_LOCKOUT_MAX);
+        self.locked_until = Some(now + lockout);
+    }
+
+    /// Forget past failures after a successful login
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_burst_then_refill() {
+        let mut bucket = TokenBucket::new();
+        let start = Instant::now();
+
+        // A full second's worth at once, then nothing
+        for _ in 0..10 {
+            assert!(bucket.try_acquire(10, start));
+        }
+        assert!(!bucket.try_acquire(10, start));
+
+        // One token per 100ms comes back, never more than the burst
+        assert!(!bucket.try_acquire(10, start + Duration::from_millis(50)));
+        assert!(bucket.try_acquire(10, start + Duration::from_millis(100)));
+        assert!(!bucket.try_acquire(10, start + Duration::from_millis(100)));
+        let later = start + Duration::from_secs(60);
+        let granted = (0..20).filter(|_| bucket.try_acquire(10, later)).count();
+        assert_eq!(granted, 10);
+
+        // A clock reading from before the last one refills nothing
+        assert!(!bucket.try_acquire(10, start));
+        assert!(!bucket.try_acquire(10, later));
+
+        assert!(bucket.try_acquire(0, later));
+    }
+
+    #[test]
+    fn test_login_backoff_doubles() {
+        let mut backoff = LoginBackoff::new();
+        let start = Instant::now();
+
+        // Failures within the allowance lock nothing
+        backoff.record_failure(3, start);
+        backoff.record_failure(3, start);
+        assert_eq!(backoff.locked_for(start), None);
+
+        backoff.record_failure(3, start);
+        assert_eq!(backoff.locked_for(start), Some(Duration::from_secs(1)));
+        let later = start + Duration::from_secs(1);
+        assert_eq!(backoff.locked_for(later), None);
+
+        backoff.record_failure(3, later);
+        assert_eq!(backoff.locked_for(later), Some(Duration::from_secs(2)));
+        for _ in 0..40 {
+            backoff.record_failure(3, later);
+        }
+        assert_eq!(backoff.locked_for(later), Some(LOGIN_LOCKOUT_MAX));
+
+        backoff.reset();
+        backoff.record_failure(3, later);
+        assert_eq!(backoff.locked_for(later), None);
+
+        let mut unlimited = LoginBackoff::new();
+        for _ in 0..100 {
+            unlimited.record_failure(0, start);
+        }
+        assert_eq!(unlimited.locked_for(start), None);
+    }
+}
@@ -0,0 +1,151 @@
+//! Write feed for replicas
+//!
+//! A replica connects and sends SYNC. It gets a snapshot of the dataset as
+//! a list of commands, then every write command applied after that point,
+//! each as a command array. The snapshot is taken under the exclusive
+//! transaction lock, and the replica's feed is attached before that lock is
+//! released, so no write falls between the two. The feed is the
+//! connection's message queue, the one its pub/sub messages go through, so
+//! writes that arrive while a snapshot is still being sent wait there until
+//! the connection loop gets to them. They count against the same output
+//! buffer limit: a replica that falls that far behind is detached and its
+//! connection closed, and it has to SYNC again.
+
+use crate::pubsub::{Message, Subscriber};
+use crate::resp::RespValue;
+use std::sync::{Mutex, MutexGuard, RwLock};
+
+/// Replicas attached with SYNC
+#[derive(Default)]
+pub struct Replication {
+    replicas: RwLock<Vec<Subscriber>>,
+    /// Held around each write while replicas are attached, so they see writes in applied order
+    order: Mutex<()>,
+}
+
+impl Replication {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start forwarding writes to a connection's queue (no-op if already attached)
+    pub fn attach(&self, feed: &Subscriber) {
+        let mut replicas = self.replicas.write().unwrap();
+        if !replicas.iter().any(|r| r.id == feed.id) {
+            replicas.push(feed.clone());
+        }
+    }
+
+    /// Number of attached replicas whose connection is still open
+    pub fn replica_count(&self) -> usize {
+        self.replicas
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|r| !r.is_closed())
+            .count()
+    }
+
+    /// Lock to hold while applying and forwarding one write, if anyone is listening
+    ///
+    /// Concurrent writes to different keys may otherwise be forwarded in a
+    /// different order than they were applied.
+    pub fn order(&self) -> Option<MutexGuard<'_, ()>> {
+        if self.replicas.read().unwrap().is_empty() {
+            return None;
+        }
+        Some(self.order.lock().unwrap())
+    }
+
+    /// Forward an applied write command to every replica
+    pub fn broadcast(&self, command: &[RespValue]) {
+        let stale = {
+            let replicas = self.replicas.read().unwrap();
+            if replicas.is_empty() {
+                return;
+            }
+
+            let command = Message::Replicate(RespValue::Array(Some(command.to_vec())));
+            let mut stale = false;
+            for replica in replicas.iter() {
+                if !replica.send(command.clone()) {
+                    stale = true;
+                }
+            }
+            stale
+        };
+
+        // Drop replicas whose connection went away or fell too far behind
+        if stale {
+            self.replicas.write().unwrap().retain(|r| !r.is_closed());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::OutputBufferLimit;
+    use crate::pubsub::PubSub;
+
+    fn bulk(s: &str) -> RespValue {
+        RespValue::BulkString(Some(s.as_bytes().to_vec()))
+    }
+
+    fn replicated(command: &[RespValue]) -> Message {
+        Message::Replicate(RespValue::Array(Some(command.to_vec())))
+    }
+
+    #[test]
+    fn test_broadcast_reaches_attached_replicas() {
+        let pubsub = PubSub::default();
+        let replication = Replication::new();
+        let (feed, mut rx) = pubsub.register();
+        let (_idle, mut idle_rx) = pubsub.register();
+        assert!(replication.order().is_none());
+
+        replication.broadcast(&[bulk("SET"), bulk("a"), bulk("1")]);
+        replication.attach(&feed);
+        replication.attach(&feed);
+        assert_eq!(replication.replica_count(), 1);
+        assert!(replication.order().is_some());
+
+        replication.broadcast(&[bulk("SET"), bulk("b"), bulk("2")]);
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            replicated(&[bulk("SET"), bulk("b"), bulk("2")])
+        );
+        assert!(rx.try_recv().is_err());
+        assert!(idle_rx.try_recv().is_err());
+
+        drop(rx);
+        replication.broadcast(&[bulk("DEL"), bulk("b")]);
+        assert_eq!(replication.replica_count(), 0);
+        assert!(replication.order().is_none());
+    }
+
+    #[test]
+    fn test_replica_over_output_buffer_limit_is_detached() {
+        let pubsub = PubSub::with_limit(OutputBufferLimit {
+            hard: 1024,
+            soft: 0,
+            soft_seconds: 0,
+        });
+        let replication = Replication::new();
+        let (slow, _slow_rx) = pubsub.register();
+        let (fast, mut fast_rx) = pubsub.register();
+        replication.attach(&slow);
+        replication.attach(&fast);
+
+        let value = "x".repeat(200);
+        for _ in 0..10 {
+            replication.broadcast(&[bulk("SET"), bulk("k"), bulk(&value)]);
+            while fast_rx.try_recv().is_ok() {}
+        }
+
+        // The replica that never read is dropped, the one keeping up stays
+        assert!(slow.is_closed());
+        assert!(!fast.is_closed());
+        assert_eq!(replication.replica_count(), 1);
+    }
+}
@@ -1,9 +1,10 @@
 //! RESP (REdis Serialization Protocol) parser and serializer
 //!
-//! Implements RESP2 protocol for Redis compatibility
+//! Implements RESP2 protocol for Redis compatibility, plus the RESP3
+//! verbatim string, map and push types
 
 use bytes::{Buf, BytesMut};
-use std::io::Cursor;
+use std::io::{self, Cursor, Write};
 
 /// Maximum bulk string size (512MB) - prevents DoS via memory exhaustion
 const MAX_BULK_STRING_SIZE: usize = 512 * 1024 * 1024;
@@ -24,30 +25,106 @@ pub enum RespValue {
     BulkString(Option<Vec<u8>>),
     /// Array: *2\r\n$3\r\nfoo\r\n$3\r\nbar\r\n
     Array(Option<Vec<RespValue>>),
+    /// Verbatim string (RESP3): =15\r\ntxt:Some string\r\n
+    ///
+    /// The second field is the three-byte format, e.g. `txt` or `mkd`
+    VerbatimString(String, [u8; 3]),
+    /// Push (RESP3): >2\r\n$7\r\nmessage\r\n$2\r\nhi\r\n
+    ///
+    /// Out-of-band data such as pub/sub messages, routed by RESP3 clients
+    /// to their push handler instead of being matched to a command
+    Push(Vec<RespValue>),
+    /// Map (RESP3): %1\r\n+proto\r\n:3\r\n
+    ///
+    /// Keys and values alternate, so the map has half as many entries as
+    /// the vector has elements
+    Map(Vec<RespValue>),
 }
 
 impl RespValue {
     /// Serialize to RESP format
     pub fn serialize(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        self.serialize_to(&mut result)
+            .expect("writing to a Vec cannot fail");
+        result
+    }
+
+    /// Serialize to RESP format, writing straight into `w`
+    pub fn serialize_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
         match self {
-            RespValue::SimpleString(s) => format!("+{}\r\n", s).into_bytes(),
-            RespValue::Error(e) => format!("-{}\r\n", e).into_bytes(),
-            RespValue::Integer(i) => format!(":{}\r\n", i).into_bytes(),
-            RespValue::BulkString(None) => b"$-1\r\n".to_vec(),
+            RespValue::SimpleString(s) => write!(w, "+{}\r\n", s),
+            RespValue::Error(e) => write!(w, "-{}\r\n", e),
+            RespValue::Integer(i) => write!(w, ":{}\r\n", i),
+            RespValue::BulkString(None) => w.write_all(b"$-1\r\n"),
             RespValue::BulkString(Some(data)) => {
-                let mut result = format!("${}\r\n", data.len()).into_bytes();
-                result.extend_from_slice(data);
-                result.extend_from_slice(b"\r\n");
-                result
+                write!(w, "${}\r\n", data.len())?;
+                w.write_all(data)?;
+                w.write_all(b"\r\n")
             }
-            RespValue::Array(None) => b"*-1\r\n".to_vec(),
-            RespValue::Array(Some(arr)) => {
-                let mut result = format!("*{}\r\n", arr.len()).into_bytes();
-                for val in arr {
-                    result.extend_from_slice(&val.serialize());
+            RespValue::Array(None) => w.write_all(b"*-1\r\n"),
+            RespValue::Array(Some(items)) | RespValue::Push(items) | RespValue::Map(items) => {
+                self.write_header(w, items.len())?;
+                for val in items {
+                    val.serialize_to(w)?;
                 }
-                result
+                Ok(())
+            }
+            RespValue::VerbatimString(text, format) => {
+                write!(w, "={}\r\n", text.len() + 4)?;
+                w.write_all(format)?;
+                w.write_all(b":")?;
+                w.write_all(text.as_bytes())?;
+                w.write_all(b"\r\n")
+            }
+        }
+    }
+
+    /// Number of bytes `serialize` would produce, counted without serializing
+    pub fn serialized_len(&self) -> u64 {
+        fn digits(n: u64) -> u64 {
+            n.checked_ilog10().map_or(1, |d| d as u64 + 1)
+        }
+        // `<prefix><len>\r\n`
+        fn header(len: usize) -> u64 {
+            3 + digits(len as u64)
+        }
+
+        match self {
+            RespValue::SimpleString(s) | RespValue::Error(s) => 3 + s.len() as u64,
+            RespValue::Integer(i) => 3 + (*i < 0) as u64 + digits(i.unsigned_abs()),
+            RespValue::BulkString(None) | RespValue::Array(None) => 5,
+            RespValue::BulkString(Some(data)) => header(data.len()) + data.len() as u64 + 2,
+            RespValue::Array(Some(items)) | RespValue::Push(items) => {
+                header(items.len()) + items.iter().map(RespValue::serialized_len).sum::<u64>()
+            }
+            RespValue::Map(items) => {
+                header(items.len() / 2) + items.iter().map(RespValue::serialized_len).sum::<u64>()
             }
+            RespValue::VerbatimString(text, _) => header(text.len() + 4) + text.len() as u64 + 6,
+        }
+    }
+
+    /// Serialize to RESP format in pieces of at least `chunk_bytes`
+    ///
+    /// Only the last piece may be shorter. Arrays are walked rather than
+    /// serialized whole, so a reply of many elements never needs one buffer
+    /// for all of it; a single bulk string still comes out in one piece.
+    pub fn chunks(&self, chunk_bytes: usize) -> ReplyChunks<'_> {
+        ReplyChunks {
+            first: Some(self),
+            stack: Vec::new(),
+            chunk_bytes: chunk_bytes.max(1),
+        }
+    }
+
+    /// Write the `*<len>`, `><len>` or `%<entries>` line in front of an
+    /// array, push or map of `len` elements
+    fn write_header<W: Write>(&self, w: &mut W, len: usize) -> io::Result<()> {
+        match self {
+            RespValue::Push(_) => write!(w, ">{}\r\n", len),
+            RespValue::Map(_) => write!(w, "%{}\r\n", len / 2),
+            _ => write!(w, "*{}\r\n", len),
         }
     }
 
@@ -70,6 +147,55 @@ impl RespValue {
     }
 }
 
+/// Iterator over the pieces of a serialized reply, see `RespValue::chunks`
+pub struct ReplyChunks<'a> {
+    /// The reply itself, until the first piece is taken
+    first: Option<&'a RespValue>,
+    /// Elements still to write of every array entered so far, innermost last
+    stack: Vec<std::slice::Iter<'a, RespValue>>,
+    chunk_bytes: usize,
+}
+
+impl<'a> ReplyChunks<'a> {
+    fn next_value(&mut self) -> Option<&'a RespValue> {
+        if let Some(value) = self.first.take() {
+            return Some(value);
+        }
+        while let Some(items) = self.stack.last_mut() {
+            match items.next() {
+                Some(value) => return Some(value),
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+        None
+    }
+}
+
+impl Iterator for ReplyChunks<'_> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let mut chunk = Vec::new();
+        while chunk.len() < self.chunk_bytes {
+            let Some(value) = self.next_value() else {
+                break;
+            };
+            let written = match value {
+                RespValue::Array(Some(items)) | RespValue::Push(items) | RespValue::Map(items) => {
+                    self.stack.push(items.iter());
+                    value.write_header(&mut chunk, items.len())
+                }
+                _ => value.serialize_to(&mut chunk),
+            };
+            written.expect("writing to a Vec cannot fail");
+        }
+
+        (!chunk.is_empty()).then_some(chunk)
+    }
+}
+
 fn parse_value(cursor: &mut Cursor<&[u8]>) -> Result<Option<RespValue>, String> {
     if !cursor.has_remaining() {
         return Ok(None);
@@ -83,6 +209,8 @@ fn parse_value(cursor: &mut Cursor<&[u8]>) -> Result<Option<RespValue>, String>
         b':' => parse_integer(cursor),
         b'$' => parse_bulk_string(cursor),
         b'*' => parse_array(cursor),
+        b'=' => parse_verbatim_string(cursor),
+        b'>' => parse_push(cursor),
         _ => Err(format!("Unknown RESP type: {}", type_byte as char)),
     }
 }
@@ -196,6 +324,78 @@ fn parse_array(cursor: &mut Cursor<&[u8]>) -> Result<Option<RespValue>, String>
     Ok(Some(RespValue::Array(Some(arr))))
 }
 
+fn parse_verbatim_string(cursor: &mut Cursor<&[u8]>) -> Result<Option<RespValue>, String> {
+    let len_line = match read_line(cursor)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    let len_str = String::from_utf8(len_line).map_err(|e| e.to_string())?;
+    let len = len_str.parse::<usize>().map_err(|e| e.to_string())?;
+
+    if len > MAX_BULK_STRING_SIZE {
+        return Err(format!(
+            "ERR verbatim string too large: {} bytes (max: {} bytes)",
+            len, MAX_BULK_STRING_SIZE
+        ));
+    }
+
+    // Payload is "fmt:" followed by the text
+    if len < 4 {
+        return Err("Verbatim string too short for format prefix".to_string());
+    }
+
+    if cursor.remaining() < len + 2 {
+        return Ok(None); // Need more data
+    }
+
+    let mut data = vec![0u8; len];
+    cursor.copy_to_slice(&mut data);
+
+    let cr = cursor.get_u8();
+    let lf = cursor.get_u8();
+    if cr != b'\r' || lf != b'\n' {
+        return Err("Expected \\r\\n after verbatim string".to_string());
+    }
+
+    if data[3] != b':' {
+        return Err("Expected ':' after verbatim string format".to_string());
+    }
+
+    let format = [data[0], data[1], data[2]];
+    let text = String::from_utf8(data.split_off(4)).map_err(|e| e.to_string())?;
+
+    Ok(Some(RespValue::VerbatimString(text, format)))
+}
+
+fn parse_push(cursor: &mut Cursor<&[u8]>) -> Result<Option<RespValue>, String> {
+    let len_line = match read_line(cursor)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    let len_str = String::from_utf8(len_line).map_err(|e| e.to_string())?;
+    let len = len_str.parse::<usize>().map_err(|e| e.to_string())?;
+
+    if len > MAX_ARRAY_SIZE {
+        return Err(format!(
+            "ERR push too large: {} elements (max: {} elements)",
+            len, MAX_ARRAY_SIZE
+        ));
+    }
+
+    let mut items = Vec::with_capacity(len);
+
+    for _ in 0..len {
+        match parse_value(cursor)? {
+            Some(val) => items.push(val),
+            None => return Ok(None), // Need more data
+        }
+    }
+
+    Ok(Some(RespValue::Push(items)))
+}
+
 fn read_line(cursor: &mut Cursor<&[u8]>) -> Result<Option<Vec<u8>>, String> {
     let start = cursor.position() as usize;
     let slice = &cursor.get_ref()[start..];
@@ -292,6 +492,143 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_verbatim_string() {
+        let data = b"=15\r\ntxt:Some string\r\n";
+        let mut buf = BytesMut::from(&data[..]);
+        let val = RespValue::parse(&mut buf).unwrap().unwrap();
+        assert_eq!(
+            val,
+            RespValue::VerbatimString("Some string".to_string(), *b"txt")
+        );
+        assert_eq!(val.serialize(), data);
+
+        // Text may itself contain CRLF, the length prefix is authoritative
+        let val = RespValue::VerbatimString("# Title\r\nbody".to_string(), *b"mkd");
+        let mut buf = BytesMut::from(&val.serialize()[..]);
+        assert_eq!(RespValue::parse(&mut buf).unwrap().unwrap(), val);
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(&b"=3\r\ntxt\r\n"[..]);
+        assert!(RespValue::parse(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_push() {
+        let data = b">3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$5\r\nhello\r\n";
+        let mut buf = BytesMut::from(&data[..]);
+        let val = RespValue::parse(&mut buf).unwrap().unwrap();
+        assert_eq!(
+            val,
+            RespValue::Push(vec![
+                RespValue::BulkString(Some(b"message".to_vec())),
+                RespValue::BulkString(Some(b"news".to_vec())),
+                RespValue::BulkString(Some(b"hello".to_vec())),
+            ])
+        );
+        assert_eq!(val.serialize(), data);
+
+        let mut buf = BytesMut::from(&data[..data.len() - 3]);
+        assert!(RespValue::parse(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_map() {
+        let map = RespValue::Map(vec![
+            RespValue::BulkString(Some(b"server".to_vec())),
+            RespValue::BulkString(Some(b"tstd".to_vec())),
+            RespValue::BulkString(Some(b"proto".to_vec())),
+            RespValue::Integer(3),
+        ]);
+        let expected = b"%2\r\n$6\r\nserver\r\n$4\r\ntstd\r\n$5\r\nproto\r\n:3\r\n";
+        assert_eq!(map.serialize(), expected);
+        assert_eq!(map.chunks(4).collect::<Vec<_>>().concat(), expected);
+    }
+
+    #[test]
+    fn test_serialize_to() {
+        let nested = RespValue::Array(Some(vec![
+            RespValue::Integer(-7),
+            RespValue::Array(None),
+            RespValue::Push(vec![RespValue::BulkString(None)]),
+        ]));
+        let cases: Vec<(RespValue, &[u8])> = vec![
+            (RespValue::SimpleString("OK".to_string()), b"+OK\r\n"),
+            (RespValue::Error("ERR bad".to_string()), b"-ERR bad\r\n"),
+            (RespValue::Integer(42), b":42\r\n"),
+            (RespValue::BulkString(None), b"$-1\r\n"),
+            (
+                RespValue::BulkString(Some(b"a\r\nb".to_vec())),
+                b"$4\r\na\r\nb\r\n",
+            ),
+            (RespValue::Array(None), b"*-1\r\n"),
+            (RespValue::Array(Some(Vec::new())), b"*0\r\n"),
+            (
+                RespValue::VerbatimString("hi".to_string(), *b"txt"),
+                b"=6\r\ntxt:hi\r\n",
+            ),
+            (nested, b"*3\r\n:-7\r\n*-1\r\n>1\r\n$-1\r\n"),
+        ];
+
+        for (value, expected) in cases {
+            let mut written = Vec::new();
+            value.serialize_to(&mut written).unwrap();
+            assert_eq!(written, expected, "{:?}", value);
+            assert_eq!(value.serialize(), expected);
+            assert_eq!(value.chunks(1).collect::<Vec<_>>().concat(), expected);
+        }
+    }
+
+    #[test]
+    fn test_reply_chunks() {
+        let reply = RespValue::Array(Some(
+            (0..1000)
+                .map(|i| {
+                    RespValue::Array(Some(vec![
+                        RespValue::Integer(i),
+                        RespValue::BulkString(Some(format!("value {}", i).into_bytes())),
+                    ]))
+                })
+                .collect(),
+        ));
+        let chunks: Vec<Vec<u8>> = reply.chunks(256).collect();
+
+        assert_eq!(chunks.concat(), reply.serialize());
+        assert!(chunks.len() > 10);
+        let (last, full) = chunks.split_last().unwrap();
+        assert!(full.iter().all(|chunk| (256..300).contains(&chunk.len())));
+        assert!(!last.is_empty());
+        assert_eq!(RespValue::Integer(1).chunks(0).count(), 1);
+    }
+
+    #[test]
+    fn test_serialized_len() {
+        for value in [
+            RespValue::SimpleString("OK".to_string()),
+            RespValue::Error("ERR no".to_string()),
+            RespValue::Integer(0),
+            RespValue::Integer(-1234),
+            RespValue::Integer(i64::MIN),
+            RespValue::BulkString(None),
+            RespValue::BulkString(Some(vec![b'x'; 1000])),
+            RespValue::Array(None),
+            RespValue::Array(Some((0..12).map(RespValue::Integer).collect())),
+            RespValue::Push(vec![RespValue::Array(Some(vec![]))]),
+            RespValue::Map(vec![
+                RespValue::BulkString(Some(b"proto".to_vec())),
+                RespValue::Integer(3),
+            ]),
+            RespValue::VerbatimString("some text".to_string(), *b"txt"),
+        ] {
+            assert_eq!(
+                value.serialized_len(),
+                value.serialize().len() as u64,
+                "{:?}",
+                value
+            );
+        }
+    }
+
     #[test]
     fn test_incomplete_data() {
         let data = b"$6\r\nfoo"; // Incomplete
@@ -0,0 +1,128 @@
+//! Parser for EVAL scripts
+//!
+//! A script is not Lua but a list of our own commands separated by `;`.
+//! Words are split on whitespace; a word in double quotes may hold spaces,
+//! `;` and `\"`/`\\` escapes. A word that is exactly `KEYS[n]` or `ARGV[n]`
+//! (counting from 1) is replaced by that key or argument. There is no
+//! branching or looping.
+
+/// Split `script` into statements of words, substituting `keys` and `argv`
+pub fn parse(
+    script: &[u8],
+    keys: &[Vec<u8>],
+    argv: &[Vec<u8>],
+) -> Result<Vec<Vec<Vec<u8>>>, String> {
+    let mut statements = Vec::new();
+    let mut words: Vec<Vec<u8>> = Vec::new();
+    let mut bytes = script.iter().copied().peekable();
+
+    while let Some(b) = bytes.next() {
+        match b {
+            b';' => {
+                if !words.is_empty() {
+                    statements.push(std::mem::take(&mut words));
+                }
+            }
+            b if b.is_ascii_whitespace() => {}
+            b'"' => {
+                let mut word = Vec::new();
+                loop {
+                    match bytes.next() {
+                        Some(b'"') => break,
+                        Some(b'\\') => match bytes.next() {
+                            Some(escaped) => word.push(escaped),
+                            None => return Err("unterminated quoted word".to_string()),
+                        },
+                        Some(b) => word.push(b),
+                        None => return Err("unterminated quoted word".to_string()),
+                    }
+                }
+                words.push(word);
+            }
+            b => {
+                let mut word = vec![b];
+                while let Some(&next) = bytes.peek() {
+                    if next == b';' || next == b'"' || next.is_ascii_whitespace() {
+                        break;
+                    }
+                    word.push(next);
+                    bytes.next();
+                }
+                words.push(substitute(word, keys, argv)?);
+            }
+        }
+    }
+    if !words.is_empty() {
+        statements.push(words);
+    }
+
+    Ok(statements)
+}
+
+/// Replace a `KEYS[n]` or `ARGV[n]` word, leaving any other word as it is
+fn substitute(word: Vec<u8>, keys: &[Vec<u8>], argv: &[Vec<u8>]) -> Result<Vec<u8>, String> {
+    let (name, values) = if word.starts_with(b"KEYS[") {
+        ("KEYS", keys)
+    } else if word.starts_with(b"ARGV[") {
+        ("ARGV", argv)
+    } else {
+        return Ok(word);
+    };
+    let Some(position) = word[5..]
+        .strip_suffix(b"]")
+        .and_then(|n| std::str::from_utf8(n).ok())
+        .and_then(|n| n.parse::<usize>().ok())
+    else {
+        return Ok(word);
+    };
+
+    match position.checked_sub(1).and_then(|i| values.get(i)) {
+        Some(value) => Ok(value.clone()),
+        None => Err(format!(
+            "{}[{}] is out of range, {} given",
+            name,
+            position,
+            values.len()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(statement: &[&str]) -> Vec<Vec<u8>> {
+        statement.iter().map(|w| w.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_parse_statements() {
+        let keys = vec![b"mykey".to_vec()];
+        let argv = vec![b"hello world".to_vec()];
+        let parsed = parse(
+            b"SET KEYS[1] ARGV[1] ;; GET  KEYS[1];\n ECHO \"a; \\\"b\\\"\" KEYS[x]",
+            &keys,
+            &argv,
+        )
+        .unwrap();
+        assert_eq!(
+            parsed,
+            vec![
+                words(&["SET", "mykey", "hello world"]),
+                words(&["GET", "mykey"]),
+                words(&["ECHO", "a; \"b\"", "KEYS[x]"]),
+            ]
+        );
+        assert!(parse(b"  ; ", &keys, &argv).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            parse(b"GET KEYS[2]", &[b"k".to_vec()], &[]),
+            Err("KEYS[2] is out of range, 1 given".to_string())
+        );
+        assert!(parse(b"GET ARGV[0]", &[], &[b"a".to_vec()]).is_err());
+        assert!(parse(b"ECHO \"open", &[], &[]).is_err());
+    }
+}
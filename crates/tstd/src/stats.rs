@@ -0,0 +1,141 @@
+//! Server-wide command throughput and latency for INFO stats
+//!
+//! Every handled command is timed and recorded without taking a lock:
+//! counters are atomics and the latency average is an EWMA kept as `f64`
+//! bits in an `AtomicU64`. Values are approximate under contention.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Weight of each new sample in the latency average
+const LATENCY_ALPHA: f64 = 0.05;
+
+/// Command counters shared by every connection
+#[derive(Debug)]
+pub struct CommandStats {
+    start: Instant,
+    /// Commands handled since startup
+    total: AtomicU64,
+    /// Second (since `start`) that `current_ops` counts
+    second: AtomicU64,
+    /// Commands handled during `second`
+    current_ops: AtomicU64,
+    /// Commands handled during the second before `second`
+    previous_ops: AtomicU64,
+    /// Moving average of command latency in microseconds (`f64` bits)
+    latency_us: AtomicU64,
+}
+
+impl Default for CommandStats {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            total: AtomicU64::new(0),
+            second: AtomicU64::new(0),
+            current_ops: AtomicU64::new(0),
+            previous_ops: AtomicU64::new(0),
+            latency_us: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+}
+
+impl CommandStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count one handled command that took `elapsed`
+    pub fn record(&self, elapsed: Duration) {
+        self.total.fetch_add(1, Ordering::Relaxed);
+        self.record_at(self.start.elapsed().as_secs(), elapsed);
+    }
+
+    fn record_at(&self, now: u64, elapsed: Duration) {
+        // Whoever moves the second on publishes the count of the finished one
+        let second = self.second.load(Ordering::Relaxed);
+        if second != now
+            && self
+                .second
+                .compare_exchange(second, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            let finished = self.current_ops.swap(0, Ordering::Relaxed);
+            let previous = if now == second + 1 { finished } else { 0 };
+            self.previous_ops.store(previous, Ordering::Relaxed);
+        }
+        self.current_ops.fetch_add(1, Ordering::Relaxed);
+
+        let sample = elapsed.as_secs_f64() * 1_000_000.0;
+        let _ = self
+            .latency_us
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                let average = f64::from_bits(bits);
+                let average = if self.total.load(Ordering::Relaxed) <= 1 {
+                    sample
+                } else {
+                    average + LATENCY_ALPHA * (sample - average)
+                };
+                Some(average.to_bits())
+            });
+    }
+
+    /// Commands handled since startup
+    pub fn total_commands(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// Commands handled during the last complete second
+    pub fn ops_per_sec(&self) -> u64 {
+        self.ops_per_sec_at(self.start.elapsed().as_secs())
+    }
+
+    fn ops_per_sec_at(&self, now: u64) -> u64 {
+        match now.saturating_sub(self.second.load(Ordering::Relaxed)) {
+            0 => self.previous_ops.load(Ordering::Relaxed),
+            1 => self.current_ops.load(Ordering::Relaxed),
+            _ => 0, // Idle for more than a second
+        }
+    }
+
+    /// Moving average of command latency in microseconds
+    pub fn latency_us(&self) -> f64 {
+        f64::from_bits(self.latency_us.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ops_per_sec() {
+        let stats = CommandStats::new();
+        for _ in 0..3 {
+            stats.record_at(5, Duration::from_micros(10));
+        }
+        assert_eq!(stats.ops_per_sec_at(5), 0);
+        assert_eq!(stats.ops_per_sec_at(6), 3);
+
+        stats.record_at(6, Duration::from_micros(10));
+        assert_eq!(stats.ops_per_sec_at(6), 3);
+        assert_eq!(stats.ops_per_sec_at(7), 1);
+        assert_eq!(stats.ops_per_sec_at(9), 0);
+
+        // A gap of idle seconds does not carry the old count over
+        stats.record_at(9, Duration::from_micros(10));
+        assert_eq!(stats.ops_per_sec_at(9), 0);
+    }
+
+    #[test]
+    fn test_latency_average() {
+        let stats = CommandStats::new();
+        stats.record(Duration::from_micros(100));
+        assert_eq!(stats.latency_us(), 100.0);
+
+        for _ in 0..200 {
+            stats.record(Duration::from_micros(10));
+        }
+        assert!((stats.latency_us() - 10.0).abs() < 1.0);
+        assert_eq!(stats.total_commands(), 201);
+    }
+}
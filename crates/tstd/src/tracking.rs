@@ -0,0 +1,214 @@
+//! Client-side caching invalidation (CLIENT TRACKING)
+//!
+//! A tracking connection is remembered for every key it reads. The first
+//! modification of such a key afterwards queues an invalidation for each
+//! connection that read it and forgets them, so a connection hears about a
+//! key once per read, the way Redis' default tracking mode behaves.
+//! Invalidations go through the connection's pub/sub queue.
+
+use crate::pubsub::{Message, Subscriber};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Key name -> connections that read it since its last modification
+///
+/// At most `max_keys` keys are remembered, like Redis'
+/// `tracking-table-max-keys`: tracking one more forgets the key tracked
+/// longest ago, and its readers get an invalidation as if it had been
+/// modified, since nobody would tell them when it is.
+#[derive(Default)]
+pub struct Tracking {
+    table: Mutex<Table>,
+    /// Most keys remembered at once (0 = no limit)
+    max_keys: usize,
+    /// Connections with tracking on; modifications skip the table while zero
+    clients: AtomicUsize,
+}
+
+#[derive(Default)]
+struct Table {
+    keys: HashMap<Arc<str>, Readers>,
+    /// Tracked keys, oldest first, each with the `since` it was tracked at;
+    /// an entry whose key has since been forgotten or tracked anew is stale
+    order: VecDeque<(Arc<str>, u64)>,
+    next: u64,
+}
+
+struct Readers {
+    since: u64,
+    subscribers: Vec<Subscriber>,
+}
+
+impl Table {
+    /// Forget the tracked key that has been tracked longest, returning it
+    /// and its readers
+    fn pop_oldest(&mut self) -> Option<(Arc<str>, Vec<Subscriber>)> {
+        while let Some((key, since)) = self.order.pop_front() {
+            if self
+                .keys
+                .get(&key)
+                .is_some_and(|readers| readers.since == since)
+            {
+                let readers = self.keys.remove(&key)?;
+                return Some((key, readers.subscribers));
+            }
+        }
+        None
+    }
+
+    /// Drop stale order entries once they outnumber the tracked keys
+    fn compact(&mut self) {
+        if self.order.len() > 2 * self.keys.len() + 64 {
+            let keys = &self.keys;
+            self.order.retain(|(key, since)| {
+                keys.get(key).is_some_and(|readers| readers.since == *since)
+            });
+        }
+    }
+}
+
+impl Tracking {
+    /// An empty table remembering at most `max_keys` keys (0 = no limit)
+    pub fn new(max_keys: usize) -> Self {
+        Self {
+            max_keys,
+            ..Self::default()
+        }
+    }
+
+    pub fn enable(&self) {
+        self.clients.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Turn tracking off for a connection, dropping the keys it read
+    pub fn disable(&self, id: u64) {
+        self.clients.fetch_sub(1, Ordering::Relaxed);
+        let mut table = self.table.lock().unwrap();
+        table.keys.retain(|_, readers| {
+            readers.subscribers.retain(|s| s.id != id);
+            !readers.subscribers.is_empty()
+        });
+        table.compact();
+    }
+
+    /// Remember that a tracking connection read `key`
+    pub fn track(&self, key: &str, subscriber: &Subscriber) {
+        let mut evicted = Vec::new();
+        {
+            let mut table = self.table.lock().unwrap();
+            if let Some(readers) = table.keys.get_mut(key) {
+                if !readers.subscribers.iter().any(|s| s.id == subscriber.id) {
+                    readers.subscribers.push(subscriber.clone());
+                }
+                return;
+            }
+
+            while self.max_keys > 0 && table.keys.len() >= self.max_keys {
+                match table.pop_oldest() {
+                    Some(oldest) => evicted.push(oldest),
+                    None => break,
+                }
+            }
+            let key: Arc<str> = Arc::from(key);
+            let since = table.next;
+            table.next += 1;
+            table.order.push_back((Arc::clone(&key), since));
+            table.keys.insert(
+                key,
+                Readers {
+                    since,
+                    subscribers: vec![subscriber.clone()],
+                },
+            );
+            table.compact();
+        }
+
+        for (key, readers) in evicted {
+            for reader in readers {
+                reader.send(Message::Invalidate(key.to_string()));
+            }
+        }
+    }
+
+    /// Notify and forget the connections that read `key`
+    pub fn invalidate(&self, key: &str) {
+        if self.clients.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let Some(readers) = self.table.lock().unwrap().keys.remove(key) else {
+            return;
+        };
+        for reader in readers.subscribers {
+            reader.send(Message::Invalidate(key.to_string()));
+        }
+    }
+
+    /// Keys remembered right now
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.table.lock().unwrap().keys.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pubsub::PubSub;
+
+    #[test]
+    fn test_invalidates_once_per_read() {
+        let pubsub = PubSub::default();
+        let tracking = Tracking::new(0);
+        let (reader, mut rx) = pubsub.register();
+        tracking.enable();
+
+        tracking.track("user:1", &reader);
+        tracking.track("user:1", &reader);
+        tracking.invalidate("user:2");
+        assert!(rx.try_recv().is_err());
+
+        tracking.invalidate("user:1");
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            Message::Invalidate("user:1".to_string())
+        );
+        assert!(rx.try_recv().is_err());
+
+        // Not read again since, so no second invalidation
+        tracking.invalidate("user:1");
+        assert!(rx.try_recv().is_err());
+
+        tracking.track("user:1", &reader);
+        tracking.disable(reader.id);
+        assert_eq!(tracking.len(), 0);
+    }
+
+    #[test]
+    fn test_table_is_capped() {
+        let pubsub = PubSub::default();
+        let tracking = Tracking::new(2);
+        let (reader, mut rx) = pubsub.register();
+        tracking.enable();
+
+        tracking.track("a", &reader);
+        tracking.track("b", &reader);
+        tracking.track("a", &reader);
+        assert!(rx.try_recv().is_err());
+
+        // A third key forgets the oldest, and its reader hears of it
+        tracking.track("c", &reader);
+        assert_eq!(tracking.len(), 2);
+        assert_eq!(rx.try_recv().unwrap(), Message::Invalidate("a".to_string()));
+        assert!(rx.try_recv().is_err());
+
+        // Tracked anew after a modification, "b" is now younger than "c"
+        tracking.invalidate("b");
+        assert_eq!(rx.try_recv().unwrap(), Message::Invalidate("b".to_string()));
+        tracking.track("b", &reader);
+        tracking.track("d", &reader);
+        assert_eq!(rx.try_recv().unwrap(), Message::Invalidate("c".to_string()));
+        assert!(rx.try_recv().is_err());
+        assert_eq!(tracking.len(), 2);
+    }
+}
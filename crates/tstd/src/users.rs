@@ -2,12 +2,14 @@
 //!
 //! Provides multi-user authentication with roles and permissions
 
+use crate::commands;
 use anyhow::{Context, Result};
-use bcrypt::{hash, verify, DEFAULT_COST};
+use bcrypt::{hash, verify, HashParts};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::RwLock;
 use tracing::{info, warn};
 
@@ -23,6 +25,25 @@ pub enum UserRole {
 }
 
 impl UserRole {
+    /// Parse an ACL category rule such as `+@all` (loose mapping onto our roles)
+    pub fn from_acl_rule(rule: &str) -> Option<Self> {
+        match rule.to_lowercase().as_str() {
+            "+@all" | "allcommands" => Some(UserRole::Admin),
+            "+@write" | "+@readwrite" => Some(UserRole::ReadWrite),
+            "+@read" | "+@readonly" => Some(UserRole::ReadOnly),
+            _ => None,
+        }
+    }
+
+    /// ACL category rule describing this role
+    pub fn acl_rule(&self) -> &'static str {
+        match self {
+            UserRole::Admin => "+@all",
+            UserRole::ReadWrite => "+@write",
+            UserRole::ReadOnly => "+@read",
+        }
+    }
+
     /// Check if role can execute a command
     pub fn can_execute(&self, command: &str) -> bool {
         let cmd = command.to_uppercase();
@@ -33,14 +54,57 @@ impl UserRole {
                 // ReadWrite cannot flush DB or manage users
                 !matches!(
                     cmd.as_str(),
-                    "FLUSHDB" | "FLUSHALL" | "USER" | "ACL" | "CONFIG"
+                    "FLUSHDB"
+                        | "FLUSHALL"
+                        | "USER"
+                        | "ACL"
+                        | "CONFIG"
+                        | "USERSTATS"
+                        | "HOTKEYS"
+                        | "SIZEHISTOGRAM"
+                        | "DUMPALL"
+                        | "DEBUG"
+                        | "SYNC"
                 )
             }
             UserRole::ReadOnly => {
                 // ReadOnly can only read
                 matches!(
                     cmd.as_str(),
-                    "GET" | "MGET" | "EXISTS" | "KEYS" | "DBSIZE" | "INFO" | "PING" | "ECHO"
+                    "GET"
+                        | "MGET"
+                        | "GETRANGE"
+                        | "STRLEN"
+                        | "HGET"
+                        | "HGETALL"
+                        | "HLEN"
+                        | "LLEN"
+                        | "LRANGE"
+                        | "EXISTS"
+                        | "KEYS"
+                        | "COUNTKEYS"
+                        | "SCAN"
+                        | "COUNTBY"
+                        | "EVAL"
+                        | "OBJECT"
+                        | "MEMORY"
+                        | "CLIENT"
+                        | "DBSIZE"
+                        | "INFO"
+                        | "PING"
+                        | "ECHO"
+                        | "HELLO"
+                        | "MULTI"
+                        | "EXEC"
+                        | "DISCARD"
+                        | "WATCH"
+                        | "UNWATCH"
+                        | "TTL"
+                        | "PTTL"
+                        | "WAIT"
+                        | "TIME"
+                        | "SUBSCRIBE"
+                        | "UNSUBSCRIBE"
                 )
             }
         }
@@ -60,12 +124,15 @@ pub struct User {
     pub active: bool,
     /// Optional database restriction (None = all databases)
     pub database: Option<String>,
+    /// Largest value this user may write (None = the store default)
+    #[serde(default)]
+    pub max_value_size: Option<usize>,
 }
 
 impl User {
-    /// Create a new user
-    pub fn new(username: String, password: &str, role: UserRole) -> Result<Self> {
-        let password_hash = hash(password, DEFAULT_COST).context("Failed to hash password")?;
+    /// Create a new user, hashing the password at bcrypt `cost`
+    pub fn new(username: String, password: &str, role: UserRole, cost: u32) -> Result<Self> {
+        let password_hash = hash(password, cost).context("Failed to hash password")?;
 
         Ok(Self {
             username,
@@ -73,6 +140,7 @@ impl User {
             role,
             active: true,
             database: None,
+            max_value_size: None,
         })
     }
 
@@ -81,6 +149,14 @@ impl User {
         verify(password, &self.password_hash).unwrap_or(false)
     }
 
+    /// Bcrypt cost the password was hashed at (None = not a bcrypt hash)
+    pub fn hash_cost(&self) -> Option<u32> {
+        self.password_hash
+            .parse::<HashParts>()
+            .ok()
+            .map(|parts| parts.get_cost())
+    }
+
     /// Check if user can execute a command
     #[allow(dead_code)]
     pub fn can_execute(&self, command: &str) -> bool {
@@ -92,17 +168,20 @@ impl User {
 pub struct UserManager {
     users: RwLock<HashMap<String, User>>,
     users_file: String,
+    /// Bcrypt cost for new hashes; older ones are raised to it on login
+    bcrypt_cost: u32,
 }
 
 impl UserManager {
-    /// Create a new user manager
-    pub fn new(data_dir: &str) -> Result<Self> {
+    /// Create a new user manager hashing passwords at bcrypt `cost`
+    pub fn new(data_dir: &str, cost: u32) -> Result<Self> {
         let users_file = format!("{}/users.json", data_dir);
         let users = Self::load_users(&users_file)?;
 
         let manager = Self {
             users: RwLock::new(users),
             users_file,
+            bcrypt_cost: cost,
         };
 
         // Create default admin user if no users exist
@@ -138,6 +217,22 @@ impl UserManager {
         Ok(map)
     }
 
+    /// Re-read the users file, replacing every user in memory
+    ///
+    /// For edits made to the file while the server runs. The file is read
+    /// and parsed before the lock is taken, so authentication only waits
+    /// for the swap. A missing or unparsable file leaves the current users
+    /// in place.
+    pub fn reload(&self) -> Result<()> {
+        if !Path::new(&self.users_file).exists() {
+            return Err(anyhow::anyhow!("No users file at {}", self.users_file));
+        }
+        let users = Self::load_users(&self.users_file)?;
+
+        *self.users.write().unwrap() = users;
+        Ok(())
+    }
+
     /// Save users to file
     fn save_users(&self) -> Result<()> {
         let users = self.users.read().unwrap();
@@ -159,7 +254,7 @@ impl UserManager {
             return Err(anyhow::anyhow!("User '{}' already exists", username));
         }
 
-        let user = User::new(username.to_string(), password, role)?;
+        let user = User::new(username.to_string(), password, role, self.bcrypt_cost)?;
         users.insert(username.to_string(), user);
         drop(users);
 
@@ -170,16 +265,49 @@ impl UserManager {
     }
 
     /// Authenticate a user
+    ///
+    /// A password hashed at a lower cost than configured is re-hashed at
+    /// the configured cost and saved, since this is the only time the
+    /// plain password is at hand.
     pub fn authenticate(&self, username: &str, password: &str) -> Option<User> {
-        let users = self.users.read().unwrap();
+        let user = {
+            let users = self.users.read().unwrap();
+            let user = users.get(username)?;
+            if !(user.active && user.verify_password(password)) {
+                return None;
+            }
+            user.clone()
+        };
 
-        if let Some(user) = users.get(username) {
-            if user.active && user.verify_password(password) {
-                return Some(user.clone());
+        if user.hash_cost().is_some_and(|cost| cost < self.bcrypt_cost) {
+            if let Err(e) = self.upgrade_hash(&user, password) {
+                warn!("Failed to upgrade password hash for {}: {:#}", username, e);
             }
         }
 
-        None
+        Some(user)
+    }
+
+    /// Re-hash a verified password at the configured cost
+    fn upgrade_hash(&self, user: &User, password: &str) -> Result<()> {
+        let upgraded = hash(password, self.bcrypt_cost).context("Failed to hash password")?;
+
+        let mut users = self.users.write().unwrap();
+        // The password may have been changed while the lock was released
+        match users.get_mut(&user.username) {
+            Some(current) if current.password_hash == user.password_hash => {
+                current.password_hash = upgraded;
+            }
+            _ => return Ok(()),
+        }
+        drop(users);
+
+        self.save_users()?;
+        info!(
+            "Upgraded password hash for user {} to cost {}",
+            user.username, self.bcrypt_cost
+        );
+        Ok(())
     }
 
     /// Delete a user
@@ -215,7 +343,8 @@ impl UserManager {
             .get_mut(username)
             .ok_or_else(|| anyhow::anyhow!("User '{}' not found", username))?;
 
-        user.password_hash = hash(new_password, DEFAULT_COST).context("Failed to hash password")?;
+        user.password_hash =
+            hash(new_password, self.bcrypt_cost).context("Failed to hash password")?;
 
         drop(users);
         self.save_users()?;
@@ -225,7 +354,6 @@ impl UserManager {
     }
 
     /// Update user role
-    #[allow(dead_code)] // Available for future use
     pub fn update_role(&self, username: &str, role: UserRole) -> Result<()> {
         let mut users = self.users.write().unwrap();
 
@@ -241,6 +369,118 @@ impl UserManager {
 
         Ok(())
     }
+
+    /// Enable or disable a user (disabled users cannot authenticate)
+    pub fn set_active(&self, username: &str, active: bool) -> Result<()> {
+        if username == "admin" && !active {
+            return Err(anyhow::anyhow!("Cannot disable admin user"));
+        }
+
+        let mut users = self.users.write().unwrap();
+
+        let user = users
+            .get_mut(username)
+            .ok_or_else(|| anyhow::anyhow!("User '{}' not found", username))?;
+
+        user.active = active;
+
+        drop(users);
+        self.save_users()?;
+        info!(
+            "{} user: {}",
+            if active { "Enabled" } else { "Disabled" },
+            username
+        );
+
+        Ok(())
+    }
+
+    /// Raise or lower the largest value a user may write (None = default)
+    pub fn set_max_value_size(&self, username: &str, max_value_size: Option<usize>) -> Result<()> {
+        let mut users = self.users.write().unwrap();
+
+        let user = users
+            .get_mut(username)
+            .ok_or_else(|| anyhow::anyhow!("User '{}' not found", username))?;
+
+        user.max_value_size = max_value_size;
+
+        drop(users);
+        self.save_users()?;
+        info!("Updated max value size for user: {}", username);
+
+        Ok(())
+    }
+
+    /// Look up a user by name
+    pub fn get_user(&self, username: &str) -> Option<User> {
+        self.users.read().unwrap().get(username).cloned()
+    }
+}
+
+/// Stats bucket for sessions that have not authenticated
+pub const ANONYMOUS_USER: &str = "(anonymous)";
+
+/// Stats bucket for command names not in the command table
+pub const UNKNOWN_COMMAND: &str = "(unknown)";
+
+/// Per-user command counters for auditing
+#[derive(Default)]
+pub struct UserStats {
+    counts: RwLock<HashMap<String, HashMap<String, AtomicU64>>>,
+}
+
+impl UserStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count one execution of `command` by `username`
+    ///
+    /// Names not in the command table all count as `UNKNOWN_COMMAND`, so
+    /// clients sending made-up names cannot grow the table.
+    pub fn record(&self, username: &str, command: &str) {
+        let command = match commands::lookup(command) {
+            Some(spec) => spec.name,
+            None => UNKNOWN_COMMAND,
+        };
+        // Fast path: existing counters only need the read lock
+        if let Some(counter) = self
+            .counts
+            .read()
+            .unwrap()
+            .get(username)
+            .and_then(|commands| commands.get(command))
+        {
+            counter.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        self.counts
+            .write()
+            .unwrap()
+            .entry(username.to_string())
+            .or_default()
+            .entry(command.to_string())
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Command counts for a user, sorted by command name
+    pub fn for_user(&self, username: &str) -> Vec<(String, u64)> {
+        let counts = self.counts.read().unwrap();
+        let mut result: Vec<(String, u64)> = counts
+            .get(username)
+            .map(|commands| {
+                commands
+                    .iter()
+                    .map(|(cmd, count)| (cmd.clone(), count.load(Ordering::Relaxed)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        result.sort();
+        result
+    }
 }
 
 #[cfg(test)]
@@ -250,7 +490,13 @@ mod tests {
 
     #[test]
     fn test_user_creation() {
-        let user = User::new("testuser".to_string(), "password123", UserRole::ReadWrite).unwrap();
+        let user = User::new(
+            "testuser".to_string(),
+            "password123",
+            UserRole::ReadWrite,
+            4,
+        )
+        .unwrap();
         assert_eq!(user.username, "testuser");
         assert!(user.verify_password("password123"));
         assert!(!user.verify_password("wrongpassword"));
@@ -258,9 +504,9 @@ mod tests {
 
     #[test]
     fn test_user_permissions() {
-        let admin = User::new("admin".to_string(), "pass", UserRole::Admin).unwrap();
-        let readwrite = User::new("rw".to_string(), "pass", UserRole::ReadWrite).unwrap();
-        let readonly = User::new("ro".to_string(), "pass", UserRole::ReadOnly).unwrap();
+        let admin = User::new("admin".to_string(), "pass", UserRole::Admin, 4).unwrap();
+        let readwrite = User::new("rw".to_string(), "pass", UserRole::ReadWrite, 4).unwrap();
+        let readonly = User::new("ro".to_string(), "pass", UserRole::ReadOnly, 4).unwrap();
 
         // Admin can do everything
         assert!(admin.can_execute("GET"));
@@ -281,7 +527,7 @@ mod tests {
     #[test]
     fn test_user_manager() {
         let dir = TempDir::new().unwrap();
-        let manager = UserManager::new(dir.path().to_str().unwrap()).unwrap();
+        let manager = UserManager::new(dir.path().to_str().unwrap(), 4).unwrap();
 
         // Default admin user should exist
         assert!(manager.authenticate("admin", "admin").is_some());
@@ -304,4 +550,81 @@ mod tests {
         assert!(manager.authenticate("testuser", "newpass").is_some());
         assert!(manager.authenticate("testuser", "testpass").is_none());
     }
+
+    #[test]
+    fn test_reload_picks_up_file_changes() {
+        let dir = TempDir::new().unwrap();
+        let data_dir = dir.path().to_str().unwrap();
+        let manager = UserManager::new(data_dir, 4).unwrap();
+
+        // Another tool rewrites users.json behind the running manager
+        let other = UserManager::new(data_dir, 4).unwrap();
+        other
+            .create_user("carol", "secret", UserRole::ReadOnly)
+            .unwrap();
+        other.change_password("admin", "rotated").unwrap();
+        assert!(manager.authenticate("carol", "secret").is_none());
+
+        manager.reload().unwrap();
+        let carol = manager.authenticate("carol", "secret").unwrap();
+        assert_eq!(carol.role, UserRole::ReadOnly);
+        assert!(manager.authenticate("admin", "admin").is_none());
+        assert!(manager.authenticate("admin", "rotated").is_some());
+
+        // A broken file is refused and the loaded users stay
+        fs::write(dir.path().join("users.json"), "not json").unwrap();
+        assert!(manager.reload().is_err());
+        fs::remove_file(dir.path().join("users.json")).unwrap();
+        assert!(manager.reload().is_err());
+        assert!(manager.authenticate("carol", "secret").is_some());
+    }
+
+    #[test]
+    fn test_login_upgrades_low_cost_hash() {
+        let dir = TempDir::new().unwrap();
+        let data_dir = dir.path().to_str().unwrap();
+        let old = UserManager::new(data_dir, 4).unwrap();
+        old.create_user("dave", "secret", UserRole::ReadWrite)
+            .unwrap();
+
+        let manager = UserManager::new(data_dir, 5).unwrap();
+        // A failed login leaves the hash alone
+        assert!(manager.authenticate("dave", "wrong").is_none());
+        assert_eq!(manager.users.read().unwrap()["dave"].hash_cost(), Some(4));
+
+        assert!(manager.authenticate("dave", "secret").is_some());
+        assert_eq!(manager.users.read().unwrap()["dave"].hash_cost(), Some(5));
+
+        // The upgraded hash was saved and still matches the password
+        let reopened = UserManager::new(data_dir, 5).unwrap();
+        assert_eq!(reopened.users.read().unwrap()["dave"].hash_cost(), Some(5));
+        assert!(reopened.authenticate("dave", "secret").is_some());
+
+        // A lower configured cost never downgrades
+        let lower = UserManager::new(data_dir, 4).unwrap();
+        assert!(lower.authenticate("dave", "secret").is_some());
+        assert_eq!(lower.users.read().unwrap()["dave"].hash_cost(), Some(5));
+    }
+
+    #[test]
+    fn test_user_stats() {
+        let stats = UserStats::new();
+        stats.record("alice", "GET");
+        stats.record("alice", "GET");
+        stats.record("alice", "SET");
+        stats.record(ANONYMOUS_USER, "GET");
+        stats.record("alice", "NOPE1");
+        stats.record("alice", "NOPE2");
+
+        assert_eq!(
+            stats.for_user("alice"),
+            vec![
+                (UNKNOWN_COMMAND.to_string(), 2),
+                ("GET".to_string(), 2),
+                ("SET".to_string(), 1)
+            ]
+        );
+        assert_eq!(stats.for_user(ANONYMOUS_USER), vec![("GET".to_string(), 1)]);
+        assert!(stats.for_user("bob").is_empty());
+    }
 }